@@ -1,28 +1,64 @@
 /// An abstraction over different ways to do DNS queries.
-use std::net::SocketAddr;
+use std::{
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream, UdpSocket},
+    time::timeout,
+};
 use trust_dns_client::{
     client::{AsyncClient, ClientFuture, ClientHandle},
+    op::{Message, ResponseCode},
     rr,
     tcp::TcpClientStream,
     udp::UdpClientStream,
 };
 use trust_dns_resolver::{
     config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
-    error::ResolveError,
+    error::{ResolveError, ResolveErrorKind},
     lookup, lookup_ip,
-    proto::{error::ProtoError, xfer::dns_request::DnsRequestOptions},
+    proto::{
+        error::{ProtoError, ProtoErrorKind},
+        iocompat::AsyncIoTokioAsStd,
+        tcp,
+        xfer::{
+            dns_request::DnsRequestOptions, BufDnsStreamHandle, DnsHandle, DnsRequest, DnsResponse,
+        },
+    },
+    system_conf::read_system_conf,
     TokioAsyncResolver,
 };
 
 pub use tokio::runtime::Runtime;
 
+/// A handle to the runtime, onto which backends spawn the background tasks of
+/// the clients they open.
 pub type RuntimeHandle = tokio::runtime::Handle;
 
+/// The preferred address family when resolving host names.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum AddressFamily {
+    /// Keep the addresses in the order returned by the resolver.
+    #[default]
+    Any,
+    /// Order IPv4 addresses before IPv6 addresses.
+    PreferIpv4,
+    /// Order IPv6 addresses before IPv4 addresses.
+    PreferIpv6,
+}
+
 #[async_trait]
-pub trait Resolver: Clone {
+pub trait Resolver: Clone + Sync {
     async fn lookup(
         &self,
         name: rr::Name,
@@ -31,6 +67,23 @@ pub trait Resolver: Clone {
     async fn lookup_ip(&self, host: rr::Name) -> Result<lookup_ip::LookupIp, ResolveError>;
     async fn lookup_soa(&self, name: rr::Name) -> Result<lookup::SoaLookup, ResolveError>;
     async fn lookup_ns(&self, name: rr::Name) -> Result<lookup::NsLookup, ResolveError>;
+
+    /// Returns all addresses of `host`, ordered according to `family`.
+    ///
+    /// Note that an empty result is not considered an error.
+    async fn lookup_addrs(
+        &self,
+        host: rr::Name,
+        family: AddressFamily,
+    ) -> Result<Vec<IpAddr>, ResolveError> {
+        let mut addrs: Vec<_> = self.lookup_ip(host).await?.iter().collect();
+        match family {
+            AddressFamily::Any => {}
+            AddressFamily::PreferIpv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            AddressFamily::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+        Ok(addrs)
+    }
 }
 
 #[async_trait]
@@ -56,21 +109,156 @@ impl Resolver for TokioAsyncResolver {
     }
 }
 
+/// A resolver that retries lookups failing with `SERVFAIL`.
+///
+/// Other failures, and negative answers in particular, are returned right
+/// away. A `NXDOMAIN` answer is authoritative, so retrying it would not
+/// change the outcome.
+#[derive(Debug, Clone)]
+pub struct ServfailRetry<R> {
+    pub resolver: R,
+    /// The number of retries after the initial lookup.
+    pub retries: u32,
+}
+
+impl<R> ServfailRetry<R> {
+    async fn run<F, Fut, T>(&self, mut lookup: F) -> Result<T, ResolveError>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T, ResolveError>> + Send,
+    {
+        let mut retries = self.retries;
+        loop {
+            match lookup().await {
+                Err(e) if retries > 0 && is_servfail(&e) => retries -= 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether the lookup failed because the server answered with `SERVFAIL`.
+pub fn is_servfail(e: &ResolveError) -> bool {
+    matches!(
+        e.kind(),
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::ServFail,
+            ..
+        }
+    )
+}
+
+#[async_trait]
+impl<R: Resolver> Resolver for ServfailRetry<R> {
+    async fn lookup(
+        &self,
+        name: rr::Name,
+        rtype: rr::RecordType,
+    ) -> Result<lookup::Lookup, ResolveError> {
+        self.run(|| self.resolver.lookup(name.clone(), rtype)).await
+    }
+
+    async fn lookup_ip(&self, host: rr::Name) -> Result<lookup_ip::LookupIp, ResolveError> {
+        self.run(|| self.resolver.lookup_ip(host.clone())).await
+    }
+
+    async fn lookup_soa(&self, name: rr::Name) -> Result<lookup::SoaLookup, ResolveError> {
+        self.run(|| self.resolver.lookup_soa(name.clone())).await
+    }
+
+    async fn lookup_ns(&self, name: rr::Name) -> Result<lookup::NsLookup, ResolveError> {
+        self.run(|| self.resolver.lookup_ns(name.clone())).await
+    }
+}
+
+// `ResolveError` is the resolver library's error type, as returned by its
+// lookups.
+#[allow(clippy::result_large_err)]
 #[async_trait]
 pub trait Backend: Clone {
     type Client: ClientHandle;
     type Resolver: Resolver;
     async fn open(
         &mut self,
-        runtime: &Runtime,
+        runtime: &RuntimeHandle,
         addr: SocketAddr,
     ) -> Result<Self::Client, ProtoError>;
     fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError>;
+    /// Opens a resolver that fails over between the given servers, e.g. when
+    /// one of them times out, or answers with SERVFAIL.
+    ///
+    /// The default implementation only uses the first address.
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        match addrs.first() {
+            Some(addr) => self.open_resolver(*addr),
+            None => Err(ResolveErrorKind::Message("no resolver address given").into()),
+        }
+    }
     fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError>;
 }
 
-#[derive(Debug, Clone)]
-pub struct TcpBackend;
+/// Settings for the resolvers opened by `TcpBackend` and `UdpBackend`.
+///
+/// Unset settings keep the defaults of the resolver library, or for the
+/// system resolver, the values from `/etc/resolv.conf`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ResolverSettings {
+    /// The time to wait for a response to each request.
+    pub timeout: Option<Duration>,
+    /// The number of attempts made for each query.
+    pub attempts: Option<usize>,
+    /// Whether to disable the cache, so that each lookup results in a
+    /// request.
+    pub no_cache: bool,
+}
+
+impl ResolverSettings {
+    fn apply(&self, opts: &mut ResolverOpts) {
+        if let Some(timeout) = self.timeout {
+            opts.timeout = timeout;
+        }
+        if let Some(attempts) = self.attempts {
+            opts.attempts = attempts;
+        }
+        if self.no_cache {
+            opts.cache_size = 0;
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn make_resolver(
+        &self,
+        addrs: &[SocketAddr],
+        protocol: Protocol,
+    ) -> Result<TokioAsyncResolver, ResolveError> {
+        let mut config = ResolverConfig::new();
+        // Negative answers are trusted, so only failures, including SERVFAIL,
+        // lead to the next server being tried.
+        for addr in addrs {
+            config.add_name_server(NameServerConfig {
+                socket_addr: *addr,
+                protocol,
+                tls_dns_name: None,
+                trust_nx_responses: true,
+            });
+        }
+        let mut opts = ResolverOpts::default();
+        self.apply(&mut opts);
+        TokioAsyncResolver::tokio(config, opts)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn make_system_resolver(&self) -> Result<TokioAsyncResolver, ResolveError> {
+        let (config, mut opts) = read_system_conf()?;
+        self.apply(&mut opts);
+        TokioAsyncResolver::tokio(config, opts)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TcpBackend {
+    pub resolver: ResolverSettings,
+}
 
 #[async_trait]
 impl Backend for TcpBackend {
@@ -79,10 +267,9 @@ impl Backend for TcpBackend {
 
     async fn open(
         &mut self,
-        runtime: &Runtime,
+        runtime: &RuntimeHandle,
         addr: SocketAddr,
     ) -> Result<Self::Client, ProtoError> {
-        use trust_dns_resolver::proto::iocompat::AsyncIoTokioAsStd;
         let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(addr);
         let (client, bg) = AsyncClient::new(Box::new(stream), sender, None).await?;
         runtime.spawn(bg);
@@ -90,16 +277,22 @@ impl Backend for TcpBackend {
     }
 
     fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
-        make_resolver(addr, Protocol::Tcp)
+        self.resolver.make_resolver(&[addr], Protocol::Tcp)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.make_resolver(addrs, Protocol::Tcp)
     }
 
     fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
-        TokioAsyncResolver::tokio_from_system_conf()
+        self.resolver.make_system_resolver()
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct UdpBackend;
+#[derive(Debug, Default, Clone)]
+pub struct UdpBackend {
+    pub resolver: ResolverSettings,
+}
 
 #[async_trait]
 impl Backend for UdpBackend {
@@ -108,7 +301,7 @@ impl Backend for UdpBackend {
 
     async fn open(
         &mut self,
-        runtime: &Runtime,
+        runtime: &RuntimeHandle,
         addr: SocketAddr,
     ) -> Result<Self::Client, ProtoError> {
         let stream = UdpClientStream::<UdpSocket>::new(addr);
@@ -118,21 +311,328 @@ impl Backend for UdpBackend {
     }
 
     fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
-        make_resolver(addr, Protocol::Udp)
+        self.resolver.make_resolver(&[addr], Protocol::Udp)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.make_resolver(addrs, Protocol::Udp)
+    }
+
+    fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.make_system_resolver()
+    }
+}
+
+/// A backend that opens resolvers using `R`, and clients for talking to
+/// authoritative servers using `C`.
+///
+/// This allows for using a different transport for each, e.g. UDP for
+/// recursive queries, and TCP for authoritative queries, whose answers may
+/// exceed the UDP message size.
+#[derive(Debug, Clone)]
+pub struct SplitBackend<R, C> {
+    pub resolver: R,
+    pub client: C,
+}
+
+#[async_trait]
+impl<R, C> Backend for SplitBackend<R, C>
+where
+    R: Backend + Send,
+    C: Backend + Send,
+{
+    type Client = C::Client;
+    type Resolver = R::Resolver;
+
+    async fn open(
+        &mut self,
+        runtime: &RuntimeHandle,
+        addr: SocketAddr,
+    ) -> Result<Self::Client, ProtoError> {
+        self.client.open(runtime, addr).await
+    }
+
+    fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.open_resolver(addr)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.open_resolvers(addrs)
+    }
+
+    fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
+        self.resolver.open_system_resolver()
+    }
+}
+
+/// A backend whose clients send each message with the ID it was built with,
+/// instead of assigning a random one, as the clients of the other backends
+/// do.
+///
+/// Each message is sent using a socket of its own, via TCP if `tcp` is set,
+/// and UDP otherwise. This is meant for debugging, e.g. for comparing captured
+/// packets with recorded wire data. Resolvers are opened using `inner`.
+#[derive(Debug, Clone)]
+pub struct FixedIdBackend<B> {
+    pub inner: B,
+    pub tcp: bool,
+}
+
+#[async_trait]
+impl<B> Backend for FixedIdBackend<B>
+where
+    B: Backend + Send,
+{
+    type Client = FixedIdClient;
+    type Resolver = B::Resolver;
+
+    async fn open(
+        &mut self,
+        _runtime: &RuntimeHandle,
+        addr: SocketAddr,
+    ) -> Result<Self::Client, ProtoError> {
+        Ok(FixedIdClient {
+            addr,
+            tcp: self.tcp,
+            source: None,
+        })
+    }
+
+    fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolver(addr)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolvers(addrs)
+    }
+
+    fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_system_resolver()
+    }
+}
+
+/// A backend whose clients send messages from the local address `source`,
+/// e.g. to choose the interface used on a multi-homed host.
+///
+/// Like with `FixedIdBackend`, each message is sent unchanged using a socket
+/// of its own, via TCP if `tcp` is set, and UDP otherwise. Resolvers are
+/// opened using `inner`, and do not use `source`.
+#[derive(Debug, Clone)]
+pub struct BoundBackend<B> {
+    pub inner: B,
+    pub tcp: bool,
+    pub source: IpAddr,
+}
+
+#[async_trait]
+impl<B> Backend for BoundBackend<B>
+where
+    B: Backend + Send,
+{
+    type Client = FixedIdClient;
+    type Resolver = B::Resolver;
+
+    async fn open(
+        &mut self,
+        _runtime: &RuntimeHandle,
+        addr: SocketAddr,
+    ) -> Result<Self::Client, ProtoError> {
+        if addr.is_ipv4() != self.source.is_ipv4() {
+            return Err(ProtoErrorKind::Msg(format!(
+                "cannot reach {} from source address {}",
+                addr, self.source
+            ))
+            .into());
+        }
+        Ok(FixedIdClient {
+            addr,
+            tcp: self.tcp,
+            source: Some(self.source),
+        })
+    }
+
+    fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolver(addr)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolvers(addrs)
+    }
+
+    fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_system_resolver()
+    }
+}
+
+/// A connected stream, as returned by the connector of a `ConnectorBackend`.
+pub trait DnsStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
+
+impl<T> DnsStream for T where T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
+
+/// The future returned by the connector of a `ConnectorBackend`.
+pub type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<Box<dyn DnsStream>>> + Send>>;
+
+/// A backend whose clients talk to the server over a stream obtained from
+/// `connect`, instead of dialing the server's address via TCP, e.g. to route
+/// DNS through a proxy or tunnel.
+///
+/// The connector is invoked with the address of the server whenever a client
+/// is opened, and the stream it returns is used like a TCP connection, i.e.,
+/// each message is prefixed with its length. Resolvers are opened using
+/// `inner`, and do not use the connector.
+#[derive(Clone)]
+pub struct ConnectorBackend<B> {
+    pub inner: B,
+    pub connect: Arc<dyn Fn(SocketAddr) -> ConnectFuture + Send + Sync>,
+}
+
+impl<B> ConnectorBackend<B> {
+    /// Creates a backend using the async closure `connect` to obtain the
+    /// stream for a server.
+    pub fn new<F, Fut, S>(inner: B, connect: F) -> Self
+    where
+        F: Fn(SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+        S: DnsStream,
+    {
+        ConnectorBackend {
+            inner,
+            connect: Arc::new(move |addr| {
+                let connecting = connect(addr);
+                Box::pin(async move {
+                    let stream = connecting.await?;
+                    Ok(Box::new(stream) as Box<dyn DnsStream>)
+                })
+            }),
+        }
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for ConnectorBackend<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectorBackend")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<B> Backend for ConnectorBackend<B>
+where
+    B: Backend + Send,
+{
+    type Client = AsyncClient;
+    type Resolver = B::Resolver;
+
+    async fn open(
+        &mut self,
+        runtime: &RuntimeHandle,
+        addr: SocketAddr,
+    ) -> Result<Self::Client, ProtoError> {
+        let stream = (self.connect)(addr).await?;
+        let (stream, sender) = tcp::TcpStream::from_stream(AsyncIoTokioAsStd(stream), addr);
+        let stream = TcpClientStream::from_stream(stream);
+        let sender = BufDnsStreamHandle::new(addr, sender);
+        let (client, bg) =
+            AsyncClient::new(futures::future::ok(stream), Box::new(sender), None).await?;
+        runtime.spawn(bg);
+        Ok(client)
+    }
+
+    fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolver(addr)
+    }
+
+    fn open_resolvers(&mut self, addrs: &[SocketAddr]) -> Result<Self::Resolver, ResolveError> {
+        self.inner.open_resolvers(addrs)
     }
 
     fn open_system_resolver(&mut self) -> Result<Self::Resolver, ResolveError> {
-        TokioAsyncResolver::tokio_from_system_conf()
+        self.inner.open_system_resolver()
+    }
+}
+
+/// The time to wait for a response to a message sent by a `FixedIdClient`.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client sending messages unchanged; see `FixedIdBackend` and
+/// `BoundBackend`.
+#[derive(Debug, Clone)]
+pub struct FixedIdClient {
+    addr: SocketAddr,
+    tcp: bool,
+    /// The local address to send from; unspecified if not given.
+    source: Option<IpAddr>,
+}
+
+impl DnsHandle for FixedIdClient {
+    type Response = Pin<Box<dyn Future<Output = Result<DnsResponse, ProtoError>> + Send>>;
+    type Error = ProtoError;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&mut self, request: R) -> Self::Response {
+        let (addr, tcp) = (self.addr, self.tcp);
+        let source = self.source.unwrap_or(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let (message, _) = request.into().into_parts();
+        Box::pin(async move {
+            let exchange = async {
+                if tcp {
+                    exchange_tcp(addr, source, &message).await
+                } else {
+                    exchange_udp(addr, source, &message).await
+                }
+            };
+            let response = timeout(EXCHANGE_TIMEOUT, exchange)
+                .await
+                .map_err(|_| ProtoError::from(ProtoErrorKind::Timeout))??;
+            Ok(response.into())
+        })
+    }
+}
+
+/// Sends `message` via UDP from the address `source`, returning the first
+/// response with a matching ID.
+async fn exchange_udp(
+    addr: SocketAddr,
+    source: IpAddr,
+    message: &Message,
+) -> Result<Message, ProtoError> {
+    let socket = UdpSocket::bind((source, 0)).await?;
+    socket.connect(addr).await?;
+    socket.send(&message.to_vec()?).await?;
+    let mut buf = vec![0; usize::from(u16::MAX)];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let response = Message::from_vec(&buf[..len])?;
+        if response.id() == message.id() {
+            return Ok(response);
+        }
     }
 }
 
-fn make_resolver(addr: SocketAddr, protocol: Protocol) -> Result<TokioAsyncResolver, ResolveError> {
-    let mut config = ResolverConfig::new();
-    config.add_name_server(NameServerConfig {
-        socket_addr: addr,
-        protocol,
-        tls_dns_name: None,
-        trust_nx_responses: true,
-    });
-    TokioAsyncResolver::tokio(config, ResolverOpts::default())
+/// Sends `message` via TCP from the address `source`, prefixed with its
+/// length, as per RFC 1035, section 4.2.2.
+async fn exchange_tcp(
+    addr: SocketAddr,
+    source: IpAddr,
+    message: &Message,
+) -> Result<Message, ProtoError> {
+    let socket = match source {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind((source, 0).into())?;
+    let mut stream = socket.connect(addr).await?;
+    let data = message.to_vec()?;
+    let len = u16::try_from(data.len())
+        .map_err(|_| ProtoErrorKind::Message("message too long for TCP"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&data).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut buf).await?;
+    Message::from_vec(&buf)
 }
@@ -0,0 +1,151 @@
+//! Reading batches of update operations from files.
+
+use std::{convert::TryFrom, io::Read};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use trust_dns_client::{
+    rr,
+    serialize::txt::{Lexer, Parser},
+};
+
+use crate::{
+    record::{RecordSet, RsData},
+    update::Operation,
+    update_message::NotInZone,
+    util,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonOp {
+    Create,
+    Append,
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonData {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl JsonData {
+    fn items(&self) -> Vec<&str> {
+        match self {
+            JsonData::Single(item) => vec![item.as_str()],
+            JsonData::Multiple(items) => items.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEntry {
+    op: JsonOp,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: Option<String>,
+    ttl: Option<u32>,
+    data: Option<JsonData>,
+}
+
+/// Reads a batch of operations from a JSON array of entries.
+///
+/// Each entry is an object with the fields `op` (one of `create`, `append` and
+/// `delete`), `name`, `type`, `ttl` and `data`, the latter being a single
+/// string or a list of strings. Only `op` and `name` are required for
+/// deletions; `ttl` is optional in any case. Names lacking a trailing dot are
+/// treated as fully qualified. All names must be within `zone`.
+pub fn read_json<R: Read>(
+    reader: R,
+    zone: &rr::Name,
+    dns_class: rr::DNSClass,
+) -> anyhow::Result<Operation> {
+    let entries: Vec<JsonEntry> = serde_json::from_reader(reader)?;
+    let entries = entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            json_batch_entry(entry, zone, dns_class)
+                .map_err(|e| anyhow!("invalid entry #{}: {}", i + 1, e))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Operation::Batch(entries))
+}
+
+fn json_batch_entry(
+    entry: JsonEntry,
+    zone: &rr::Name,
+    dns_class: rr::DNSClass,
+) -> anyhow::Result<Operation> {
+    let name = util::make_fqdn(&rr::Name::from_ascii(&entry.name)?, false)?;
+    if !zone.zone_of(&name) {
+        return Err(NotInZone::new(name, zone.clone()).into());
+    }
+    let items = entry.data.as_ref().map(JsonData::items).unwrap_or_default();
+    let rset = match &entry.record_type {
+        Some(rtype) => {
+            let rset = RecordSet::new(name.clone(), RsData::from_parts(rtype, items)?)
+                .with_class(dns_class);
+            match entry.ttl {
+                Some(ttl) => rset.with_ttl(ttl),
+                None => rset,
+            }
+        }
+        None if items.is_empty() => {
+            return match entry.op {
+                JsonOp::Delete => Ok(Operation::DeleteAll(name)),
+                _ => Err(anyhow!("missing type")),
+            };
+        }
+        None => return Err(anyhow!("data given without type")),
+    };
+    match entry.op {
+        JsonOp::Delete => Ok(Operation::Delete(rset)),
+        _ if rset.is_empty() => Err(anyhow!("missing data")),
+        JsonOp::Create => Ok(Operation::Create(rset)),
+        JsonOp::Append => Ok(Operation::Append(rset)),
+    }
+}
+
+/// Reads a batch of operations from records in master file format.
+///
+/// The records are grouped into RRsets, each of which is turned into an
+/// operation using `operation`, e.g. `Operation::Create`. Relative names are
+/// interpreted relative to `zone`, unless changed by an `$ORIGIN` directive.
+/// Records without an explicit TTL get the one given by the last `$TTL`
+/// directive, or `default_ttl` if there is none. As all records of an RRset
+/// share a TTL, an RRset whose records differ in that respect gets the lowest
+/// of them. All names must be within `zone`.
+pub fn read_zonefile(
+    text: &str,
+    zone: &rr::Name,
+    dns_class: rr::DNSClass,
+    default_ttl: u32,
+    operation: fn(RecordSet) -> Operation,
+) -> anyhow::Result<Operation> {
+    // The parser only knows about TTLs given in the file, so the default is
+    // provided as an initial `$TTL` directive.
+    let text = format!("$TTL {}\n{}", default_ttl, text);
+    let (_, rrsets) =
+        Parser::new().parse(Lexer::new(&text), Some(zone.clone()), Some(dns_class))?;
+    let entries = rrsets
+        .values()
+        .map(|rrset| {
+            let records: Vec<_> = rrset.records_without_rrsigs().cloned().collect();
+            let rset = RecordSet::try_from(records.as_slice())
+                .map_err(|e| anyhow!("invalid records for {}: {}", rrset.name(), e))?;
+            let rset = match records.iter().map(rr::Record::ttl).min() {
+                Some(ttl) => rset.with_ttl(ttl),
+                None => rset,
+            };
+            if !zone.zone_of(rset.name()) {
+                return Err(NotInZone::new(rset.name().clone(), zone.clone()).into());
+            }
+            Ok(operation(rset))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Operation::Batch(entries))
+}
@@ -1,26 +1,52 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     fs,
-    io::{BufRead, BufReader, Write},
+    io::{IsTerminal, Write},
     net::{IpAddr, SocketAddr},
-    path::{Path, PathBuf},
-    time::Duration,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
 };
+#[cfg(feature = "tsig")]
+use std::{io::BufReader, path::Path};
 
 use anyhow::anyhow;
-use data_encoding::BASE64;
-use futures::{future, StreamExt};
+use futures::{future, StreamExt, TryStreamExt};
 use structopt::StructOpt;
-use tokio::runtime::Runtime;
-use trust_dns_client::{proto::error::ProtoError, rr};
-use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use tokio::{
+    runtime::{self, Runtime},
+    time::sleep,
+};
+#[cfg(feature = "tsig")]
+use trust_dns_client::proto::xfer::DnsHandle;
+use trust_dns_client::{
+    op::{self, Message},
+    proto::error::ProtoError,
+    rr,
+};
+use trust_dns_resolver::{
+    config::ResolverOpts, error::ResolveError, system_conf::read_system_conf,
+};
 
 use tdns_cli::{
-    query::{self, perform_query, Query},
+    batch,
+    config::{Config, Transport},
+    query::{self, perform_query, InvalidRecordType, Query, QueryFailures},
     record::{RecordSet, RsData},
-    tsig,
-    update::{monitor_update, perform_update, Expectation, Monitor, Operation, Update},
-    util, Backend, TcpBackend, UdpBackend,
+    serial::{poll_serials, SerialWatch, Serials},
+    update::{
+        authoritative_servers, find_zone, monitor_update, monitor_update_with_progress,
+        perform_update, print_poll_event, resolve_master, ConcurrentChange, CurrentValueMismatch,
+        Expectation, Monitor, MonitorEvent, MonitorMetrics, MonitorTimeout, NameserverWarning,
+        Operation, PollEvent, TriesExhausted, Update, UpdateNotApplied, UpdateOutcome,
+        UpdateRejected,
+    },
+    util, Backend, BoundBackend, FixedIdBackend, ResolverSettings, ServfailRetry, SplitBackend,
+    TcpBackend, UdpBackend,
 };
+#[cfg(feature = "tsig")]
+use tdns_cli::{tsig, update_message};
 
 /// DNS client utilities
 #[allow(clippy::large_enum_variant)]
@@ -30,39 +56,371 @@ enum Tdns {
     Update(UpdateOpt),
     /// Issue DNS queries
     Query(QueryOpt),
+    /// Send a DNS message read from a file
+    Send(SendOpt),
+    /// Check that a server accepts a TSIG key
+    #[cfg(feature = "tsig")]
+    TsigTest(TsigTestOpt),
+    /// Watch the SOA serials of a zone's nameservers
+    SerialWatch(SerialWatchOpt),
+}
+
+impl Tdns {
+    fn common(&self) -> &CommonOpt {
+        match self {
+            Tdns::Update(opt) => &opt.common,
+            Tdns::Query(opt) => &opt.common,
+            Tdns::Send(opt) => &opt.common,
+            #[cfg(feature = "tsig")]
+            Tdns::TsigTest(opt) => &opt.common,
+            Tdns::SerialWatch(opt) => &opt.common,
+        }
+    }
+
+    /// Returns the context given in JSON error messages: the server or first
+    /// resolver used, and the zone to update, if known.
+    fn error_context(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut context = serde_json::Map::new();
+        let server = match self {
+            Tdns::Query(opt) => opt.server.as_ref(),
+            Tdns::Send(opt) => Some(&opt.server),
+            Tdns::Update(opt) => opt.server.as_ref(),
+            #[cfg(feature = "tsig")]
+            Tdns::TsigTest(opt) => Some(&opt.server),
+            Tdns::SerialWatch(opt) => opt.server.as_ref(),
+        };
+        if let Some(server) = server {
+            context.insert("server".into(), server.to_string().into());
+        }
+        if let Some(resolver) = self.common().resolver.first() {
+            context.insert("resolver".into(), resolver.to_string().into());
+        }
+        let zone = match self {
+            Tdns::Update(opt) => opt.zone.as_ref(),
+            #[cfg(feature = "tsig")]
+            Tdns::TsigTest(opt) => Some(&opt.zone),
+            Tdns::SerialWatch(opt) => Some(&opt.zone),
+            _ => None,
+        };
+        if let Some(zone) = zone {
+            context.insert("zone".into(), zone.to_string().into());
+        }
+        context
+    }
+
+    /// Fills in the options not given on the command line from `config`.
+    fn apply_config(&mut self, config: &Config) {
+        match self {
+            Tdns::Update(opt) => {
+                opt.common.apply_config(config);
+                // Without TSIG support, a key file in the config file is
+                // ignored, so the same config can be used with any build.
+                #[cfg(feature = "tsig")]
+                if opt.key_file.is_none() {
+                    opt.key_file = config.key_file.clone();
+                }
+                opt.timeout = opt.timeout.or(config.timeout);
+                opt.interval = opt.interval.or(config.interval);
+            }
+            Tdns::Query(opt) => {
+                opt.common.apply_config(config);
+                opt.common_types = config.common_types.clone();
+            }
+            Tdns::Send(opt) => opt.common.apply_config(config),
+            Tdns::SerialWatch(opt) => opt.common.apply_config(config),
+            #[cfg(feature = "tsig")]
+            Tdns::TsigTest(opt) => {
+                opt.common.apply_config(config);
+                if opt.key_file.is_none() {
+                    opt.key_file = config.key_file.clone();
+                }
+            }
+        }
+    }
 }
 
 #[derive(StructOpt)]
 struct CommonOpt {
     /// Specify the recusor to use, including the port number.
     ///
-    /// If not specified, the first nameserver specified in `/etc/resolv.conf`
-    /// is used.
+    /// May be given multiple times, in which case the next resolver is tried
+    /// if one fails. If not specified, the address given in the
+    /// `TDNS_RESOLVER` environment variable is used, if set, then the
+    /// resolvers given in the config file. Otherwise, the first nameserver
+    /// specified in `/etc/resolv.conf` is used.
+    #[structopt(long, env = "TDNS_RESOLVER", number_of_values = 1)]
+    resolver: Vec<util::SocketName>,
+    /// The resolver to use if none is specified, and the system
+    /// configuration lists none, e.g. as `/etc/resolv.conf` is missing.
     #[structopt(long)]
-    resolver: Option<SocketAddr>,
+    fallback_resolver: Option<util::SocketName>,
     /// Use TCP for all DNS requests.
     #[structopt(long)]
     tcp: bool,
+    /// Use UDP for DNS requests, even if the config file specifies TCP.
+    #[structopt(long, conflicts_with = "tcp")]
+    udp: bool,
+    /// Timeout in seconds for each request sent by the resolver.
+    #[structopt(long)]
+    resolver_timeout: Option<u64>,
+    /// The number of attempts the resolver makes for each query.
+    #[structopt(long)]
+    resolver_attempts: Option<usize>,
+    /// Disable the resolver's cache.
+    #[structopt(long)]
+    no_cache: bool,
+    /// Read defaults from this config file instead of
+    /// `~/.config/tdns/config.toml`.
+    #[structopt(long, env = "TDNS_CONFIG", conflicts_with = "no-config")]
+    config: Option<PathBuf>,
+    /// Do not read any config file.
+    #[structopt(long)]
+    no_config: bool,
+    /// The format of the message written on failure, `text` or `json`.
+    #[structopt(long, default_value = "text")]
+    error_format: ErrorFormat,
+}
+
+impl CommonOpt {
+    fn read_config(&self) -> anyhow::Result<Config> {
+        match &self.config {
+            _ if self.no_config => Ok(Config::default()),
+            Some(path) => Config::read(path),
+            None => Config::read_default(),
+        }
+    }
+
+    fn resolver_settings(&self) -> anyhow::Result<ResolverSettings> {
+        if self.resolver_timeout == Some(0) {
+            return Err(anyhow!("--resolver-timeout must be positive"));
+        }
+        if self.resolver_attempts == Some(0) {
+            return Err(anyhow!("--resolver-attempts must be positive"));
+        }
+        Ok(ResolverSettings {
+            timeout: self.resolver_timeout.map(Duration::from_secs),
+            attempts: self.resolver_attempts,
+            no_cache: self.no_cache,
+        })
+    }
+
+    fn apply_config(&mut self, config: &Config) {
+        if self.resolver.is_empty() {
+            self.resolver = config.resolver.clone();
+        }
+        if self.fallback_resolver.is_none() {
+            self.fallback_resolver = config.fallback_resolver.clone();
+        }
+        if !self.udp && config.transport == Some(Transport::Tcp) {
+            self.tcp = true;
+        }
+        // The fallback takes the place of the system resolver, both for
+        // lookups via the resolver library and for queries sent directly.
+        if let (true, Some(fallback)) = (self.resolver.is_empty(), &self.fallback_resolver) {
+            if let Err(e) = util::get_system_resolvers() {
+                eprintln!("warning: {}; using fallback resolver {}", e, fallback);
+                self.resolver = vec![fallback.clone()];
+            }
+        }
+    }
+}
+
+/// A record type given with `--type`, or the `common` pseudo-type.
+#[derive(Debug, Clone, Copy)]
+enum QueryType {
+    Type(rr::RecordType),
+    Common,
+}
+
+impl FromStr for QueryType {
+    type Err = InvalidRecordType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "COMMON" | "ALL" => Ok(QueryType::Common),
+            _ => match query::parse_record_type(s) {
+                Ok(rtype) => Ok(QueryType::Type(rtype)),
+                Err(error) => Err(InvalidRecordType {
+                    name: s.to_owned(),
+                    error,
+                }),
+            },
+        }
+    }
+}
+
+/// The format of the message written when a command fails.
+#[derive(Debug, Clone, Copy)]
+enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(anyhow!("unknown error format, expected 'text' or 'json'")),
+        }
+    }
+}
+
+/// The format of the file written by `--metrics-file`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricsFormat {
+    Prometheus,
+    Json,
+}
+
+impl FromStr for MetricsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prometheus" => Ok(MetricsFormat::Prometheus),
+            "json" => Ok(MetricsFormat::Json),
+            _ => Err(anyhow!(
+                "unknown metrics format, expected 'prometheus' or 'json'"
+            )),
+        }
+    }
 }
 
 // This is just so that `structopt` does not treat options of this type as
-// taking multiple arguments.
-type RTypes = Vec<rr::RecordType>;
+// taking multiple arguments. Unknown types are kept along with the parse
+// error, so that they can be skipped with a warning.
+type RTypes = Vec<Result<QueryType, InvalidRecordType>>;
 
-fn parse_rtypes(s: &str) -> Result<RTypes, ProtoError> {
-    let s = s.to_uppercase();
-    util::parse_comma_separated(&s)
+fn parse_rtypes(s: &str) -> RTypes {
+    s.to_uppercase().split(',').map(str::parse).collect()
 }
 
 #[derive(StructOpt)]
 struct QueryOpt {
     #[structopt(flatten)]
     common: CommonOpt,
-    entry: rr::Name,
-    #[structopt(long = "type", short = "t", parse(try_from_str = parse_rtypes))]
+    /// Server to send the queries to, overriding --resolver.
+    #[structopt(long)]
+    server: Option<util::SocketName>,
+    #[structopt(required_unless = "chaos")]
+    entry: Option<rr::Name>,
+    /// The record types to query for, separated by commas.
+    ///
+    /// The pseudo-type `common` (or `all`) stands for a list of commonly used
+    /// types, which can be changed in the config file.
+    #[structopt(long = "type", short = "t", parse(from_str = parse_rtypes))]
     record_types: Option<RTypes>,
+    /// Fail if any record type given with --type is unknown, instead of
+    /// skipping it with a warning.
+    #[structopt(long)]
+    strict: bool,
+    #[structopt(skip)]
+    common_types: Option<Vec<rr::RecordType>>,
+    /// The output format, e.g. `zone`, optionally overridden for specific
+    /// record types, as in `zone,A=short`.
     #[structopt(long = "fmt", short = "f")]
-    display_format: Option<query::DisplayFormat>,
+    display_format: Option<query::DisplayFormats>,
+    /// Show only the record data, like `--fmt short`.
+    #[structopt(long, short, conflicts_with = "display-format")]
+    short: bool,
+    /// Precede the record data with the TTL in short format.
+    #[structopt(long)]
+    ttl: bool,
+    /// Display this TTL for all records instead of their own, e.g. to
+    /// produce reproducible zone files from a resolver's cached records.
+    #[structopt(long, parse(try_from_str = util::parse_ttl))]
+    reset_ttl: Option<u32>,
+    /// Use the search list from `/etc/resolv.conf` for names that are not
+    /// fully qualified.
+    #[structopt(long)]
+    search: bool,
+    /// With --search, names with more dots than this are tried verbatim
+    /// first; defaults to the `ndots` option in `/etc/resolv.conf`.
+    #[structopt(long, requires = "search")]
+    ndots: Option<usize>,
+    /// Write the response message in wire format to this file.
+    #[structopt(long)]
+    raw_output: Option<PathBuf>,
+    /// Require the queried name to be fully qualified, i.e., to have a
+    /// trailing dot.
+    #[structopt(long)]
+    fqdn: bool,
+    /// Display owner names relative to this origin in zone format.
+    #[structopt(long)]
+    origin: Option<rr::Name>,
+    /// Stop at the first failed query.
+    #[structopt(long)]
+    fail_fast: bool,
+    /// Retry lookups failing with SERVFAIL up to this many times before
+    /// counting them as failed. NXDOMAIN answers are never retried.
+    #[structopt(long, value_name = "n", conflicts_with_all = &["trace", "authoritative"])]
+    retry_servfail: Option<u32>,
+    /// Resolve the targets of ANAME records to addresses.
+    #[structopt(long)]
+    flatten_aname: bool,
+    /// Show the addresses given as hints in SVCB and HTTPS records.
+    #[structopt(long)]
+    resolve_hints: bool,
+    /// Clear the RD (recursion desired) flag, querying the resolver directly.
+    #[structopt(long)]
+    norecurse: bool,
+    /// Request DNSSEC records by setting the DO (DNSSEC OK) flag.
+    #[structopt(long)]
+    dnssec: bool,
+    /// Set the CD (checking disabled) flag, so that a validating resolver
+    /// returns data even if it fails validation.
+    #[structopt(long)]
+    cd: bool,
+    /// The class to query.
+    #[structopt(long, default_value = "IN")]
+    class: rr::DNSClass,
+    /// Query the CH class, with TXT as default type. Without a name, query
+    /// the names servers identify themselves by, e.g. `version.bind`.
+    #[structopt(long, conflicts_with = "trace")]
+    chaos: bool,
+    /// Pad queries with the EDNS padding option to a multiple of this size
+    /// in bytes, e.g. 128, and show whether responses are padded.
+    #[structopt(long, value_name = "block-size")]
+    pad: Option<u16>,
+    /// Follow the delegation of the name from the root zone, showing the
+    /// response of each zone's nameserver.
+    #[structopt(
+        long,
+        conflicts_with_all = &["server", "search", "raw-output", "norecurse", "dnssec", "cd"]
+    )]
+    trace: bool,
+    /// Count queries without any records as failed.
+    #[structopt(long)]
+    empty_is_error: bool,
+    /// The response sections to show: 'answer' or 'all'.
+    #[structopt(long, default_value = "answer")]
+    sections: query::Sections,
+    /// Annotate each answer with the address of the server that provided it.
+    #[structopt(long)]
+    show_server: bool,
+    /// Write the query and response messages as hex dumps readable by
+    /// `text2pcap`, the queries to stderr and the responses to stdout,
+    /// instead of showing the records.
+    #[structopt(long, conflicts_with_all = &["trace", "chaos", "raw-output"])]
+    hexdump: bool,
+    /// Only validate the arguments and the resolver configuration, and show
+    /// how the queries would be performed, without sending any.
+    #[structopt(long, alias = "no-op")]
+    check_only: bool,
+    /// Query each authoritative nameserver of the name's zone directly,
+    /// instead of the resolver, annotating the answers with the server.
+    #[structopt(
+        long,
+        conflicts_with_all = &["server", "search", "raw-output", "trace", "hexdump"]
+    )]
+    authoritative: bool,
+    /// Use this message ID for the queries, instead of a random one. This
+    /// is meant for debugging.
+    #[structopt(long, hidden = true)]
+    id: Option<u16>,
 }
 
 impl QueryOpt {
@@ -83,20 +441,203 @@ impl QueryOpt {
         })
     }
 
-    fn to_query(&self) -> Query {
-        let record_types = self
-            .record_types
-            .as_ref()
-            .map(|cs| cs.to_vec())
-            .unwrap_or_else(|| vec![rr::RecordType::A]);
-        Query {
-            entry: self.entry.clone(),
-            display_format: Self::get_display_format(self.display_format, &record_types),
-            record_types,
+    /// Checks the record types given with `--type`, returning the number of
+    /// unknown ones, which are skipped with a warning unless `--strict` is
+    /// given.
+    fn check_types(&self) -> anyhow::Result<usize> {
+        let types = self.record_types.as_deref().unwrap_or(&[]);
+        let skipped = query::skipped_record_types(types, self.strict)?;
+        for invalid in &skipped {
+            eprintln!(
+                "warning: skipping record type {}: {}",
+                invalid.name, invalid.error
+            );
+        }
+        Ok(skipped.len())
+    }
+
+    fn check_names(&self) -> anyhow::Result<()> {
+        // Relative names are qualified in `Query::names`, taking the search
+        // list into account, so this only needs to enforce `--fqdn`.
+        if let Some(entry) = &self.entry {
+            util::make_fqdn(entry, self.fqdn)?;
+        }
+        Ok(())
+    }
+
+    /// Wraps `resolver` so that it retries lookups as per `--retry-servfail`.
+    fn servfail_retry<R>(&self, resolver: R) -> ServfailRetry<R> {
+        ServfailRetry {
+            resolver,
+            retries: self.retry_servfail.unwrap_or(0),
+        }
+    }
+
+    fn get_class(&self) -> rr::DNSClass {
+        if self.chaos {
+            rr::DNSClass::CH
+        } else {
+            self.class
+        }
+    }
+
+    /// Whether the queries are sent to the server directly, instead of
+    /// going through the resolver library, as needed for options the latter
+    /// does not support.
+    fn sends_directly(&self, query: &Query) -> bool {
+        // The authoritative servers are queried directly in any case, but
+        // only their answer records are shown.
+        !self.authoritative
+            && (self.raw_output.is_some()
+                || self.hexdump
+                || self.norecurse
+                || self.dnssec
+                || self.cd
+                || self.sections == query::Sections::All
+                || self.id.is_some()
+                || query.dns_class != rr::DNSClass::IN
+                || query.pad_block.is_some()
+                || query.uses_format(query::DisplayFormat::JsonRfc8427))
+    }
+
+    fn to_query(&self, entry: &rr::Name) -> anyhow::Result<Query> {
+        if self.pad == Some(0) {
+            return Err(anyhow!("the --pad block size must be positive"));
         }
+        let common_types = self.common_types.as_deref().unwrap_or(query::COMMON_TYPES);
+        let mut record_types = Vec::new();
+        for qtype in self.record_types.iter().flatten().flatten() {
+            let expanded = match qtype {
+                QueryType::Type(rtype) => std::slice::from_ref(rtype),
+                QueryType::Common => common_types,
+            };
+            // Types given more than once, e.g. via `common,A`, are only
+            // queried once.
+            for rtype in expanded {
+                if !record_types.contains(rtype) {
+                    record_types.push(*rtype);
+                }
+            }
+        }
+        if self.record_types.is_none() {
+            record_types.push(if self.chaos {
+                rr::RecordType::TXT
+            } else {
+                rr::RecordType::A
+            });
+        }
+        let (search, ndots) = if self.search {
+            let (config, opts) = read_system_conf()?;
+            let search = config.search().iter().chain(config.domain());
+            (search.cloned().collect(), self.ndots.unwrap_or(opts.ndots))
+        } else {
+            (Vec::new(), ResolverOpts::default().ndots)
+        };
+        // `--short` conflicts with `--fmt`, so there is no precedence to
+        // decide on.
+        let formats = if self.short {
+            query::DisplayFormats {
+                default: Some(query::DisplayFormat::Short),
+                ..Default::default()
+            }
+        } else {
+            self.display_format.clone().unwrap_or_default()
+        };
+        // The default format is chosen based on the types it applies to.
+        let default_types: Vec<_> = record_types
+            .iter()
+            .copied()
+            .filter(|rtype| !formats.by_type.contains_key(rtype))
+            .collect();
+        Ok(Query {
+            entry: entry.clone(),
+            display_format: Self::get_display_format(formats.default, &default_types),
+            type_formats: formats.by_type,
+            record_types,
+            search,
+            ndots,
+            origin: self.origin.clone(),
+            recursion_desired: !self.norecurse,
+            dnssec_ok: self.dnssec,
+            checking_disabled: self.cd,
+            show_ttl: self.ttl,
+            reset_ttl: self.reset_ttl,
+            message_id: self.id,
+            dns_class: self.get_class(),
+            pad_block: self.pad,
+        })
     }
 }
 
+#[derive(StructOpt)]
+struct SendOpt {
+    #[structopt(flatten)]
+    common: CommonOpt,
+    /// File containing the DNS message in wire format.
+    #[structopt(long)]
+    message: PathBuf,
+    /// Server to send the message to.
+    #[structopt(long)]
+    server: util::SocketName,
+    /// Write the response message in wire format to this file.
+    #[structopt(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct SerialWatchOpt {
+    #[structopt(flatten)]
+    common: CommonOpt,
+    /// The zone whose nameservers to watch.
+    zone: rr::Name,
+    /// The server to compare the serials to, instead of the primary master
+    /// named in the SOA record.
+    #[structopt(long)]
+    server: Option<util::SocketName>,
+    /// The number of seconds to wait between polls.
+    #[structopt(long, default_value = "10")]
+    interval: u64,
+    /// Stop after this many polls, instead of polling until interrupted.
+    #[structopt(long)]
+    count: Option<u64>,
+    /// Fail if a nameserver is behind by more than this many serial numbers
+    /// in the last poll.
+    #[structopt(long, requires = "count")]
+    max_lag: Option<u32>,
+    /// Excluded IP address.
+    #[structopt(long)]
+    exclude: Option<IpAddr>,
+    /// Show warnings about the nameserver lookup.
+    #[structopt(long, short)]
+    verbose: bool,
+}
+
+#[cfg(feature = "tsig")]
+#[derive(StructOpt)]
+struct TsigTestOpt {
+    #[structopt(flatten)]
+    common: CommonOpt,
+    /// Server to send the signed request to.
+    #[structopt(long)]
+    server: util::SocketName,
+    /// Zone to query the SOA record of, or to send the empty update for.
+    #[structopt(long)]
+    zone: rr::Name,
+    /// Send an empty update instead of an SOA query, to check that the key
+    /// may be used to update the zone.
+    #[structopt(long)]
+    update: bool,
+    /// TSIG key in NAME:ALGORITHM:BASE64-DATA notation, or just NAME when used
+    /// in combination with --key-file.
+    #[structopt(long)]
+    key: Option<String>,
+    #[structopt(long)]
+    key_file: Option<PathBuf>,
+    /// Select the key from --key-file whose name matches the zone.
+    #[structopt(long)]
+    key_for_zone: bool,
+}
+
 #[derive(StructOpt)]
 struct UpdateOpt {
     #[structopt(flatten)]
@@ -107,27 +648,66 @@ struct UpdateOpt {
     timeout: Option<u64>,
     #[structopt(long)]
     server: Option<util::SocketName>,
+    /// The zone to update; by default, it is determined by looking up the SOA
+    /// record of the entry and its ancestors.
     #[structopt(long)]
     zone: Option<rr::Name>,
+    /// Without --zone, assume the zone is the parent of the entry, instead of
+    /// looking it up.
+    #[structopt(long)]
+    no_soa_walk: bool,
     /// Entry to update and/or monitor.
-    entry: rr::Name,
+    entry: Option<rr::Name>,
+    /// Require all names to be fully qualified, i.e., to have a trailing dot.
+    #[structopt(long)]
+    fqdn: bool,
     /// RRset for update and/or monitoring.
     rs_data: Option<RsData>,
     /// TSIG key in NAME:ALGORITHM:BASE64-DATA notation, or just NAME when used
     /// in combination with --key-file.
+    #[cfg(feature = "tsig")]
     #[structopt(long)]
     key: Option<String>,
+    #[cfg(feature = "tsig")]
     #[structopt(long)]
     key_file: Option<PathBuf>,
+    /// Select the key from --key-file whose name matches the zone.
+    #[cfg(feature = "tsig")]
+    #[structopt(long)]
+    key_for_zone: bool,
     /// Excluded IP address.
     #[structopt(long)]
     exclude: Option<IpAddr>,
-    /// The TTL for added records.
-    #[structopt(long)]
+    /// The TTL for added records, in seconds or with units like '1h30m'.
+    #[structopt(long, parse(try_from_str = util::parse_ttl))]
     ttl: Option<u32>,
+    /// The class of the records to update.
+    #[structopt(long, default_value = "IN")]
+    class: rr::DNSClass,
+    /// Use the TTL of the existing RRset when appending.
+    #[structopt(long)]
+    match_ttl: bool,
+    /// Skip appending if all the records are already present.
+    #[structopt(long)]
+    skip_if_present: bool,
     /// Do not perform the update.
     #[structopt(long)]
     no_op: bool,
+    /// Use this message ID for the update, instead of a random one. This is
+    /// meant for debugging.
+    #[structopt(long, hidden = true)]
+    id: Option<u16>,
+    /// Allow deleting all NS records at the zone apex, which makes the zone
+    /// unresolvable.
+    #[structopt(long)]
+    force: bool,
+    /// After creating or appending, check that the server the update was
+    /// sent to has applied it.
+    #[structopt(long)]
+    verify: bool,
+    /// Perform deletions without asking for confirmation.
+    #[structopt(long, short)]
+    yes: bool,
     /// Delete matching records.
     #[structopt(long)]
     delete: bool,
@@ -139,80 +719,194 @@ struct UpdateOpt {
     /// Ensures that no records for the added types exist.
     #[structopt(long)]
     create: bool,
+    /// Make the RRset consist of exactly the specified records, adding and
+    /// deleting only the records that differ.
+    #[structopt(long)]
+    ensure: bool,
+    /// Only update if the RRset currently consists of exactly these records.
+    #[structopt(long)]
+    require_current: Option<RsData>,
+    /// Read the records to create, append or delete from this JSON file.
+    #[structopt(long)]
+    json_file: Option<PathBuf>,
+    /// Read the records to create or append from this file in master file
+    /// format.
+    #[structopt(long)]
+    zonefile: Option<PathBuf>,
+    /// Use TCP for requests to authoritative nameservers only.
+    #[structopt(long)]
+    auth_tcp: bool,
     /// Do not monitor nameservers for the update.
     #[structopt(long)]
     no_wait: bool,
-    /// Show informational messages during execution.
-    #[structopt(long, short)]
-    verbose: bool,
+    /// Start monitoring concurrently with sending the update.
+    #[structopt(long)]
+    monitor_during_update: bool,
+    /// Show informational messages during execution; when given twice, also
+    /// show the answers of the nameservers.
+    #[structopt(long, short, parse(from_occurrences))]
+    verbose: u8,
     /// The number of seconds to wait between checking.
     #[structopt(long)]
     interval: Option<u64>,
+    /// Let the interval between checks grow up to this number of seconds.
+    #[structopt(long)]
+    interval_max: Option<u64>,
+    /// The factor by which the interval grows after each check, if
+    /// --interval-max is given.
+    #[structopt(long, default_value = "2")]
+    backoff_factor: f64,
+    /// Give up on a nameserver after this many checks without a match.
+    #[structopt(long)]
+    tries: Option<u32>,
+    /// The number of seconds after which to determine the nameservers anew.
+    #[structopt(long)]
+    ns_refresh: Option<u64>,
+    /// Maximum number of nameservers to poll simultaneously.
+    #[structopt(long)]
+    poll_concurrency: Option<usize>,
+    /// Timeout in seconds for the lookups of the zone's SOA and NS records.
+    #[structopt(long)]
+    lookup_timeout: Option<u64>,
+    /// The number of times to retry a failed SOA or NS lookup.
+    #[structopt(long)]
+    lookup_retries: Option<u32>,
+    /// Which address of the primary master to use: 'first', 'random', or an index.
+    #[structopt(long, default_value = "first")]
+    master_addr_select: util::AddrSelect,
+    /// Write monitoring metrics in Prometheus text format to this file.
+    #[structopt(long)]
+    metrics_file: Option<PathBuf>,
+    /// The format of the --metrics-file, `prometheus` or `json`.
+    #[structopt(long, default_value = "prometheus")]
+    metrics_format: MetricsFormat,
+    /// Send the update and the polls from this local address.
+    #[structopt(long)]
+    source: Option<IpAddr>,
 }
 
 impl UpdateOpt {
+    fn get_entry(&self) -> anyhow::Result<&rr::Name> {
+        self.entry
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing ENTRY argument"))
+    }
+
     fn get_rset(&self) -> anyhow::Result<RecordSet> {
         let rs_data = self
             .rs_data
             .clone()
             .ok_or_else(|| anyhow!("Missing RS-DATA argument"))?;
-        Ok(RecordSet::new(self.entry.clone(), rs_data))
+        Ok(RecordSet::new(self.get_entry()?.clone(), rs_data).with_class(self.class))
     }
 
     fn get_operation(&self) -> anyhow::Result<Option<Operation>> {
-        let op_flags = &[self.create, self.delete, self.append];
+        let op_flags = &[self.create, self.delete, self.append, self.ensure];
+        let from_file = self.json_file.is_some() || self.zonefile.is_some();
+        if from_file && (self.entry.is_some() || self.require_current.is_some()) {
+            return Err(anyhow!(
+                "--json-file and --zonefile cannot be combined with an entry or --require-current"
+            ));
+        }
+        if let Some(json_file) = &self.json_file {
+            if op_flags.iter().any(|&flag| flag) || self.zonefile.is_some() {
+                return Err(anyhow!("--json-file cannot be combined with an operation"));
+            }
+            let file = fs::File::open(json_file)?;
+            return Ok(Some(batch::read_json(file, &self.get_zone()?, self.class)?));
+        }
+        if let Some(zonefile) = &self.zonefile {
+            let operation: fn(RecordSet) -> Operation = match op_flags {
+                [true, false, false, false] => Operation::Create,
+                [false, false, true, false] => Operation::Append,
+                _ => return Err(anyhow!("--zonefile requires either --create or --append")),
+            };
+            let text = fs::read_to_string(zonefile)?;
+            return Ok(Some(batch::read_zonefile(
+                &text,
+                &self.get_zone()?,
+                self.class,
+                self.ttl.unwrap_or(3600),
+                operation,
+            )?));
+        }
         let operation = match op_flags.iter().filter(|&&flag| flag).count() {
             0 => return Ok(None),
             1 => match op_flags.iter().position(|flag| *flag).unwrap() {
                 0 => Operation::Create(self.get_rset()?),
                 1 => match &self.rs_data {
-                    Some(rs_data) => {
-                        Operation::Delete(RecordSet::new(self.entry.clone(), rs_data.clone()))
-                    }
-                    None => Operation::DeleteAll(self.entry.clone()),
+                    Some(rs_data) => Operation::Delete(
+                        RecordSet::new(self.get_entry()?.clone(), rs_data.clone())
+                            .with_class(self.class),
+                    ),
+                    None => Operation::DeleteAll(self.get_entry()?.clone()),
                 },
                 2 => Operation::Append(self.get_rset()?),
+                3 => Operation::Ensure(self.get_rset()?),
                 _ => unreachable!(),
             },
             _ => return Err(anyhow!("Conflicting operations specified")),
         };
+        if self.require_current.is_some() && self.create {
+            return Err(anyhow!(
+                "--require-current cannot be combined with --create"
+            ));
+        }
         Ok(Some(operation))
     }
 
+    /// Makes all names given fully qualified.
+    ///
+    /// With `--fqdn`, relative names are rejected, otherwise they are treated
+    /// as if given with a trailing dot, so that they are never subject to the
+    /// resolver's search list.
+    fn qualify_names(&mut self) -> anyhow::Result<()> {
+        if let Some(entry) = &self.entry {
+            self.entry = Some(util::make_fqdn(entry, self.fqdn)?);
+        }
+        if let Some(zone) = &self.zone {
+            self.zone = Some(util::make_fqdn(zone, self.fqdn)?);
+        }
+        if let Some(server) = &self.server {
+            self.server = Some(server.to_fqdn(self.fqdn)?);
+        }
+        Ok(())
+    }
+
+    fn get_lookup_retry(&self) -> util::Retry {
+        let default = util::Retry::default();
+        util::Retry {
+            timeout: self
+                .lookup_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.timeout),
+            attempts: self
+                .lookup_retries
+                .map(|n| n.saturating_add(1))
+                .unwrap_or(default.attempts),
+        }
+    }
+
+    fn get_zone(&self) -> anyhow::Result<rr::Name> {
+        match (&self.zone, &self.entry) {
+            (Some(zone), _) => Ok(zone.clone()),
+            (None, Some(entry)) => Ok(entry.base_name()),
+            (None, None) => Err(anyhow!("--zone is required when no ENTRY is given")),
+        }
+    }
+
+    #[cfg(feature = "tsig")]
     fn get_tsig_key(&self) -> anyhow::Result<Option<tsig::Key>> {
-        if let Some(key) = &self.key {
-            let parts: Vec<_> = key.split(':').collect();
-            match parts.len() {
-                1 => {
-                    let key_name = parts[0].parse()?;
-                    if let Some(file_name) = &self.key_file {
-                        Ok(Some(read_key(file_name, Some(&key_name))?))
-                    } else {
-                        Err(anyhow!("--key-file option required with --key=NAME"))
-                    }
-                }
-                3 => {
-                    let (name, algo, data) = (parts[0], parts[1], parts[2]);
-                    Ok(Some(tsig::Key::new(
-                        name.parse()?,
-                        tsig::Algorithm::from_name(&algo.parse()?)?,
-                        BASE64.decode(data.as_bytes())?,
-                    )))
-                }
-                _ => Err(anyhow!(
-                    "expected NAME or NAME:ALGORITHM:KEY, found {}",
-                    key
-                )),
-            }
-        } else if let Some(key_file) = &self.key_file {
-            Ok(Some(read_key(key_file, None)?))
+        let zone = if self.key_for_zone {
+            Some(self.get_zone()?)
         } else {
-            Ok(None)
-        }
+            None
+        };
+        get_tsig_key(self.key.as_deref(), self.key_file.as_deref(), zone.as_ref())
     }
 
     fn to_update(&self) -> anyhow::Result<Option<Update>> {
-        let zone = self.zone.clone().unwrap_or_else(|| self.entry.base_name());
+        let zone = self.get_zone()?;
         if self.no_op {
             return Ok(None);
         }
@@ -223,22 +917,46 @@ impl UpdateOpt {
             },
             server: self.server.clone(),
             zone,
+            #[cfg(feature = "tsig")]
             tsig_key: self.get_tsig_key()?,
             ttl: self.ttl.unwrap_or(3600),
+            dns_class: self.class,
+            match_ttl: self.match_ttl,
+            skip_if_present: self.skip_if_present,
+            lookup_retry: self.get_lookup_retry(),
+            master_addr_select: self.master_addr_select,
+            require_current: match &self.require_current {
+                Some(rs_data) => Some(
+                    RecordSet::new(self.get_entry()?.clone(), rs_data.clone())
+                        .with_class(self.class),
+                ),
+                None => None,
+            },
+            verify: self.verify,
+            force: self.force,
+            message_id: self.id,
         }))
     }
 
     fn to_monitor(&self) -> anyhow::Result<Option<Monitor>> {
-        let zone = self.zone.clone().unwrap_or_else(|| self.entry.base_name());
-        if self.no_wait {
+        // Monitoring is restricted to a single entry, so updates read from a
+        // file are not monitored.
+        if self.no_wait || self.json_file.is_some() || self.zonefile.is_some() {
             return Ok(None);
         }
+        let zone = self.get_zone()?;
+        if !(self.backoff_factor >= 1.0 && self.backoff_factor.is_finite()) {
+            return Err(anyhow!("--backoff-factor must be at least 1"));
+        }
+        if self.tries == Some(0) {
+            return Err(anyhow!("--tries must be positive"));
+        }
         Ok(Some(Monitor {
             zone,
-            entry: self.entry.clone(),
+            entry: self.get_entry()?.clone(),
             expectation: match self.get_operation()? {
                 None => Expectation::Is(self.get_rset()?),
-                Some(Operation::Create(rset)) => Expectation::Is(rset),
+                Some(Operation::Create(rset) | Operation::Ensure(rset)) => Expectation::Is(rset),
                 Some(Operation::Append(rset)) => Expectation::Contains(rset),
                 Some(Operation::Delete(rset)) => {
                     if rset.is_empty() {
@@ -248,139 +966,1300 @@ impl UpdateOpt {
                     }
                 }
                 Some(Operation::DeleteAll(_)) => Expectation::Empty(rr::RecordType::ANY),
+                Some(Operation::Batch(_)) => unreachable!(),
             },
             exclude: self.exclude.into_iter().collect(),
+            dns_class: self.class,
             interval: Duration::from_secs(self.interval.unwrap_or(1)),
+            interval_max: self.interval_max.map(Duration::from_secs),
+            backoff_factor: self.backoff_factor,
             timeout: Duration::from_secs(self.timeout.unwrap_or(60)),
-            verbose: self.verbose,
+            tries: self.tries,
+            verbose: self.verbose > 0,
+            ns_refresh: self.ns_refresh.map(Duration::from_secs),
+            poll_concurrency: self.poll_concurrency,
+            source: self.source,
+            lookup_retry: self.get_lookup_retry(),
         }))
     }
 }
 
-/// Reads a TSIG key from a file.
+#[cfg(feature = "tsig")]
+/// Determines the TSIG key given via `--key` and `--key-file`; with
+/// `--key-for-zone`, `key_for_zone` is the zone whose key is selected.
 ///
-/// If `key_name` is `None`, the first key will be returned, otherwise the first
-/// key matching `key_name` will be returned. When no matching key was found, or
-/// the file could not be parsed, an error will be returned.
-fn read_key(path: &Path, key_name: Option<&rr::Name>) -> anyhow::Result<tsig::Key> {
-    let file = fs::File::open(path)?;
-    let input = BufReader::new(file);
-    for line in input.lines() {
-        let line = line?;
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
+/// Only the selected entry of the key file needs to be valid; other invalid
+/// entries are skipped with a warning.
+fn get_tsig_key(
+    key: Option<&str>,
+    key_file: Option<&Path>,
+    key_for_zone: Option<&rr::Name>,
+) -> anyhow::Result<Option<tsig::Key>> {
+    let spec: Option<tsig::KeySpec> = key.map(str::parse).transpose()?;
+    // The key file is not consulted when the key itself is given.
+    let file = match (&spec, key_file) {
+        (Some(tsig::KeySpec::Key(_)), _) | (_, None) => None,
+        (_, Some(path)) => Some(read_key_file(path)?),
+    };
+    let key = tsig::get_key(spec, file.as_ref(), key_for_zone).map_err(|e| {
+        let path = match key_file {
+            Some(path) => path.display(),
+            None => return anyhow::Error::from(e),
+        };
+        match &e {
+            // The line number follows the path, as in compiler messages.
+            tsig::GetKeyError::Select(tsig::SelectKeyError::Invalid { .. }) => {
+                anyhow!("{}:{}", path, e)
+            }
+            tsig::GetKeyError::Select(_) | tsig::GetKeyError::NoZoneKey { .. } => {
+                anyhow!("{}: {}", path, e)
+            }
+            _ => e.into(),
         }
-        let parts: Vec<_> = line.split(':').collect();
-        if parts.len() != 3 {
-            return Err(anyhow!(
-                "invalid line in key file; expected NAME:ALGORITHM:KEY, found {}",
-                line
-            ));
+    })?;
+    if let (Some(file), Some(path)) = (&file, key_file) {
+        for (line_no, e) in file.invalid_entries() {
+            eprintln!(
+                "warning: skipping invalid key {}:{}: {}",
+                path.display(),
+                line_no,
+                e
+            );
         }
-        let name = parts[0].parse()?;
-        if key_name.is_none() || Some(&name) == key_name {
-            let (algo, data) = (parts[1], parts[2]);
-            return Ok(tsig::Key::new(
-                name,
-                tsig::Algorithm::from_name(&algo.parse()?)?,
-                BASE64.decode(data.as_bytes())?,
-            ));
+    }
+    Ok(key)
+}
+
+#[cfg(feature = "tsig")]
+/// Reads the entries of a TSIG key file; see `tsig::read_keys` for its format.
+fn read_key_file(path: &Path) -> anyhow::Result<tsig::KeyFile> {
+    let file = fs::File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    tsig::KeyFile::read(BufReader::new(file)).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+/// Determines the address of a server given as resolver or query target.
+///
+/// Host names are resolved using the system resolver.
+async fn resolve_server<D: Backend>(
+    mut dns: D,
+    server: Option<&util::SocketName>,
+) -> anyhow::Result<Option<SocketAddr>> {
+    match server {
+        None => Ok(None),
+        Some(util::SocketName::SocketAddr(addr)) => Ok(Some(*addr)),
+        Some(util::SocketName::IpAddr(ip)) => Ok(Some(SocketAddr::new(*ip, 53))),
+        Some(name) => {
+            let resolver = dns.open_system_resolver().map_err(|e| {
+                anyhow!(
+                    "could not read the system resolver configuration to resolve {}: {}; \
+                     give its address instead",
+                    name,
+                    e
+                )
+            })?;
+            Ok(Some(name.to_fqdn(false)?.resolve(resolver, 53).await?))
         }
     }
-    if let Some(key_name) = key_name {
-        Err(anyhow!("key {} not found in {}", key_name, path.display()))
-    } else {
-        Err(anyhow!("no key found in {}", path.display()))
+}
+
+async fn open_resolver<D: Backend + 'static>(
+    mut dns: D,
+    servers: &[util::SocketName],
+) -> anyhow::Result<D::Resolver> {
+    if servers.is_empty() {
+        // The resolver library accepts a configuration without nameservers,
+        // only to fail each lookup with an obscure error.
+        util::get_system_resolvers().map_err(system_resolver_error)?;
+        return dns.open_system_resolver().map_err(|e| {
+            anyhow!(
+                "could not read the system resolver configuration: {}; \
+                 use --resolver or --fallback-resolver to specify one",
+                e
+            )
+        });
     }
+    let mut addrs = Vec::with_capacity(servers.len());
+    for server in servers {
+        addrs.extend(resolve_server(dns.clone(), Some(server)).await?);
+    }
+    Ok(dns.open_resolvers(&addrs)?)
 }
 
-fn open_resolver<D: Backend + 'static>(
+/// Opens a resolver for each of the addresses of `servers`, or of the system
+/// configuration's nameservers, if `servers` is empty.
+async fn open_resolvers_by_addr<D: Backend + 'static>(
     mut dns: D,
-    addr: Option<SocketAddr>,
-) -> Result<D::Resolver, ResolveError> {
-    if let Some(addr) = addr {
-        Ok(dns.open_resolver(addr)?)
+    servers: &[util::SocketName],
+) -> anyhow::Result<Vec<(SocketAddr, D::Resolver)>> {
+    let addrs = if servers.is_empty() {
+        util::get_system_resolvers().map_err(system_resolver_error)?
     } else {
-        Ok(dns.open_system_resolver()?)
+        let mut addrs = Vec::with_capacity(servers.len());
+        for server in servers {
+            addrs.extend(resolve_server(dns.clone(), Some(server)).await?);
+        }
+        addrs
+    };
+    addrs
+        .into_iter()
+        .map(|addr| Ok((addr, dns.open_resolver(addr)?)))
+        .collect()
+}
+
+fn system_resolver_error(e: util::SystemResolverError) -> anyhow::Error {
+    anyhow!(
+        "could not determine the system resolver: {}; \
+         use --resolver or --fallback-resolver to specify one",
+        e
+    )
+}
+
+/// Writes the server annotation for `--show-server`, alongside the records in
+/// zone format, and to stderr otherwise, like the flags.
+fn write_server(format: query::DisplayFormat, server: SocketAddr) -> std::io::Result<()> {
+    match format {
+        query::DisplayFormat::Zone => query::write_server(&mut std::io::stdout(), server),
+        query::DisplayFormat::Short
+        | query::DisplayFormat::Hosts
+        | query::DisplayFormat::JsonRfc8427 => query::write_server(&mut std::io::stderr(), server),
+    }
+}
+
+/// Writes whether a response is padded, like the server annotation.
+fn write_padding(format: query::DisplayFormat, response: &Message) -> std::io::Result<()> {
+    match format {
+        query::DisplayFormat::Zone => query::write_padding(&mut std::io::stdout(), response),
+        query::DisplayFormat::Short
+        | query::DisplayFormat::Hosts
+        | query::DisplayFormat::JsonRfc8427 => {
+            query::write_padding(&mut std::io::stderr(), response)
+        }
     }
 }
 
-async fn run_update<D: Backend + 'static>(
+/// Writes the note that TTLs were reset, if they were and are shown at all;
+/// like the server annotation, this goes to stdout only in zone format.
+fn write_ttl_reset(query: &Query) -> std::io::Result<()> {
+    match query.reset_ttl {
+        Some(ttl) if query.uses_format(query::DisplayFormat::Zone) => {
+            query::write_ttl_reset(&mut std::io::stdout(), ttl)
+        }
+        Some(ttl) if query.show_ttl && query.uses_format(query::DisplayFormat::Short) => {
+            query::write_ttl_reset(&mut std::io::stderr(), ttl)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes `record` on a line of its own, unless the display format of
+/// `query` skips it.
+fn write_record_line<W: Write>(
+    writer: &mut W,
+    query: &Query,
+    record: &rr::Record,
+) -> std::io::Result<()> {
+    if query.format_for(record.record_type()).shows(record) {
+        query.write_record(writer, record)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+async fn run_update<D: Backend + Send + 'static>(
     runtime: &Runtime,
     dns: D,
-    opt: UpdateOpt,
+    mut opt: UpdateOpt,
 ) -> anyhow::Result<()> {
-    let resolver = open_resolver(dns.clone(), opt.common.resolver)?;
-    if let Some(update) = opt.to_update()? {
-        perform_update(runtime, dns.clone(), resolver.clone(), update).await?;
+    opt.qualify_names()?;
+    let resolver = open_resolver(dns.clone(), &opt.common.resolver).await?;
+    if let (None, Some(entry), false) = (&opt.zone, &opt.entry, opt.no_soa_walk) {
+        // The result is kept in `opt`, so that both the update and
+        // monitoring use it.
+        opt.zone = Some(find_zone(&resolver, entry).await?);
+    }
+    let report = |outcome| match (outcome, &opt.entry) {
+        (UpdateOutcome::AlreadyPresent, Some(entry)) => {
+            println!("{}: records already present; update skipped", entry);
+        }
+        (UpdateOutcome::Ensured(changes), Some(entry)) => {
+            if changes.is_empty() {
+                println!(
+                    "{}: records already in desired state; update skipped",
+                    entry
+                );
+                return;
+            }
+            let mut summary = Vec::new();
+            if !changes.added.is_empty() {
+                summary.push(format!("added {}", changes.added.data()));
+            }
+            if !changes.removed.is_empty() {
+                summary.push(format!("removed {}", changes.removed.data()));
+            }
+            println!("{}: {}", entry, summary.join(", "));
+        }
+        _ => {}
+    };
+    let mut update = opt.to_update()?;
+    if let Some(update) = &mut update {
+        // Resolve the server up front, so that a fallback to one of the
+        // zone's nameservers can be pointed out before confirming and
+        // sending the update.
+        let master = resolve_master(resolver.clone(), update).await?;
+        if let Some(fallback) = master.fallback {
+            eprintln!("warning: {}", fallback);
+        }
+        if !opt.yes {
+            confirm_deletions(update, master.addr)?;
+        }
+        update.server = Some(util::SocketName::SocketAddr(master.addr));
     }
-    if let Some(monitor) = opt.to_monitor()? {
-        monitor_update(runtime, dns, resolver, monitor).await?;
+    match (update, opt.to_monitor()?) {
+        (Some(update), Some(monitor)) if opt.monitor_during_update => {
+            let (outcome, ()) = future::try_join(
+                perform_update(runtime, dns.clone(), resolver.clone(), update),
+                run_monitor(runtime, dns, resolver, monitor, &opt),
+            )
+            .await?;
+            report(outcome);
+        }
+        (update, monitor) => {
+            if let Some(update) = update {
+                report(perform_update(runtime, dns.clone(), resolver.clone(), update).await?);
+            }
+            if let Some(monitor) = monitor {
+                run_monitor(runtime, dns, resolver, monitor, &opt).await?;
+            }
+        }
     }
     Ok(())
 }
 
-async fn run_query<D: Backend + 'static>(dns: D, opt: QueryOpt) -> anyhow::Result<()> {
-    let resolver = open_resolver(dns.clone(), opt.common.resolver)?;
-    let query = opt.to_query();
-    let (n_failed, total) = perform_query(resolver, query.clone())
-        .fold((0_usize, 0_usize), |(n_failed, total), item| {
-            let mut stdout = std::io::stdout();
-            let success = match item {
-                Ok(records) => {
-                    for record in records {
-                        query::write_record(&mut stdout, &record, query.display_format).unwrap();
-                        stdout.write_all(b"\n").unwrap();
+/// Asks for confirmation on the terminal if `update`, to be sent to `master`,
+/// deletes any records.
+///
+/// When standard input is not a terminal, deletions are refused, so that they
+/// must be confirmed upfront via `--yes` in scripts.
+fn confirm_deletions(update: &Update, master: SocketAddr) -> anyhow::Result<()> {
+    fn collect(operation: &Operation, deletions: &mut Vec<String>) {
+        match operation {
+            Operation::Delete(rset) => deletions.push(rset.to_string()),
+            Operation::DeleteAll(name) => deletions.push(format!("{} (all records)", name)),
+            Operation::Batch(entries) => {
+                for entry in entries {
+                    collect(entry, deletions);
+                }
+            }
+            // The records deleted to ensure an RRset are only determined
+            // when performing the update; the desired state is given
+            // explicitly, so they are not confirmed.
+            Operation::Create(_) | Operation::Append(_) | Operation::Ensure(_) => {}
+        }
+    }
+    let mut deletions = Vec::new();
+    collect(&update.operation, &mut deletions);
+    if deletions.is_empty() {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "refusing to delete records without confirmation; use --yes when not \
+             running interactively"
+        ));
+    }
+    let mut stderr = std::io::stderr();
+    match &update.server {
+        Some(util::SocketName::HostName(name, _)) => writeln!(
+            stderr,
+            "Deleting from zone {} via {} ({}):",
+            update.zone, name, master
+        )?,
+        _ => writeln!(stderr, "Deleting from zone {} via {}:", update.zone, master)?,
+    }
+    for deletion in &deletions {
+        writeln!(stderr, "  {}", deletion)?;
+    }
+    write!(stderr, "Proceed? [y/N] ")?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(anyhow!("update aborted")),
+    }
+}
+
+/// Monitors the update, writing metrics to the `--metrics-file`, if given.
+///
+/// The metrics are written even if monitoring fails, e.g. due to a timeout.
+async fn run_monitor<D: Backend + Send + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    resolver: D::Resolver,
+    monitor: Monitor,
+    opt: &UpdateOpt,
+) -> anyhow::Result<()> {
+    let show_answers = opt.verbose > 1;
+    let path = match opt.metrics_file.as_deref() {
+        Some(path) => path,
+        None if !show_answers => {
+            monitor_update(runtime, dns, resolver, monitor).await?;
+            return Ok(());
+        }
+        None => {
+            let expectation = monitor.expectation.clone();
+            monitor_update_with_progress(runtime, dns, resolver, monitor, |event| match event {
+                MonitorEvent::Warning(warning) => print_nameserver_warning(warning, true),
+                MonitorEvent::Poll(event) => {
+                    print_poll_event(event, &expectation);
+                    print_poll_answer(event);
+                }
+                MonitorEvent::Nameservers(_) => {}
+            })
+            .await?;
+            return Ok(());
+        }
+    };
+    let (zone, entry) = (monitor.zone.clone(), monitor.entry.clone());
+    let verbose = monitor.verbose;
+    let expectation = monitor.expectation.clone();
+    let metrics = RefCell::new(MonitorMetrics::default());
+    let result = monitor_update_with_progress(runtime, dns, resolver, monitor, |event| {
+        let event = match event {
+            MonitorEvent::Warning(warning) => return print_nameserver_warning(warning, verbose),
+            // Include nameservers that never answer, so their absence of
+            // convergence shows up in the metrics.
+            MonitorEvent::Nameservers(servers) => return metrics.borrow_mut().seed(servers),
+            MonitorEvent::Poll(event) => event,
+        };
+        if verbose {
+            print_poll_event(event, &expectation);
+        }
+        if show_answers {
+            print_poll_answer(event);
+        }
+        metrics.borrow_mut().record(event);
+    })
+    .await;
+    // Write to a temporary file first, so that collectors never see a
+    // partially written file.
+    let mut contents = Vec::new();
+    match opt.metrics_format {
+        MetricsFormat::Prometheus => {
+            metrics
+                .borrow()
+                .write_prometheus(&mut contents, &zone, &entry)?
+        }
+        MetricsFormat::Json => {
+            serde_json::to_writer_pretty(&mut contents, &metrics.borrow().to_json(&zone, &entry))?;
+            contents.push(b'\n');
+        }
+    }
+    // Appending to the file name keeps a target named `*.tmp` intact.
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, contents)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| anyhow!("could not write metrics to {}: {}", path.display(), e))?;
+    result.map(|_| ())
+}
+
+/// Prints a warning about determining the nameservers of a zone; some of them
+/// are only shown in verbose mode.
+fn print_nameserver_warning(warning: &NameserverWarning, verbose: bool) {
+    if verbose || !warning.is_verbose() {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+/// Prints the answer records of a poll, including their TTLs.
+fn print_poll_answer(event: &PollEvent) {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for record in &event.records {
+        let _ = stdout
+            .write_all(b"  ")
+            .and_then(|_| {
+                query::write_record(&mut stdout, record, query::DisplayFormat::Zone, None)
+            })
+            .and_then(|_| stdout.write_all(b"\n"));
+    }
+}
+
+async fn run_query<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    opt: QueryOpt,
+) -> anyhow::Result<()> {
+    opt.check_names()?;
+    let n_skipped = opt.check_types()?;
+    let entry = match &opt.entry {
+        Some(entry) => entry,
+        None => return run_chaos(runtime, dns, &opt).await,
+    };
+    let query = opt.to_query(entry)?;
+    for &rtype in &query.record_types {
+        use rr::RecordType::{A, AAAA};
+        if query.format_for(rtype) == query::DisplayFormat::Hosts && rtype != A && rtype != AAAA {
+            eprintln!(
+                "warning: {} records are not shown in hosts format",
+                query::record_type_name(rtype)
+            );
+        }
+    }
+    if opt.check_only {
+        return check_query(dns, &query, query.names(), &opt, n_skipped).await;
+    }
+    if opt.trace {
+        return run_trace(runtime, dns, query, &opt, n_skipped).await;
+    }
+    let servers = match &opt.server {
+        Some(server) => std::slice::from_ref(server),
+        None => &opt.common.resolver,
+    };
+    if opt.sends_directly(&query) {
+        // Only the first resolver is used, as the query is sent directly.
+        let addr = resolve_server(dns.clone(), servers.first()).await?;
+        return run_message_query(runtime, dns, addr, query, &opt, n_skipped).await;
+    }
+    let resolver = open_resolver(dns.clone(), servers).await?;
+    if let (Some(origin), true) = (&query.origin, query.uses_format(query::DisplayFormat::Zone)) {
+        query::write_origin(&mut std::io::stdout(), origin)?;
+    }
+    write_ttl_reset(&query)?;
+    let mut total = query.record_types.len();
+    let mut n_failed = 0;
+    let mut results = if opt.authoritative {
+        let name = &query.names()[0];
+        let zone = find_zone(&resolver, name).await?;
+        let authoritative =
+            authoritative_servers(runtime, dns.clone(), resolver.clone(), &zone, &[]).await?;
+        for warning in &authoritative.warnings {
+            print_nameserver_warning(warning, false);
+        }
+        let servers: Vec<_> = authoritative
+            .servers
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        // Each server answers for each record type.
+        total = servers.len() * query.record_types.len();
+        query::perform_query_direct(runtime, dns.clone(), servers, query.clone()).boxed_local()
+    } else if opt.show_server {
+        // The resolver does not tell which of its servers has answered, so
+        // each server gets a resolver of its own.
+        let resolvers = open_resolvers_by_addr(dns.clone(), servers)
+            .await?
+            .into_iter()
+            .map(|(addr, resolver)| (addr, opt.servfail_retry(resolver)))
+            .collect();
+        query::perform_query_via(resolvers, query.clone()).boxed_local()
+    } else {
+        perform_query(opt.servfail_retry(resolver.clone()), query.clone()).boxed_local()
+    };
+    let mut stdout = std::io::stdout();
+    while let Some(item) = results.next().await {
+        match item {
+            Ok(result) => {
+                if let Some(server) = result.server {
+                    write_server(query.format_for(result.record_type), server)?;
+                }
+                if let Some(negative) = &result.negative {
+                    eprintln!(";; {}", negative);
+                    if opt.empty_is_error {
+                        n_failed += 1;
+                        if opt.fail_fast {
+                            break;
+                        }
                     }
-                    true
+                    continue;
                 }
-                Err(e) => match e.kind() {
-                    ResolveErrorKind::NoRecordsFound { .. } => true,
-                    _ => {
-                        eprintln!("error response for query: {}", e);
-                        false
+                for record in result.records {
+                    write_record_line(&mut stdout, &query, &record)?;
+                    if opt.resolve_hints {
+                        for hint in query::svcb_hints(&record) {
+                            write_record_line(&mut stdout, &query, &hint)?;
+                        }
                     }
-                },
-            };
-            future::ready((n_failed + if success { 0 } else { 1 }, total + 1))
-        })
-        .await;
-    if n_failed > 0 {
-        return Err(anyhow!("{}/{} queries failed", n_failed, total,));
+                    if let (true, Some(target)) = (opt.flatten_aname, record.rdata().as_aname()) {
+                        match query::flatten_aname(resolver.clone(), target).await {
+                            Ok(flattened) => {
+                                for record in flattened {
+                                    write_record_line(&mut stdout, &query, &record)?;
+                                }
+                            }
+                            Err(e) => eprintln!("could not flatten ANAME {}: {}", target, e),
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                // A remaining `NoRecordsFound` error carries a failure
+                // response code, such as `SERVFAIL`.
+                if let Some(negative) = query::NegativeAnswer::from_error(&e) {
+                    eprintln!(";; {}", negative);
+                } else {
+                    eprintln!("error response for query: {}", e);
+                }
+                n_failed += 1;
+                if opt.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+    QueryFailures::check(n_failed, total, n_skipped)?;
+    Ok(())
+}
+
+/// Shows how the queries would be performed, for `--check-only`.
+///
+/// The resolver is opened, which validates its configuration, but no queries
+/// are sent; hence servers given as host names are not resolved.
+async fn check_query<D: Backend + 'static>(
+    mut dns: D,
+    query: &Query,
+    names: Vec<rr::Name>,
+    opt: &QueryOpt,
+    n_skipped: usize,
+) -> anyhow::Result<()> {
+    let servers = match &opt.server {
+        Some(server) => std::slice::from_ref(server),
+        None => &opt.common.resolver,
+    };
+    let mut addrs = Vec::with_capacity(servers.len());
+    for server in servers {
+        match server {
+            util::SocketName::HostName(..) => {}
+            _ => addrs.extend(resolve_server(dns.clone(), Some(server)).await?),
+        }
+    }
+    let servers: Vec<_> = if servers.is_empty() {
+        let addrs = util::get_system_resolvers().map_err(system_resolver_error)?;
+        dns.open_system_resolver()?;
+        addrs.iter().map(ToString::to_string).collect()
+    } else {
+        if !addrs.is_empty() {
+            dns.open_resolvers(&addrs)?;
+        }
+        servers.iter().map(ToString::to_string).collect()
+    };
+    let via = if opt.trace {
+        "delegation from the root zone".to_owned()
+    } else if opt.authoritative {
+        "authoritative nameservers of the zone, without recursion".to_owned()
+    } else if opt.entry.is_none() || opt.sends_directly(query) {
+        format!(
+            "{} directly, with recursion {}",
+            servers[0],
+            if query.recursion_desired {
+                "desired"
+            } else {
+                "not desired"
+            }
+        )
+    } else if opt.server.is_none() && opt.common.resolver.is_empty() {
+        format!("system resolver {}", servers.join(", "))
+    } else {
+        format!("resolver {}", servers.join(", "))
+    };
+    let names: Vec<_> = names.iter().map(ToString::to_string).collect();
+    let types: Vec<_> = query
+        .record_types
+        .iter()
+        .map(|rtype| query::record_type_name(*rtype))
+        .collect();
+    let mut formats = vec![query.display_format.to_string()];
+    for (rtype, format) in &query.type_formats {
+        formats.push(format!("{}={}", query::record_type_name(*rtype), format));
+    }
+    let mut stdout = std::io::stdout();
+    writeln!(stdout, "names: {}", names.join(", "))?;
+    writeln!(stdout, "types: {}", types.join(", "))?;
+    writeln!(stdout, "class: {}", query.dns_class)?;
+    writeln!(stdout, "format: {}", formats.join(", "))?;
+    writeln!(stdout, "via: {}", via)?;
+    if n_skipped > 0 {
+        return Err(anyhow!(
+            "{} of the given record types are unknown and would be skipped",
+            n_skipped
+        ));
+    }
+    Ok(())
+}
+
+/// Queries the `TXT` records in the `CH` class by which servers identify
+/// themselves, writing each one labeled with its name.
+///
+/// As servers usually provide only some of them, this only fails if none of
+/// the names yields any records.
+async fn run_chaos<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    opt: &QueryOpt,
+) -> anyhow::Result<()> {
+    if opt.record_types.is_some() {
+        return Err(anyhow!("--type requires a name to query with --chaos"));
+    }
+    if opt.check_only {
+        let names = query::CHAOS_NAMES
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        let query = opt.to_query(&names[0])?;
+        return check_query(dns, &query, names, opt, 0).await;
+    }
+    let server = opt.server.as_ref().or_else(|| opt.common.resolver.first());
+    let addr = match resolve_server(dns.clone(), server).await? {
+        Some(addr) => addr,
+        None => util::get_system_resolver().map_err(system_resolver_error)?,
+    };
+    let mut stdout = std::io::stdout();
+    let mut n_answered = 0;
+    for name in query::CHAOS_NAMES {
+        let name: rr::Name = name.parse()?;
+        let query = opt.to_query(&name)?;
+        let responses: Vec<_> = query::perform_raw_query(runtime, dns.clone(), addr, query)
+            .try_collect()
+            .await?;
+        let response = &responses[0];
+        if response.answers().is_empty() {
+            match query::NegativeAnswer::from_response(response) {
+                Some(negative) => eprintln!(";; {}", negative),
+                None => eprintln!(";; no records for {}", name),
+            }
+            continue;
+        }
+        n_answered += 1;
+        for record in response.answers() {
+            write!(stdout, "{}: ", name.to_string().trim_end_matches('.'))?;
+            query::write_record(&mut stdout, record, query::DisplayFormat::Short, None)?;
+            writeln!(stdout)?;
+        }
+    }
+    if n_answered == 0 {
+        let total = query::CHAOS_NAMES.len();
+        return Err(QueryFailures {
+            n_failed: total,
+            total,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Follows the delegation of the queried name from the root zone, writing the
+/// response received from the nameserver of each zone.
+async fn run_trace<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    query: Query,
+    opt: &QueryOpt,
+    n_skipped: usize,
+) -> anyhow::Result<()> {
+    let rtype = match query.record_types.as_slice() {
+        [rtype] => *rtype,
+        _ => return Err(anyhow!("--trace requires querying a single record type")),
+    };
+    // Referrals are only useful in zone format, so it is used unless another
+    // format is given explicitly.
+    let format = if opt.display_format.is_none() && !opt.short {
+        query::DisplayFormat::Zone
+    } else {
+        query.format_for(rtype)
+    };
+    let resolver = open_resolver(dns.clone(), &opt.common.resolver).await?;
+    let name = query.names().remove(0);
+    let mut stdout = std::io::stdout();
+    let mut written = Ok(());
+    let write_step = |stdout: &mut std::io::Stdout, step: &query::TraceStep| {
+        if format == query::DisplayFormat::JsonRfc8427 {
+            return writeln!(stdout, "{}", query::message_json(&step.response)?);
+        }
+        query::write_trace_step(stdout, step)?;
+        query::write_sections(stdout, &step.response, format, query.origin.as_ref())
+    };
+    let response = query::trace(runtime, dns, resolver, name, rtype, |step| {
+        if written.is_ok() {
+            written = write_step(&mut stdout, step);
+        }
+    })
+    .await?;
+    written?;
+    let failed = response.response_code() != op::ResponseCode::NoError
+        || (opt.empty_is_error && response.answers().is_empty());
+    let result = QueryFailures::check(usize::from(failed), 1, n_skipped);
+    if result.is_err() {
+        if let Some(negative) = query::NegativeAnswer::from_response(&response) {
+            eprintln!(";; {}", negative);
+        }
+    }
+    Ok(result?)
+}
+
+/// Performs the query by sending messages to the resolver directly, instead of
+/// going through the resolver library.
+///
+/// If `--raw-output` is given, the response is written to it in wire format.
+async fn run_message_query<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    addr: Option<SocketAddr>,
+    query: Query,
+    opt: &QueryOpt,
+    n_skipped: usize,
+) -> anyhow::Result<()> {
+    let raw_output = opt.raw_output.as_deref();
+    let sections = opt.sections;
+    if raw_output.is_some() && query.record_types.len() != 1 {
+        return Err(anyhow!(
+            "--raw-output requires querying a single record type"
+        ));
+    }
+    let addr = match addr {
+        Some(addr) => addr,
+        None => util::get_system_resolver().map_err(system_resolver_error)?,
+    };
+    let exchanges: Vec<_> = query::perform_raw_exchange(runtime, dns, addr, query.clone())
+        .try_collect()
+        .await?;
+    let total = exchanges.len();
+    let mut n_empty = 0;
+    let mut stdout = std::io::stdout();
+    if opt.hexdump {
+        for mut exchange in exchanges {
+            if exchange.response.answers().is_empty() {
+                n_empty += 1;
+            }
+            write_hexdumps(&mut exchange, addr)?;
+        }
+        let n_failed = if opt.empty_is_error { n_empty } else { 0 };
+        QueryFailures::check(n_failed, total, n_skipped)?;
+        return Ok(());
+    }
+    let responses = exchanges.into_iter().map(|exchange| exchange.response);
+    if let (Some(origin), true) = (&query.origin, query.uses_format(query::DisplayFormat::Zone)) {
+        query::write_origin(&mut stdout, origin)?;
+    }
+    write_ttl_reset(&query)?;
+    for mut response in responses {
+        // The annotations of each response follow the format of the queried
+        // record type.
+        let format = match response.queries().first() {
+            Some(q) => query.format_for(q.query_type()),
+            None => query.display_format,
+        };
+        // Messages in JSON format always include all sections.
+        let all_sections =
+            sections == query::Sections::All || format == query::DisplayFormat::JsonRfc8427;
+        if let Some(negative) = query::NegativeAnswer::from_response(&response) {
+            n_empty += 1;
+            if !all_sections {
+                eprintln!(";; {}", negative);
+            }
+        }
+        if let Some(raw_output) = raw_output {
+            fs::write(raw_output, response.to_vec()?)?;
+        }
+        if opt.show_server {
+            write_server(format, addr)?;
+        }
+        if query.pad_block.is_some() {
+            write_padding(format, &response)?;
+        }
+        if all_sections {
+            // The sections are written without going through
+            // `Query::write_record`, so the TTLs are reset in the message.
+            if let Some(ttl) = query.reset_ttl {
+                for record in response.answers_mut() {
+                    record.set_ttl(ttl);
+                }
+                for record in response.name_servers_mut() {
+                    record.set_ttl(ttl);
+                }
+                for record in response.additionals_mut() {
+                    record.set_ttl(ttl);
+                }
+            }
+            if format == query::DisplayFormat::JsonRfc8427 {
+                writeln!(stdout, "{}", query::message_json(&response)?)?;
+                continue;
+            }
+            query::write_flags(&mut stdout, &response)?;
+            query::write_sections(&mut stdout, &response, format, query.origin.as_ref())?;
+            continue;
+        }
+        if !query.recursion_desired {
+            match format {
+                query::DisplayFormat::Zone => query::write_flags(&mut stdout, &response)?,
+                query::DisplayFormat::Short
+                | query::DisplayFormat::Hosts
+                | query::DisplayFormat::JsonRfc8427 => {
+                    query::write_flags(&mut std::io::stderr(), &response)?
+                }
+            }
+        }
+        for record in response.answers() {
+            write_record_line(&mut stdout, &query, record)?;
+        }
+    }
+    let n_failed = if opt.empty_is_error { n_empty } else { 0 };
+    QueryFailures::check(n_failed, total, n_skipped)?;
+    Ok(())
+}
+
+/// Writes the query of `exchange` to stderr, and its response to stdout, as
+/// hex dumps labeled with the query and the server.
+fn write_hexdumps(exchange: &mut query::Exchange, server: SocketAddr) -> anyhow::Result<()> {
+    let label = format!(
+        "{} {} {}",
+        exchange.name,
+        exchange.query.queries()[0].query_class(),
+        query::record_type_name(exchange.record_type)
+    );
+    // The client may have sent the query with an ID of its own, which the
+    // response carries.
+    exchange.query.set_id(exchange.response.id());
+    query::write_hexdump(
+        &mut std::io::stderr(),
+        &format!("query for {} to {}", label, server),
+        &exchange.query.to_vec()?,
+    )?;
+    query::write_hexdump(
+        &mut std::io::stdout(),
+        &format!("response for {} from {}", label, server),
+        &exchange.response.to_vec()?,
+    )?;
+    Ok(())
+}
+
+async fn run_send<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    opt: SendOpt,
+) -> anyhow::Result<()> {
+    let wire = fs::read(&opt.message)?;
+    let resolver = open_resolver(dns.clone(), &opt.common.resolver).await?;
+    let addr = opt.server.resolve(resolver, 53).await?;
+    let response = match query::send_message(runtime, dns, addr, &wire).await {
+        Ok(response) => response,
+        Err(query::SendError::Parse(e)) => {
+            return Err(anyhow!("could not parse {}: {}", opt.message.display(), e))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(output) = &opt.output {
+        fs::write(output, response.to_vec()?)?;
     }
+    query::write_response(&mut std::io::stdout(), &response)?;
     Ok(())
 }
 
+/// Sends a request signed with the given key to the server, and checks that
+/// it was accepted.
+#[cfg(feature = "tsig")]
+async fn run_tsig_test<D: Backend + 'static>(
+    runtime: &Runtime,
+    mut dns: D,
+    opt: TsigTestOpt,
+) -> anyhow::Result<()> {
+    let key = get_tsig_key(
+        opt.key.as_deref(),
+        opt.key_file.as_deref(),
+        Some(&opt.zone).filter(|_| opt.key_for_zone),
+    )?
+    .ok_or_else(|| anyhow!("no key given; use --key or --key-file"))?;
+    let mut message = if opt.update {
+        update_message::empty(opt.zone.clone(), rr::DNSClass::IN)
+    } else {
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .add_query(op::Query::query(opt.zone.clone(), rr::RecordType::SOA));
+        message
+    };
+    tsig::add_signature(&mut message, &key)?;
+    let resolver = open_resolver(dns.clone(), &opt.common.resolver).await?;
+    let addr = opt.server.resolve(resolver, 53).await?;
+    let mut server = dns.open(runtime.handle(), addr).await?;
+    let response = server.send(message).await?;
+    if let Some(e) = tsig::ServerError::from_response(&response) {
+        return Err(e.into());
+    }
+    let request = if opt.update { "update" } else { "query" };
+    match response.response_code() {
+        op::ResponseCode::NoError if tsig::is_signed(&response) => {
+            println!("key {} accepted by {} for {}", key.name(), addr, request);
+            Ok(())
+        }
+        op::ResponseCode::NoError => Err(anyhow!(
+            "the response of {} is not signed; the server may not support TSIG",
+            addr
+        )),
+        op::ResponseCode::Refused => Err(anyhow!(
+            "{} refused the signed {}; the key may not be allowed to {} {}",
+            addr,
+            request,
+            request,
+            opt.zone
+        )),
+        op::ResponseCode::NotAuth => Err(anyhow!("{} is not authoritative for {}", addr, opt.zone)),
+        code => Err(anyhow!(
+            "{} answered the signed {} with {}",
+            addr,
+            request,
+            code
+        )),
+    }
+}
+
 async fn run(runtime: &Runtime, tdns: Tdns) -> anyhow::Result<()> {
+    let resolver = tdns.common().resolver_settings()?;
+    let (tcp_dns, udp_dns) = (TcpBackend { resolver }, UdpBackend { resolver });
     match tdns {
         Tdns::Query(opt) => {
+            let tcp = opt.common.tcp;
+            match (opt.id, tcp) {
+                (Some(_), true) => {
+                    let dns = FixedIdBackend {
+                        inner: tcp_dns,
+                        tcp,
+                    };
+                    run_query(runtime, dns, opt).await?
+                }
+                (Some(_), false) => {
+                    let dns = FixedIdBackend {
+                        inner: udp_dns,
+                        tcp,
+                    };
+                    run_query(runtime, dns, opt).await?
+                }
+                (None, true) => run_query(runtime, tcp_dns, opt).await?,
+                (None, false) => run_query(runtime, udp_dns, opt).await?,
+            }
+        }
+        Tdns::Update(opt) => {
+            if let Some(source) = opt.source {
+                // The clients of `BoundBackend` send messages unchanged, so
+                // this also covers `--id`.
+                let tcp = opt.common.tcp || opt.auth_tcp;
+                if opt.common.tcp {
+                    let dns = BoundBackend {
+                        inner: tcp_dns,
+                        tcp,
+                        source,
+                    };
+                    run_update(runtime, dns, opt).await?
+                } else {
+                    let dns = BoundBackend {
+                        inner: udp_dns,
+                        tcp,
+                        source,
+                    };
+                    run_update(runtime, dns, opt).await?
+                }
+            } else if opt.id.is_some() {
+                // The resolver library assigns IDs of its own, so only the
+                // messages sent to authoritative servers use the given ID.
+                let tcp = opt.common.tcp || opt.auth_tcp;
+                if opt.common.tcp {
+                    let dns = FixedIdBackend {
+                        inner: tcp_dns,
+                        tcp,
+                    };
+                    run_update(runtime, dns, opt).await?
+                } else {
+                    let dns = FixedIdBackend {
+                        inner: udp_dns,
+                        tcp,
+                    };
+                    run_update(runtime, dns, opt).await?
+                }
+            } else if opt.common.tcp {
+                run_update(runtime, tcp_dns, opt).await?
+            } else if opt.auth_tcp {
+                let dns = SplitBackend {
+                    resolver: udp_dns,
+                    client: tcp_dns,
+                };
+                run_update(runtime, dns, opt).await?
+            } else {
+                run_update(runtime, udp_dns, opt).await?
+            }
+        }
+        Tdns::Send(opt) => {
             if opt.common.tcp {
-                run_query(TcpBackend, opt).await?
+                run_send(runtime, tcp_dns, opt).await?
             } else {
-                run_query(UdpBackend, opt).await?
+                run_send(runtime, udp_dns, opt).await?
             }
         }
-        Tdns::Update(opt) => {
+        #[cfg(feature = "tsig")]
+        Tdns::TsigTest(opt) => {
             if opt.common.tcp {
-                run_update(runtime, TcpBackend, opt).await?
+                run_tsig_test(runtime, tcp_dns, opt).await?
             } else {
-                run_update(runtime, UdpBackend, opt).await?
+                run_tsig_test(runtime, udp_dns, opt).await?
+            }
+        }
+        Tdns::SerialWatch(opt) => {
+            if opt.common.tcp {
+                run_serial_watch(runtime, tcp_dns, opt).await?
+            } else {
+                run_serial_watch(runtime, udp_dns, opt).await?
             }
         }
     }
     Ok(())
 }
 
+/// Polls the SOA serials of the zone's nameservers, printing a table of them
+/// after each poll.
+async fn run_serial_watch<D: Backend + 'static>(
+    runtime: &Runtime,
+    dns: D,
+    opt: SerialWatchOpt,
+) -> anyhow::Result<()> {
+    let resolver = open_resolver(dns.clone(), &opt.common.resolver).await?;
+    let options = SerialWatch {
+        zone: util::make_fqdn(&opt.zone, false)?,
+        primary: opt.server.clone(),
+        exclude: opt.exclude.into_iter().collect(),
+        lookup_retry: Default::default(),
+    };
+    // When each nameserver was first seen behind, for as long as it stays so.
+    let mut behind_since = HashMap::new();
+    let mut n_polls = 0;
+    loop {
+        if n_polls > 0 {
+            sleep(Duration::from_secs(opt.interval)).await;
+            println!();
+        }
+        n_polls += 1;
+        let last = opt.count.is_some_and(|count| n_polls >= count);
+        // A failed poll, e.g. due to the NS lookup failing, does not end
+        // watching, unless it is the last one.
+        let serials = match poll_serials(runtime, dns.clone(), resolver.clone(), &options).await {
+            Ok(serials) => serials,
+            Err(e) if last => return Err(e),
+            Err(e) => {
+                eprintln!("poll failed: {:#}", e);
+                continue;
+            }
+        };
+        for warning in &serials.warnings {
+            print_nameserver_warning(warning, opt.verbose);
+        }
+        let now = Instant::now();
+        let reference = serials.reference();
+        for ns in &serials.nameservers {
+            let name = ns.server.to_string();
+            match reference.and_then(|reference| ns.lag(reference)) {
+                Some(lag) if lag > 0 => {
+                    behind_since.entry(name).or_insert(now);
+                }
+                _ => {
+                    behind_since.remove(&name);
+                }
+            }
+        }
+        print_serials(&serials, &behind_since, now);
+        if last {
+            return check_serial_lag(&serials, opt.max_lag);
+        }
+    }
+}
+
+/// Prints the serials of the primary and the nameservers, marking the
+/// nameservers that are behind or could not be queried with a `*`.
+fn print_serials(serials: &Serials, behind_since: &HashMap<String, Instant>, now: Instant) {
+    let reference = serials.reference();
+    match &serials.primary {
+        Ok(primary) => match &primary.serial {
+            Ok(serial) => println!("primary {} ({}): {}", primary.server, primary.addr, serial),
+            Err(e) => println!(
+                "primary {} ({}): error: {}",
+                primary.server, primary.addr, e
+            ),
+        },
+        Err(e) => println!("primary unknown: {}", e),
+    }
+    let rows: Vec<_> = serials
+        .nameservers
+        .iter()
+        .map(|ns| {
+            let (serial, status) = match (&ns.serial, reference) {
+                (Ok(serial), Some(reference)) => {
+                    let status = match ns.lag(reference) {
+                        Some(0) => "in sync".to_owned(),
+                        Some(lag) => match behind_since.get(&ns.server.to_string()) {
+                            Some(since) if now > *since => format!(
+                                "behind by {} for {}",
+                                lag,
+                                util::HumanDuration(now - *since)
+                            ),
+                            _ => format!("behind by {}", lag),
+                        },
+                        None => format!("ahead by {}", serial.wrapping_sub(reference)),
+                    };
+                    (serial.to_string(), status)
+                }
+                (Ok(serial), None) => (serial.to_string(), String::new()),
+                (Err(e), _) => ("-".to_owned(), format!("error: {}", e)),
+            };
+            let marked = !matches!(
+                reference.and_then(|reference| ns.lag(reference)),
+                Some(0) | None if ns.serial.is_ok()
+            );
+            (
+                marked,
+                ns.server.to_string(),
+                ns.addr.ip().to_string(),
+                serial,
+                status,
+            )
+        })
+        .collect();
+    let width = |column: fn(&(bool, String, String, String, String)) -> &String| {
+        rows.iter().map(|row| column(row).len()).max().unwrap_or(0)
+    };
+    let (name_width, addr_width, serial_width) = (
+        width(|row| &row.1),
+        width(|row| &row.2),
+        width(|row| &row.3),
+    );
+    for (marked, name, addr, serial, status) in &rows {
+        let line = format!(
+            "{} {:name_width$}  {:addr_width$}  {:>serial_width$}  {}",
+            if *marked { '*' } else { ' ' },
+            name,
+            addr,
+            serial,
+            status,
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Checks that no nameserver is behind the reference serial by more than
+/// `max_lag`, or could not be queried.
+fn check_serial_lag(serials: &Serials, max_lag: Option<u32>) -> anyhow::Result<()> {
+    let max_lag = match max_lag {
+        Some(max_lag) => max_lag,
+        None => return Ok(()),
+    };
+    let reference = serials
+        .reference()
+        .ok_or_else(|| anyhow!("could not determine the serial to compare to"))?;
+    let lagging: Vec<_> = serials
+        .nameservers
+        .iter()
+        .filter(|ns| match &ns.serial {
+            Ok(_) => ns.lag(reference).is_some_and(|lag| lag > max_lag),
+            Err(_) => true,
+        })
+        .map(|ns| ns.server.to_string())
+        .collect();
+    if lagging.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "nameservers behind by more than {} or not reachable: {}",
+        max_lag,
+        lagging.join(", ")
+    ))
+}
+
 fn main() {
-    let runtime = Runtime::new().unwrap();
-    let tdns = Tdns::from_args();
-    let rc = match runtime.block_on(run(&runtime, tdns)) {
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let args = util::translate_dig_options(std::env::args_os().collect()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let mut tdns = Tdns::from_iter(args);
+    let start = Instant::now();
+    let error_format = tdns.common().error_format;
+    let config = tdns.common().read_config();
+    if let Ok(config) = &config {
+        tdns.apply_config(config);
+    }
+    let context = tdns.error_context();
+    let result = match config {
+        Ok(_) => runtime.block_on(run(&runtime, tdns)).map_err(|e| {
+            let kind = error_kind(&e);
+            (e, kind)
+        }),
+        Err(e) => Err((e, "config")),
+    };
+    let rc = match result {
         Ok(_) => 0,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            1
+        Err((e, kind)) => {
+            let rc = e
+                .downcast_ref::<QueryFailures>()
+                .map_or(1, QueryFailures::exit_code);
+            match error_format {
+                ErrorFormat::Text => eprintln!("Error: {}", e),
+                ErrorFormat::Json => {
+                    let mut error = context;
+                    error.insert("kind".into(), kind.into());
+                    error.insert("message".into(), e.to_string().into());
+                    error.insert("elapsed".into(), start.elapsed().as_secs_f64().into());
+                    // The message gives the timeout in a readable form.
+                    if let Some(MonitorTimeout(timeout)) = e.downcast_ref() {
+                        let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+                        error.insert("timeout_ms".into(), millis.into());
+                    }
+                    if let Some(exhausted) = e.downcast_ref::<TriesExhausted>() {
+                        let servers: Vec<_> = exhausted
+                            .servers
+                            .iter()
+                            .map(|name| name.to_string())
+                            .collect();
+                        error.insert("servers".into(), servers.into());
+                    }
+                    error.insert("exit_code".into(), rc.into());
+                    eprintln!("{}", serde_json::Value::Object(error));
+                }
+            }
+            rc
         }
     };
     std::process::exit(rc);
 }
+
+/// Classifies an error for JSON error messages, based on the first error in
+/// its chain that is of a known type.
+fn error_kind(e: &anyhow::Error) -> &'static str {
+    for cause in e.chain() {
+        if cause.is::<QueryFailures>() {
+            return "query-failed";
+        } else if cause.is::<CurrentValueMismatch>() {
+            return "current-value-mismatch";
+        } else if cause.is::<ConcurrentChange>() {
+            return "concurrent-change";
+        } else if cause.is::<UpdateRejected>() {
+            return "update-rejected";
+        } else if cause.is::<UpdateNotApplied>() {
+            return "update-not-applied";
+        } else if cause.is::<MonitorTimeout>() {
+            return "timeout";
+        } else if cause.is::<TriesExhausted>() {
+            return "tries-exhausted";
+        } else if cause.is::<util::SystemResolverError>() {
+            return "system-resolver";
+        } else if cause.is::<ResolveError>() || cause.is::<ProtoError>() {
+            return "dns";
+        } else if cause.is::<std::io::Error>() {
+            return "io";
+        }
+        #[cfg(feature = "tsig")]
+        if cause.is::<tsig::ServerError>() || cause.is::<tsig::Error>() {
+            return "tsig";
+        }
+    }
+    "other"
+}
@@ -0,0 +1,146 @@
+//! Defaults for command-line options, read from a configuration file.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use trust_dns_client::rr;
+
+use crate::util::SocketName;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Resolvers {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    resolver: Option<Resolvers>,
+    fallback_resolver: Option<String>,
+    transport: Option<Transport>,
+    key_file: Option<PathBuf>,
+    timeout: Option<u64>,
+    interval: Option<u64>,
+    common_types: Option<Vec<String>>,
+}
+
+/// The transport used for DNS requests.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Defaults for command-line options, which take precedence over these.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// The resolvers to use, in order.
+    pub resolver: Vec<SocketName>,
+    /// The resolver to use if none is given, and the system configuration
+    /// cannot be read.
+    pub fallback_resolver: Option<SocketName>,
+    /// The transport to use for all DNS requests.
+    pub transport: Option<Transport>,
+    /// The file to read TSIG keys from.
+    pub key_file: Option<PathBuf>,
+    /// The time to wait for an update to be applied, in seconds.
+    pub timeout: Option<u64>,
+    /// The interval between checks while monitoring, in seconds.
+    pub interval: Option<u64>,
+    /// The record types to query for with the `common` pseudo-type, if they
+    /// should differ from `query::COMMON_TYPES`.
+    pub common_types: Option<Vec<rr::RecordType>>,
+}
+
+impl Config {
+    /// Parses a configuration in TOML format.
+    ///
+    /// Relative key file paths are kept as they are, i.e., relative to the
+    /// working directory.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let file: ConfigFile = toml::from_str(s)?;
+        let resolver = match file.resolver {
+            None => Vec::new(),
+            Some(Resolvers::Single(name)) => vec![name],
+            Some(Resolvers::Multiple(names)) => names,
+        };
+        let resolver = resolver
+            .iter()
+            .map(|name| {
+                name.parse()
+                    .map_err(|e| anyhow!("invalid resolver '{}': {}", name, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let fallback_resolver = file
+            .fallback_resolver
+            .map(|name| {
+                name.parse()
+                    .map_err(|e| anyhow!("invalid fallback resolver '{}': {}", name, e))
+            })
+            .transpose()?;
+        let common_types = file
+            .common_types
+            .map(|types| {
+                types
+                    .iter()
+                    .map(|rtype| {
+                        rtype
+                            .to_uppercase()
+                            .parse()
+                            .map_err(|e| anyhow!("invalid record type '{}': {}", rtype, e))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        if common_types.as_ref().is_some_and(Vec::is_empty) {
+            return Err(anyhow!("common-types must not be empty"));
+        }
+        Ok(Config {
+            resolver,
+            fallback_resolver,
+            transport: file.transport,
+            key_file: file.key_file,
+            timeout: file.timeout,
+            interval: file.interval,
+            common_types,
+        })
+    }
+
+    /// Reads the configuration from `path`.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("could not read config file {}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| anyhow!("invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Reads the configuration from the default location, if there is a file
+    /// at that location.
+    pub fn read_default() -> anyhow::Result<Self> {
+        let path = match default_path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        match fs::metadata(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            _ => Self::read(&path),
+        }
+    }
+}
+
+/// Returns the default location of the configuration file,
+/// `$XDG_CONFIG_HOME/tdns/config.toml`, with `XDG_CONFIG_HOME` defaulting to
+/// `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("tdns").join("config.toml"))
+}
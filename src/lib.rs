@@ -1,5 +1,9 @@
+pub mod batch;
+pub mod config;
 pub mod query;
 pub mod record;
+pub mod serial;
+#[cfg(feature = "tsig")]
 pub mod tsig;
 pub mod update;
 pub mod update_message;
@@ -7,4 +11,7 @@ pub mod util;
 
 pub mod backend;
 
-pub use backend::{Backend, Resolver, Runtime, TcpBackend, UdpBackend};
+pub use backend::{
+    AddressFamily, Backend, BoundBackend, ConnectorBackend, FixedIdBackend, Resolver,
+    ResolverSettings, Runtime, ServfailRetry, SplitBackend, TcpBackend, UdpBackend,
+};
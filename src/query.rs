@@ -1,25 +1,44 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fmt::{self, Write},
     io,
+    net::{IpAddr, SocketAddr},
     str::{self, FromStr},
+    sync::Arc,
+    time::Duration,
 };
 
+#[cfg(feature = "dnssec")]
 use chrono::DateTime;
-use data_encoding::{Encoding, BASE32, BASE64, HEXLOWER};
-use futures::stream::{FuturesUnordered, Stream};
+#[cfg(feature = "dnssec")]
+use data_encoding::BASE32;
+use data_encoding::{Encoding, BASE64, HEXLOWER, HEXUPPER};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
+use tokio::time::timeout;
 
-use trust_dns_client::rr::{
-    self,
-    dnssec::Nsec3HashAlgorithm,
-    rdata::{self, caa, DNSSECRData},
+#[cfg(feature = "dnssec")]
+use trust_dns_client::rr::{dnssec::Nsec3HashAlgorithm, rdata::DNSSECRData};
+use trust_dns_client::{
+    op,
+    proto::{
+        error::ProtoError,
+        xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse},
+    },
+    rr::{
+        self,
+        rdata::{self, caa, svcb},
+    },
+    serialize::binary::BinEncoder,
 };
-use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 
-use crate::Resolver;
+use crate::{util, AddressFamily, Backend, Resolver, Runtime};
 
 #[derive(Debug, Clone)]
 pub enum ParseDisplayFormatError {
     UnknownFormat,
+    UnknownType(String),
+    MultipleDefaults,
 }
 
 impl fmt::Display for ParseDisplayFormatError {
@@ -27,14 +46,38 @@ impl fmt::Display for ParseDisplayFormatError {
         use ParseDisplayFormatError::*;
         match self {
             UnknownFormat => write!(f, "unknown format"),
+            UnknownType(rtype) => write!(f, "unknown record type '{}'", rtype),
+            MultipleDefaults => write!(f, "more than one format given without a record type"),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl std::error::Error for ParseDisplayFormatError {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum DisplayFormat {
     Short,
     Zone,
+    /// Address records as `/etc/hosts` lines; other records are not shown.
+    Hosts,
+    /// Complete messages in the JSON encoding of RFC 8427, one per line.
+    JsonRfc8427,
+}
+
+impl DisplayFormat {
+    /// Whether `record` is shown at all in this format.
+    ///
+    /// `OPT` pseudo-records are only shown in the JSON format, as they have
+    /// no place in zone files.
+    pub fn shows(self, record: &rr::Record) -> bool {
+        match self {
+            DisplayFormat::Short | DisplayFormat::Zone => {
+                record.record_type() != rr::RecordType::OPT
+            }
+            DisplayFormat::JsonRfc8427 => true,
+            DisplayFormat::Hosts => matches!(record.rdata(), rr::RData::A(_) | rr::RData::AAAA(_)),
+        }
+    }
 }
 
 impl FromStr for DisplayFormat {
@@ -44,37 +87,1198 @@ impl FromStr for DisplayFormat {
         match s {
             "short" => Ok(DisplayFormat::Short),
             "zone" => Ok(DisplayFormat::Zone),
+            "hosts" => Ok(DisplayFormat::Hosts),
+            "json-rfc8427" => Ok(DisplayFormat::JsonRfc8427),
             _ => Err(ParseDisplayFormatError::UnknownFormat),
         }
     }
 }
 
+impl fmt::Display for DisplayFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DisplayFormat::Short => "short",
+            DisplayFormat::Zone => "zone",
+            DisplayFormat::Hosts => "hosts",
+            DisplayFormat::JsonRfc8427 => "json-rfc8427",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Display formats for specific record types, along with an optional format
+/// for the other types.
+///
+/// These are parsed from a comma-separated list of `TYPE=FORMAT` items and at
+/// most one plain format, e.g. `zone,A=short`.
+#[derive(Debug, Clone, Default)]
+pub struct DisplayFormats {
+    pub default: Option<DisplayFormat>,
+    pub by_type: BTreeMap<rr::RecordType, DisplayFormat>,
+}
+
+impl FromStr for DisplayFormats {
+    type Err = ParseDisplayFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut formats = DisplayFormats::default();
+        for item in s.split(',') {
+            match item.split_once('=') {
+                Some((rtype, format)) => {
+                    let rtype = parse_record_type(&rtype.to_uppercase())
+                        .map_err(|_| ParseDisplayFormatError::UnknownType(rtype.to_owned()))?;
+                    formats.by_type.insert(rtype, format.parse()?);
+                }
+                None if formats.default.is_some() => {
+                    return Err(ParseDisplayFormatError::MultipleDefaults)
+                }
+                None => formats.default = Some(item.parse()?),
+            }
+        }
+        Ok(formats)
+    }
+}
+
+/// The sections of a response message to display.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Sections {
+    Answer,
+    All,
+}
+
+impl FromStr for Sections {
+    type Err = ParseSectionsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "answer" => Ok(Sections::Answer),
+            "all" => Ok(Sections::All),
+            _ => Err(ParseSectionsError),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseSectionsError;
+
+impl fmt::Display for ParseSectionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown sections, expected 'answer' or 'all'")
+    }
+}
+
+impl std::error::Error for ParseSectionsError {}
+
+/// The record types queried for by the `common` pseudo-type, as a substitute
+/// for `ANY` queries, which servers may answer only minimally (RFC 8482).
+pub const COMMON_TYPES: &[rr::RecordType] = &[
+    rr::RecordType::A,
+    rr::RecordType::AAAA,
+    rr::RecordType::MX,
+    rr::RecordType::TXT,
+    rr::RecordType::NS,
+    rr::RecordType::SOA,
+    rr::RecordType::CAA,
+    rr::RecordType::SRV,
+];
+
 #[derive(Debug, Clone)]
 pub struct Query {
     pub entry: rr::Name,
     pub record_types: Vec<rr::RecordType>,
+    /// The display format for records of types not in `type_formats`.
     pub display_format: DisplayFormat,
+    /// The display formats for specific record types.
+    pub type_formats: BTreeMap<rr::RecordType, DisplayFormat>,
+    /// Domains to append to `entry` when it is not fully qualified.
+    pub search: Vec<rr::Name>,
+    /// The number of labels `entry` must exceed to be tried verbatim before
+    /// the `search` domains are appended.
+    pub ndots: usize,
+    /// If given, owner names below this name are displayed relative to it.
+    pub origin: Option<rr::Name>,
+    /// Whether to set the RD (recursion desired) flag on queries.
+    ///
+    /// This is only honored by `perform_raw_query`; queries performed via a
+    /// resolver are always recursive.
+    pub recursion_desired: bool,
+    /// Whether to request DNSSEC records by setting the DO (DNSSEC OK) flag
+    /// of EDNS.
+    ///
+    /// Like `recursion_desired`, this is only honored by `perform_raw_query`.
+    pub dnssec_ok: bool,
+    /// Whether to set the CD (checking disabled) flag, so that a validating
+    /// resolver passes on data that fails DNSSEC validation.
+    ///
+    /// Like `recursion_desired`, this is only honored by `perform_raw_query`.
+    pub checking_disabled: bool,
+    /// Whether to precede the record data with the TTL in short format.
+    ///
+    /// Zone format always includes the TTL.
+    pub show_ttl: bool,
+    /// If given, the TTL displayed for every record instead of its own.
+    ///
+    /// Records received from a caching resolver carry the remaining cache
+    /// time, so this allows producing reproducible master files.
+    pub reset_ttl: Option<u32>,
+    /// The ID of the query messages; random if not given.
+    ///
+    /// Like `recursion_desired`, this is only honored by `perform_raw_query`,
+    /// and the ID is only sent as-is by backends that keep message IDs, such
+    /// as `FixedIdBackend`.
+    pub message_id: Option<u16>,
+    /// The class to query.
+    ///
+    /// Like `recursion_desired`, this is only honored by `perform_raw_query`;
+    /// the resolver only queries the `IN` class.
+    pub dns_class: rr::DNSClass,
+    /// If given, the query messages are padded to a multiple of this block
+    /// size using the EDNS padding option; see `pad_message`.
+    ///
+    /// Like `recursion_desired`, this is only honored by `perform_raw_query`.
+    pub pad_block: Option<u16>,
 }
 
+impl Query {
+    /// Returns the fully qualified names to try, in order, when looking up
+    /// `entry`.
+    ///
+    /// This follows the `resolv.conf` conventions: names that are already
+    /// fully qualified, or when no search domains are given, are used
+    /// as-is. Otherwise, the search domains are appended in turn, with the
+    /// verbatim name being tried first if it has more than `ndots` labels,
+    /// and last otherwise.
+    pub fn names(&self) -> Vec<rr::Name> {
+        let mut verbatim = self.entry.clone();
+        verbatim.set_fqdn(true);
+        if self.entry.is_fqdn() || self.search.is_empty() {
+            return vec![verbatim];
+        }
+        let verbatim_first = usize::from(self.entry.num_labels()) > self.ndots;
+        let mut names = Vec::with_capacity(self.search.len() + 1);
+        if verbatim_first {
+            names.push(verbatim.clone());
+        }
+        for domain in &self.search {
+            let name = self.entry.clone().append_domain(domain);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        if !verbatim_first && !names.contains(&verbatim) {
+            names.push(verbatim);
+        }
+        names
+    }
+
+    /// Writes a record according to the display settings.
+    pub fn write_record<W: io::Write>(
+        &self,
+        writer: &mut W,
+        record: &rr::Record,
+    ) -> io::Result<()> {
+        let reset;
+        let record = match self.reset_ttl {
+            Some(ttl) => {
+                reset = with_ttl(record, ttl);
+                &reset
+            }
+            None => record,
+        };
+        let format = self.format_for(record.record_type());
+        if let (true, DisplayFormat::Short) = (self.show_ttl, format) {
+            write!(writer, "{} ", record.ttl())?;
+        }
+        write_record(writer, record, format, self.origin.as_ref())
+    }
+
+    /// Returns the display format for records of type `rtype`.
+    pub fn format_for(&self, rtype: rr::RecordType) -> DisplayFormat {
+        self.type_formats
+            .get(&rtype)
+            .copied()
+            .unwrap_or(self.display_format)
+    }
+
+    /// Whether any records may be displayed in `format`.
+    pub fn uses_format(&self, format: DisplayFormat) -> bool {
+        self.display_format == format || self.type_formats.values().any(|&f| f == format)
+    }
+}
+
+/// The answer to the query for one of the record types of a `Query`.
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    /// The name that yielded this result; with a search list, this is the
+    /// first name that had records, or the last one tried otherwise.
+    pub name: rr::Name,
+    /// The record type that was queried for.
+    pub record_type: rr::RecordType,
+    /// The answer records, which may include records of other types, such as
+    /// `CNAME` records leading to the actual answer.
+    pub records: Vec<rr::Record>,
+    /// The address of the server that provided the answer, if known.
+    pub server: Option<SocketAddr>,
+    /// The response code of the answer; `NoError` or `NXDomain`, as other
+    /// response codes are reported as errors.
+    pub response_code: op::ResponseCode,
+    /// The details of the answer if it was negative, i.e., if `records` is
+    /// empty.
+    pub negative: Option<NegativeAnswer>,
+}
+
+impl QueryResult {
+    /// Whether the name exists, i.e., the answer was not `NXDOMAIN`.
+    pub fn name_exists(&self) -> bool {
+        self.response_code != op::ResponseCode::NXDomain
+    }
+}
+
+/// Performs the queries via `resolver`, yielding the answer for each record
+/// type.
+///
+/// Negative answers (`NXDOMAIN` or `NODATA`) are yielded as results without
+/// records; other failures, including `SERVFAIL`, are yielded as errors.
+///
+/// The queries for each record type are issued concurrently, all of them
+/// over the single `resolver` passed in, which is not cloned per record type.
+/// As the resolver does not tell which of its servers has answered, the
+/// results carry no server address.
 pub fn perform_query(
     resolver: impl Resolver + 'static,
     options: Query,
-) -> impl Stream<Item = Result<Vec<rr::Record>, ResolveError>> {
-    let entry = options.entry;
+) -> impl Stream<Item = Result<QueryResult, ResolveError>> {
+    query_stream(resolver, options, None)
+}
+
+/// Like `perform_query`, but limits the time each record type's lookup may
+/// take to `per_lookup`.
+///
+/// A lookup that does not complete in time yields an error of kind
+/// `ResolveErrorKind::Timeout`, without affecting the other lookups.
+pub fn perform_query_with_timeout(
+    resolver: impl Resolver + 'static,
+    options: Query,
+    per_lookup: Duration,
+) -> impl Stream<Item = Result<QueryResult, ResolveError>> {
+    query_stream(resolver, options, Some(per_lookup))
+}
+
+fn query_stream(
+    resolver: impl Resolver + 'static,
+    options: Query,
+    per_lookup: Option<Duration>,
+) -> impl Stream<Item = Result<QueryResult, ResolveError>> {
+    let names = options.names();
+    let resolver = Arc::new(resolver);
+    options
+        .record_types
+        .into_iter()
+        .map(move |rtype| {
+            let resolver = Arc::clone(&resolver);
+            let names = names.clone();
+            async move {
+                let lookup = lookup_names(&*resolver, names, rtype);
+                match per_lookup {
+                    Some(duration) => timeout(duration, lookup)
+                        .await
+                        .map_err(|_| ResolveError::from(ResolveErrorKind::Timeout))?,
+                    None => lookup.await,
+                }
+            }
+        })
+        .collect::<FuturesUnordered<_>>()
+}
+
+/// Performs the queries via the given resolvers, yielding the answer for each
+/// record type, along with the address of the server that provided it.
+///
+/// Each resolver is expected to talk to the single server it is paired with.
+/// For each record type, the resolvers are tried in turn, moving on to the
+/// next one on errors, including `SERVFAIL`, like a resolver opened via
+/// `Backend::open_resolvers` would. A negative answer is final.
+pub fn perform_query_via<R>(
+    resolvers: Vec<(SocketAddr, R)>,
+    options: Query,
+) -> impl Stream<Item = Result<QueryResult, ResolveError>>
+where
+    R: Resolver + 'static,
+{
+    let names = options.names();
+    let resolvers = Arc::new(resolvers);
     options
         .record_types
         .into_iter()
         .map(move |rtype| {
-            let resolver = resolver.clone();
-            let entry = entry.clone();
+            let resolvers = Arc::clone(&resolvers);
+            let names = names.clone();
             async move {
-                let lookup = resolver.lookup(entry.clone(), rtype).await?;
-                Ok(lookup.record_iter().cloned().collect::<Vec<_>>())
+                let mut last_error = None;
+                for (addr, resolver) in resolvers.iter() {
+                    match lookup_names(resolver, names.clone(), rtype).await {
+                        Ok(result) => {
+                            return Ok(QueryResult {
+                                server: Some(*addr),
+                                ..result
+                            })
+                        }
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error
+                    .unwrap_or_else(|| ResolveErrorKind::Message("no resolvers given").into()))
             }
         })
         .collect::<FuturesUnordered<_>>()
 }
 
+/// Looks up the records of type `rtype` for each of `names` in turn, until
+/// one of them yields an answer.
+///
+/// If none does, the negative answer for the last name is returned.
+async fn lookup_names(
+    resolver: &impl Resolver,
+    names: Vec<rr::Name>,
+    rtype: rr::RecordType,
+) -> Result<QueryResult, ResolveError> {
+    let mut negative = None;
+    for name in names {
+        match resolver.lookup(name.clone(), rtype).await {
+            Ok(lookup) => {
+                return Ok(QueryResult {
+                    name,
+                    record_type: rtype,
+                    records: lookup.record_iter().cloned().collect(),
+                    server: None,
+                    response_code: op::ResponseCode::NoError,
+                    negative: None,
+                })
+            }
+            Err(e) => match NegativeAnswer::from_error(&e) {
+                Some(answer) if answer.is_negative() => negative = Some((name, answer)),
+                _ => return Err(e),
+            },
+        }
+    }
+    let (name, answer) = negative.expect("at least one name is always tried");
+    Ok(QueryResult {
+        name,
+        record_type: rtype,
+        records: Vec::new(),
+        server: None,
+        response_code: answer.response_code,
+        negative: Some(answer),
+    })
+}
+
+/// A negative answer, i.e., one that indicates that there are no records for
+/// a query, along with the information on how long it may be cached.
+#[derive(Debug, Clone)]
+pub struct NegativeAnswer {
+    pub query: op::Query,
+    /// Either `NXDomain`, or `NoError` for a NODATA answer; other response
+    /// codes indicate a failure rather than a negative answer.
+    pub response_code: op::ResponseCode,
+    /// The SOA record from the authority section, if present.
+    pub soa: Option<rdata::SOA>,
+    /// The negative caching TTL, as per RFC 2308, section 5.
+    pub negative_ttl: Option<u32>,
+}
+
+impl NegativeAnswer {
+    /// Extracts the negative answer from a `NoRecordsFound` error.
+    pub fn from_error(e: &ResolveError) -> Option<Self> {
+        match e.kind() {
+            ResolveErrorKind::NoRecordsFound {
+                query,
+                soa,
+                negative_ttl,
+                response_code,
+                ..
+            } => Some(NegativeAnswer {
+                query: query.clone(),
+                response_code: *response_code,
+                soa: soa.clone(),
+                negative_ttl: *negative_ttl,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a proper negative answer, i.e., `NXDOMAIN` or `NODATA`,
+    /// as opposed to a failure such as `SERVFAIL`.
+    pub fn is_negative(&self) -> bool {
+        matches!(
+            self.response_code,
+            op::ResponseCode::NoError | op::ResponseCode::NXDomain
+        )
+    }
+
+    /// Extracts the negative answer from a response message, if it has no
+    /// answer records.
+    pub fn from_response(message: &op::Message) -> Option<Self> {
+        if !message.answers().is_empty() {
+            return None;
+        }
+        let query = message.queries().first()?.clone();
+        let soa = message
+            .name_servers()
+            .iter()
+            .find_map(|record| record.rdata().as_soa().map(|soa| (record.ttl(), soa)));
+        Some(NegativeAnswer {
+            query,
+            response_code: message.response_code(),
+            negative_ttl: soa.map(|(ttl, soa)| ttl.min(soa.minimum())),
+            soa: soa.map(|(_, soa)| soa.clone()),
+        })
+    }
+}
+
+impl fmt::Display for NegativeAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "no {} records for {} ({})",
+            record_type_name(self.query.query_type()),
+            self.query.name(),
+            self.response_code
+        )?;
+        if let Some(ttl) = self.negative_ttl {
+            write!(f, "; negative TTL {}", ttl)?;
+        }
+        if let Some(soa) = &self.soa {
+            write!(f, "; SOA {}", DisplayRData(&rr::RData::SOA(soa.clone())))?;
+        }
+        Ok(())
+    }
+}
+
+/// The maximum number of ANAME records followed by `flatten_aname`.
+const MAX_ANAME_CHAIN: usize = 8;
+
+/// Resolves the addresses an ANAME record points to, as an authoritative
+/// server implementing ANAME would.
+///
+/// Should the target itself carry an ANAME record, that one is followed as
+/// well, up to a fixed limit; loops are detected and end the chain. The
+/// returned records are the `A` and `AAAA` records of the final target(s).
+pub async fn flatten_aname(
+    resolver: impl Resolver,
+    target: &rr::Name,
+) -> Result<Vec<rr::Record>, ResolveError> {
+    let mut seen = BTreeSet::new();
+    let mut records = Vec::new();
+    let mut next = Some(target.clone());
+    while let Some(name) = next.take() {
+        if seen.len() >= MAX_ANAME_CHAIN || !seen.insert(name.clone()) {
+            break;
+        }
+        for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+            records.extend(lookup_records(&resolver, name.clone(), *rtype).await?);
+        }
+        next = lookup_records(&resolver, name, rr::RecordType::ANAME)
+            .await?
+            .iter()
+            .find_map(|record| record.rdata().as_aname().cloned());
+    }
+    Ok(records)
+}
+
+/// Looks up the records of the given type, with an empty result not
+/// considered an error.
+async fn lookup_records(
+    resolver: &impl Resolver,
+    name: rr::Name,
+    rtype: rr::RecordType,
+) -> Result<Vec<rr::Record>, ResolveError> {
+    match resolver.lookup(name, rtype).await {
+        Ok(lookup) => Ok(lookup
+            .record_iter()
+            .filter(|record| record.record_type() == rtype)
+            .cloned()
+            .collect()),
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(Vec::new()),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Performs the queries directly against `server`, yielding the complete
+/// response messages.
+///
+/// In contrast to `perform_query`, this does not go through a resolver, so it
+/// provides access to the whole response, not just the answer records. When
+/// multiple names are to be tried (see `Query::names`), the response for the
+/// first name that yields any answers is returned. A single connection to
+/// `server` is shared by all queries.
+pub fn perform_raw_query<D>(
+    runtime: &Runtime,
+    dns: D,
+    server: SocketAddr,
+    options: Query,
+) -> impl Stream<Item = Result<DnsResponse, ProtoError>> + '_
+where
+    D: Backend + 'static,
+{
+    perform_raw_exchange(runtime, dns, server, options).map_ok(|exchange| exchange.response)
+}
+
+/// A query sent by `perform_raw_exchange`, along with its response.
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    /// The queried name, which responses need not repeat.
+    pub name: rr::Name,
+    pub record_type: rr::RecordType,
+    /// The query message, as passed to the client.
+    ///
+    /// Clients other than those of `FixedIdBackend` send it with an ID of
+    /// their own; the response carries that ID.
+    pub query: op::Message,
+    pub response: DnsResponse,
+}
+
+/// Like `perform_raw_query`, but yields the query message each response is
+/// for along with it.
+pub fn perform_raw_exchange<D>(
+    runtime: &Runtime,
+    mut dns: D,
+    server: SocketAddr,
+    options: Query,
+) -> impl Stream<Item = Result<Exchange, ProtoError>> + '_
+where
+    D: Backend + 'static,
+{
+    let names = options.names();
+    let record_types = options.record_types;
+    let recursion_desired = options.recursion_desired;
+    let dnssec_ok = options.dnssec_ok;
+    let checking_disabled = options.checking_disabled;
+    let message_id = options.message_id;
+    let dns_class = options.dns_class;
+    let pad_block = options.pad_block;
+    stream::once(async move { dns.open(runtime.handle(), server).await })
+        .map_ok(move |client| {
+            record_types
+                .clone()
+                .into_iter()
+                .map(|rtype| {
+                    let mut client = client.clone();
+                    let names = names.clone();
+                    async move {
+                        let mut last_exchange = None;
+                        for name in names {
+                            let mut message =
+                                query_message(name.clone(), rtype, dns_class, recursion_desired);
+                            if let Some(id) = message_id {
+                                message.set_id(id);
+                            }
+                            message.set_checking_disabled(checking_disabled);
+                            if dnssec_ok {
+                                edns_mut(&mut message).set_dnssec_ok(true);
+                            }
+                            if let Some(block_size) = pad_block {
+                                pad_message(&mut message, block_size)?;
+                            }
+                            let request =
+                                DnsRequest::new(message.clone(), DnsRequestOptions::default());
+                            let response = client.send(request).await?;
+                            let exchange = Exchange {
+                                name,
+                                record_type: rtype,
+                                query: message,
+                                response,
+                            };
+                            if !exchange.response.answers().is_empty() {
+                                return Ok(exchange);
+                            }
+                            last_exchange = Some(exchange);
+                        }
+                        Ok(last_exchange.expect("at least one name is always tried"))
+                    }
+                })
+                .collect::<FuturesUnordered<_>>()
+        })
+        .try_flatten()
+}
+
+/// The error returned by `send_message`.
+#[derive(Debug)]
+pub enum SendError {
+    /// The given bytes are not a valid DNS message.
+    Parse(ProtoError),
+    /// The message could not be sent, or no response was received.
+    Send(ProtoError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Parse(e) => write!(f, "could not parse message: {}", e),
+            SendError::Send(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Sends the DNS message given in wire format to `server`, returning the
+/// response.
+///
+/// The message is sent as given, without any of the processing applied to
+/// the queries of `perform_raw_query`; only its ID is replaced by clients
+/// other than those of `FixedIdBackend`.
+pub async fn send_message<D: Backend>(
+    runtime: &Runtime,
+    mut dns: D,
+    server: SocketAddr,
+    wire: &[u8],
+) -> Result<DnsResponse, SendError> {
+    let message = op::Message::from_vec(wire).map_err(SendError::Parse)?;
+    let mut client = dns
+        .open(runtime.handle(), server)
+        .await
+        .map_err(SendError::Send)?;
+    client.send(message).await.map_err(SendError::Send)
+}
+
+/// Writes the response code of `response`, followed by the records of its
+/// answer, authority and additional sections in zone file format.
+pub fn write_response<W: io::Write>(writer: &mut W, response: &op::Message) -> io::Result<()> {
+    writeln!(writer, ";; response code: {}", response.response_code())?;
+    for record in response
+        .answers()
+        .iter()
+        .chain(response.name_servers())
+        .chain(response.additionals())
+    {
+        write_record(writer, record, DisplayFormat::Zone, None)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Performs the queries directly against each of `servers`, with the RD flag
+/// cleared, yielding the answer of each server for each record type.
+///
+/// This is meant for verifying the data served by the authoritative servers
+/// of a zone, e.g. as determined by `update::authoritative_servers`, without
+/// a recursor in the path. Like with `perform_query`, negative answers are
+/// yielded as results without records, and responses with other response
+/// codes as errors; in contrast to it, the results carry the address of the
+/// server. As the servers are queried like by `perform_raw_query`, the message
+/// ID, class and padding of `options` are honored.
+pub fn perform_query_direct<D>(
+    runtime: &Runtime,
+    dns: D,
+    servers: Vec<SocketAddr>,
+    options: Query,
+) -> impl Stream<Item = Result<QueryResult, ResolveError>> + '_
+where
+    D: Backend + 'static,
+{
+    let options = Query {
+        recursion_desired: false,
+        ..options
+    };
+    stream::select_all(servers.into_iter().map(move |addr| {
+        perform_raw_exchange(runtime, dns.clone(), addr, options.clone())
+            .map(move |exchange| match exchange {
+                Ok(exchange) => direct_result(addr, exchange),
+                Err(e) => Err(format!("{}: {}", addr, e)),
+            })
+            .map_err(ResolveError::from)
+            .boxed_local()
+    }))
+}
+
+/// Turns the response of `server` to a query sent by `perform_query_direct`
+/// into its result.
+fn direct_result(server: SocketAddr, exchange: Exchange) -> Result<QueryResult, String> {
+    let response = exchange.response;
+    let negative = NegativeAnswer::from_response(&response);
+    if let Some(answer) = negative.as_ref().filter(|answer| !answer.is_negative()) {
+        return Err(format!("{}: {}", server, answer));
+    }
+    Ok(QueryResult {
+        name: exchange.name,
+        record_type: exchange.record_type,
+        records: response.answers().to_vec(),
+        server: Some(server),
+        response_code: response.response_code(),
+        negative,
+    })
+}
+
+/// The EDNS option code of the padding option, as per RFC 7830.
+const EDNS_PADDING: u16 = 12;
+
+/// The UDP payload size advertised in queries with EDNS, as recommended by
+/// the DNS flag day 2020.
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// Adds the EDNS padding option (RFC 7830) to `message`, so that its size in
+/// wire format becomes a multiple of `block_size`.
+///
+/// RFC 8467 recommends a block size of 128 bytes for queries. The message
+/// gets an EDNS OPT record if it has none yet; as the padding is computed
+/// from the encoded message, it must not be changed afterwards, e.g. by
+/// signing it.
+pub fn pad_message(message: &mut op::Message, block_size: u16) -> Result<(), ProtoError> {
+    let padding = |len| rr::rdata::opt::EdnsOption::Unknown(EDNS_PADDING, vec![0; len]);
+    edns_mut(message).options_mut().insert(padding(0));
+    let len = message.to_vec()?.len();
+    let block_size = usize::from(block_size.max(1));
+    message
+        .edns_mut()
+        .options_mut()
+        .insert(padding((block_size - len % block_size) % block_size));
+    Ok(())
+}
+
+/// Returns the EDNS OPT record of `message`, adding one if it has none yet.
+fn edns_mut(message: &mut op::Message) -> &mut op::Edns {
+    if message.edns().is_none() {
+        message
+            .edns_mut()
+            .set_max_payload(EDNS_PAYLOAD_SIZE)
+            .set_version(0);
+    }
+    message.edns_mut()
+}
+
+/// Returns the length of the EDNS padding of `message`, if it is padded.
+pub fn padding_len(message: &op::Message) -> Option<usize> {
+    let option = message
+        .edns()?
+        .option(rr::rdata::opt::EdnsCode::from(EDNS_PADDING))?;
+    Some(usize::from(option.len()))
+}
+
+/// Writes a comment line telling whether `message` is padded, and its size
+/// in wire format.
+pub fn write_padding<W: io::Write>(writer: &mut W, message: &op::Message) -> io::Result<()> {
+    let size = message
+        .to_vec()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .len();
+    match padding_len(message) {
+        Some(len) => writeln!(writer, ";; PADDING: {} bytes, size {}", len, size),
+        None => writeln!(writer, ";; PADDING: none, size {}", size),
+    }
+}
+
+/// Writes `data` as a hex dump that `text2pcap` can read, preceded by
+/// `label` as a comment.
+///
+/// Each line gives the offset of its first byte, followed by up to 16 bytes;
+/// as each dump starts at offset zero, `text2pcap` turns consecutive dumps
+/// into separate packets.
+pub fn write_hexdump<W: io::Write>(writer: &mut W, label: &str, data: &[u8]) -> io::Result<()> {
+    writeln!(writer, "# {}", label)?;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        write!(writer, "{:06x}", i * 16)?;
+        for byte in chunk {
+            write!(writer, " {:02x}", byte)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// The names of the `TXT` records in the `CH` class by which servers
+/// identify themselves: BIND's `version.bind` and `hostname.bind`, and
+/// `id.server` as per RFC 4892.
+pub const CHAOS_NAMES: &[&str] = &["version.bind.", "hostname.bind.", "id.server."];
+
+/// The maximum number of referrals followed by `trace`.
+const MAX_REFERRALS: usize = 32;
+
+/// A response received while tracing the delegation of a name; see `trace`.
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+    /// The zone whose nameserver was queried.
+    pub zone: rr::Name,
+    /// The name of the nameserver queried.
+    pub server_name: rr::Name,
+    pub server: SocketAddr,
+    /// Whether the address of the nameserver was looked up via the
+    /// resolver, as the referral to it did not include glue.
+    pub glueless: bool,
+    pub response: DnsResponse,
+}
+
+/// The error returned when tracing the delegation of a name fails.
+#[derive(Debug)]
+pub enum TraceError {
+    /// The root nameservers could not be determined.
+    Roots(Box<ResolveError>),
+    /// None of the nameservers of the zone could be queried.
+    Unreachable {
+        zone: rr::Name,
+        error: Option<ProtoError>,
+    },
+    /// A referral that does not lead closer to the queried name.
+    BadReferral {
+        zone: Box<rr::Name>,
+        referral: Box<rr::Name>,
+    },
+    TooManyReferrals,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TraceError::*;
+        match self {
+            Roots(e) => write!(f, "could not determine the root nameservers: {}", e),
+            Unreachable { zone, error: None } => {
+                write!(f, "could not resolve any nameserver of {}", zone)
+            }
+            Unreachable {
+                zone,
+                error: Some(e),
+            } => write!(f, "no reachable nameserver for {}: {}", zone, e),
+            BadReferral { zone, referral } => write!(
+                f,
+                "nameserver for {} gave a referral to {}, which is not below it",
+                zone, referral
+            ),
+            TooManyReferrals => write!(f, "more than {} referrals", MAX_REFERRALS),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// A nameserver to query while tracing, with its address, if given by glue.
+type TraceServer = (rr::Name, Option<IpAddr>);
+
+/// Follows the delegation of `name` from the root zone down to its
+/// authoritative nameservers, like `dig +trace`, calling `progress` with the
+/// response received for each zone.
+///
+/// The root nameservers, and those nameservers referred to without glue, are
+/// looked up via `resolver`; all other queries are sent to the nameservers
+/// directly, with the RD flag cleared. The final response, i.e., the first
+/// one that is not a referral, is returned.
+pub async fn trace<D, P>(
+    runtime: &Runtime,
+    mut dns: D,
+    resolver: D::Resolver,
+    name: rr::Name,
+    rtype: rr::RecordType,
+    mut progress: P,
+) -> Result<DnsResponse, TraceError>
+where
+    D: Backend,
+    P: FnMut(&TraceStep),
+{
+    let roots = resolver
+        .lookup_ns(rr::Name::root())
+        .await
+        .map_err(|e| TraceError::Roots(Box::new(e)))?;
+    let mut zone = rr::Name::root();
+    let mut servers: Vec<TraceServer> = roots.iter().map(|ns| (ns.clone(), None)).collect();
+    for _ in 0..MAX_REFERRALS {
+        let step = trace_zone(runtime, &mut dns, &resolver, &zone, &servers, &name, rtype).await?;
+        progress(&step);
+        match referral(&step.response, &zone, &name)? {
+            Some((next_zone, next_servers)) => {
+                zone = next_zone;
+                servers = next_servers;
+            }
+            None => return Ok(step.response),
+        }
+    }
+    Err(TraceError::TooManyReferrals)
+}
+
+/// Queries the first nameserver of `zone` that can be reached.
+async fn trace_zone<D: Backend>(
+    runtime: &Runtime,
+    dns: &mut D,
+    resolver: &D::Resolver,
+    zone: &rr::Name,
+    servers: &[TraceServer],
+    name: &rr::Name,
+    rtype: rr::RecordType,
+) -> Result<TraceStep, TraceError> {
+    let mut last_error = None;
+    for (server_name, glue) in servers {
+        let ip = match glue {
+            Some(ip) => *ip,
+            None => match resolver
+                .lookup_addrs(server_name.clone(), AddressFamily::PreferIpv4)
+                .await
+            {
+                Ok(addrs) => match addrs.first() {
+                    Some(ip) => *ip,
+                    None => continue,
+                },
+                Err(_) => continue,
+            },
+        };
+        let server = SocketAddr::new(ip, 53);
+        let response = async {
+            let mut client = dns.open(runtime.handle(), server).await?;
+            let message = query_message(name.clone(), rtype, rr::DNSClass::IN, false);
+            client
+                .send(DnsRequest::new(message, DnsRequestOptions::default()))
+                .await
+        };
+        match response.await {
+            Ok(response) => {
+                return Ok(TraceStep {
+                    zone: zone.clone(),
+                    server_name: server_name.clone(),
+                    server,
+                    // The root nameservers are never given by glue.
+                    glueless: glue.is_none() && !zone.is_root(),
+                    response,
+                });
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(TraceError::Unreachable {
+        zone: zone.clone(),
+        error: last_error,
+    })
+}
+
+/// Extracts the zone and nameservers a response refers to, if it is a
+/// referral, i.e., a response without answers, but NS records in its
+/// authority section.
+fn referral(
+    response: &op::Message,
+    zone: &rr::Name,
+    name: &rr::Name,
+) -> Result<Option<(rr::Name, Vec<TraceServer>)>, TraceError> {
+    if !response.answers().is_empty() || response.response_code() != op::ResponseCode::NoError {
+        return Ok(None);
+    }
+    let ns_records: Vec<_> = response
+        .name_servers()
+        .iter()
+        .filter(|record| record.record_type() == rr::RecordType::NS)
+        .collect();
+    let next_zone = match ns_records.first() {
+        Some(record) => record.name().clone(),
+        None => return Ok(None),
+    };
+    if next_zone.num_labels() <= zone.num_labels()
+        || !zone.zone_of(&next_zone)
+        || !next_zone.zone_of(name)
+    {
+        return Err(TraceError::BadReferral {
+            zone: Box::new(zone.clone()),
+            referral: Box::new(next_zone),
+        });
+    }
+    let mut servers: Vec<TraceServer> = ns_records
+        .iter()
+        .filter(|record| record.name() == &next_zone)
+        .filter_map(|record| record.rdata().as_ns())
+        .map(|ns| (ns.clone(), util::glue_address(response.additionals(), ns)))
+        .collect();
+    // Nameservers with glue are tried first, avoiding extra lookups.
+    servers.sort_by_key(|(_, glue)| glue.is_none());
+    Ok(Some((next_zone, servers)))
+}
+
+/// Writes the comment preceding the response of a trace step, telling the
+/// zone and the nameserver that has answered.
+pub fn write_trace_step<W: io::Write>(writer: &mut W, step: &TraceStep) -> io::Result<()> {
+    write!(
+        writer,
+        ";; {} from {} ({})",
+        step.zone, step.server_name, step.server
+    )?;
+    if step.glueless {
+        write!(writer, ", looked up without glue")?;
+    }
+    writeln!(writer)
+}
+
+/// Builds a query message, like `DnsHandle::lookup` does, but allowing to
+/// clear the RD flag and to query another class.
+fn query_message(
+    name: rr::Name,
+    rtype: rr::RecordType,
+    dns_class: rr::DNSClass,
+    recursion_desired: bool,
+) -> op::Message {
+    let mut query = op::Query::query(name, rtype);
+    query.set_query_class(dns_class);
+    let mut message = op::Message::new();
+    message
+        .add_query(query)
+        .set_id(rand::random())
+        .set_message_type(op::MessageType::Query)
+        .set_op_code(op::OpCode::Query)
+        .set_recursion_desired(recursion_desired);
+    message
+}
+
+/// Record types unknown to `trust-dns` whose data consists of
+/// character-strings, like that of `TXT` records, along with their mnemonics.
+const TEXT_TYPES: &[(u16, &str)] = &[
+    (56, "NINFO"),
+    (99, "SPF"),
+    (258, "AVC"),
+    (261, "RESINFO"),
+    (262, "WALLET"),
+];
+
+/// Returns the mnemonic of a record type that is unknown to `trust-dns`, but
+/// whose data is shown like that of `TXT` records.
+fn text_type_name(code: u16) -> Option<&'static str> {
+    TEXT_TYPES
+        .iter()
+        .find(|(text_code, _)| *text_code == code)
+        .map(|(_, name)| *name)
+}
+
+/// Parses a record type, also accepting the mnemonics of the record types in
+/// `TEXT_TYPES`, and the `TYPE` notation of RFC 3597, e.g. `TYPE258`.
+pub fn parse_record_type(s: &str) -> Result<rr::RecordType, ProtoError> {
+    let e = match s.parse() {
+        Ok(rtype) => return Ok(rtype),
+        Err(e) => e,
+    };
+    let upper = s.to_uppercase();
+    if let Some((code, _)) = TEXT_TYPES.iter().find(|(_, name)| *name == upper) {
+        return Ok(rr::RecordType::Unknown(*code));
+    }
+    match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+        Some(Ok(code)) => Ok(rr::RecordType::from(code)),
+        _ => Err(e),
+    }
+}
+
+/// A record type name that could not be parsed.
+#[derive(Debug, Clone)]
+pub struct InvalidRecordType {
+    pub name: String,
+    pub error: ProtoError,
+}
+
+impl fmt::Display for InvalidRecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid record type {}: {}", self.name, self.error)
+    }
+}
+
+impl std::error::Error for InvalidRecordType {}
+
+/// Determines the unknown record types to skip among `types`, the results of
+/// parsing the types to query.
+///
+/// Unknown types are only skipped if `strict` is not set and there is at least
+/// one valid type; otherwise, the first unknown type is returned as error.
+pub fn skipped_record_types<T>(
+    types: &[Result<T, InvalidRecordType>],
+    strict: bool,
+) -> Result<Vec<&InvalidRecordType>, InvalidRecordType> {
+    let invalid: Vec<_> = types.iter().filter_map(|t| t.as_ref().err()).collect();
+    match invalid.first() {
+        Some(&first) if strict || invalid.len() == types.len() => Err(first.clone()),
+        _ => Ok(invalid),
+    }
+}
+
+/// Error indicating that some or all queries have failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryFailures {
+    pub n_failed: usize,
+    pub total: usize,
+}
+
+impl QueryFailures {
+    /// Checks the outcome of `total` queries, of which `n_failed` have failed.
+    ///
+    /// The `n_skipped` record types that were skipped as unknown count as
+    /// failed queries, so that the exit status reflects them.
+    pub fn check(n_failed: usize, total: usize, n_skipped: usize) -> Result<(), Self> {
+        let failures = QueryFailures {
+            n_failed: n_failed + n_skipped,
+            total: total + n_skipped,
+        };
+        if failures.n_failed > 0 {
+            return Err(failures);
+        }
+        Ok(())
+    }
+
+    /// The exit status: 2 if only some of the queries have failed, 3 if all
+    /// of them have.
+    pub fn exit_code(&self) -> i32 {
+        if self.n_failed < self.total {
+            2
+        } else {
+            3
+        }
+    }
+}
+
+impl fmt::Display for QueryFailures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} queries failed", self.n_failed, self.total)
+    }
+}
+
+impl std::error::Error for QueryFailures {}
+
+/// Splits record data into the character-strings it consists of, returning
+/// `None` if the data is malformed.
+pub(crate) fn character_strings(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut strings = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        if rest.len() < usize::from(len) {
+            return None;
+        }
+        let (string, rest) = rest.split_at(usize::from(len));
+        strings.push(string);
+        data = rest;
+    }
+    Some(strings)
+}
+
+/// Writes character-strings separated by spaces, as in the data of `TXT`
+/// records.
+fn write_character_strings<T: AsRef<[u8]>>(f: &mut fmt::Formatter, strings: &[T]) -> fmt::Result {
+    for (i, string) in strings.iter().enumerate() {
+        if i > 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{}", CharacterString(string.as_ref()))?;
+    }
+    Ok(())
+}
+
+/// Returns the wire format of `rdata`.
+fn rdata_wire(rdata: &rr::RData) -> Result<Vec<u8>, ProtoError> {
+    let mut wire = Vec::new();
+    let mut encoder = BinEncoder::new(&mut wire);
+    // Without compression, the data is self-contained.
+    encoder.set_canonical_names(true);
+    rdata.emit(&mut encoder)?;
+    Ok(wire)
+}
+
+/// Displays record data in the generic notation of RFC 3597, section 5.
+struct DisplayGeneric<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for DisplayGeneric<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\\# {}", self.0.len())?;
+        if !self.0.is_empty() {
+            write!(f, " {}", DisplayEncoded(&HEXUPPER, self.0))?;
+        }
+        Ok(())
+    }
+}
+
 struct CharacterString<'a>(&'a [u8]);
 
 impl<'a> fmt::Display for CharacterString<'a> {
@@ -141,7 +1345,7 @@ impl<'a> fmt::Display for CharacterStringContents<'a> {
 }
 
 #[derive(Debug, Copy, Clone)]
-struct DisplayRData<'a>(&'a rr::RData);
+pub(crate) struct DisplayRData<'a>(pub(crate) &'a rr::RData);
 
 impl<'a> fmt::Display for DisplayRData<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -179,6 +1383,7 @@ impl<'a> fmt::Display for DisplayRData<'a> {
                 }
             }
             CNAME(name) => write!(f, "{}", name)?,
+            #[cfg(feature = "dnssec")]
             DNSSEC(sec) => write!(f, "{}", DisplayDNSSECRData(sec))?,
             MX(mx) => write!(f, "{} {}", mx.preference(), mx.exchange())?,
             NAPTR(naptr) => write!(
@@ -230,25 +1435,122 @@ impl<'a> fmt::Display for DisplayRData<'a> {
                 u8::from(tlsa.matching()),
                 DisplayEncoded(&HEXLOWER, tlsa.cert_data())
             )?,
-            TXT(txt) => {
-                for (i, data) in txt.txt_data().iter().enumerate() {
-                    let chars = CharacterString(data);
-                    if i + 1 < txt.txt_data().len() {
-                        write!(f, "{} ", chars)?;
-                    } else {
-                        write!(f, "{}", chars)?;
+            TXT(txt) => write_character_strings(f, txt.txt_data())?,
+            HTTPS(svcb) | SVCB(svcb) => write!(f, "{}", DisplaySvcb(svcb))?,
+            HINFO(hinfo) => write!(
+                f,
+                "{} {}",
+                CharacterString(hinfo.cpu()),
+                CharacterString(hinfo.os())
+            )?,
+            Unknown { code, rdata } => {
+                let data = rdata.anything().unwrap_or_default();
+                match character_strings(data) {
+                    Some(strings) if text_type_name(*code).is_some() => {
+                        write_character_strings(f, &strings)?
                     }
+                    _ => write!(f, "{}", DisplayGeneric(data))?,
+                }
+            }
+            NULL(null) => write!(f, "{}", DisplayGeneric(null.anything().unwrap_or_default()))?,
+            ZERO => write!(f, "{}", DisplayGeneric(&[]))?,
+            // `OPT` records are skipped by `DisplayFormat::shows`, and are
+            // usually not even part of a response's sections, as `trust-dns`
+            // keeps them apart as EDNS data.
+            OPT(_) => write!(
+                f,
+                "{}",
+                DisplayGeneric(&rdata_wire(self.0).map_err(|_| fmt::Error)?)
+            )?,
+            #[cfg(not(feature = "dnssec"))]
+            DNSSEC(_) => write!(
+                f,
+                "{}",
+                DisplayGeneric(&rdata_wire(self.0).map_err(|_| fmt::Error)?)
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Displays SVCB and HTTPS record data in presentation format.
+///
+/// This differs from the `Display` implementation of `svcb::SVCB` in not
+/// adding trailing commas to lists of values.
+struct DisplaySvcb<'a>(&'a svcb::SVCB);
+
+impl<'a> fmt::Display for DisplaySvcb<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn write_list<T: fmt::Display>(
+            f: &mut fmt::Formatter,
+            items: impl IntoIterator<Item = T>,
+        ) -> fmt::Result {
+            for (i, item) in items.into_iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
                 }
+                write!(f, "{}", item)?;
+            }
+            Ok(())
+        }
+        let svcb = self.0;
+        write!(f, "{} {}", svcb.svc_priority(), svcb.target_name())?;
+        for (key, value) in svcb.svc_params() {
+            write!(f, " {}", key)?;
+            match value {
+                svcb::SvcParamValue::NoDefaultAlpn => continue,
+                _ => f.write_str("=")?,
             }
-            // TODO: What to do with records that have no specified presentation?
-            NULL(_) | OPT(_) | Unknown { .. } | ZERO | HINFO(_) | HTTPS(_) | SVCB(_) => {
-                write!(f, "{:?}", self.0)?
+            match value {
+                svcb::SvcParamValue::Mandatory(keys) => write_list(f, &keys.0)?,
+                svcb::SvcParamValue::Alpn(ids) => write_list(f, &ids.0)?,
+                svcb::SvcParamValue::NoDefaultAlpn => {}
+                svcb::SvcParamValue::Port(port) => write!(f, "{}", port)?,
+                svcb::SvcParamValue::Ipv4Hint(hint) => write_list(f, &hint.0)?,
+                svcb::SvcParamValue::Ipv6Hint(hint) => write_list(f, &hint.0)?,
+                svcb::SvcParamValue::EchConfig(config) => {
+                    write!(f, "{}", DisplayEncoded(&BASE64, &config.0))?
+                }
+                svcb::SvcParamValue::Unknown(unknown) => {
+                    write!(f, "{}", CharacterString(&unknown.0.concat()))?
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Returns the addresses given by the `ipv4hint` and `ipv6hint` parameters of
+/// an SVCB or HTTPS record, as `A` and `AAAA` records.
+///
+/// The returned records are owned by the record's target name, or its owner
+/// name, if the target name is the root, and carry its TTL. Records of other
+/// types have no hints.
+pub fn svcb_hints(record: &rr::Record) -> Vec<rr::Record> {
+    let svcb = match record.rdata() {
+        rr::RData::HTTPS(svcb) | rr::RData::SVCB(svcb) => svcb,
+        _ => return Vec::new(),
+    };
+    let target = if svcb.target_name().is_root() {
+        record.name()
+    } else {
+        svcb.target_name()
+    };
+    svcb.svc_params()
+        .iter()
+        .flat_map(|(_, value)| match value {
+            svcb::SvcParamValue::Ipv4Hint(hint) => {
+                hint.0.iter().map(|addr| rr::RData::A(*addr)).collect()
+            }
+            svcb::SvcParamValue::Ipv6Hint(hint) => {
+                hint.0.iter().map(|addr| rr::RData::AAAA(*addr)).collect()
+            }
+            _ => Vec::new(),
+        })
+        .map(|rdata| rr::Record::from_rdata(target.clone(), record.ttl(), rdata))
+        .collect()
+}
+
 struct DisplayEncoded<'a>(&'a Encoding, &'a [u8]);
 
 impl<'a> fmt::Display for DisplayEncoded<'a> {
@@ -259,8 +1561,10 @@ impl<'a> fmt::Display for DisplayEncoded<'a> {
     }
 }
 
+#[cfg(feature = "dnssec")]
 struct ShowTimestamp(u32);
 
+#[cfg(feature = "dnssec")]
 impl fmt::Display for ShowTimestamp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let time =
@@ -269,9 +1573,11 @@ impl fmt::Display for ShowTimestamp {
     }
 }
 
+#[cfg(feature = "dnssec")]
 #[derive(Debug, Copy, Clone)]
 struct DisplayDNSSECRData<'a>(&'a DNSSECRData);
 
+#[cfg(feature = "dnssec")]
 impl<'a> fmt::Display for DisplayDNSSECRData<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use DNSSECRData::*;
@@ -378,6 +1684,7 @@ impl<'a> fmt::Display for DisplayDNSSECRData<'a> {
     }
 }
 
+#[cfg(feature = "dnssec")]
 struct DisplayNSEC3Common<'a> {
     hash_algorithm: Nsec3HashAlgorithm,
     opt_out: bool,
@@ -385,6 +1692,7 @@ struct DisplayNSEC3Common<'a> {
     salt: &'a [u8],
 }
 
+#[cfg(feature = "dnssec")]
 impl<'a> From<&'a rdata::NSEC3> for DisplayNSEC3Common<'a> {
     fn from(nsec3: &'a rdata::NSEC3) -> Self {
         DisplayNSEC3Common {
@@ -396,6 +1704,7 @@ impl<'a> From<&'a rdata::NSEC3> for DisplayNSEC3Common<'a> {
     }
 }
 
+#[cfg(feature = "dnssec")]
 impl<'a> From<&'a rdata::NSEC3PARAM> for DisplayNSEC3Common<'a> {
     fn from(nsec3: &'a rdata::NSEC3PARAM) -> Self {
         DisplayNSEC3Common {
@@ -407,6 +1716,7 @@ impl<'a> From<&'a rdata::NSEC3PARAM> for DisplayNSEC3Common<'a> {
     }
 }
 
+#[cfg(feature = "dnssec")]
 impl<'a> fmt::Display for DisplayNSEC3Common<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // RFC 5155, Section 4.3
@@ -424,8 +1734,10 @@ impl<'a> fmt::Display for DisplayNSEC3Common<'a> {
     }
 }
 
+#[cfg(feature = "dnssec")]
 struct DisplayNSECTypeBitMaps<'a>(&'a [rr::RecordType]);
 
+#[cfg(feature = "dnssec")]
 impl<'a> fmt::Display for DisplayNSECTypeBitMaps<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (i, record_type) in self.0.iter().enumerate() {
@@ -436,10 +1748,104 @@ impl<'a> fmt::Display for DisplayNSECTypeBitMaps<'a> {
     }
 }
 
+/// Displays an owner name, relative to an origin, if given.
+///
+/// This follows the master file conventions, i.e., the origin itself is
+/// displayed as `@`, names below the origin are displayed as relative names,
+/// and all other names are displayed as fully qualified names.
+struct DisplayOwner<'a>(&'a rr::Name, Option<&'a rr::Name>);
+
+impl<'a> fmt::Display for DisplayOwner<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, origin) = match self.1 {
+            Some(origin) if origin.zone_of(self.0) => (self.0, origin),
+            _ => return write!(f, "{}", self.0),
+        };
+        let n_relative = name.num_labels() - origin.num_labels();
+        if n_relative == 0 {
+            return f.write_str("@");
+        }
+        let mut relative = rr::Name::from_labels(name.iter().take(usize::from(n_relative)))
+            .map_err(|_| fmt::Error)?;
+        relative.set_fqdn(false);
+        write!(f, "{}", relative)
+    }
+}
+
+/// Writes an `$ORIGIN` directive, as used in master files.
+pub fn write_origin<W: io::Write>(writer: &mut W, origin: &rr::Name) -> io::Result<()> {
+    let mut origin = origin.clone();
+    origin.set_fqdn(true);
+    writeln!(writer, "$ORIGIN {}", origin)
+}
+
+/// Writes a comment line naming the server that provided an answer.
+pub fn write_server<W: io::Write>(writer: &mut W, server: SocketAddr) -> io::Result<()> {
+    writeln!(writer, ";; SERVER: {}", server)
+}
+
+/// Writes a comment line noting that the TTLs shown were reset to `ttl`.
+pub fn write_ttl_reset<W: io::Write>(writer: &mut W, ttl: u32) -> io::Result<()> {
+    writeln!(writer, ";; TTLs reset to {}", ttl)
+}
+
+/// Returns a copy of `record` with its TTL replaced by `ttl`.
+pub fn with_ttl(record: &rr::Record, ttl: u32) -> rr::Record {
+    let mut record = record.clone();
+    record.set_ttl(ttl);
+    record
+}
+
+/// Writes the header flags of a response, in the style of `dig`, as a master
+/// file comment.
+pub fn write_flags<W: io::Write>(writer: &mut W, message: &op::Message) -> io::Result<()> {
+    let flags = [
+        (message.message_type() == op::MessageType::Response, "qr"),
+        (message.authoritative(), "aa"),
+        (message.truncated(), "tc"),
+        (message.recursion_desired(), "rd"),
+        (message.recursion_available(), "ra"),
+        (message.authentic_data(), "ad"),
+        (message.checking_disabled(), "cd"),
+    ];
+    let flags: Vec<_> = flags
+        .iter()
+        .filter_map(|(set, name)| if *set { Some(*name) } else { None })
+        .collect();
+    writeln!(writer, ";; flags: {}", flags.join(" "))
+}
+
+/// Writes the answer, authority and additional sections of a response, each
+/// preceded by a header comment; empty sections are omitted.
+pub fn write_sections<W: io::Write>(
+    writer: &mut W,
+    message: &op::Message,
+    format: DisplayFormat,
+    origin: Option<&rr::Name>,
+) -> io::Result<()> {
+    let sections = [
+        ("ANSWER", message.answers()),
+        ("AUTHORITY", message.name_servers()),
+        ("ADDITIONAL", message.additionals()),
+    ];
+    for (name, records) in sections.iter() {
+        if records.is_empty() {
+            continue;
+        }
+        writeln!(writer, ";; {} SECTION:", name)?;
+        for record in records.iter().filter(|record| format.shows(record)) {
+            write_record(writer, record, format, origin)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
 pub fn write_record<W: io::Write>(
     writer: &mut W,
     record: &rr::Record,
     format: DisplayFormat,
+    origin: Option<&rr::Name>,
 ) -> io::Result<()> {
     match format {
         DisplayFormat::Short => {
@@ -449,13 +1855,119 @@ pub fn write_record<W: io::Write>(
             write!(
                 writer,
                 "{} {} {} {} {}",
-                record.name(),
+                DisplayOwner(record.name(), origin),
                 record.ttl(),
                 record.dns_class(),
-                record.record_type(),
+                record_type_name(record.record_type()),
                 DisplayRData(record.rdata()),
             )?;
         }
+        DisplayFormat::Hosts => {
+            // Host names in `/etc/hosts` are written without the trailing
+            // dot; records not shown in this format are skipped by callers.
+            let name = record.name().to_string();
+            match record.rdata() {
+                rr::RData::A(addr) => write!(writer, "{}\t{}", addr, name.trim_end_matches('.'))?,
+                rr::RData::AAAA(addr) => {
+                    write!(writer, "{}\t{}", addr, name.trim_end_matches('.'))?
+                }
+                _ => {}
+            }
+        }
+        DisplayFormat::JsonRfc8427 => write!(writer, "{}", record_json(record)?)?,
     }
     Ok(())
 }
+
+/// Returns the JSON representation of `message` according to RFC 8427,
+/// including the header fields and all sections.
+///
+/// The EDNS `OPT` record and any `SIG(0)` records are included in the
+/// additional section, where they occur on the wire.
+pub fn message_json(message: &op::Message) -> io::Result<serde_json::Value> {
+    let mut additionals = message.additionals().to_vec();
+    additionals.extend(message.edns().map(rr::Record::from));
+    additionals.extend(message.sig0().iter().cloned());
+    let mut json = serde_json::Map::new();
+    let mut set = |key: &str, value: serde_json::Value| json.insert(key.into(), value);
+    set("ID", message.id().into());
+    set(
+        "QR",
+        (message.message_type() == op::MessageType::Response).into(),
+    );
+    set("Opcode", u8::from(message.op_code()).into());
+    set("AA", message.authoritative().into());
+    set("TC", message.truncated().into());
+    set("RD", message.recursion_desired().into());
+    set("RA", message.recursion_available().into());
+    set("AD", message.authentic_data().into());
+    set("CD", message.checking_disabled().into());
+    set("RCODE", message.response_code().low().into());
+    set("QDCOUNT", message.queries().len().into());
+    set("ANCOUNT", message.answers().len().into());
+    set("NSCOUNT", message.name_servers().len().into());
+    set("ARCOUNT", additionals.len().into());
+    // A single question is represented by members of the message itself.
+    if let [query] = message.queries() {
+        set("QNAME", query.name().to_string().into());
+        set("QTYPE", u16::from(query.query_type()).into());
+        set("QTYPEname", record_type_name(query.query_type()).into());
+        set("QCLASS", u16::from(query.query_class()).into());
+        set("QCLASSname", query.query_class().to_string().into());
+    } else {
+        let questions = message.queries().iter().map(|query| {
+            serde_json::json!({
+                "NAME": query.name().to_string(),
+                "TYPE": u16::from(query.query_type()),
+                "TYPEname": record_type_name(query.query_type()),
+                "CLASS": u16::from(query.query_class()),
+                "CLASSname": query.query_class().to_string(),
+            })
+        });
+        set("questionRRs", questions.collect());
+    }
+    let records = |records: &[rr::Record]| -> io::Result<serde_json::Value> {
+        records.iter().map(record_json).collect()
+    };
+    set("answerRRs", records(message.answers())?);
+    set("authorityRRs", records(message.name_servers())?);
+    set("additionalRRs", records(&additionals)?);
+    Ok(json.into())
+}
+
+/// Returns the RFC 8427 JSON representation of `record`.
+///
+/// Besides the `RDATAHEX` member, the data is given in presentation format
+/// for the types RFC 8427 defines an `rdata` member for.
+fn record_json(record: &rr::Record) -> io::Result<serde_json::Value> {
+    let rdata =
+        rdata_wire(record.rdata()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let rtype = record.record_type();
+    let mut json = serde_json::json!({
+        "NAME": record.name().to_string(),
+        "TYPE": u16::from(rtype),
+        "TYPEname": record_type_name(rtype),
+        "CLASS": u16::from(record.dns_class()),
+        "CLASSname": record.dns_class().to_string(),
+        "TTL": record.ttl(),
+        "RDLENGTH": rdata.len(),
+        "RDATAHEX": HEXUPPER.encode(&rdata),
+    });
+    use rr::RData::*;
+    if let A(_) | AAAA(_) | CNAME(_) | NS(_) | PTR(_) | TXT(_) = record.rdata() {
+        json[format!("rdata{}", rtype)] = DisplayRData(record.rdata()).to_string().into();
+    }
+    Ok(json)
+}
+
+/// Returns the mnemonic of `rtype`, using the `TYPE` notation of RFC 3597 for
+/// types without one.
+pub fn record_type_name(rtype: rr::RecordType) -> String {
+    match rtype {
+        rr::RecordType::Unknown(code) => match text_type_name(code) {
+            Some(name) => name.to_owned(),
+            None => format!("TYPE{}", code),
+        },
+        _ => rtype.to_string(),
+    }
+}
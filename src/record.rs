@@ -1,35 +1,68 @@
 use std::{
+    cmp::Ordering,
     collections::{btree_set, BTreeSet},
     convert::TryFrom,
     fmt,
+    hash::{Hash, Hasher},
     net::{self, Ipv4Addr, Ipv6Addr},
     str::{self, FromStr},
     string::FromUtf8Error,
 };
 
-use trust_dns_client::rr::{self, rdata};
+use data_encoding::{BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
+use trust_dns_client::rr::{
+    self,
+    dnssec::{Algorithm, DigestType},
+    rdata::{self, DNSSECRData, DNSSECRecordType},
+};
+
+use crate::query::{self, DisplayRData};
 
 /// This is a representation of the record set as described in RFC 2136.
 ///
 /// A domain name identifies a node within the domain name space tree structure.
 /// Each node has a set (possibly empty) of Resource Records (RRs).  All RRs
 /// having the same NAME, CLASS and TYPE are called a Resource Record Set (RRset
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq)]
 pub struct RecordSet {
     name: rr::Name,
     dns_class: rr::DNSClass,
+    ttl: Option<u32>,
     data: RsData,
 }
 
+/// Record sets are compared without their TTL, which is not part of the
+/// RRset's contents; e.g. a set read from a server, which never carries a
+/// TTL, equals the desired set given with one.
+impl PartialEq for RecordSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.dns_class == other.dns_class && self.data == other.data
+    }
+}
+
 impl RecordSet {
     pub fn new(name: rr::Name, data: RsData) -> Self {
         RecordSet {
             name,
             dns_class: rr::DNSClass::IN,
+            ttl: None,
             data,
         }
     }
 
+    /// Sets the class of the record set, which defaults to `IN`.
+    pub fn with_class(mut self, dns_class: rr::DNSClass) -> Self {
+        self.dns_class = dns_class;
+        self
+    }
+
+    /// Sets the TTL of the records, which takes precedence over the one
+    /// passed to `to_rrset`.
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
     pub fn name(&self) -> &rr::Name {
         &self.name
     }
@@ -38,15 +71,26 @@ impl RecordSet {
         self.dns_class
     }
 
+    /// Returns the TTL of the records, if one was set via `with_ttl`.
+    pub fn ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
     pub fn record_type(&self) -> rr::RecordType {
         self.data.record_type()
     }
 
+    /// Returns the records, using `ttl` unless the record set carries its own
+    /// TTL.
     pub fn to_rrset(&self, ttl: u32) -> rr::RecordSet {
+        let ttl = self.ttl.unwrap_or(ttl);
         let mut rrset = rr::RecordSet::with_ttl(self.name.clone(), self.record_type(), ttl);
         for data in self.iter_data() {
             rrset.add_rdata(data);
         }
+        // Records are added with class IN, so the class is set afterwards,
+        // which applies it to them as well.
+        rrset.set_dns_class(self.dns_class);
         rrset
     }
 
@@ -57,8 +101,16 @@ impl RecordSet {
     pub fn iter_data(&self) -> RsDataIter {
         let inner = match &self.data {
             RsData::TXT(txts) => RsDataIterInner::TXT(txts.iter()),
+            RsData::AVC(avcs) => RsDataIterInner::AVC(avcs.iter()),
             RsData::A(addrs) => RsDataIterInner::A(addrs.iter()),
             RsData::AAAA(addrs) => RsDataIterInner::AAAA(addrs.iter()),
+            RsData::OPENPGPKEY(keys) => RsDataIterInner::OPENPGPKEY(keys.iter()),
+            RsData::NAPTR(naptrs) => RsDataIterInner::NAPTR(naptrs.iter()),
+            RsData::MX(mxs) => RsDataIterInner::MX(mxs.iter()),
+            RsData::NS(names) => RsDataIterInner::NS(names.iter()),
+            RsData::SRV(srvs) => RsDataIterInner::SRV(srvs.iter()),
+            RsData::DS(dss) => RsDataIterInner::DS(dss.iter()),
+            RsData::DNSKEY(keys) => RsDataIterInner::DNSKEY(keys.iter()),
         };
         RsDataIter(inner)
     }
@@ -72,8 +124,29 @@ impl RecordSet {
                     false
                 }
             }
+            (
+                RsData::AVC(avcs),
+                rr::RData::Unknown {
+                    code: AVC_CODE,
+                    rdata,
+                },
+            ) => match avc_string(rdata) {
+                Ok(avc) => avcs.contains(&avc),
+                Err(_) => false,
+            },
             (RsData::A(addrs), rr::RData::A(addr)) => addrs.contains(addr),
             (RsData::AAAA(addrs), rr::RData::AAAA(addr)) => addrs.contains(addr),
+            (RsData::OPENPGPKEY(keys), rr::RData::OPENPGPKEY(key)) => {
+                keys.contains(key.public_key())
+            }
+            (RsData::NAPTR(naptrs), rr::RData::NAPTR(naptr)) => naptrs.contains(&naptr.into()),
+            (RsData::MX(mxs), rr::RData::MX(mx)) => mxs.contains(&mx.into()),
+            (RsData::NS(names), rr::RData::NS(name)) => names.contains(name),
+            (RsData::SRV(srvs), rr::RData::SRV(srv)) => srvs.contains(&srv.into()),
+            (RsData::DS(dss), rr::RData::DNSSEC(DNSSECRData::DS(ds))) => dss.contains(&ds.into()),
+            (RsData::DNSKEY(keys), rr::RData::DNSSEC(DNSSECRData::DNSKEY(key))) => {
+                keys.contains(&key.into())
+            }
             _ => false,
         }
     }
@@ -81,8 +154,16 @@ impl RecordSet {
     pub fn is_empty(&self) -> bool {
         match &self.data {
             RsData::TXT(txts) => txts.is_empty(),
+            RsData::AVC(avcs) => avcs.is_empty(),
             RsData::A(addrs) => addrs.is_empty(),
             RsData::AAAA(addrs) => addrs.is_empty(),
+            RsData::OPENPGPKEY(keys) => keys.is_empty(),
+            RsData::NAPTR(naptrs) => naptrs.is_empty(),
+            RsData::MX(mxs) => mxs.is_empty(),
+            RsData::NS(names) => names.is_empty(),
+            RsData::SRV(srvs) => srvs.is_empty(),
+            RsData::DS(dss) => dss.is_empty(),
+            RsData::DNSKEY(keys) => keys.is_empty(),
         }
     }
 
@@ -93,13 +174,127 @@ impl RecordSet {
         }
         match (&self.data, &other.data) {
             (TXT(txts), TXT(other_txts)) => txts.is_subset(other_txts),
+            (AVC(avcs), AVC(other_avcs)) => avcs.is_subset(other_avcs),
             (A(addrs), A(other_addrs)) => addrs.is_subset(other_addrs),
             (AAAA(addrs), AAAA(other_addrs)) => addrs.is_subset(other_addrs),
+            (OPENPGPKEY(keys), OPENPGPKEY(other_keys)) => keys.is_subset(other_keys),
+            (NAPTR(naptrs), NAPTR(other_naptrs)) => naptrs.is_subset(other_naptrs),
+            (MX(mxs), MX(other_mxs)) => mxs.is_subset(other_mxs),
+            (NS(names), NS(other_names)) => names.is_subset(other_names),
+            (SRV(srvs), SRV(other_srvs)) => srvs.is_subset(other_srvs),
+            (DS(dss), DS(other_dss)) => dss.is_subset(other_dss),
+            (DNSKEY(keys), DNSKEY(other_keys)) => keys.is_subset(other_keys),
             _ => false,
         }
     }
+
+    /// Returns the empty record set with the same name, class and type.
+    pub fn cleared(&self) -> RecordSet {
+        RecordSet {
+            name: self.name.clone(),
+            dns_class: self.dns_class,
+            ttl: self.ttl,
+            data: self.data.cleared(),
+        }
+    }
+
+    /// Returns the key, i.e., name, class and type, of the record set.
+    pub fn key(&self) -> RsKey {
+        RsKey {
+            name: self.name.clone(),
+            dns_class: self.dns_class,
+            record_type: self.record_type(),
+        }
+    }
+
+    /// Returns the record set containing the data of both `self` and
+    /// `other`, which must have the same key.
+    pub fn union(&self, other: &RecordSet) -> Result<RecordSet, RecordSetMismatch> {
+        self.combine(other, SetOp::Union)
+    }
+
+    /// Returns the record set containing the data present in both `self` and
+    /// `other`, which must have the same key.
+    pub fn intersection(&self, other: &RecordSet) -> Result<RecordSet, RecordSetMismatch> {
+        self.combine(other, SetOp::Intersection)
+    }
+
+    /// Returns the record set containing the data of `self` not present in
+    /// `other`, which must have the same key.
+    ///
+    /// For a desired and an actual record set, `desired.difference(&actual)`
+    /// gives the data to add, while `actual.difference(&desired)` gives the
+    /// data to remove.
+    pub fn difference(&self, other: &RecordSet) -> Result<RecordSet, RecordSetMismatch> {
+        self.combine(other, SetOp::Difference)
+    }
+
+    fn combine(&self, other: &RecordSet, op: SetOp) -> Result<RecordSet, RecordSetMismatch> {
+        use RsData::*;
+        let mismatch = || RecordSetMismatch {
+            left: Box::new(self.key()),
+            right: Box::new(other.key()),
+        };
+        if self.name != other.name || self.dns_class != other.dns_class {
+            return Err(mismatch());
+        }
+        let data = match (&self.data, &other.data) {
+            (TXT(txts), TXT(other_txts)) => TXT(op.apply(txts, other_txts)),
+            (AVC(avcs), AVC(other_avcs)) => AVC(op.apply(avcs, other_avcs)),
+            (A(addrs), A(other_addrs)) => A(op.apply(addrs, other_addrs)),
+            (AAAA(addrs), AAAA(other_addrs)) => AAAA(op.apply(addrs, other_addrs)),
+            (OPENPGPKEY(keys), OPENPGPKEY(other_keys)) => OPENPGPKEY(op.apply(keys, other_keys)),
+            (NAPTR(naptrs), NAPTR(other_naptrs)) => NAPTR(op.apply(naptrs, other_naptrs)),
+            (MX(mxs), MX(other_mxs)) => MX(op.apply(mxs, other_mxs)),
+            (NS(names), NS(other_names)) => NS(op.apply(names, other_names)),
+            (SRV(srvs), SRV(other_srvs)) => SRV(op.apply(srvs, other_srvs)),
+            (DS(dss), DS(other_dss)) => DS(op.apply(dss, other_dss)),
+            (DNSKEY(keys), DNSKEY(other_keys)) => DNSKEY(op.apply(keys, other_keys)),
+            _ => return Err(mismatch()),
+        };
+        Ok(RecordSet {
+            name: self.name.clone(),
+            dns_class: self.dns_class,
+            ttl: self.ttl,
+            data,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl SetOp {
+    fn apply<T: Ord + Clone>(self, left: &BTreeSet<T>, right: &BTreeSet<T>) -> BTreeSet<T> {
+        match self {
+            SetOp::Union => left.union(right).cloned().collect(),
+            SetOp::Intersection => left.intersection(right).cloned().collect(),
+            SetOp::Difference => left.difference(right).cloned().collect(),
+        }
+    }
+}
+
+/// The error returned when combining record sets with different keys.
+///
+/// The keys are boxed to keep the `Result`s carrying this error small.
+#[derive(Debug)]
+pub struct RecordSetMismatch {
+    pub left: Box<RsKey>,
+    pub right: Box<RsKey>,
+}
+
+impl fmt::Display for RecordSetMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record sets differ: {} vs. {}", self.left, self.right)
+    }
 }
 
+impl std::error::Error for RecordSetMismatch {}
+
 impl fmt::Display for RecordSet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} ({})", self.name, self.data)
@@ -119,7 +314,24 @@ impl<'a> Iterator for RsDataIter<'a> {
             AAAA(iter) => iter.next().map(|item| rr::RData::AAAA(*item)),
             TXT(iter) => iter
                 .next()
-                .map(|item| rr::RData::TXT(rdata::TXT::new(vec![item.into()]))),
+                .map(|item| rr::RData::TXT(rdata::TXT::from_bytes(item.strings()))),
+            AVC(iter) => iter.next().map(|item| rr::RData::Unknown {
+                code: AVC_CODE,
+                rdata: avc_rdata(item),
+            }),
+            OPENPGPKEY(iter) => iter
+                .next()
+                .map(|item| rr::RData::OPENPGPKEY(rdata::OPENPGPKEY::new(item.clone()))),
+            NAPTR(iter) => iter.next().map(|item| rr::RData::NAPTR(item.to_rdata())),
+            MX(iter) => iter.next().map(|item| rr::RData::MX(item.to_rdata())),
+            NS(iter) => iter.next().map(|item| rr::RData::NS(item.clone())),
+            SRV(iter) => iter.next().map(|item| rr::RData::SRV(item.to_rdata())),
+            DS(iter) => iter
+                .next()
+                .map(|item| rr::RData::DNSSEC(DNSSECRData::DS(item.to_rdata()))),
+            DNSKEY(iter) => iter
+                .next()
+                .map(|item| rr::RData::DNSSEC(DNSSECRData::DNSKEY(item.to_rdata()))),
         }
     }
 }
@@ -127,62 +339,180 @@ impl<'a> Iterator for RsDataIter<'a> {
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 enum RsDataIterInner<'a> {
-    TXT(btree_set::Iter<'a, String>),
+    TXT(btree_set::Iter<'a, Txt>),
+    AVC(btree_set::Iter<'a, Txt>),
     A(btree_set::Iter<'a, Ipv4Addr>),
     AAAA(btree_set::Iter<'a, Ipv6Addr>),
+    OPENPGPKEY(btree_set::Iter<'a, Vec<u8>>),
+    NAPTR(btree_set::Iter<'a, Naptr>),
+    MX(btree_set::Iter<'a, Mx>),
+    NS(btree_set::Iter<'a, rr::Name>),
+    SRV(btree_set::Iter<'a, Srv>),
+    DS(btree_set::Iter<'a, Ds>),
+    DNSKEY(btree_set::Iter<'a, Dnskey>),
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum RsData {
-    TXT(BTreeSet<String>), // TODO: simplified, only single value for now.
+    /// The text of each `TXT` record. Text longer than a character-string
+    /// is split into multiple ones, which are joined again when reading
+    /// records, as is done for SPF and DKIM records; see `Txt`.
+    TXT(BTreeSet<Txt>),
+    /// Application visibility and control data, which is text like that of
+    /// `TXT` records.
+    AVC(BTreeSet<Txt>),
     A(BTreeSet<Ipv4Addr>),
     AAAA(BTreeSet<Ipv6Addr>),
+    /// OpenPGP public keys, as per RFC 7929; given in base64 notation.
+    OPENPGPKEY(BTreeSet<Vec<u8>>),
+    /// Naming authority pointers, as per RFC 3403; given in presentation
+    /// format.
+    NAPTR(BTreeSet<Naptr>),
+    /// Mail exchangers; given as preference and exchange name. The records
+    /// are ordered by preference.
+    MX(BTreeSet<Mx>),
+    /// Authoritative nameservers; given as host names.
+    NS(BTreeSet<rr::Name>),
+    /// Service locations, as per RFC 2782; given as priority, weight, port
+    /// and target name. The records are ordered by priority, then weight.
+    SRV(BTreeSet<Srv>),
+    /// Delegation signers, as per RFC 4034; given in presentation format.
+    DS(BTreeSet<Ds>),
+    /// DNSSEC public keys, as per RFC 4034; given in presentation format.
+    DNSKEY(BTreeSet<Dnskey>),
 }
 
 impl RsData {
     pub fn record_type(&self) -> rr::RecordType {
         match self {
             RsData::TXT(_) => rr::RecordType::TXT,
+            RsData::AVC(_) => rr::RecordType::Unknown(AVC_CODE),
             RsData::A(_) => rr::RecordType::A,
             RsData::AAAA(_) => rr::RecordType::AAAA,
+            RsData::OPENPGPKEY(_) => rr::RecordType::OPENPGPKEY,
+            RsData::NAPTR(_) => rr::RecordType::NAPTR,
+            RsData::MX(_) => rr::RecordType::MX,
+            RsData::NS(_) => rr::RecordType::NS,
+            RsData::SRV(_) => rr::RecordType::SRV,
+            RsData::DS(_) => rr::RecordType::DNSSEC(DNSSECRecordType::DS),
+            RsData::DNSKEY(_) => rr::RecordType::DNSSEC(DNSSECRecordType::DNSKEY),
+        }
+    }
+
+    /// Returns empty data of the same type.
+    pub fn cleared(&self) -> Self {
+        match self {
+            RsData::TXT(_) => RsData::TXT(BTreeSet::new()),
+            RsData::AVC(_) => RsData::AVC(BTreeSet::new()),
+            RsData::A(_) => RsData::A(BTreeSet::new()),
+            RsData::AAAA(_) => RsData::AAAA(BTreeSet::new()),
+            RsData::OPENPGPKEY(_) => RsData::OPENPGPKEY(BTreeSet::new()),
+            RsData::NAPTR(_) => RsData::NAPTR(BTreeSet::new()),
+            RsData::MX(_) => RsData::MX(BTreeSet::new()),
+            RsData::NS(_) => RsData::NS(BTreeSet::new()),
+            RsData::SRV(_) => RsData::SRV(BTreeSet::new()),
+            RsData::DS(_) => RsData::DS(BTreeSet::new()),
+            RsData::DNSKEY(_) => RsData::DNSKEY(BTreeSet::new()),
+        }
+    }
+
+    /// Constructs record set data from a type name and the textual
+    /// representation of each data item.
+    ///
+    /// Unlike parsing the `TYPE:ITEM,...` notation, this allows for items
+    /// containing commas.
+    pub fn from_parts<'a, I>(rtype: &str, items: I) -> Result<Self, RsDataParseError>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let items = items.into_iter();
+        match rtype.to_uppercase().as_str() {
+            "TXT" => Ok(RsData::TXT(items.map(Txt::from).collect())),
+            "AVC" => Ok(RsData::AVC(items.map(Txt::from).collect())),
+            "A" => {
+                let addrs = items
+                    .map(|item| item.parse().map_err(RsDataParseError::Addr))
+                    .collect::<Result<_, _>>()?;
+                Ok(RsData::A(addrs))
+            }
+            "AAAA" => {
+                let addrs = items
+                    .map(|item| item.parse().map_err(RsDataParseError::Addr))
+                    .collect::<Result<_, _>>()?;
+                Ok(RsData::AAAA(addrs))
+            }
+            "OPENPGPKEY" => {
+                let keys = items
+                    .map(|item| {
+                        BASE64
+                            .decode(item.as_bytes())
+                            .map_err(RsDataParseError::Base64)
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(RsData::OPENPGPKEY(keys))
+            }
+            "NAPTR" => Ok(RsData::NAPTR(
+                items.map(str::parse).collect::<Result<_, _>>()?,
+            )),
+            "MX" => Ok(RsData::MX(items.map(str::parse).collect::<Result<_, _>>()?)),
+            "NS" => {
+                let names = items
+                    .map(|item| {
+                        parse_fqdn(item).ok_or_else(|| RsDataParseError::Ns(item.to_owned()))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(RsData::NS(names))
+            }
+            "SRV" => Ok(RsData::SRV(
+                items.map(str::parse).collect::<Result<_, _>>()?,
+            )),
+            "DS" => Ok(RsData::DS(items.map(str::parse).collect::<Result<_, _>>()?)),
+            "DNSKEY" => Ok(RsData::DNSKEY(
+                items.map(str::parse).collect::<Result<_, _>>()?,
+            )),
+            _ => Err(RsDataParseError::UnknownType),
         }
     }
 }
 
 impl fmt::Display for RsData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // FIXME: DRY
         match self {
-            RsData::A(addrs) => {
-                write!(f, "A:")?;
-                for (i, addr) in addrs.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ",")?;
-                    }
-                    write!(f, "{}", addr)?;
-                }
-            }
-            RsData::AAAA(addrs) => {
-                write!(f, "A:")?;
-                for (i, addr) in addrs.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ",")?;
-                    }
-                    write!(f, "{}", addr)?;
-                }
-            }
-            RsData::TXT(txts) => {
-                write!(f, "TXT:")?;
-                for (i, txt) in txts.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ",")?;
-                    }
-                    write!(f, "{}", txt)?;
-                }
+            RsData::A(addrs) => write_items(f, "A", addrs, |f, addr| write!(f, "{}", addr)),
+            RsData::AAAA(addrs) => write_items(f, "AAAA", addrs, |f, addr| write!(f, "{}", addr)),
+            RsData::TXT(txts) => write_items(f, "TXT", txts, |f, txt| write_txt(f, txt.as_str())),
+            RsData::AVC(avcs) => write_items(f, "AVC", avcs, |f, avc| write_txt(f, avc.as_str())),
+            RsData::OPENPGPKEY(keys) => write_items(f, "OPENPGPKEY", keys, |f, key| {
+                write!(f, "{}", BASE64.encode(key))
+            }),
+            RsData::NAPTR(naptrs) => {
+                write_items(f, "NAPTR", naptrs, |f, naptr| write!(f, "{}", naptr))
             }
+            RsData::MX(mxs) => write_items(f, "MX", mxs, |f, mx| write!(f, "{}", mx)),
+            RsData::NS(names) => write_items(f, "NS", names, |f, name| write!(f, "{}", name)),
+            RsData::SRV(srvs) => write_items(f, "SRV", srvs, |f, srv| write!(f, "{}", srv)),
+            RsData::DS(dss) => write_items(f, "DS", dss, |f, ds| write!(f, "{}", ds)),
+            RsData::DNSKEY(keys) => write_items(f, "DNSKEY", keys, |f, key| write!(f, "{}", key)),
+        }
+    }
+}
+
+/// Writes `items` as `RsData` does, i.e. the record type followed by a colon
+/// and the comma-separated items, each written by `write_item`.
+fn write_items<'a, T: 'a>(
+    f: &mut fmt::Formatter,
+    rtype: &str,
+    items: impl IntoIterator<Item = &'a T>,
+    write_item: impl Fn(&mut fmt::Formatter, &T) -> fmt::Result,
+) -> fmt::Result {
+    write!(f, "{}:", rtype)?;
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
         }
-        Ok(())
+        write_item(f, item)?;
     }
+    Ok(())
 }
 
 impl FromStr for RsData {
@@ -191,35 +521,95 @@ impl FromStr for RsData {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = s.splitn(2, ':').collect();
         if parts.len() == 1 {
-            return match parts[0].to_uppercase().as_str() {
-                "TXT" => Ok(RsData::TXT(Default::default())),
-                "A" => Ok(RsData::A(Default::default())),
-                "AAAA" => Ok(RsData::AAAA(Default::default())),
-                _ => Err(RsDataParseError::UnknownType),
-            };
+            return RsData::from_parts(parts[0], None);
         }
         if parts.len() != 2 {
             return Err(RsDataParseError::MissingType);
         }
-        let (rtype, rdata) = (parts[0].to_uppercase(), parts[1]);
-        let rdata_parts = rdata.split(',');
-        match rtype.as_str() {
-            "TXT" => Ok(RsData::TXT(rdata_parts.map(|s| s.to_owned()).collect())),
-            "A" => {
-                let addrs = rdata_parts
-                    .map(|part| part.parse().map_err(RsDataParseError::Addr))
-                    .collect::<Result<_, _>>()?;
-                Ok(RsData::A(addrs))
+        let items = split_items(parts[1])?;
+        if parts[0].eq_ignore_ascii_case("TXT") || parts[0].eq_ignore_ascii_case("AVC") {
+            let txts = items
+                .into_iter()
+                .map(unquote_txt)
+                .collect::<Result<Vec<_>, _>>()?;
+            return RsData::from_parts(parts[0], txts.iter().map(String::as_str));
+        }
+        RsData::from_parts(parts[0], items)
+    }
+}
+
+/// Splits `s` into comma-separated items, keeping commas within double quotes.
+///
+/// Within quotes, a backslash escapes the following character. The items are
+/// returned as-is, i.e., including quotes and backslashes.
+fn split_items(s: &str) -> Result<Vec<&str>, RsDataParseError> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                chars.next();
             }
-            "AAAA" => {
-                let addrs = rdata_parts
-                    .map(|part| part.parse().map_err(RsDataParseError::Addr))
-                    .collect::<Result<_, _>>()?;
-                Ok(RsData::AAAA(addrs))
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&s[start..i]);
+                start = i + 1;
             }
-            _ => Err(RsDataParseError::UnknownType),
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(RsDataParseError::Quoting(format!(
+            "unterminated quote in {}",
+            s
+        )));
+    }
+    items.push(&s[start..]);
+    Ok(items)
+}
+
+/// Returns the text of a `TXT` item, which is taken verbatim, unless it starts
+/// with a double quote.
+///
+/// In the latter case, the item must consist of a single quoted string, in
+/// which a backslash escapes the following character.
+fn unquote_txt(item: &str) -> Result<String, RsDataParseError> {
+    let quoted = match item.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => return Ok(item.to_owned()),
+    };
+    let mut txt = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => txt.extend(chars.next()),
+            '"' if chars.as_str().is_empty() => return Ok(txt),
+            '"' => break,
+            c => txt.push(c),
+        }
+    }
+    Err(RsDataParseError::Quoting(format!(
+        "expected a single quoted string, found {}",
+        item
+    )))
+}
+
+/// Writes a `TXT` item so that `unquote_txt` yields it again, quoting it only
+/// if needed.
+fn write_txt(f: &mut fmt::Formatter, txt: &str) -> fmt::Result {
+    if !txt.starts_with('"') && !txt.contains([',', '"']) {
+        return write!(f, "{}", txt);
+    }
+    write!(f, "\"")?;
+    for c in txt.chars() {
+        if c == '"' || c == '\\' {
+            write!(f, "\\")?;
         }
+        write!(f, "{}", c)?;
     }
+    write!(f, "\"")
 }
 
 #[derive(Debug)]
@@ -227,6 +617,14 @@ pub enum RsDataParseError {
     MissingType,
     UnknownType,
     Addr(net::AddrParseError),
+    Base64(data_encoding::DecodeError),
+    Naptr(String),
+    Mx(String),
+    Ns(String),
+    Srv(String),
+    Ds(String),
+    Dnskey(String),
+    Quoting(String),
 }
 
 impl fmt::Display for RsDataParseError {
@@ -236,12 +634,579 @@ impl fmt::Display for RsDataParseError {
             MissingType => write!(f, "missing type"),
             UnknownType => write!(f, "unknown type"),
             Addr(e) => write!(f, "invalid address: {}", e),
+            Base64(e) => write!(f, "invalid base64 data: {}", e),
+            Naptr(msg) => write!(f, "invalid NAPTR data: {}", msg),
+            Mx(msg) => write!(f, "invalid MX data: {}", msg),
+            Ns(name) => write!(f, "invalid NS name: {}", name),
+            Srv(msg) => write!(f, "invalid SRV data: {}", msg),
+            Ds(msg) => write!(f, "invalid DS data: {}", msg),
+            Dnskey(msg) => write!(f, "invalid DNSKEY data: {}", msg),
+            Quoting(msg) => write!(f, "invalid quoting: {}", msg),
         }
     }
 }
 
 impl std::error::Error for RsDataParseError {}
 
+/// The text of a `TXT` or `AVC` record, which consists of one or more
+/// character-strings of up to 255 bytes each.
+///
+/// Text is compared regardless of how it is split into character-strings.
+/// For text read from a record, the boundaries of its character-strings are
+/// kept, so that the record can be deleted again; otherwise, the text is split
+/// into character-strings of the maximum length.
+#[derive(Debug, Clone)]
+pub struct Txt {
+    text: String,
+    /// The lengths of the character-strings, if read from a record.
+    lengths: Option<Vec<u8>>,
+}
+
+impl Txt {
+    /// Returns the text of the given character-strings, joining them.
+    pub fn from_strings<S: AsRef<[u8]>>(strings: &[S]) -> Result<Self, FromUtf8Error> {
+        let text = String::from_utf8(strings.iter().flat_map(|s| s.as_ref()).copied().collect())?;
+        Ok(Txt {
+            text,
+            lengths: Some(strings.iter().map(|s| s.as_ref().len() as u8).collect()),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the character-strings of the text.
+    pub fn strings(&self) -> Vec<&[u8]> {
+        let bytes = self.text.as_bytes();
+        match &self.lengths {
+            Some(lengths) => {
+                let mut rest = bytes;
+                lengths
+                    .iter()
+                    .map(|&len| {
+                        let (string, tail) = rest.split_at(usize::from(len));
+                        rest = tail;
+                        string
+                    })
+                    .collect()
+            }
+            None if bytes.is_empty() => vec![bytes],
+            None => bytes.chunks(usize::from(u8::MAX)).collect(),
+        }
+    }
+}
+
+impl From<&str> for Txt {
+    fn from(text: &str) -> Self {
+        Txt::from(text.to_owned())
+    }
+}
+
+impl From<String> for Txt {
+    fn from(text: String) -> Self {
+        Txt {
+            text,
+            lengths: None,
+        }
+    }
+}
+
+impl PartialEq for Txt {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Txt {}
+
+impl Hash for Txt {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text.hash(state)
+    }
+}
+
+impl PartialOrd for Txt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Txt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.text.cmp(&other.text)
+    }
+}
+
+impl fmt::Display for Txt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The data of a `NAPTR` record.
+///
+/// This mirrors `rdata::NAPTR`, but is ordered, so it can be part of an
+/// `RsData` set.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: Vec<u8>,
+    pub services: Vec<u8>,
+    pub regexp: Vec<u8>,
+    pub replacement: rr::Name,
+}
+
+impl Naptr {
+    pub fn to_rdata(&self) -> rdata::NAPTR {
+        rdata::NAPTR::new(
+            self.order,
+            self.preference,
+            self.flags.clone().into_boxed_slice(),
+            self.services.clone().into_boxed_slice(),
+            self.regexp.clone().into_boxed_slice(),
+            self.replacement.clone(),
+        )
+    }
+}
+
+impl From<&rdata::NAPTR> for Naptr {
+    fn from(naptr: &rdata::NAPTR) -> Self {
+        Naptr {
+            order: naptr.order(),
+            preference: naptr.preference(),
+            flags: naptr.flags().to_vec(),
+            services: naptr.services().to_vec(),
+            regexp: naptr.regexp().to_vec(),
+            replacement: naptr.replacement().clone(),
+        }
+    }
+}
+
+impl fmt::Display for Naptr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", DisplayRData(&rr::RData::NAPTR(self.to_rdata())))
+    }
+}
+
+impl FromStr for Naptr {
+    type Err = RsDataParseError;
+
+    /// Parses the presentation format, e.g.
+    /// `100 10 "U" "E2U+sip" "!^.*$!sip:info@example.org!" .`.
+    ///
+    /// The replacement name is treated as fully qualified, even without a
+    /// trailing dot.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| RsDataParseError::Naptr(msg.to_owned());
+        let fields = split_character_strings(s).ok_or_else(|| invalid("unbalanced quotes"))?;
+        if fields.len() != 6 {
+            return Err(invalid("expected 6 fields"));
+        }
+        let number = |field: &[u8], what: &str| {
+            str::from_utf8(field)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid(&format!("invalid {}", what)))
+        };
+        let flags = fields[2].clone();
+        if !flags.iter().all(u8::is_ascii_alphanumeric) {
+            return Err(invalid("flags must be alphanumeric"));
+        }
+        let mut replacement = str::from_utf8(&fields[5])
+            .ok()
+            .and_then(|s| rr::Name::from_ascii(s).ok())
+            .ok_or_else(|| invalid("invalid replacement name"))?;
+        replacement.set_fqdn(true);
+        Ok(Naptr {
+            order: number(&fields[0], "order")?,
+            preference: number(&fields[1], "preference")?,
+            flags,
+            services: fields[3].clone(),
+            regexp: fields[4].clone(),
+            replacement,
+        })
+    }
+}
+
+/// The data of an `MX` record.
+///
+/// The field order makes sets of these ordered by preference, so that the
+/// records are listed and sent in the order they are meant to be used in.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Mx {
+    pub preference: u16,
+    pub exchange: rr::Name,
+}
+
+impl Mx {
+    pub fn to_rdata(&self) -> rdata::MX {
+        rdata::MX::new(self.preference, self.exchange.clone())
+    }
+}
+
+impl From<&rdata::MX> for Mx {
+    fn from(mx: &rdata::MX) -> Self {
+        Mx {
+            preference: mx.preference(),
+            exchange: mx.exchange().clone(),
+        }
+    }
+}
+
+impl fmt::Display for Mx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", DisplayRData(&rr::RData::MX(self.to_rdata())))
+    }
+}
+
+impl FromStr for Mx {
+    type Err = RsDataParseError;
+
+    /// Parses the presentation format, e.g. `10 mail.example.org`.
+    ///
+    /// The exchange name is treated as fully qualified, even without a
+    /// trailing dot.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| RsDataParseError::Mx(msg.to_owned());
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() != 2 {
+            return Err(invalid("expected preference and exchange"));
+        }
+        Ok(Mx {
+            preference: fields[0]
+                .parse()
+                .map_err(|_| invalid("invalid preference"))?,
+            exchange: parse_fqdn(fields[1]).ok_or_else(|| invalid("invalid exchange name"))?,
+        })
+    }
+}
+
+/// The data of an `SRV` record.
+///
+/// Like `Mx`, sets of these are ordered by priority, and then by weight.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: rr::Name,
+}
+
+impl Srv {
+    pub fn to_rdata(&self) -> rdata::SRV {
+        rdata::SRV::new(self.priority, self.weight, self.port, self.target.clone())
+    }
+}
+
+impl From<&rdata::SRV> for Srv {
+    fn from(srv: &rdata::SRV) -> Self {
+        Srv {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().clone(),
+        }
+    }
+}
+
+impl fmt::Display for Srv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", DisplayRData(&rr::RData::SRV(self.to_rdata())))
+    }
+}
+
+impl FromStr for Srv {
+    type Err = RsDataParseError;
+
+    /// Parses the presentation format, e.g. `10 5 5060 sip.example.org`.
+    ///
+    /// The target name is treated as fully qualified, even without a trailing
+    /// dot.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| RsDataParseError::Srv(msg.to_owned());
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(invalid("expected priority, weight, port and target"));
+        }
+        let number = |field: &str, what: &str| {
+            field
+                .parse()
+                .map_err(|_| invalid(&format!("invalid {}", what)))
+        };
+        Ok(Srv {
+            priority: number(fields[0], "priority")?,
+            weight: number(fields[1], "weight")?,
+            port: number(fields[2], "port")?,
+            target: parse_fqdn(fields[3]).ok_or_else(|| invalid("invalid target name"))?,
+        })
+    }
+}
+
+/// The data of a `DS` record.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: Algorithm,
+    pub digest_type: DigestType,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn to_rdata(&self) -> rdata::DS {
+        rdata::DS::new(
+            self.key_tag,
+            self.algorithm,
+            self.digest_type,
+            self.digest.clone(),
+        )
+    }
+}
+
+impl From<&rdata::DS> for Ds {
+    fn from(ds: &rdata::DS) -> Self {
+        Ds {
+            key_tag: ds.key_tag(),
+            algorithm: ds.algorithm(),
+            digest_type: ds.digest_type(),
+            digest: ds.digest().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Ds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.key_tag,
+            DisplayAlgorithm(self.algorithm),
+            u8::from(self.digest_type),
+            HEXLOWER.encode(&self.digest)
+        )
+    }
+}
+
+impl FromStr for Ds {
+    type Err = RsDataParseError;
+
+    /// Parses the presentation format, e.g. `12345 ECDSAP256SHA256 2
+    /// 3b5e...`, i.e., the key tag, the algorithm as a mnemonic or number,
+    /// the digest type number, and the digest in hex.
+    ///
+    /// The digest may be split by whitespace, and must have the length
+    /// mandated by its digest type; only SHA-1, SHA-256 and SHA-384 digests
+    /// are supported.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| RsDataParseError::Ds(msg.to_owned());
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(invalid(
+                "expected key tag, algorithm, digest type and digest",
+            ));
+        }
+        let key_tag = fields[0].parse().map_err(|_| invalid("invalid key tag"))?;
+        let algorithm = parse_algorithm(fields[1]).ok_or_else(|| invalid("invalid algorithm"))?;
+        let (digest_type, digest_len) = match fields[2].parse::<u8>() {
+            Ok(1) => (DigestType::SHA1, 20),
+            Ok(2) => (DigestType::SHA256, 32),
+            Ok(4) => (DigestType::SHA384, 48),
+            Ok(_) => return Err(invalid("unsupported digest type")),
+            Err(_) => return Err(invalid("invalid digest type")),
+        };
+        let digest = HEXLOWER_PERMISSIVE
+            .decode(fields[3..].concat().as_bytes())
+            .map_err(|_| invalid("invalid hex digest"))?;
+        if digest.len() != digest_len {
+            return Err(invalid(&format!(
+                "expected a digest of {} octets for digest type {}, found {}",
+                digest_len,
+                u8::from(digest_type),
+                digest.len()
+            )));
+        }
+        Ok(Ds {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+        })
+    }
+}
+
+/// The data of a `DNSKEY` record.
+///
+/// Of the flags, only the zone key, revoked and secure entry point flags
+/// can be represented, which are the only ones defined.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Dnskey {
+    pub zone_key: bool,
+    pub secure_entry_point: bool,
+    pub revoke: bool,
+    pub algorithm: Algorithm,
+    pub public_key: Vec<u8>,
+}
+
+/// The `DNSKEY` flag bits, as per RFC 4034 and RFC 5011.
+const DNSKEY_ZONE_KEY: u16 = 0x0100;
+const DNSKEY_REVOKE: u16 = 0x0080;
+const DNSKEY_SECURE_ENTRY_POINT: u16 = 0x0001;
+
+impl Dnskey {
+    pub fn to_rdata(&self) -> rdata::DNSKEY {
+        rdata::DNSKEY::new(
+            self.zone_key,
+            self.secure_entry_point,
+            self.revoke,
+            self.algorithm,
+            self.public_key.clone(),
+        )
+    }
+
+    pub fn flags(&self) -> u16 {
+        let flag = |set, bit| if set { bit } else { 0 };
+        flag(self.zone_key, DNSKEY_ZONE_KEY)
+            | flag(self.revoke, DNSKEY_REVOKE)
+            | flag(self.secure_entry_point, DNSKEY_SECURE_ENTRY_POINT)
+    }
+}
+
+impl From<&rdata::DNSKEY> for Dnskey {
+    fn from(key: &rdata::DNSKEY) -> Self {
+        Dnskey {
+            zone_key: key.zone_key(),
+            secure_entry_point: key.secure_entry_point(),
+            revoke: key.revoke(),
+            algorithm: key.algorithm(),
+            public_key: key.public_key().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Dnskey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} 3 {} {}",
+            self.flags(),
+            DisplayAlgorithm(self.algorithm),
+            BASE64.encode(&self.public_key)
+        )
+    }
+}
+
+impl FromStr for Dnskey {
+    type Err = RsDataParseError;
+
+    /// Parses the presentation format, e.g. `257 3 ECDSAP256SHA256 mdsswUyr...`,
+    /// i.e., the flags, the protocol, which must be 3, the algorithm as a
+    /// mnemonic or number, and the public key in base64.
+    ///
+    /// The public key may be split by whitespace.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| RsDataParseError::Dnskey(msg.to_owned());
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(invalid(
+                "expected flags, protocol, algorithm and public key",
+            ));
+        }
+        let flags: u16 = fields[0].parse().map_err(|_| invalid("invalid flags"))?;
+        if flags & !(DNSKEY_ZONE_KEY | DNSKEY_REVOKE | DNSKEY_SECURE_ENTRY_POINT) != 0 {
+            return Err(invalid("unsupported flags"));
+        }
+        if fields[1] != "3" {
+            return Err(invalid("protocol must be 3"));
+        }
+        let algorithm = parse_algorithm(fields[2]).ok_or_else(|| invalid("invalid algorithm"))?;
+        let public_key = BASE64
+            .decode(fields[3..].concat().as_bytes())
+            .map_err(|_| invalid("invalid base64 public key"))?;
+        Ok(Dnskey {
+            zone_key: flags & DNSKEY_ZONE_KEY != 0,
+            secure_entry_point: flags & DNSKEY_SECURE_ENTRY_POINT != 0,
+            revoke: flags & DNSKEY_REVOKE != 0,
+            algorithm,
+            public_key,
+        })
+    }
+}
+
+/// Displays a DNSSEC algorithm by its mnemonic, or by number if it has none.
+struct DisplayAlgorithm(Algorithm);
+
+impl fmt::Display for DisplayAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Algorithm::Unknown(n) => write!(f, "{}", n),
+            algorithm => f.write_str(algorithm.as_str()),
+        }
+    }
+}
+
+/// Parses a DNSSEC algorithm, given by mnemonic or number.
+fn parse_algorithm(s: &str) -> Option<Algorithm> {
+    if let Ok(n) = s.parse() {
+        return Some(Algorithm::from_u8(n));
+    }
+    [5, 7, 8, 10, 13, 14, 15]
+        .into_iter()
+        .map(Algorithm::from_u8)
+        .find(|algorithm| algorithm.as_str().eq_ignore_ascii_case(s))
+}
+
+fn parse_fqdn(s: &str) -> Option<rr::Name> {
+    let mut name = rr::Name::from_ascii(s).ok()?;
+    name.set_fqdn(true);
+    Some(name)
+}
+
+/// Splits `s` into whitespace-separated fields, which may be quoted, as in
+/// master files.
+///
+/// Within fields, backslash escapes, including `\DDD` for arbitrary octets,
+/// are interpreted. Returns `None` if a quoted field is not terminated.
+fn split_character_strings(s: &str) -> Option<Vec<Vec<u8>>> {
+    let mut fields = Vec::new();
+    let mut bytes = s.bytes().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let quoted = match bytes.peek() {
+            None => return Some(fields),
+            Some(b'"') => {
+                bytes.next();
+                true
+            }
+            Some(_) => false,
+        };
+        let mut field = Vec::new();
+        loop {
+            match bytes.next() {
+                None if quoted => return None,
+                None => break,
+                Some(b'"') if quoted => break,
+                Some(c) if !quoted && c.is_ascii_whitespace() => break,
+                Some(b'\\') => {
+                    let digits: Vec<_> = (0..3)
+                        .map_while(|_| bytes.next_if(u8::is_ascii_digit))
+                        .collect();
+                    if digits.is_empty() {
+                        field.push(bytes.next()?);
+                    } else {
+                        let value = digits
+                            .iter()
+                            .fold(0u32, |n, d| n * 10 + u32::from(d - b'0'));
+                        field.push(u8::try_from(value).ok()?);
+                    }
+                }
+                Some(c) => field.push(c),
+            }
+        }
+        fields.push(field);
+    }
+}
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct RsKey {
     name: rr::Name,
@@ -261,6 +1226,12 @@ impl RsKey {
     }
 }
 
+impl fmt::Display for RsKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.name, self.dns_class, self.record_type)
+    }
+}
+
 impl From<&rr::Record> for RsKey {
     fn from(rr: &rr::Record) -> Self {
         RsKey {
@@ -271,14 +1242,29 @@ impl From<&rr::Record> for RsKey {
     }
 }
 
-fn txt_string(txt: &rdata::TXT) -> Result<String, TryFromRecordsError> {
-    let data = txt.txt_data();
-    if data.len() != 1 {
-        return Err(TryFromRecordsError::UnsupportedTxtValue);
+/// Returns the text of a `TXT` record.
+fn txt_string(txt: &rdata::TXT) -> Result<Txt, TryFromRecordsError> {
+    Txt::from_strings(txt.txt_data()).map_err(TryFromRecordsError::FromUtf8)
+}
+
+/// The type code of `AVC` records, which `trust-dns` does not know about.
+const AVC_CODE: u16 = 258;
+
+/// Returns the text of `AVC` record data.
+fn avc_string(rdata: &rdata::NULL) -> Result<Txt, TryFromRecordsError> {
+    let strings = query::character_strings(rdata.anything().unwrap_or_default())
+        .ok_or(TryFromRecordsError::UnsupportedTxtValue)?;
+    Txt::from_strings(&strings).map_err(TryFromRecordsError::FromUtf8)
+}
+
+/// Returns the data of an `AVC` record with the text `avc`.
+fn avc_rdata(avc: &Txt) -> rdata::NULL {
+    let mut data = Vec::with_capacity(avc.as_str().len() + 1);
+    for string in avc.strings() {
+        data.push(string.len() as u8);
+        data.extend_from_slice(string);
     }
-    str::from_utf8(&data[0])
-        .map(Into::into)
-        .map_err(TryFromRecordsError::Utf8)
+    rdata::NULL::with(data)
 }
 
 impl TryFrom<&[rr::Record]> for RecordSet {
@@ -308,11 +1294,61 @@ impl TryFrom<&[rr::Record]> for RecordSet {
                             .map(|rr| txt_string(rr.rdata().as_txt().unwrap()))
                             .collect::<Result<_, _>>()?,
                     ),
+                    rr::RecordType::Unknown(AVC_CODE) => RsData::AVC(
+                        rrs.iter()
+                            .map(|rr| match rr.rdata() {
+                                rr::RData::Unknown { rdata, .. } => avc_string(rdata),
+                                _ => Err(TryFromRecordsError::UnsupportedTxtValue),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    rr::RecordType::NAPTR => RsData::NAPTR(
+                        rrs.iter()
+                            .map(|rr| rr.rdata().as_naptr().unwrap().into())
+                            .collect(),
+                    ),
+                    rr::RecordType::MX => RsData::MX(
+                        rrs.iter()
+                            .map(|rr| rr.rdata().as_mx().unwrap().into())
+                            .collect(),
+                    ),
+                    rr::RecordType::NS => RsData::NS(
+                        rrs.iter()
+                            .map(|rr| rr.rdata().as_ns().unwrap().clone())
+                            .collect(),
+                    ),
+                    rr::RecordType::SRV => RsData::SRV(
+                        rrs.iter()
+                            .map(|rr| rr.rdata().as_srv().unwrap().into())
+                            .collect(),
+                    ),
+                    rr::RecordType::DNSSEC(DNSSECRecordType::DS) => RsData::DS(
+                        rrs.iter()
+                            .map(|rr| match rr.rdata() {
+                                rr::RData::DNSSEC(DNSSECRData::DS(ds)) => Ok(ds.into()),
+                                _ => Err(TryFromRecordsError::UnsupportedType(rr.record_type())),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    rr::RecordType::DNSSEC(DNSSECRecordType::DNSKEY) => RsData::DNSKEY(
+                        rrs.iter()
+                            .map(|rr| match rr.rdata() {
+                                rr::RData::DNSSEC(DNSSECRData::DNSKEY(key)) => Ok(key.into()),
+                                _ => Err(TryFromRecordsError::UnsupportedType(rr.record_type())),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    rr::RecordType::OPENPGPKEY => RsData::OPENPGPKEY(
+                        rrs.iter()
+                            .map(|rr| rr.rdata().as_openpgpkey().unwrap().public_key().to_vec())
+                            .collect(),
+                    ),
                     rtype => return Err(TryFromRecordsError::UnsupportedType(rtype)),
                 };
                 Ok(RecordSet {
                     name: key.name.clone(),
                     dns_class: key.dns_class,
+                    ttl: None,
                     data,
                 })
             }
@@ -328,7 +1364,6 @@ pub enum TryFromRecordsError {
     UnsupportedType(rr::RecordType),
     UnsupportedTxtValue,
     FromUtf8(FromUtf8Error),
-    Utf8(str::Utf8Error),
 }
 
 impl fmt::Display for TryFromRecordsError {
@@ -339,7 +1374,6 @@ impl fmt::Display for TryFromRecordsError {
             MultipleKeys(_) => write!(f, "multiple keys"),
             UnsupportedType(rtype) => write!(f, "unsupported record type {}", rtype),
             UnsupportedTxtValue => write!(f, "unsupported TXT value"),
-            Utf8(e) => write!(f, "non-UTF8 content: {}", e),
             FromUtf8(e) => write!(f, "non-UTF8 content: {}", e),
         }
     }
@@ -0,0 +1,232 @@
+//! Comparing the SOA serials of the authoritative nameservers of a zone.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use futures::{
+    future,
+    stream::{self, StreamExt},
+};
+use trust_dns_client::{
+    client::ClientHandle, error::ClientError, op::ResponseCode, proto::error::ProtoError, rr,
+};
+
+use crate::{
+    update::{nameserver_addrs, resolve_nameservers, NameserverWarning, NsSelection},
+    util::{self, SocketName},
+    Backend, Resolver, Runtime,
+};
+
+/// The settings for polling the SOA serials of the nameservers of a zone.
+#[derive(Debug, Clone)]
+pub struct SerialWatch {
+    pub zone: rr::Name,
+    /// The server whose serial the nameservers are compared to; by default,
+    /// the primary master named in the zone's SOA record.
+    pub primary: Option<SocketName>,
+    pub exclude: Vec<IpAddr>,
+    /// Bounds the lookups used to determine the primary and the nameservers.
+    pub lookup_retry: util::Retry,
+}
+
+/// The SOA serial of a server, or the error querying it failed with.
+#[derive(Debug, Clone)]
+pub struct ServerSerial {
+    pub server: SocketName,
+    pub addr: SocketAddr,
+    pub serial: Result<u32, SerialError>,
+}
+
+/// The error querying the SOA serial of a server failed with.
+#[derive(Debug, Clone)]
+pub enum SerialError {
+    /// The server could not be connected to.
+    Connect(ProtoError),
+    Query(ClientError),
+    /// The response did not include an SOA record.
+    NoSoa(ResponseCode),
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerialError::Connect(e) => e.fmt(f),
+            SerialError::Query(e) => e.fmt(f),
+            SerialError::NoSoa(code) => write!(f, "no SOA record in response ({})", code),
+        }
+    }
+}
+
+impl std::error::Error for SerialError {}
+
+impl ServerSerial {
+    /// Returns how far the serial of the server is behind `reference`; see
+    /// `serial_lag`.
+    pub fn lag(&self, reference: u32) -> Option<u32> {
+        self.serial
+            .as_ref()
+            .ok()
+            .and_then(|serial| serial_lag(*serial, reference))
+    }
+}
+
+/// The serials obtained by polling the nameservers of a zone once.
+#[derive(Debug, Clone)]
+pub struct Serials {
+    /// The serial of the primary, or the reason its address could not be
+    /// determined.
+    pub primary: Result<ServerSerial, String>,
+    /// The serials of the nameservers, ordered by name.
+    pub nameservers: Vec<ServerSerial>,
+    /// Warnings about determining the nameservers.
+    pub warnings: Vec<NameserverWarning>,
+}
+
+impl Serials {
+    /// Returns the serial the nameservers are compared to.
+    ///
+    /// This is the serial of the primary, or if that is not available, the
+    /// highest serial of the nameservers.
+    pub fn reference(&self) -> Option<u32> {
+        if let Ok(ServerSerial {
+            serial: Ok(serial), ..
+        }) = &self.primary
+        {
+            return Some(*serial);
+        }
+        self.nameservers
+            .iter()
+            .filter_map(|ns| ns.serial.as_ref().ok().copied())
+            .reduce(|highest, serial| {
+                if serial_lag(highest, serial).is_some() {
+                    serial
+                } else {
+                    highest
+                }
+            })
+    }
+}
+
+/// Returns how far `serial` is behind `reference`, using serial number
+/// arithmetic as per RFC 1982, or `None` if it is ahead.
+pub fn serial_lag(serial: u32, reference: u32) -> Option<u32> {
+    let lag = reference.wrapping_sub(serial);
+    if lag < 1 << 31 {
+        Some(lag)
+    } else {
+        None
+    }
+}
+
+/// Queries the SOA serial of the primary of the zone, and those of its
+/// authoritative nameservers.
+///
+/// The nameservers are determined anew on each call, like at the start of
+/// monitoring an update, so changes to the zone's `NS` records are picked up.
+/// Failing to query a server is not an error, but recorded in its
+/// `ServerSerial`.
+pub async fn poll_serials<D>(
+    runtime: &Runtime,
+    dns: D,
+    resolver: D::Resolver,
+    options: &SerialWatch,
+) -> anyhow::Result<Serials>
+where
+    D: Backend,
+{
+    let selection = NsSelection {
+        zone: &options.zone,
+        lookup_retry: options.lookup_retry,
+        exclude: &options.exclude,
+        source: None,
+    };
+    let resolved = resolve_nameservers(runtime, dns.clone(), resolver.clone(), &selection).await?;
+    let nameservers = nameserver_addrs(&resolved.nameservers, &options.exclude)
+        .into_iter()
+        .map(|(name, addr)| (SocketName::HostName(name, None), addr))
+        .collect::<Vec<_>>();
+    let primary = async {
+        let (server, addr) = resolve_primary(resolver, options).await?;
+        let serial = query_serial(runtime, dns.clone(), &options.zone, addr).await;
+        Ok(ServerSerial {
+            server,
+            addr,
+            serial,
+        })
+    };
+    let limit = nameservers.len().max(1);
+    let polls = stream::iter(nameservers)
+        .map(|(server, addr)| {
+            let dns = dns.clone();
+            async move {
+                let serial = query_serial(runtime, dns, &options.zone, addr).await;
+                ServerSerial {
+                    server,
+                    addr,
+                    serial,
+                }
+            }
+        })
+        .buffered(limit)
+        .collect::<Vec<_>>();
+    let (primary, nameservers) = future::join(primary, polls).await;
+    Ok(Serials {
+        primary,
+        nameservers,
+        warnings: resolved.warnings,
+    })
+}
+
+/// Determines the name and address of the primary, which is the server given
+/// via `SerialWatch::primary`, or the primary master named in the zone's SOA
+/// record.
+async fn resolve_primary<R: Resolver>(
+    resolver: R,
+    options: &SerialWatch,
+) -> Result<(SocketName, SocketAddr), String> {
+    let server = match &options.primary {
+        Some(server) => server.clone(),
+        None => {
+            let soa = options
+                .lookup_retry
+                .run(|| resolver.lookup_soa(options.zone.clone()))
+                .await
+                .map_err(|e| format!("could not look up SOA record of {}: {}", options.zone, e))?;
+            match soa.iter().next() {
+                Some(soa) => SocketName::HostName(soa.mname().clone(), None),
+                None => return Err(format!("SOA record for {} not found", options.zone)),
+            }
+        }
+    };
+    match server.resolve(resolver, 53).await {
+        Ok(addr) => Ok((server, addr)),
+        Err(e) => Err(format!("could not resolve {}: {}", server, e)),
+    }
+}
+
+/// Queries the SOA serial of `zone` at the server `addr`.
+async fn query_serial<D: Backend>(
+    runtime: &Runtime,
+    mut dns: D,
+    zone: &rr::Name,
+    addr: SocketAddr,
+) -> Result<u32, SerialError> {
+    let mut server = dns
+        .open(runtime.handle(), addr)
+        .await
+        .map_err(SerialError::Connect)?;
+    let response = server
+        .query(zone.clone(), rr::DNSClass::IN, rr::RecordType::SOA)
+        .await
+        .map_err(SerialError::Query)?;
+    response
+        .answers()
+        .iter()
+        .find_map(|record| match record.rdata() {
+            rr::RData::SOA(soa) => Some(soa.serial()),
+            _ => None,
+        })
+        .ok_or_else(|| SerialError::NoSoa(response.response_code()))
+}
@@ -2,9 +2,12 @@
 use std::{
     convert::{TryFrom, TryInto},
     fmt,
+    io::{self, BufRead},
+    str::FromStr,
     time::{SystemTime, SystemTimeError},
 };
 
+use data_encoding::BASE64;
 use digest::KeyInit;
 use hmac::{Hmac, Mac};
 use once_cell::sync::Lazy;
@@ -12,7 +15,7 @@ use trust_dns_client::{
     op,
     proto::error::{ProtoError, ProtoResult},
     rr,
-    serialize::binary::{BinEncodable, BinEncoder},
+    serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder},
 };
 
 #[derive(Debug)]
@@ -52,7 +55,7 @@ impl From<SystemTimeError> for Error {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Algorithm {
     HmacSha224,
     HmacSha256,
@@ -132,6 +135,301 @@ impl Key {
             secret: secret.into(),
         }
     }
+
+    /// Parses a key in `NAME:ALGORITHM:BASE64-SECRET` notation, e.g.
+    /// `mykey:hmac-sha256:c2VjcmV0`.
+    ///
+    /// Whitespace around each field is ignored.
+    pub fn from_spec(spec: &str) -> Result<Self, ParseKeyError> {
+        let parts: Vec<_> = spec.split(':').map(str::trim).collect();
+        if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+            return Err(ParseKeyError::Syntax(spec.to_owned()));
+        }
+        let (name, algo, data) = (parts[0], parts[1], parts[2]);
+        let name = name
+            .parse()
+            .map_err(|e| ParseKeyError::Name(name.to_owned(), e))?;
+        let algorithm = rr::Name::from_ascii(algo)
+            .ok()
+            .and_then(|algo| Algorithm::from_name(&algo).ok())
+            .ok_or_else(|| ParseKeyError::Algorithm(algo.to_owned()))?;
+        let secret = BASE64
+            .decode(data.as_bytes())
+            .map_err(ParseKeyError::Secret)?;
+        Ok(Key::new(name, algorithm, secret))
+    }
+
+    pub fn name(&self) -> &rr::Name {
+        &self.name
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+}
+
+/// A key as given on the command line: either the key itself, or the name
+/// of a key to look up in a key file.
+#[derive(Debug, Clone)]
+pub enum KeySpec {
+    Name(rr::Name),
+    Key(Key),
+}
+
+impl FromStr for KeySpec {
+    type Err = ParseKeyError;
+
+    /// Parses either `NAME` or `NAME:ALGORITHM:BASE64-SECRET`; see
+    /// `Key::from_spec`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            return Key::from_spec(s).map(KeySpec::Key);
+        }
+        s.parse()
+            .map(KeySpec::Name)
+            .map_err(|e| ParseKeyError::Name(s.to_owned(), e))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ParseKeyError {
+    Syntax(String),
+    Name(String, ProtoError),
+    Algorithm(String),
+    Secret(data_encoding::DecodeError),
+}
+
+impl fmt::Display for ParseKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ParseKeyError::*;
+        match self {
+            Syntax(spec) => write!(f, "expected NAME:ALGORITHM:KEY, found {}", spec),
+            Name(name, e) => write!(f, "invalid key name {}: {}", name, e),
+            Algorithm(algo) => write!(f, "invalid algorithm {}: unknown algorithm", algo),
+            Secret(e) => write!(f, "invalid key data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseKeyError {}
+
+/// Reads all keys from `input`.
+///
+/// Each non-blank line is expected to contain a key in `NAME:ALGORITHM:KEY`
+/// notation, as accepted by `Key::from_spec`. Anything following a `#`
+/// character is ignored, so keys may be annotated with comments.
+pub fn read_keys<R: BufRead>(input: R) -> Result<Vec<Key>, ReadKeysError> {
+    let file = KeyFile::read(input).map_err(ReadKeysError::Io)?;
+    file.entries
+        .into_iter()
+        .map(|entry| {
+            entry.key.map_err(|error| ReadKeysError::Parse {
+                line_no: entry.line_no,
+                error,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum ReadKeysError {
+    Io(io::Error),
+    Parse {
+        line_no: usize,
+        error: ParseKeyError,
+    },
+}
+
+impl fmt::Display for ReadKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadKeysError::Io(e) => write!(f, "{}", e),
+            ReadKeysError::Parse { line_no, error } => write!(f, "{}: {}", line_no, error),
+        }
+    }
+}
+
+impl std::error::Error for ReadKeysError {}
+
+/// The entries of a key file, in the format described for `read_keys`.
+///
+/// Each entry is parsed on its own, so that an invalid entry only causes an
+/// error when it is the one selected.
+#[derive(Debug)]
+pub struct KeyFile {
+    entries: Vec<KeyEntry>,
+}
+
+#[derive(Debug)]
+struct KeyEntry {
+    line_no: usize,
+    /// The name of the key, if at least that could be parsed.
+    name: Option<rr::Name>,
+    key: Result<Key, ParseKeyError>,
+}
+
+impl KeyFile {
+    pub fn read<R: BufRead>(input: R) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => &line[..],
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let key = Key::from_spec(line);
+            let name = match &key {
+                Ok(key) => Some(key.name().clone()),
+                Err(_) => line
+                    .split(':')
+                    .next()
+                    .and_then(|name| name.trim().parse().ok()),
+            };
+            entries.push(KeyEntry {
+                line_no: i + 1,
+                name,
+                key,
+            });
+        }
+        Ok(KeyFile { entries })
+    }
+
+    /// Selects the first key named `name`, or the first key if `name` is
+    /// `None`.
+    pub fn select(&self, name: Option<&rr::Name>) -> Result<Key, SelectKeyError> {
+        let entry = match name {
+            Some(name) => self
+                .entries
+                .iter()
+                .find(|entry| entry.name.as_ref() == Some(name)),
+            None => self.entries.first(),
+        };
+        let entry = entry.ok_or_else(|| SelectKeyError::NotFound(name.cloned()))?;
+        entry.key.clone().map_err(|error| SelectKeyError::Invalid {
+            line_no: entry.line_no,
+            error,
+        })
+    }
+
+    /// Returns the names of the valid keys.
+    pub fn names(&self) -> Vec<&rr::Name> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.as_ref().ok().map(Key::name))
+            .collect()
+    }
+
+    /// Returns the line numbers and parse errors of the invalid entries.
+    pub fn invalid_entries(&self) -> impl Iterator<Item = (usize, &ParseKeyError)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.as_ref().err().map(|e| (entry.line_no, e)))
+    }
+}
+
+#[derive(Debug)]
+pub enum SelectKeyError {
+    /// There is no key with the given name, or no key at all.
+    NotFound(Option<rr::Name>),
+    /// The selected entry is invalid.
+    Invalid {
+        line_no: usize,
+        error: ParseKeyError,
+    },
+}
+
+impl fmt::Display for SelectKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectKeyError::NotFound(Some(name)) => write!(f, "key {} not found", name),
+            SelectKeyError::NotFound(None) => write!(f, "no key found"),
+            SelectKeyError::Invalid { line_no, error } => write!(f, "{}: {}", line_no, error),
+        }
+    }
+}
+
+impl std::error::Error for SelectKeyError {}
+
+/// Determines the key given by `spec` and `file`, i.e., the `--key` and
+/// `--key-file` options.
+///
+/// With `zone`, the key named like the zone is selected from `file`, which
+/// cannot be combined with `spec`. Without a key name in `spec`, the first key
+/// of `file` is used. `file` is only needed when a key is selected from it.
+pub fn get_key(
+    spec: Option<KeySpec>,
+    file: Option<&KeyFile>,
+    zone: Option<&rr::Name>,
+) -> Result<Option<Key>, GetKeyError> {
+    if let Some(zone) = zone {
+        if spec.is_some() {
+            return Err(GetKeyError::ZoneWithKey);
+        }
+        let file = file.ok_or(GetKeyError::ZoneWithoutKeyFile)?;
+        return match file.select(Some(zone)) {
+            Ok(key) => Ok(Some(key)),
+            Err(SelectKeyError::NotFound(_)) => Err(GetKeyError::NoZoneKey {
+                zone: zone.clone(),
+                available: file.names().into_iter().cloned().collect(),
+            }),
+            Err(e) => Err(GetKeyError::Select(e)),
+        };
+    }
+    let key = match (spec, file) {
+        (Some(KeySpec::Key(key)), _) => key,
+        (Some(KeySpec::Name(name)), Some(file)) => file.select(Some(&name))?,
+        (Some(KeySpec::Name(_)), None) => return Err(GetKeyError::NameWithoutKeyFile),
+        (None, Some(file)) => file.select(None)?,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(key))
+}
+
+#[derive(Debug)]
+pub enum GetKeyError {
+    ZoneWithKey,
+    ZoneWithoutKeyFile,
+    NameWithoutKeyFile,
+    /// There is no key named like the zone; the names of the valid keys are
+    /// included.
+    NoZoneKey {
+        zone: rr::Name,
+        available: Vec<rr::Name>,
+    },
+    Select(SelectKeyError),
+}
+
+impl fmt::Display for GetKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use GetKeyError::*;
+        match self {
+            ZoneWithKey => write!(f, "--key-for-zone cannot be combined with --key"),
+            ZoneWithoutKeyFile => write!(f, "--key-file option required with --key-for-zone"),
+            NameWithoutKeyFile => write!(f, "--key-file option required with --key=NAME"),
+            NoZoneKey { zone, available } => {
+                let names: Vec<_> = available.iter().map(ToString::to_string).collect();
+                write!(
+                    f,
+                    "no key for zone {} found; available keys: {}",
+                    zone,
+                    names.join(", ")
+                )
+            }
+            Select(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for GetKeyError {}
+
+impl From<SelectKeyError> for GetKeyError {
+    fn from(e: SelectKeyError) -> Self {
+        GetKeyError::Select(e)
+    }
 }
 
 pub fn add_signature(msg: &mut op::Message, key: &Key) -> Result<(), Error> {
@@ -224,6 +522,116 @@ impl BinEncodable for TSIG {
     }
 }
 
+/// The record type code of TSIG records.
+const TSIG_TYPE: u16 = 250;
+
+/// An error reported by the server in the TSIG record of a response, as
+/// described in RFC 8945, section 5.3.
+#[derive(Debug, Clone)]
+pub struct ServerError {
+    /// The name of the key the request was signed with.
+    pub key_name: rr::Name,
+    /// The error code, e.g. 17 for BADKEY.
+    pub code: u16,
+    /// The server's time, in seconds since the epoch, which is included in
+    /// BADTIME responses.
+    pub server_time: Option<u64>,
+}
+
+impl ServerError {
+    pub const BADSIG: u16 = 16;
+    pub const BADKEY: u16 = 17;
+    pub const BADTIME: u16 = 18;
+    pub const BADTRUNC: u16 = 22;
+
+    /// Extracts the TSIG error from a response, if it carries a TSIG record
+    /// with a non-zero error code.
+    ///
+    /// The signature of the response is not verified, as responses carrying
+    /// an error are usually not signed.
+    pub fn from_response(msg: &op::Message) -> Option<Self> {
+        let record = msg
+            .additionals()
+            .iter()
+            .rfind(|record| u16::from(record.rr_type()) == TSIG_TYPE)?;
+        let data = match record.rdata() {
+            rr::RData::Unknown { rdata, .. } => rdata.anything()?,
+            _ => return None,
+        };
+        let (code, other_data) = read_tsig_error(data).ok()?;
+        if code == 0 {
+            return None;
+        }
+        let server_time = match (code, other_data.as_slice()) {
+            (Self::BADTIME, &[a, b, c, d, e, f]) => {
+                Some(u64::from_be_bytes([0, 0, a, b, c, d, e, f]))
+            }
+            _ => None,
+        };
+        Some(ServerError {
+            key_name: record.name().clone(),
+            code,
+            server_time,
+        })
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Self::BADKEY => write!(
+                f,
+                "the server does not know the key {} (BADKEY); check the key name and algorithm",
+                self.key_name
+            )?,
+            Self::BADSIG => write!(
+                f,
+                "the signature made with key {} did not match (BADSIG); check the key secret",
+                self.key_name
+            )?,
+            Self::BADTIME => {
+                write!(
+                    f,
+                    "the signature time is outside the server's window (BADTIME); \
+                     check the clock synchronization (e.g. NTP)"
+                )?;
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+                if let (Some(server_time), Ok(now)) = (self.server_time, now) {
+                    let skew = i128::from(now.as_secs()) - i128::from(server_time);
+                    write!(f, "; the local clock is {} seconds off", skew)?;
+                }
+            }
+            Self::BADTRUNC => write!(f, "the server did not accept the truncated MAC (BADTRUNC)")?,
+            code => write!(f, "TSIG error {} for key {}", code, self.key_name)?,
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Whether `msg` carries a TSIG record; its signature is not verified.
+pub fn is_signed(msg: &op::Message) -> bool {
+    msg.additionals()
+        .iter()
+        .any(|record| u16::from(record.rr_type()) == TSIG_TYPE)
+}
+
+/// Reads the error code and other data fields of TSIG record data.
+fn read_tsig_error(data: &[u8]) -> ProtoResult<(u16, Vec<u8>)> {
+    let mut decoder = BinDecoder::new(data);
+    rr::Name::read(&mut decoder)?; // algorithm name
+    decoder.read_slice(6)?; // time signed
+    decoder.read_u16()?; // fudge
+    let mac_size = decoder.read_u16()?.unverified();
+    decoder.read_slice(usize::from(mac_size))?;
+    decoder.read_u16()?; // original ID
+    let error = decoder.read_u16()?.unverified();
+    let other_len = decoder.read_u16()?.unverified();
+    let other_data = decoder.read_vec(usize::from(other_len))?.unverified();
+    Ok((error, other_data))
+}
+
 fn emit_u48(encoder: &mut BinEncoder, n: u64) -> ProtoResult<()> {
     encoder.emit_u16((n >> 32) as u16)?;
     encoder.emit_u32(n as u32)?;
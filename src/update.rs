@@ -1,25 +1,34 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     convert::TryFrom,
-    fmt,
+    fmt, io,
     net::{IpAddr, SocketAddr},
-    rc::Rc,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
-use futures::stream::{FuturesUnordered, TryStreamExt};
-use tokio::time::{sleep, timeout};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
 use trust_dns_client::{
-    op::{Message, Query},
+    op::{Message, Query, ResponseCode},
     proto::xfer::{DnsHandle, DnsRequestOptions},
     rr,
 };
+use trust_dns_resolver::error::ResolveErrorKind;
 
+#[cfg(feature = "tsig")]
+use crate::tsig;
 use crate::{
-    record::{RecordSet, RsData},
-    tsig, update_message,
+    backend::RuntimeHandle,
+    record::{RecordSet, RecordSetMismatch, RsData, TryFromRecordsError},
+    update_message,
     util::{self, SocketName},
-    Backend, Resolver, Runtime,
+    AddressFamily, Backend, Resolver, Runtime,
 };
 
 #[derive(Debug, Clone)]
@@ -27,32 +36,53 @@ pub struct Update {
     pub zone: rr::Name,
     pub server: Option<SocketName>,
     pub operation: Operation,
+    #[cfg(feature = "tsig")]
     pub tsig_key: Option<tsig::Key>,
     pub ttl: u32,
+    /// The class used when deleting all records of a name; the other
+    /// operations use the class of their record set.
+    pub dns_class: rr::DNSClass,
+    /// When appending, use the TTL of the existing RRset, if there is one,
+    /// instead of `ttl`.
+    pub match_ttl: bool,
+    /// When appending, do not send the update if all the records are already
+    /// present.
+    pub skip_if_present: bool,
+    /// Bounds the SOA lookup used to determine the primary master.
+    pub lookup_retry: util::Retry,
+    /// Chooses among multiple addresses of the primary master.
+    pub master_addr_select: util::AddrSelect,
+    /// If set, the update only takes effect if the RRset currently consists of
+    /// exactly these records; if empty, only if the RRset does not exist.
+    ///
+    /// When ensuring an RRset, this is set to the records read from the
+    /// server, so that concurrent changes are not overwritten.
+    pub require_current: Option<RecordSet>,
+    /// After creating or appending, query the server the update was sent to,
+    /// and fail unless the records are present.
+    pub verify: bool,
+    /// Perform operations that delete all `NS` records at the apex of the
+    /// zone, which are refused otherwise, as they make the zone
+    /// unresolvable.
+    pub force: bool,
+    /// The ID of the update message; random if not given.
+    ///
+    /// The ID is only sent as-is by backends that keep message IDs, such as
+    /// `FixedIdBackend`.
+    pub message_id: Option<u16>,
 }
 
 impl Update {
-    pub fn get_update(&self) -> Result<Message, tsig::Error> {
-        let ttl = self.ttl;
-        let mut message = match &self.operation {
-            Operation::Create(rset) => {
-                update_message::create(rset.to_rrset(ttl), self.zone.clone())
-            }
-            Operation::Append(rset) => {
-                update_message::append(rset.to_rrset(ttl), self.zone.clone(), false)
-            }
-            Operation::Delete(rset) => {
-                if rset.is_empty() {
-                    let record = rr::Record::with(rset.name().clone(), rset.record_type(), ttl);
-                    update_message::delete_rrset(record, self.zone.clone())
-                } else {
-                    update_message::delete_by_rdata(rset.to_rrset(ttl), self.zone.clone())
-                }
-            }
-            Operation::DeleteAll(name) => {
-                update_message::delete_all(name.clone(), self.zone.clone(), rr::DNSClass::IN)
-            }
-        };
+    pub fn get_update(&self) -> anyhow::Result<Message> {
+        let mut message =
+            update_message::build_unsigned(&self.operation, &self.zone, self.dns_class, self.ttl)?;
+        if let Some(id) = self.message_id {
+            message.set_id(id);
+        }
+        if let Some(rset) = &self.require_current {
+            update_message::require_rrset(&mut message, rset.to_rrset(0), &self.zone)?;
+        }
+        #[cfg(feature = "tsig")]
         if let Some(key) = &self.tsig_key {
             tsig::add_signature(&mut message, key)?;
         }
@@ -65,15 +95,55 @@ pub struct Monitor {
     pub zone: rr::Name,
     pub entry: rr::Name,
     pub interval: Duration,
+    /// If set, the interval between polls of a nameserver grows by
+    /// `backoff_factor` after each poll, up to this maximum.
+    pub interval_max: Option<Duration>,
+    pub backoff_factor: f64,
     pub timeout: Duration,
+    /// If set, a nameserver that was polled this many times without providing
+    /// the expected records is given up on, failing monitoring once the other
+    /// nameservers are done. The `timeout` applies regardless.
+    pub tries: Option<u32>,
     pub verbose: bool,
     pub exclude: Vec<IpAddr>,
     pub expectation: Expectation,
+    /// The class of the records queried for.
+    pub dns_class: rr::DNSClass,
+    /// If set, the nameserver addresses are determined anew in this interval.
+    ///
+    /// Otherwise, the nameserver addresses are determined only once, at the
+    /// start of monitoring.
+    pub ns_refresh: Option<Duration>,
+    /// Bounds the NS lookup used to determine the authoritative nameservers.
+    pub lookup_retry: util::Retry,
+    /// The maximum number of nameservers polled simultaneously; unbounded if
+    /// not given.
+    ///
+    /// A nameserver holds its slot only while a poll is outstanding, and
+    /// releases it while waiting for the next poll.
+    pub poll_concurrency: Option<usize>,
+    /// The local address the polls are sent from, if any.
+    ///
+    /// Only nameservers with an address of the same family are polled; the
+    /// others are skipped with a warning. Sending from the address is up to
+    /// the backend, e.g. `BoundBackend`.
+    pub source: Option<IpAddr>,
 }
 
 impl Monitor {
+    fn ns_selection(&self) -> NsSelection<'_> {
+        NsSelection {
+            zone: &self.zone,
+            lookup_retry: self.lookup_retry,
+            exclude: &self.exclude,
+            source: self.source,
+        }
+    }
+
     fn get_query(&self) -> Query {
-        Query::query(self.entry.clone(), self.expectation.record_type())
+        let mut query = Query::query(self.entry.clone(), self.expectation.record_type());
+        query.set_query_class(self.dns_class);
+        query
     }
 }
 
@@ -83,6 +153,13 @@ pub enum Operation {
     Append(RecordSet),
     Delete(RecordSet),
     DeleteAll(rr::Name),
+    /// Multiple operations, which are sent in a single update message, and
+    /// hence are applied atomically.
+    Batch(Vec<Operation>),
+    /// Makes the RRset consist of exactly the given records, by adding and
+    /// deleting only the records that differ from its current state, as read
+    /// from the primary master.
+    Ensure(RecordSet),
 }
 
 impl Operation {
@@ -93,6 +170,82 @@ impl Operation {
     pub fn delete(name: rr::Name, data: RsData) -> Self {
         Operation::Delete(RecordSet::new(name, data))
     }
+
+    /// Returns the names of the nameservers given by the `NS` records at the
+    /// apex of `zone` after performing the operation, given the `current`
+    /// ones, or `None` if the operation does not affect them.
+    ///
+    /// Deleting all records of the apex leaves its `NS` records alone, as
+    /// mandated by RFC 2136.
+    pub fn apex_ns_after(
+        &self,
+        zone: &rr::Name,
+        current: &BTreeSet<rr::Name>,
+    ) -> Option<BTreeSet<rr::Name>> {
+        let apex_ns = |rset: &RecordSet| match rset.data() {
+            RsData::NS(names) if rset.name() == zone => Some(names.clone()),
+            _ => None,
+        };
+        match self {
+            Operation::Create(rset) | Operation::Append(rset) => {
+                apex_ns(rset).map(|names| current.union(&names).cloned().collect())
+            }
+            // An empty record set deletes the whole RRset.
+            Operation::Delete(rset) => apex_ns(rset).map(|names| {
+                if names.is_empty() {
+                    BTreeSet::new()
+                } else {
+                    current.difference(&names).cloned().collect()
+                }
+            }),
+            Operation::Ensure(rset) => apex_ns(rset),
+            Operation::DeleteAll(_) => None,
+            Operation::Batch(operations) => operations.iter().fold(None, |after, operation| {
+                operation
+                    .apex_ns_after(zone, after.as_ref().unwrap_or(current))
+                    .or(after)
+            }),
+        }
+    }
+}
+
+/// The records added and removed to bring an RRset into its desired state.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Changes {
+    pub added: RecordSet,
+    pub removed: RecordSet,
+}
+
+impl Changes {
+    /// Determines the changes needed to turn `current` into `desired`.
+    pub fn between(current: &RecordSet, desired: &RecordSet) -> Result<Self, RecordSetMismatch> {
+        Ok(Changes {
+            added: desired.difference(current)?,
+            removed: current.difference(desired)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Returns the operation performing the changes, deleting the removed
+    /// records before adding the new ones.
+    pub fn to_operation(&self) -> Operation {
+        let entries = [
+            Operation::Delete(self.removed.clone()),
+            Operation::Append(self.added.clone()),
+        ];
+        Operation::Batch(
+            entries
+                .into_iter()
+                .filter(|operation| match operation {
+                    Operation::Delete(rset) | Operation::Append(rset) => !rset.is_empty(),
+                    _ => true,
+                })
+                .collect(),
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -155,138 +308,1343 @@ impl fmt::Display for Expectation {
     }
 }
 
+/// The outcome of a successful `perform_update` call.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum UpdateOutcome {
+    /// The update was sent to the primary master.
+    Sent,
+    /// The update was skipped, as the records to append were already present;
+    /// see `Update::skip_if_present`.
+    AlreadyPresent,
+    /// The changes made for `Operation::Ensure`; when they are empty, the
+    /// RRset already was in the desired state, and no update was sent.
+    Ensured(Box<Changes>),
+}
+
 pub async fn perform_update<D>(
     runtime: &Runtime,
     mut dns: D,
     resolver: D::Resolver,
-    options: Update,
-) -> anyhow::Result<()>
+    mut options: Update,
+) -> anyhow::Result<UpdateOutcome>
 where
     D: Backend,
     D::Resolver: 'static,
 {
+    let master = resolve_master(resolver, &options).await?.addr;
+    let mut server = dns.open(runtime.handle(), master).await?;
+    if !options.force {
+        check_apex_ns::<D>(&mut server, &options).await?;
+    }
+    if let Operation::Append(rset) = &options.operation {
+        if options.match_ttl || options.skip_if_present {
+            let mut query = Query::query(rset.name().clone(), rset.record_type());
+            query.set_query_class(rset.dns_class());
+            let response = server.lookup(query, DnsRequestOptions::default()).await?;
+            if options.skip_if_present {
+                if let Ok(current) = RecordSet::try_from(response.answers()) {
+                    if rset.iter_data().all(|data| current.contains(&data)) {
+                        return Ok(UpdateOutcome::AlreadyPresent);
+                    }
+                }
+            }
+            if let (true, Some(record)) = (options.match_ttl, response.answers().first()) {
+                options.ttl = record.ttl();
+            }
+        }
+    }
+    let mut changes = None;
+    if let Operation::Ensure(desired) = &options.operation {
+        let desired = desired.clone();
+        let mut query = Query::query(desired.name().clone(), desired.record_type());
+        query.set_query_class(desired.dns_class());
+        let response = server.lookup(query, DnsRequestOptions::default()).await?;
+        let records: Vec<_> = response
+            .answers()
+            .iter()
+            .filter(|record| record.record_type() == desired.record_type())
+            .cloned()
+            .collect();
+        let current = if records.is_empty() {
+            desired.cleared()
+        } else {
+            RecordSet::try_from(records.as_slice())?
+        };
+        if let Some(required) = &options.require_current {
+            if required.data() != current.data() {
+                return Err(CurrentValueMismatch(required.clone()).into());
+            }
+        }
+        let ensured = Changes::between(&current, &desired)?;
+        if ensured.is_empty() {
+            return Ok(UpdateOutcome::Ensured(Box::new(ensured)));
+        }
+        if let (true, Some(record)) = (options.match_ttl, records.first()) {
+            options.ttl = record.ttl();
+        }
+        options.operation = ensured.to_operation();
+        options.require_current = Some(current);
+        changes = Some((desired, ensured));
+    }
     let message = options.get_update()?;
-    let master = if let Some(sockname) = options.server {
-        sockname.resolve(resolver, 53).await?
-    } else if let Some(soa) = resolver
-        .lookup_soa(options.zone.clone())
-        .await?
+    let response = server.send(message).await?;
+    #[cfg(feature = "tsig")]
+    if let Some(e) = tsig::ServerError::from_response(&response) {
+        return Err(e.into());
+    }
+    match (response.response_code(), options.require_current) {
+        (ResponseCode::NoError, _) => {}
+        // When ensuring, the required records are the ones read.
+        (ResponseCode::NXRRSet | ResponseCode::YXRRSet, Some(current)) if changes.is_some() => {
+            return Err(ConcurrentChange(current).into())
+        }
+        (ResponseCode::NXRRSet, Some(rset)) => return Err(CurrentValueMismatch(rset).into()),
+        (response_code, _) => {
+            return Err(UpdateRejected {
+                server: master,
+                response_code,
+            }
+            .into())
+        }
+    }
+    let outcome = match &changes {
+        Some((_, ensured)) => UpdateOutcome::Ensured(Box::new(ensured.clone())),
+        None => UpdateOutcome::Sent,
+    };
+    if options.verify {
+        let (rset, expectation) = match (&options.operation, &changes) {
+            (_, Some((desired, _))) => (desired, Expectation::Is(desired.clone())),
+            (Operation::Create(rset), _) => (rset, Expectation::Is(rset.clone())),
+            (Operation::Append(rset), _) => (rset, Expectation::Contains(rset.clone())),
+            _ => return Ok(outcome),
+        };
+        let mut query = Query::query(rset.name().clone(), rset.record_type());
+        query.set_query_class(rset.dns_class());
+        let response = server.lookup(query, DnsRequestOptions::default()).await?;
+        if !expectation.satisfied_by(response.answers()) {
+            return Err(UpdateNotApplied {
+                expectation,
+                records: response.answers().to_vec(),
+            }
+            .into());
+        }
+    }
+    Ok(outcome)
+}
+
+/// Refuses the operation of `options` if it deletes all `NS` records at the
+/// apex of the zone, as read from `server`.
+async fn check_apex_ns<D: Backend>(server: &mut D::Client, options: &Update) -> anyhow::Result<()> {
+    let zone = &options.zone;
+    if options
+        .operation
+        .apex_ns_after(zone, &BTreeSet::new())
+        .is_none()
+    {
+        return Ok(());
+    }
+    let mut query = Query::query(zone.clone(), rr::RecordType::NS);
+    query.set_query_class(options.dns_class);
+    let response = server.lookup(query, DnsRequestOptions::default()).await?;
+    let current: BTreeSet<_> = response
+        .answers()
         .iter()
-        .next()
+        .filter_map(|record| record.rdata().as_ns())
+        .cloned()
+        .collect();
+    match options.operation.apex_ns_after(zone, &current) {
+        Some(after) if after.is_empty() && !current.is_empty() => {
+            Err(ApexNsDeletion(zone.clone()).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Determines the zone `name` belongs to, by looking up the SOA record of
+/// `name` and each of its ancestors in turn, until one is found.
+pub async fn find_zone<R: Resolver>(resolver: &R, name: &rr::Name) -> anyhow::Result<rr::Name> {
+    let mut candidate = name.clone();
+    loop {
+        match resolver
+            .lookup(candidate.clone(), rr::RecordType::SOA)
+            .await
+        {
+            // The answer may also contain the SOA record of a CNAME target,
+            // which is not the zone of `candidate`.
+            Ok(lookup)
+                if lookup
+                    .record_iter()
+                    .any(|r| r.record_type() == rr::RecordType::SOA && r.name() == &candidate) =>
+            {
+                return Ok(candidate);
+            }
+            Ok(_) => {}
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => {}
+                _ => return Err(anyhow!("could not determine the zone of {}: {}", name, e)),
+            },
+        }
+        if candidate.is_root() {
+            return Err(anyhow!(
+                "could not determine the zone of {}: no SOA record found",
+                name
+            ));
+        }
+        candidate = candidate.base_name();
+    }
+}
+
+/// The server an update is sent to, as determined by `resolve_master`.
+#[derive(Debug, Clone)]
+pub struct Master {
+    pub addr: SocketAddr,
+    /// Set when the primary master named in the zone's SOA record could not
+    /// be resolved, and a nameserver of the zone is used instead.
+    pub fallback: Option<MasterFallback>,
+}
+
+/// Describes why an update is sent to a nameserver instead of the primary
+/// master.
+#[derive(Debug, Clone)]
+pub struct MasterFallback {
+    /// The primary master named in the SOA record.
+    pub mname: rr::Name,
+    /// The nameserver used instead.
+    pub nameserver: rr::Name,
+    /// Why resolving `mname` failed.
+    pub error: String,
+}
+
+impl fmt::Display for MasterFallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not resolve primary master {} ({}); sending the update to nameserver {} \
+             instead, use --server to specify the primary explicitly",
+            self.mname, self.error, self.nameserver
+        )
+    }
+}
+
+/// Determines the address of the server to send an update to.
+///
+/// Unless a server is given explicitly, this is the primary master named in
+/// the zone's SOA record. If that name does not resolve, as is common for
+/// hidden primaries, the zone's nameservers are tried instead, in the hope that
+/// one of them forwards the update; `Master::fallback` tells when this
+/// happened, so callers can warn about it.
+pub async fn resolve_master<R: Resolver>(resolver: R, options: &Update) -> anyhow::Result<Master> {
+    if let Some(sockname) = &options.server {
+        let addr = sockname
+            .resolve_with(resolver, 53, options.master_addr_select)
+            .await?;
+        return Ok(Master {
+            addr,
+            fallback: None,
+        });
+    }
+    let soa = options
+        .lookup_retry
+        .run(|| resolver.lookup_soa(options.zone.clone()))
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "could not determine primary master of {}: {}",
+                options.zone,
+                e
+            )
+        })?;
+    let mname = match soa.iter().next() {
+        Some(soa) => soa.mname().clone(),
+        None => return Err(anyhow!("SOA record for {} not found", options.zone)),
+    };
+    let e = match SocketName::HostName(mname.clone(), None)
+        .resolve_with(resolver.clone(), 53, options.master_addr_select)
+        .await
     {
-        util::SocketName::HostName(soa.mname().clone(), None)
-            .resolve(resolver, 53)
-            .await?
-    } else {
-        return Err(anyhow!("SOA record for {} not found", options.zone));
+        Ok(addr) => {
+            return Ok(Master {
+                addr,
+                fallback: None,
+            })
+        }
+        Err(e) => e,
     };
-    let mut server = dns.open(runtime, master).await?;
-    // TODO: probably should check response
-    server.send(message).await?;
-    Ok(())
+    if let Ok(nameservers) = options
+        .lookup_retry
+        .run(|| resolver.lookup_ns(options.zone.clone()))
+        .await
+    {
+        for name in nameservers.iter().filter(|name| **name != mname) {
+            if let Ok(addr) = SocketName::HostName(name.clone(), None)
+                .resolve(resolver.clone(), 53)
+                .await
+            {
+                return Ok(Master {
+                    addr,
+                    fallback: Some(MasterFallback {
+                        mname,
+                        nameserver: name.clone(),
+                        error: e.to_string(),
+                    }),
+                });
+            }
+        }
+    }
+    Err(anyhow!(
+        "could not resolve primary master {} of {}: {}; use --server to specify it explicitly",
+        mname,
+        options.zone,
+        e
+    ))
+}
+
+/// The result of polling a single authoritative nameserver once.
+#[derive(Debug, Clone)]
+pub struct PollEvent {
+    pub server_name: rr::Name,
+    pub server_addr: IpAddr,
+    /// Whether the expectation was satisfied by the server's answer.
+    pub matched: bool,
+    /// The records in the server's answer.
+    pub records: Vec<rr::Record>,
+    /// Why the poll failed, if the server did not answer, e.g. due to a
+    /// timeout. The poll is not `matched` then.
+    pub error: Option<String>,
+    /// The time elapsed since monitoring started.
+    pub elapsed: Duration,
+    /// The number of polls of the server so far, including this one.
+    pub poll: u32,
+}
+
+impl PollEvent {
+    /// Returns the answer records as a record set.
+    pub fn record_set(&self) -> Result<RecordSet, TryFromRecordsError> {
+        RecordSet::try_from(self.records.as_slice())
+    }
+}
+
+/// An event reported to the `progress` callback while monitoring.
+#[derive(Debug, Clone)]
+pub enum MonitorEvent {
+    /// The names and addresses of the nameservers polled; reported before
+    /// the first poll, and after each refresh of the nameservers.
+    Nameservers(Vec<(rr::Name, SocketAddr)>),
+    /// A warning about determining the nameservers to poll.
+    Warning(NameserverWarning),
+    /// A nameserver was polled.
+    Poll(PollEvent),
+}
+
+/// A warning about determining the nameservers of a zone, which did not
+/// prevent determining at least some of them.
+#[derive(Debug, Clone)]
+pub enum NameserverWarning {
+    /// The glue records could not be obtained from a parent nameserver, so
+    /// in-zone nameservers are resolved recursively.
+    GlueUnavailable { zone: rr::Name, error: String },
+    /// There is no glue for an in-zone nameserver, so it is resolved
+    /// recursively.
+    MissingGlue(rr::Name),
+    /// The nameserver name could not be resolved, so it is skipped.
+    Unresolvable {
+        server_name: rr::Name,
+        error: String,
+    },
+    /// The nameserver has no address of the family of the source address, so
+    /// it is skipped.
+    NoSourceFamily {
+        server_name: rr::Name,
+        source: IpAddr,
+    },
+    /// The nameservers could not be determined again after the refresh
+    /// interval, so the previous set is kept.
+    RefreshFailed { zone: rr::Name, error: String },
 }
 
+impl NameserverWarning {
+    /// Whether the warning is only of interest in verbose mode.
+    pub fn is_verbose(&self) -> bool {
+        matches!(
+            self,
+            NameserverWarning::MissingGlue(_) | NameserverWarning::RefreshFailed { .. }
+        )
+    }
+}
+
+impl fmt::Display for NameserverWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use NameserverWarning::*;
+        match self {
+            GlueUnavailable { zone, error } => write!(
+                f,
+                "could not obtain glue for {}, resolving in-zone nameservers recursively: {}",
+                zone, error
+            ),
+            MissingGlue(server_name) => write!(
+                f,
+                "no glue for in-zone nameserver {}, resolving recursively",
+                server_name
+            ),
+            Unresolvable { server_name, error } => write!(
+                f,
+                "skipping nameserver {}, which could not be resolved: {}",
+                server_name, error
+            ),
+            NoSourceFamily {
+                server_name,
+                source,
+            } => write!(
+                f,
+                "skipping nameserver {}, which has no address of the family of source address {}",
+                server_name, source
+            ),
+            RefreshFailed { zone, error } => write!(
+                f,
+                "could not refresh nameservers of {}, keeping previous set: {}",
+                zone, error
+            ),
+        }
+    }
+}
+
+/// How a nameserver converged on the expected records during monitoring.
+#[derive(Debug, Clone)]
+pub struct Convergence {
+    pub server_name: rr::Name,
+    pub server_addr: IpAddr,
+    /// The number of polls until the server served the expected records,
+    /// including the final one.
+    pub polls: u32,
+    /// The time elapsed from the start of monitoring until the final poll.
+    pub elapsed: Duration,
+}
+
+/// Monitors the authoritative nameservers of the zone until each of them
+/// serves the expected records, returning how each of them converged.
+///
+/// Nameservers excluded from monitoring are not included in the result.
+/// Warnings about determining the nameservers are printed to stderr; use
+/// `monitor_update_with_progress` to handle them otherwise.
 pub async fn monitor_update<D>(
     runtime: &Runtime,
     dns: D,
     resolver: D::Resolver,
     options: Monitor,
-) -> anyhow::Result<()>
+) -> anyhow::Result<Vec<Convergence>>
 where
-    D: Backend,
+    D: Backend + Send + 'static,
 {
-    let options = Rc::new(options);
-    let authorative = resolver.lookup_ns(options.zone.clone()).await?;
+    let verbose = options.verbose;
+    let expectation = options.expectation.clone();
+    monitor_update_with_progress(runtime, dns, resolver, options, |event| match event {
+        MonitorEvent::Warning(warning) => {
+            if verbose || !warning.is_verbose() {
+                eprintln!("warning: {}", warning);
+            }
+        }
+        MonitorEvent::Poll(event) => {
+            if verbose {
+                print_poll_event(event, &expectation);
+            }
+        }
+        MonitorEvent::Nameservers(_) => {}
+    })
+    .await
+}
+
+/// Prints the outcome of a poll, as done by `monitor_update` in verbose mode.
+pub fn print_poll_event(event: &PollEvent, expectation: &Expectation) {
+    if let Some(error) = &event.error {
+        println!("{}: poll failed: {}", event.server_name, error);
+    } else if event.matched {
+        println!(
+            "{}: match found after {}, {} {}",
+            event.server_name,
+            util::HumanDuration(event.elapsed),
+            event.poll,
+            if event.poll == 1 { "poll" } else { "polls" }
+        );
+    } else {
+        let rset = match event.record_set() {
+            Ok(rs) => format!("{}", rs.data()),
+            Err(e) => format!("{}", e),
+        };
+        println!(
+            "{}: records not matching: {}, found {}",
+            event.server_name, expectation, rset,
+        );
+    }
+}
+
+/// Monitors the authoritative nameservers like `monitor_update`, invoking
+/// `progress` for each poll of a nameserver, and for each warning about
+/// determining the nameservers.
+///
+/// Note that `progress` is invoked regardless of the `verbose` setting, and
+/// that nothing is printed.
+///
+/// The returned future is `Send` if the backend is `Send` and `progress` is
+/// `Sync`, so it may be spawned onto a multi-threaded runtime.
+pub async fn monitor_update_with_progress<D, P>(
+    runtime: &Runtime,
+    dns: D,
+    resolver: D::Resolver,
+    options: Monitor,
+    progress: P,
+) -> anyhow::Result<Vec<Convergence>>
+where
+    D: Backend + Send + 'static,
+    P: Fn(&MonitorEvent),
+{
+    let started = Instant::now();
+    let options = Arc::new(options);
+    let resolved = resolve_nameservers(
+        runtime,
+        dns.clone(),
+        resolver.clone(),
+        &options.ns_selection(),
+    )
+    .await?;
+    for warning in resolved.warnings {
+        progress(&MonitorEvent::Warning(warning));
+    }
+    let nameservers = resolved.nameservers;
     match timeout(
         options.timeout,
-        poll_for_update(runtime, dns, resolver, authorative, Rc::clone(&options)),
+        poll_for_update(
+            runtime,
+            dns,
+            resolver,
+            nameservers,
+            &progress,
+            started,
+            Arc::clone(&options),
+        ),
     )
-    .await?
+    .await
     {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow!(
-            "timeout; update not complete within {}ms",
-            options.timeout.as_millis()
-        )),
+        Ok(result) => result,
+        Err(_) => Err(MonitorTimeout(options.timeout).into()),
     }
 }
 
-async fn poll_for_update<D, I>(
+/// The error returned when an update is rejected because the RRset given via
+/// `Update::require_current` did not match the records present on the server.
+#[derive(Debug)]
+pub struct CurrentValueMismatch(pub RecordSet);
+
+impl fmt::Display for CurrentValueMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "current value did not match; expected {}", self.0)
+    }
+}
+
+impl std::error::Error for CurrentValueMismatch {}
+
+/// The error returned when ensuring an RRset, if it was changed on the server
+/// after reading it, so that the update was not applied.
+///
+/// Carries the records read; if empty, the RRset did not exist.
+#[derive(Debug)]
+pub struct ConcurrentChange(pub RecordSet);
+
+impl fmt::Display for ConcurrentChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(
+                f,
+                "{} {} was created concurrently; update not applied",
+                self.0.name(),
+                self.0.record_type()
+            )
+        } else {
+            write!(f, "{} was changed concurrently; update not applied", self.0)
+        }
+    }
+}
+
+impl std::error::Error for ConcurrentChange {}
+
+/// The error returned when an update would delete all `NS` records at the
+/// apex of the zone, unless `Update::force` is set.
+#[derive(Debug)]
+pub struct ApexNsDeletion(pub rr::Name);
+
+impl fmt::Display for ApexNsDeletion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "refusing to delete the last NS records of zone {}, which would make it \
+             unresolvable; use --force to override",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ApexNsDeletion {}
+
+/// The error returned when the server answers an update with a response code
+/// other than `NOERROR`.
+#[derive(Debug)]
+pub struct UpdateRejected {
+    pub server: SocketAddr,
+    pub response_code: ResponseCode,
+}
+
+impl fmt::Display for UpdateRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "update rejected by {}: {}",
+            self.server, self.response_code
+        )?;
+        match self.response_code {
+            ResponseCode::Refused | ResponseCode::NotAuth => write!(
+                f,
+                "; the server may not be the primary master, or not allow the update"
+            ),
+            ResponseCode::NotZone => write!(f, "; the name is not within the zone"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for UpdateRejected {}
+
+/// The error returned when verifying an update, i.e., the server accepted the
+/// update, but did not apply it, e.g. due to its policy.
+#[derive(Debug)]
+pub struct UpdateNotApplied {
+    pub expectation: Expectation,
+    /// The records the server answered with.
+    pub records: Vec<rr::Record>,
+}
+
+impl fmt::Display for UpdateNotApplied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "update accepted but not applied; {}", self.expectation)?;
+        match RecordSet::try_from(self.records.as_slice()) {
+            Ok(rset) => write!(f, ", found {}", rset.data()),
+            Err(_) if self.records.is_empty() => write!(f, ", found no records"),
+            Err(_) => write!(f, ", found {} unexpected records", self.records.len()),
+        }
+    }
+}
+
+impl std::error::Error for UpdateNotApplied {}
+
+/// The error returned when monitoring does not see the update on all
+/// nameservers within the configured timeout.
+#[derive(Debug)]
+pub struct MonitorTimeout(pub Duration);
+
+impl fmt::Display for MonitorTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timeout; update not complete within {}",
+            util::HumanDuration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for MonitorTimeout {}
+
+/// The error returned when monitoring gave up on nameservers that did not
+/// serve the expected records within the number of tries given by
+/// `Monitor::tries`.
+#[derive(Debug)]
+pub struct TriesExhausted {
+    pub tries: u32,
+    /// The nameservers given up on.
+    pub servers: Vec<rr::Name>,
+}
+
+impl fmt::Display for TriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "update not seen after {} {} on ",
+            self.tries,
+            if self.tries == 1 { "try" } else { "tries" }
+        )?;
+        for (i, server) in self.servers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", server)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TriesExhausted {}
+
+/// Statistics about the polls of each nameserver during monitoring, collected
+/// from `PollEvent`s.
+///
+/// Nameservers that never answer are only included if added via `seed`,
+/// e.g. from `MonitorEvent::Nameservers`.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorMetrics {
+    servers: BTreeMap<rr::Name, ServerMetrics>,
+}
+
+#[derive(Debug, Clone)]
+struct ServerMetrics {
+    addr: IpAddr,
+    polls: u64,
+    converged_after: Option<Duration>,
+}
+
+impl ServerMetrics {
+    fn to_json(&self, name: &rr::Name) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "server": name.to_string(),
+            "address": self.addr.to_string(),
+            "polls": self.polls,
+            "converged": self.converged_after.is_some(),
+        });
+        if let Some(elapsed) = self.converged_after {
+            json["convergence_seconds"] = elapsed.as_secs_f64().into();
+        }
+        json
+    }
+}
+
+impl MonitorMetrics {
+    /// Adds the given nameservers, as not yet polled and not converged.
+    pub fn seed(&mut self, servers: &[(rr::Name, SocketAddr)]) {
+        for (name, addr) in servers {
+            self.servers.entry(name.clone()).or_insert(ServerMetrics {
+                addr: addr.ip(),
+                polls: 0,
+                converged_after: None,
+            });
+        }
+    }
+
+    pub fn record(&mut self, event: &PollEvent) {
+        let server = self
+            .servers
+            .entry(event.server_name.clone())
+            .or_insert(ServerMetrics {
+                addr: event.server_addr,
+                polls: 0,
+                converged_after: None,
+            });
+        server.polls += 1;
+        if event.matched {
+            server.converged_after = Some(event.elapsed);
+        }
+    }
+
+    /// Writes the metrics in the Prometheus text exposition format.
+    pub fn write_prometheus<W: io::Write>(
+        &self,
+        writer: &mut W,
+        zone: &rr::Name,
+        entry: &rr::Name,
+    ) -> io::Result<()> {
+        let labels = format!(
+            "zone=\"{}\",entry=\"{}\"",
+            escape_label(&zone.to_string()),
+            escape_label(&entry.to_string())
+        );
+        let total: u64 = self.servers.values().map(|server| server.polls).sum();
+        writeln!(
+            writer,
+            "# HELP tdns_monitor_polls_total Number of polls across all nameservers."
+        )?;
+        writeln!(writer, "# TYPE tdns_monitor_polls_total counter")?;
+        writeln!(writer, "tdns_monitor_polls_total{{{}}} {}", labels, total)?;
+        writeln!(
+            writer,
+            "# HELP tdns_monitor_server_polls_total Number of polls of a nameserver."
+        )?;
+        writeln!(writer, "# TYPE tdns_monitor_server_polls_total counter")?;
+        for (name, server) in &self.servers {
+            writeln!(
+                writer,
+                "tdns_monitor_server_polls_total{{{},server=\"{}\",address=\"{}\"}} {}",
+                labels,
+                escape_label(&name.to_string()),
+                server.addr,
+                server.polls
+            )?;
+        }
+        writeln!(
+            writer,
+            "# HELP tdns_monitor_server_converged Whether a nameserver served the expected records."
+        )?;
+        writeln!(writer, "# TYPE tdns_monitor_server_converged gauge")?;
+        for (name, server) in &self.servers {
+            writeln!(
+                writer,
+                "tdns_monitor_server_converged{{{},server=\"{}\",address=\"{}\"}} {}",
+                labels,
+                escape_label(&name.to_string()),
+                server.addr,
+                server.converged_after.is_some() as u8
+            )?;
+        }
+        writeln!(
+            writer,
+            "# HELP tdns_monitor_server_convergence_seconds Time until a nameserver served the expected records."
+        )?;
+        writeln!(
+            writer,
+            "# TYPE tdns_monitor_server_convergence_seconds gauge"
+        )?;
+        for (name, server) in &self.servers {
+            if let Some(elapsed) = server.converged_after {
+                writeln!(
+                    writer,
+                    "tdns_monitor_server_convergence_seconds{{{},server=\"{}\",address=\"{}\"}} {}",
+                    labels,
+                    escape_label(&name.to_string()),
+                    server.addr,
+                    elapsed.as_secs_f64()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the metrics as a JSON object, with an entry for each
+    /// nameserver in its `servers` array.
+    ///
+    /// As nameservers are no longer polled once they served the expected
+    /// records, the `polls` of a converged nameserver is the number of polls
+    /// it took to converge.
+    pub fn to_json(&self, zone: &rr::Name, entry: &rr::Name) -> serde_json::Value {
+        let total: u64 = self.servers.values().map(|server| server.polls).sum();
+        let servers: Vec<_> = self
+            .servers
+            .iter()
+            .map(|(name, server)| server.to_json(name))
+            .collect();
+        serde_json::json!({
+            "zone": zone.to_string(),
+            "entry": entry.to_string(),
+            "polls_total": total,
+            "servers": servers,
+        })
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Addresses of the authoritative nameservers, keyed by their names.
+pub(crate) type Nameservers = BTreeMap<rr::Name, IpAddr>;
+
+/// The settings determining the nameservers of a zone to poll; see the
+/// fields of `Monitor` of the same names.
+pub(crate) struct NsSelection<'a> {
+    pub zone: &'a rr::Name,
+    pub lookup_retry: util::Retry,
+    pub exclude: &'a [IpAddr],
+    pub source: Option<IpAddr>,
+}
+
+/// Determines the addresses of the authoritative nameservers of `zone`, in
+/// the same way as the nameservers polled when monitoring an update, so that
+/// they can be queried directly, e.g. via `query::perform_query_direct`.
+///
+/// Nameservers whose address is in `exclude` are not included in the result.
+pub async fn authoritative_servers<D>(
     runtime: &Runtime,
     dns: D,
     resolver: D::Resolver,
-    authorative: I,
-    options: Rc<Monitor>,
-) -> anyhow::Result<()>
+    zone: &rr::Name,
+    exclude: &[IpAddr],
+) -> anyhow::Result<AuthoritativeServers>
 where
-    I: IntoIterator<Item = rr::Name>,
     D: Backend,
 {
-    let results: FuturesUnordered<_> = authorative
+    let selection = NsSelection {
+        zone,
+        lookup_retry: Default::default(),
+        exclude,
+        source: None,
+    };
+    let resolved = resolve_nameservers(runtime, dns, resolver, &selection).await?;
+    Ok(AuthoritativeServers {
+        servers: nameserver_addrs(&resolved.nameservers, exclude),
+        warnings: resolved.warnings,
+    })
+}
+
+/// The nameservers determined by `authoritative_servers`.
+#[derive(Debug, Clone)]
+pub struct AuthoritativeServers {
+    pub servers: Vec<(rr::Name, SocketAddr)>,
+    /// Warnings about nameservers that were skipped, or whose addresses were
+    /// determined in a fallback manner.
+    pub warnings: Vec<NameserverWarning>,
+}
+
+/// Returns the addresses to query the nameservers at, leaving out the
+/// excluded ones.
+pub(crate) fn nameserver_addrs(
+    nameservers: &Nameservers,
+    exclude: &[IpAddr],
+) -> Vec<(rr::Name, SocketAddr)> {
+    nameservers
+        .iter()
+        .filter(|(_, ip)| !exclude.contains(ip))
+        .map(|(server_name, ip)| (server_name.clone(), SocketAddr::new(*ip, 53)))
+        .collect()
+}
+
+/// The nameservers determined by `resolve_nameservers`, along with the
+/// warnings about determining them.
+pub(crate) struct ResolvedNameservers {
+    pub nameservers: Nameservers,
+    pub warnings: Vec<NameserverWarning>,
+}
+
+/// Determines the authoritative nameservers of the zone, and resolves their
+/// addresses.
+///
+/// Nameservers whose name cannot be resolved are skipped with a warning; only
+/// if none of them can be resolved, an error is returned.
+pub(crate) async fn resolve_nameservers<D>(
+    runtime: &Runtime,
+    dns: D,
+    resolver: D::Resolver,
+    options: &NsSelection<'_>,
+) -> anyhow::Result<ResolvedNameservers>
+where
+    D: Backend,
+{
+    let authorative = options
+        .lookup_retry
+        .run(|| resolver.lookup_ns(options.zone.clone()))
+        .await
+        .map_err(|e| anyhow!("could not determine nameservers of {}: {}", options.zone, e))?;
+    let mut warnings = Vec::new();
+    let glue = if authorative.iter().any(|name| options.zone.zone_of(name)) {
+        match lookup_glue(runtime, dns, resolver.clone(), options.zone).await {
+            Ok(glue) => glue,
+            Err(e) => {
+                warnings.push(NameserverWarning::GlueUnavailable {
+                    zone: options.zone.clone(),
+                    error: e.to_string(),
+                });
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let mut nameservers = Nameservers::new();
+    let (mut n_unresolved, mut n_skipped) = (0, 0);
+    for server_name in authorative.iter() {
+        let ip = match util::glue_address(&glue, server_name) {
+            Some(ip) => ip,
+            None => {
+                if options.zone.zone_of(server_name) {
+                    warnings.push(NameserverWarning::MissingGlue(server_name.clone()));
+                }
+                let resolved = resolver
+                    .lookup_addrs(server_name.clone(), AddressFamily::Any)
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|addrs| addrs.first().copied().ok_or_else(|| "no address".into()));
+                match resolved {
+                    Ok(ip) => ip,
+                    Err(error) => {
+                        warnings.push(NameserverWarning::Unresolvable {
+                            server_name: server_name.clone(),
+                            error,
+                        });
+                        n_unresolved += 1;
+                        continue;
+                    }
+                }
+            }
+        };
+        let ip = match options.source {
+            Some(source) if source.is_ipv4() != ip.is_ipv4() => {
+                match source_family_addr(&resolver, server_name, source).await {
+                    Some(ip) => ip,
+                    None => {
+                        warnings.push(NameserverWarning::NoSourceFamily {
+                            server_name: server_name.clone(),
+                            source,
+                        });
+                        n_skipped += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => ip,
+        };
+        nameservers.insert(server_name.clone(), ip);
+    }
+    // Without any nameserver to poll, monitoring would succeed without having
+    // checked anything.
+    if let (true, true, Some(source)) = (nameservers.is_empty(), n_skipped > 0, options.source) {
+        return Err(anyhow!(
+            "no nameserver of {} can be reached from source address {}",
+            options.zone,
+            source
+        ));
+    }
+    if nameservers.is_empty() && n_unresolved > 0 {
+        return Err(anyhow!(
+            "none of the nameservers of {} could be resolved",
+            options.zone
+        ));
+    }
+    if nameservers.is_empty() {
+        return Err(anyhow!(
+            "no authoritative nameservers found for {}",
+            options.zone
+        ));
+    }
+    if nameservers.values().all(|ip| options.exclude.contains(ip)) {
+        return Err(anyhow!(
+            "all nameservers of {} are excluded from monitoring",
+            options.zone
+        ));
+    }
+    Ok(ResolvedNameservers {
+        nameservers,
+        warnings,
+    })
+}
+
+/// Looks up an address of `server_name` of the same family as `source`.
+async fn source_family_addr<R: Resolver>(
+    resolver: &R,
+    server_name: &rr::Name,
+    source: IpAddr,
+) -> Option<IpAddr> {
+    let family = if source.is_ipv4() {
+        AddressFamily::PreferIpv4
+    } else {
+        AddressFamily::PreferIpv6
+    };
+    let addrs = resolver
+        .lookup_addrs(server_name.clone(), family)
+        .await
+        .ok()?;
+    addrs
         .into_iter()
-        .map(move |server_name| {
-            poll_server(
-                runtime,
-                dns.clone(),
-                resolver.clone(),
-                server_name,
-                Rc::clone(&options),
-            )
-        })
-        .collect();
-    results.try_collect().await?;
-    Ok(())
+        .find(|addr| addr.is_ipv4() == source.is_ipv4())
 }
 
-async fn poll_server<D>(
+/// Obtains the glue records for the nameservers of `zone` from the
+/// nameservers of its parent zone, trying each of them in turn until one
+/// answers.
+///
+/// The addresses of nameservers that live inside `zone` itself should be taken
+/// from the glue records in the parent's referral, as a recursive lookup may
+/// yield stale or no data while the zone's delegation is being changed.
+async fn lookup_glue<D>(
     runtime: &Runtime,
     mut dns: D,
     resolver: D::Resolver,
-    server_name: rr::Name,
-    options: Rc<Monitor>,
-) -> anyhow::Result<()>
+    zone: &rr::Name,
+) -> anyhow::Result<Vec<rr::Record>>
 where
     D: Backend,
 {
-    let ip = resolver
-        .lookup_ip(server_name.clone())
-        .await?
-        .iter()
-        .next()
-        .ok_or_else(|| anyhow!("could not resolve {}", &server_name))?;
-    if options.exclude.contains(&ip) {
-        return Ok(());
-    }
-    let mut server = dns.open(runtime, SocketAddr::new(ip, 53)).await?;
-    let server_name = server_name.clone();
-    let options = Rc::clone(&options);
-    let query = options.get_query();
-    loop {
-        if let Ok(response) = server
-            .lookup(query.clone(), DnsRequestOptions::default())
+    let parent_ns = resolver.lookup_ns(zone.base_name()).await?;
+    let mut last_error = None;
+    for server_name in parent_ns.iter() {
+        let ip = match resolver
+            .lookup_addrs(server_name.clone(), AddressFamily::Any)
             .await
         {
-            let answers = response.answers();
-            let hit = options.expectation.satisfied_by(answers);
-            if options.verbose {
-                if hit {
-                    println!("{}: match found", &server_name);
-                } else {
-                    let rset = match RecordSet::try_from(answers) {
-                        Ok(rs) => format!("{}", rs.data()),
-                        Err(e) => format!("{}", e),
-                    };
-                    println!(
-                        "{}: records not matching: {}, found {}",
-                        server_name, options.expectation, rset,
-                    );
+            Ok(addrs) => match addrs.first() {
+                Some(ip) => *ip,
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+        let mut server = match dns.open(runtime.handle(), SocketAddr::new(ip, 53)).await {
+            Ok(server) => server,
+            Err(e) => {
+                last_error = Some(anyhow!("could not connect to {}: {}", server_name, e));
+                continue;
+            }
+        };
+        let query = Query::query(zone.clone(), rr::RecordType::NS);
+        match server.lookup(query, DnsRequestOptions::default()).await {
+            Ok(response) => return Ok(response.additionals().to_vec()),
+            Err(e) => last_error = Some(anyhow!("{}: {}", server_name, e)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("no reachable nameserver for {}", zone.base_name())))
+}
+
+/// State shared between the polls of the individual nameservers.
+struct PollState {
+    /// The nameservers that have provided the expected records.
+    done: Mutex<BTreeMap<rr::Name, Convergence>>,
+    /// The number of polls of each nameserver, kept here so that it carries
+    /// over when polling is restarted after refreshing the nameservers.
+    polls: Mutex<BTreeMap<rr::Name, u32>>,
+    /// The nameservers that have used up their tries.
+    exhausted: Mutex<Vec<rr::Name>>,
+    /// When monitoring started.
+    started: Instant,
+    /// Limits the number of nameservers polled at the same time; a
+    /// nameserver holds a slot only during a single poll, so that the others
+    /// are polled while it waits for the next one.
+    slots: Semaphore,
+}
+
+/// A poll event sent to the task invoking the progress callback, along with
+/// the sender to acknowledge it on.
+type ProgressMessage = (PollEvent, oneshot::Sender<()>);
+
+/// The tasks polling the individual nameservers, which are aborted when
+/// dropped, e.g. when monitoring times out.
+struct PollTasks(Vec<JoinHandle<anyhow::Result<()>>>);
+
+impl PollTasks {
+    /// Waits for all tasks to finish, invoking `progress` for the events they
+    /// send meanwhile, and returns the first error of any task.
+    async fn join<P>(
+        mut self,
+        events: &mut mpsc::UnboundedReceiver<ProgressMessage>,
+        progress: &P,
+    ) -> anyhow::Result<()>
+    where
+        P: Fn(&MonitorEvent),
+    {
+        let mut pending: FuturesUnordered<_> = self.0.iter_mut().collect();
+        loop {
+            tokio::select! {
+                Some((event, ack)) = events.recv() => {
+                    progress(&MonitorEvent::Poll(event));
+                    let _ = ack.send(());
                 }
+                result = pending.next() => match result {
+                    Some(Ok(result)) => result?,
+                    Some(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+impl Drop for PollTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Polls each nameserver in a task of its own, so that the polls run in
+/// parallel on a multi-threaded runtime.
+///
+/// The `progress` callback is invoked on the calling task, which the polling
+/// tasks wait for before polling again.
+async fn poll_for_update<D, P>(
+    runtime: &Runtime,
+    dns: D,
+    resolver: D::Resolver,
+    mut nameservers: Nameservers,
+    progress: &P,
+    started: Instant,
+    options: Arc<Monitor>,
+) -> anyhow::Result<Vec<Convergence>>
+where
+    D: Backend + Send + 'static,
+    P: Fn(&MonitorEvent),
+{
+    let state = Arc::new(PollState {
+        done: Mutex::new(BTreeMap::new()),
+        polls: Mutex::new(BTreeMap::new()),
+        exhausted: Mutex::new(Vec::new()),
+        started,
+        slots: Semaphore::new(
+            options
+                .poll_concurrency
+                .unwrap_or(Semaphore::MAX_PERMITS)
+                .clamp(1, Semaphore::MAX_PERMITS),
+        ),
+    });
+    let (sender, mut events) = mpsc::unbounded_channel();
+    loop {
+        let servers = nameserver_addrs(&nameservers, &options.exclude);
+        progress(&MonitorEvent::Nameservers(servers.clone()));
+        let servers: Vec<_> = servers
+            .into_iter()
+            .filter(|(server_name, _)| {
+                !state.done.lock().unwrap().contains_key(server_name)
+                    && !state.exhausted.lock().unwrap().contains(server_name)
+            })
+            .collect();
+        let tasks = servers
+            .into_iter()
+            .map(|(server_name, addr)| {
+                runtime.spawn(poll_server(
+                    runtime.handle().clone(),
+                    dns.clone(),
+                    server_name,
+                    addr,
+                    Arc::clone(&state),
+                    sender.clone(),
+                    Arc::clone(&options),
+                ))
+            })
+            .collect();
+        let polling = PollTasks(tasks).join(&mut events, progress);
+        let refresh = match options.ns_refresh {
+            Some(refresh) => refresh,
+            None => {
+                polling.await?;
+                break;
             }
-            if hit {
-                return Ok(());
-            } else {
-                sleep(options.interval).await;
+        };
+        if let Ok(result) = timeout(refresh, polling).await {
+            result?;
+            break;
+        }
+        // The refresh interval has elapsed; the servers that have already
+        // provided the expected records are not polled again.
+        match resolve_nameservers(
+            runtime,
+            dns.clone(),
+            resolver.clone(),
+            &options.ns_selection(),
+        )
+        .await
+        {
+            Ok(refreshed) => {
+                let mut refreshed_nameservers = refreshed.nameservers;
+                for warning in refreshed.warnings {
+                    // A nameserver that cannot be resolved anymore is polled
+                    // at its previous address.
+                    if let NameserverWarning::Unresolvable { server_name, .. } = &warning {
+                        if let Some(ip) = nameservers.get(server_name) {
+                            refreshed_nameservers.insert(server_name.clone(), *ip);
+                            continue;
+                        }
+                    }
+                    progress(&MonitorEvent::Warning(warning));
+                }
+                nameservers = refreshed_nameservers;
             }
+            Err(e) => progress(&MonitorEvent::Warning(NameserverWarning::RefreshFailed {
+                zone: options.zone.clone(),
+                error: e.to_string(),
+            })),
+        }
+    }
+    let exhausted = std::mem::take(&mut *state.exhausted.lock().unwrap());
+    if let (false, Some(tries)) = (exhausted.is_empty(), options.tries) {
+        return Err(TriesExhausted {
+            tries,
+            servers: exhausted,
+        }
+        .into());
+    }
+    let done = std::mem::take(&mut *state.done.lock().unwrap());
+    Ok(done.into_values().collect())
+}
+
+async fn poll_server<D>(
+    runtime: RuntimeHandle,
+    mut dns: D,
+    server_name: rr::Name,
+    addr: SocketAddr,
+    state: Arc<PollState>,
+    events: mpsc::UnboundedSender<ProgressMessage>,
+    options: Arc<Monitor>,
+) -> anyhow::Result<()>
+where
+    D: Backend,
+{
+    // The connection is kept open across polls, which saves TCP handshakes
+    // during long monitoring.
+    let mut server = None;
+    let query = options.get_query();
+    let mut interval = options.interval;
+    loop {
+        let slot = state.slots.acquire().await?;
+        let client = match &mut server {
+            Some(client) => Ok(client),
+            None => match dns.open(&runtime, addr).await {
+                Ok(client) => Ok(server.insert(client)),
+                Err(e) => Err(format!("could not connect: {}", e)),
+            },
+        };
+        let answer = match client {
+            Ok(client) => client
+                .lookup(query.clone(), DnsRequestOptions::default())
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        drop(slot);
+        let (records, error) = match answer {
+            Ok(response) => (response.answers().to_vec(), None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        let failed = error.is_some();
+        let hit = !failed && options.expectation.satisfied_by(&records);
+        let poll = {
+            let mut polls = state.polls.lock().unwrap();
+            let poll = polls.entry(server_name.clone()).or_insert(0);
+            *poll += 1;
+            *poll
+        };
+        let event = PollEvent {
+            server_name: server_name.clone(),
+            server_addr: addr.ip(),
+            matched: hit,
+            records,
+            error,
+            elapsed: state.started.elapsed(),
+            poll,
+        };
+        let convergence = Convergence {
+            server_name: event.server_name.clone(),
+            server_addr: addr.ip(),
+            polls: poll,
+            elapsed: event.elapsed,
+        };
+        let (ack, acked) = oneshot::channel();
+        if events.send((event, ack)).is_ok() {
+            let _ = acked.await;
+        }
+        if hit {
+            state.done.lock().unwrap().insert(server_name, convergence);
+            return Ok(());
+        }
+        // Polls that got no answer count as well, so that an unreachable
+        // nameserver is given up on, too.
+        if options.tries.is_some_and(|tries| poll >= tries) {
+            state.exhausted.lock().unwrap().push(server_name);
+            return Ok(());
+        }
+        if failed {
+            // The connection may have been closed, e.g. by a server timing
+            // out idle TCP connections, so it is re-established for the next
+            // poll.
+            server = None;
+        }
+        sleep(interval).await;
+        if let Some(interval_max) = options.interval_max {
+            interval = Duration::try_from_secs_f64(interval.as_secs_f64() * options.backoff_factor)
+                .unwrap_or(interval_max)
+                .min(interval_max);
         }
     }
 }
@@ -1,13 +1,163 @@
+use std::fmt;
+
 use trust_dns_client::{
     op::{Message, MessageType, OpCode, Query, UpdateMessage},
     rr::{rdata::NULL, DNSClass, Name, RData, Record, RecordSet, RecordType},
 };
 
+#[cfg(feature = "tsig")]
+use crate::tsig;
+use crate::update::Operation;
+
+/// The error returned when a record name is not within the zone to update.
+///
+/// The names are boxed to keep the `Result`s carrying this error small.
+#[derive(Debug)]
+pub struct NotInZone {
+    pub name: Box<Name>,
+    pub zone: Box<Name>,
+}
+
+impl NotInZone {
+    pub fn new(name: Name, zone: Name) -> Self {
+        NotInZone {
+            name: Box::new(name),
+            zone: Box::new(zone),
+        }
+    }
+}
+
+impl fmt::Display for NotInZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry {} is not within zone {}", self.name, self.zone)
+    }
+}
+
+impl std::error::Error for NotInZone {}
+
+/// The error returned when an update message cannot be built.
+#[derive(Debug)]
+pub enum BuildError {
+    NotInZone(NotInZone),
+    /// A batch operation without any entries.
+    EmptyBatch,
+    #[cfg(feature = "tsig")]
+    Tsig(tsig::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NotInZone(e) => e.fmt(f),
+            BuildError::EmptyBatch => write!(f, "no operations given for update"),
+            #[cfg(feature = "tsig")]
+            BuildError::Tsig(e) => write!(f, "could not sign update: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<NotInZone> for BuildError {
+    fn from(e: NotInZone) -> Self {
+        BuildError::NotInZone(e)
+    }
+}
+
+#[cfg(feature = "tsig")]
+impl From<tsig::Error> for BuildError {
+    fn from(e: tsig::Error) -> Self {
+        BuildError::Tsig(e)
+    }
+}
+
+/// Builds the update message for `operation`, signed with `key`, if given.
+///
+/// Records added by the operation get the TTL `ttl`, unless their record set
+/// carries a TTL of its own. `dns_class` is the class of the records deleted by
+/// `Operation::DeleteAll`; the other operations carry their class.
+///
+/// As the current records are not known here, `Operation::Ensure` replaces
+/// the whole RRset; `perform_update` instead sends only the differences.
+#[cfg(feature = "tsig")]
+pub fn build(
+    operation: &Operation,
+    zone: &Name,
+    dns_class: DNSClass,
+    ttl: u32,
+    key: Option<&tsig::Key>,
+) -> Result<Message, BuildError> {
+    let mut message = build_unsigned(operation, zone, dns_class, ttl)?;
+    if let Some(key) = key {
+        tsig::add_signature(&mut message, key)?;
+    }
+    Ok(message)
+}
+
+/// Builds the update message for `operation`, like `build`, but without
+/// signing it.
+///
+/// This allows adding further prerequisites, which must happen before the
+/// message is signed.
+pub fn build_unsigned(
+    operation: &Operation,
+    zone: &Name,
+    dns_class: DNSClass,
+    ttl: u32,
+) -> Result<Message, BuildError> {
+    let message = match operation {
+        Operation::Create(rset) => create(rset.to_rrset(ttl), zone.clone())?,
+        Operation::Append(rset) => append(rset.to_rrset(ttl), zone.clone(), false)?,
+        Operation::Delete(rset) => {
+            if rset.is_empty() {
+                let mut record = Record::with(rset.name().clone(), rset.record_type(), ttl);
+                record.set_dns_class(rset.dns_class());
+                delete_rrset(record, zone.clone())?
+            } else {
+                delete_by_rdata(rset.to_rrset(ttl), zone.clone())?
+            }
+        }
+        Operation::DeleteAll(name) => delete_all(name.clone(), zone.clone(), dns_class)?,
+        // Without knowing the current records, the whole RRset is replaced.
+        Operation::Ensure(rset) => {
+            let mut record = Record::with(rset.name().clone(), rset.record_type(), ttl);
+            record.set_dns_class(rset.dns_class());
+            let mut message = delete_rrset(record, zone.clone())?;
+            if !rset.is_empty() {
+                merge(
+                    &mut message,
+                    append(rset.to_rrset(ttl), zone.clone(), false)?,
+                );
+            }
+            message
+        }
+        Operation::Batch(entries) => {
+            let mut entries = entries.iter();
+            let first = entries.next().ok_or(BuildError::EmptyBatch)?;
+            let mut message = build_unsigned(first, zone, dns_class, ttl)?;
+            for entry in entries {
+                let other = build_unsigned(entry, zone, dns_class, ttl)?;
+                merge(&mut message, other);
+            }
+            message
+        }
+    };
+    Ok(message)
+}
+
+fn check_zone_of(zone_origin: &Name, name: &Name) -> Result<(), NotInZone> {
+    if zone_origin.zone_of(name) {
+        Ok(())
+    } else {
+        Err(NotInZone::new(name.clone(), zone_origin.clone()))
+    }
+}
+
 // This code is taken from `update_message.rs` in the `trust_dns` crate, and
 // adapted to omit EDNS.
-pub fn create(rrset: RecordSet, zone_origin: Name) -> Message {
+pub fn create(rrset: RecordSet, zone_origin: Name) -> Result<Message, NotInZone> {
     // TODO: assert non-empty rrset?
-    assert!(zone_origin.zone_of(rrset.name()));
+    check_zone_of(&zone_origin, rrset.name())?;
 
     // for updates, the query section is used for the zone
     let mut zone = Query::new();
@@ -28,11 +178,11 @@ pub fn create(rrset: RecordSet, zone_origin: Name) -> Message {
     prerequisite.set_dns_class(DNSClass::NONE);
     message.add_pre_requisite(prerequisite);
     message.add_updates(rrset);
-    message
+    Ok(message)
 }
 
-pub fn append(rrset: RecordSet, zone_origin: Name, must_exist: bool) -> Message {
-    assert!(zone_origin.zone_of(rrset.name()));
+pub fn append(rrset: RecordSet, zone_origin: Name, must_exist: bool) -> Result<Message, NotInZone> {
+    check_zone_of(&zone_origin, rrset.name())?;
 
     // for updates, the query section is used for the zone
     let mut zone: Query = Query::new();
@@ -57,11 +207,11 @@ pub fn append(rrset: RecordSet, zone_origin: Name, must_exist: bool) -> Message
 
     message.add_updates(rrset);
 
-    message
+    Ok(message)
 }
 
-pub fn delete_by_rdata(mut rrset: RecordSet, zone_origin: Name) -> Message {
-    assert!(zone_origin.zone_of(rrset.name()));
+pub fn delete_by_rdata(mut rrset: RecordSet, zone_origin: Name) -> Result<Message, NotInZone> {
+    check_zone_of(&zone_origin, rrset.name())?;
 
     // for updates, the query section is used for the zone
     let mut zone: Query = Query::new();
@@ -84,11 +234,11 @@ pub fn delete_by_rdata(mut rrset: RecordSet, zone_origin: Name) -> Message {
     rrset.set_ttl(0);
     message.add_updates(rrset);
 
-    message
+    Ok(message)
 }
 
-pub fn delete_rrset(mut record: Record, zone_origin: Name) -> Message {
-    assert!(zone_origin.zone_of(record.name()));
+pub fn delete_rrset(mut record: Record, zone_origin: Name) -> Result<Message, NotInZone> {
+    check_zone_of(&zone_origin, record.name())?;
 
     // for updates, the query section is used for the zone
     let mut zone: Query = Query::new();
@@ -113,11 +263,51 @@ pub fn delete_rrset(mut record: Record, zone_origin: Name) -> Message {
     record.set_rdata(RData::NULL(NULL::new()));
     message.add_update(record);
 
-    message
+    Ok(message)
 }
 
-pub fn delete_all(name_of_records: Name, zone_origin: Name, dns_class: DNSClass) -> Message {
-    assert!(zone_origin.zone_of(&name_of_records));
+/// Adds a value-dependent "RRset exists" prerequisite (RFC 2136, section
+/// 2.4.2) to `message`, so the update only takes effect if the records of the
+/// RRset are exactly those in `rrset`.
+///
+/// If `rrset` is empty, an "RRset does not exist" prerequisite (section 2.4.3)
+/// is added instead.
+pub fn require_rrset(
+    message: &mut Message,
+    rrset: RecordSet,
+    zone_origin: &Name,
+) -> Result<(), NotInZone> {
+    check_zone_of(zone_origin, rrset.name())?;
+
+    if rrset.is_empty() {
+        let mut prerequisite = Record::with(rrset.name().clone(), rrset.record_type(), 0);
+        prerequisite.set_dns_class(DNSClass::NONE);
+        message.add_pre_requisite(prerequisite);
+        return Ok(());
+    }
+    for mut record in rrset.into_iter() {
+        // the TTL must be 0, the class is the zone class
+        record.set_ttl(0);
+        message.add_pre_requisite(record);
+    }
+    Ok(())
+}
+
+/// Adds the prerequisites and updates of `other` to `message`, so that
+/// multiple updates of the same zone can be sent as a single message.
+pub fn merge(message: &mut Message, mut other: Message) {
+    // in update messages, the prerequisites are carried in the answer
+    // section, and the updates in the authority section
+    message.add_pre_requisites(other.take_answers());
+    message.add_updates(other.take_name_servers());
+}
+
+pub fn delete_all(
+    name_of_records: Name,
+    zone_origin: Name,
+    dns_class: DNSClass,
+) -> Result<Message, NotInZone> {
+    check_zone_of(&zone_origin, &name_of_records)?;
 
     // for updates, the query section is used for the zone
     let mut zone: Query = Query::new();
@@ -144,5 +334,27 @@ pub fn delete_all(name_of_records: Name, zone_origin: Name, dns_class: DNSClass)
 
     message.add_update(record);
 
+    Ok(message)
+}
+
+/// Builds an update message for `zone_origin` without any prerequisites or
+/// updates.
+///
+/// Servers process such an update without changing the zone, so, once
+/// signed, it shows whether a key is allowed to update the zone.
+pub fn empty(zone_origin: Name, dns_class: DNSClass) -> Message {
+    // for updates, the query section is used for the zone
+    let mut zone: Query = Query::new();
+    zone.set_name(zone_origin)
+        .set_query_class(dns_class)
+        .set_query_type(RecordType::SOA);
+
+    let mut message: Message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Update)
+        .set_recursion_desired(false);
+    message.add_zone(zone);
     message
 }
@@ -1,14 +1,37 @@
 use std::{
-    fmt,
-    net::{IpAddr, SocketAddr},
+    ffi::OsString,
+    fmt, fs,
+    future::Future,
+    io,
+    net::{self, IpAddr, SocketAddr},
     num::ParseIntError,
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
-use trust_dns_client::{op::ResponseCode, proto::error::ProtoError, rr};
+use rand::seq::SliceRandom;
+use tokio::time::timeout;
+
+use trust_dns_client::{
+    op::{Query, ResponseCode},
+    proto::error::ProtoError,
+    rr,
+};
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 
-use crate::Resolver;
+use crate::{AddressFamily, Resolver};
+
+/// Returns the address of `name` given by the glue records `glue`, if any.
+pub fn glue_address(glue: &[rr::Record], name: &rr::Name) -> Option<IpAddr> {
+    glue.iter()
+        .filter(|record| record.name() == name)
+        .find_map(|record| match record.rdata() {
+            rr::RData::A(addr) => Some(IpAddr::V4(*addr)),
+            rr::RData::AAAA(addr) => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+}
 
 pub fn parse_comma_separated<T>(s: &str) -> Result<Vec<T>, T::Err>
 where
@@ -19,6 +42,377 @@ where
         .collect::<Result<_, _>>()
 }
 
+/// Parses a TTL given in seconds, or using BIND-style units.
+///
+/// A bare number is taken as seconds; otherwise, each number must be
+/// followed by one of the units `s`, `m`, `h`, `d` or `w` (case-insensitive),
+/// and the parts are added up, e.g. `1h30m`.
+pub fn parse_ttl(s: &str) -> Result<u32, ParseTtlError> {
+    if s.is_empty() {
+        return Err(ParseTtlError::Empty);
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return u32::try_from(secs).map_err(|_| ParseTtlError::Overflow(s.into()));
+    }
+    let mut total: u32 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(ParseTtlError::Invalid(s.into()));
+        }
+        let mut chars = rest[digits..].chars();
+        let unit = chars
+            .next()
+            .ok_or_else(|| ParseTtlError::Invalid(s.into()))?;
+        let factor = match unit.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(ParseTtlError::Unit(unit)),
+        };
+        let value = rest[..digits]
+            .parse::<u32>()
+            .ok()
+            .and_then(|n| n.checked_mul(factor))
+            .and_then(|n| total.checked_add(n))
+            .ok_or_else(|| ParseTtlError::Overflow(s.into()))?;
+        total = value;
+        rest = chars.as_str();
+    }
+    Ok(total)
+}
+
+#[derive(Debug)]
+pub enum ParseTtlError {
+    Empty,
+    Invalid(String),
+    Unit(char),
+    Overflow(String),
+}
+
+impl fmt::Display for ParseTtlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ParseTtlError::*;
+        match self {
+            Empty => write!(f, "empty TTL"),
+            Invalid(s) => write!(
+                f,
+                "invalid TTL '{}', expected seconds or a number with unit, like '1h30m'",
+                s
+            ),
+            Unit(c) => write!(
+                f,
+                "invalid TTL unit '{}', expected one of 's', 'm', 'h', 'd' or 'w'",
+                c
+            ),
+            Overflow(s) => write!(f, "TTL '{}' exceeds the maximum of {} seconds", s, u32::MAX),
+        }
+    }
+}
+
+impl std::error::Error for ParseTtlError {}
+
+/// Displays a duration using the units `d`, `h`, `m` and `s`, e.g. `5m30s`,
+/// for readability in messages.
+///
+/// Fractional seconds are shown as decimals, and durations below one second
+/// in milliseconds, e.g. `1.5s` and `250ms`.
+#[derive(Debug, Clone, Copy)]
+pub struct HumanDuration(pub Duration);
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let millis = self.0.subsec_millis();
+        if secs == 0 {
+            return write!(f, "{}ms", millis);
+        }
+        let units = [(86400, 'd'), (3600, 'h'), (60, 'm')];
+        let mut rest = secs;
+        for (unit_secs, unit) in units {
+            if rest >= unit_secs {
+                write!(f, "{}{}", rest / unit_secs, unit)?;
+                rest %= unit_secs;
+            }
+        }
+        match (rest, millis) {
+            (0, 0) => Ok(()),
+            (_, 0) => write!(f, "{}s", rest),
+            _ => {
+                let fraction = format!("{:03}", millis);
+                write!(f, "{}.{}s", rest, fraction.trim_end_matches('0'))
+            }
+        }
+    }
+}
+
+/// Bounds the time spent on a lookup.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// The time to wait for each attempt.
+    pub timeout: Duration,
+    /// The number of attempts; a value of zero is treated like one.
+    pub attempts: u32,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            timeout: Duration::from_secs(10),
+            attempts: 2,
+        }
+    }
+}
+
+impl Retry {
+    /// Runs the lookup produced by `lookup`, retrying it on failure.
+    ///
+    /// If all attempts fail, the error of the last attempt is returned.
+    pub async fn run<F, Fut, T, E>(&self, mut lookup: F) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut last_error = RetryError::Timeout(self.timeout);
+        for _ in 0..self.attempts.max(1) {
+            match timeout(self.timeout, lookup()).await {
+                Ok(Ok(result)) => return Ok(result),
+                Ok(Err(e)) => last_error = RetryError::Failed(e),
+                Err(_) => last_error = RetryError::Timeout(self.timeout),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[derive(Debug)]
+pub enum RetryError<E> {
+    Timeout(Duration),
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryError::Timeout(duration) => {
+                write!(f, "timed out after {}ms", duration.as_millis())
+            }
+            RetryError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for RetryError<E> {}
+
+/// Returns `name` as a fully qualified name.
+///
+/// Names given without a trailing dot are relative, and would be subject to
+/// the resolver's search list, which is rarely what is intended. Unless
+/// `strict` is true, such names are thus treated as if the trailing dot was
+/// given. If `strict` is true, an error is returned for relative names instead.
+pub fn make_fqdn(name: &rr::Name, strict: bool) -> Result<rr::Name, NotFullyQualified> {
+    if strict && !name.is_fqdn() {
+        return Err(NotFullyQualified(name.clone()));
+    }
+    let mut name = name.clone();
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+#[derive(Debug)]
+pub struct NotFullyQualified(rr::Name);
+
+impl fmt::Display for NotFullyQualified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "name {} is not fully qualified (missing trailing dot)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NotFullyQualified {}
+
+/// The dig-style `+option` arguments accepted by `tdns query`, along with the
+/// option each one stands for, if any; options that select the default
+/// behavior are accepted, but have no effect.
+const DIG_OPTIONS: &[(&str, Option<&str>)] = &[
+    ("tcp", Some("--tcp")),
+    ("notcp", Some("--udp")),
+    ("vc", Some("--tcp")),
+    ("novc", Some("--udp")),
+    ("short", Some("--short")),
+    ("noshort", None),
+    ("recurse", None),
+    ("norecurse", Some("--norecurse")),
+    ("dnssec", Some("--dnssec")),
+    ("nodnssec", None),
+    ("cd", Some("--cd")),
+    ("nocd", None),
+];
+
+/// Replaces the dig-style `+option` arguments of `tdns query` with the
+/// corresponding options, given the full command line `args`.
+///
+/// Arguments following `--` are left alone, as are the arguments of the other
+/// subcommands.
+pub fn translate_dig_options(args: Vec<OsString>) -> Result<Vec<OsString>, UnsupportedDigOption> {
+    match args.get(1) {
+        Some(subcommand) if subcommand == "query" => {}
+        _ => return Ok(args),
+    }
+    let mut translated = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    for arg in args.by_ref() {
+        if arg == "--" {
+            translated.push(arg);
+            break;
+        }
+        let option = match arg.to_str().and_then(|arg| arg.strip_prefix('+')) {
+            Some(option) => option,
+            None => {
+                translated.push(arg);
+                continue;
+            }
+        };
+        match DIG_OPTIONS.iter().find(|(name, _)| *name == option) {
+            Some((_, Some(replacement))) => translated.push(replacement.into()),
+            Some((_, None)) => {}
+            None => return Err(UnsupportedDigOption(arg.to_string_lossy().into_owned())),
+        }
+    }
+    translated.extend(args);
+    Ok(translated)
+}
+
+#[derive(Debug)]
+pub struct UnsupportedDigOption(String);
+
+impl fmt::Display for UnsupportedDigOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let supported: Vec<_> = DIG_OPTIONS
+            .iter()
+            .map(|(name, _)| format!("+{}", name))
+            .collect();
+        write!(
+            f,
+            "unsupported dig-style option '{}'; supported are: {}",
+            self.0,
+            supported.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedDigOption {}
+
+/// The location of the system resolver configuration.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Returns the address of the first nameserver in the system configuration.
+///
+/// On Unix, this is the first `nameserver` entry in `/etc/resolv.conf`.
+pub fn get_system_resolver() -> Result<SocketAddr, SystemResolverError> {
+    Ok(get_system_resolvers()?[0])
+}
+
+/// Returns the addresses of all nameservers in the system configuration, in
+/// order.
+///
+/// The result is never empty; a configuration without nameservers is reported
+/// as an error.
+pub fn get_system_resolvers() -> Result<Vec<SocketAddr>, SystemResolverError> {
+    read_resolv_conf(Path::new(RESOLV_CONF))
+}
+
+/// Reads the addresses of the nameservers listed in the `resolv.conf` file at
+/// `path`.
+///
+/// Each address is only returned once. As with `get_system_resolvers`, an
+/// empty result is reported as an error.
+pub fn read_resolv_conf(path: &Path) -> Result<Vec<SocketAddr>, SystemResolverError> {
+    let text = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => SystemResolverError::Missing(path.to_owned()),
+        _ => SystemResolverError::Io(path.to_owned(), e),
+    })?;
+    let mut addrs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split(['#', ';']).next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            continue;
+        }
+        let invalid = |message: &str| SystemResolverError::Parse {
+            path: path.to_owned(),
+            line: i + 1,
+            message: message.to_owned(),
+        };
+        let word = words.next().ok_or_else(|| invalid("missing address"))?;
+        // Link-local IPv6 addresses may carry a zone index, which
+        // `IpAddr` does not support.
+        let ip: IpAddr = word
+            .split('%')
+            .next()
+            .unwrap_or(word)
+            .parse()
+            .map_err(|e: net::AddrParseError| invalid(&e.to_string()))?;
+        let addr = SocketAddr::new(ip, 53);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(SystemResolverError::NoNameservers(path.to_owned()));
+    }
+    Ok(addrs)
+}
+
+/// The reason the system resolver could not be determined.
+#[derive(Debug)]
+pub enum SystemResolverError {
+    /// The configuration file does not exist.
+    Missing(PathBuf),
+    /// The configuration file could not be read.
+    Io(PathBuf, io::Error),
+    /// A `nameserver` entry is invalid.
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// The configuration does not list any nameservers.
+    NoNameservers(PathBuf),
+}
+
+impl fmt::Display for SystemResolverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SystemResolverError::*;
+        match self {
+            Missing(path) => write!(f, "{} does not exist", path.display()),
+            Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            Parse {
+                path,
+                line,
+                message,
+            } => write!(
+                f,
+                "{}:{}: invalid nameserver entry: {}",
+                path.display(),
+                line,
+                message
+            ),
+            NoNameservers(path) => write!(f, "no nameservers listed in {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SystemResolverError {}
+
 /// A potential unresolved host name, with an optional port number.
 #[derive(Debug, Clone)]
 pub enum SocketName {
@@ -27,29 +421,95 @@ pub enum SocketName {
     IpAddr(IpAddr),
 }
 
+/// Strategy for choosing one of the addresses a host name resolves to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum AddrSelect {
+    /// Use the first address.
+    #[default]
+    First,
+    /// Use an address chosen at random.
+    Random,
+    /// Use the address at the given, zero-based, index.
+    Index(usize),
+}
+
+impl FromStr for AddrSelect {
+    type Err = ParseAddrSelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(AddrSelect::First),
+            "random" => Ok(AddrSelect::Random),
+            _ => s
+                .parse()
+                .map(AddrSelect::Index)
+                .map_err(|_| ParseAddrSelectError(s.into())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseAddrSelectError(String);
+
+impl fmt::Display for ParseAddrSelectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid address selection '{}', expected 'first', 'random', or an index",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseAddrSelectError {}
+
 impl SocketName {
     pub async fn resolve(
         &self,
         resolver: impl Resolver,
         default_port: u16,
+    ) -> Result<SocketAddr, ResolveError> {
+        self.resolve_with(resolver, default_port, AddrSelect::First)
+            .await
+    }
+
+    /// Resolves the socket name, using `select` to choose among multiple
+    /// addresses of a host name.
+    pub async fn resolve_with(
+        &self,
+        resolver: impl Resolver,
+        default_port: u16,
+        select: AddrSelect,
     ) -> Result<SocketAddr, ResolveError> {
         match self {
             SocketName::HostName(name, port) => {
                 let port = port.unwrap_or(default_port);
-                let lookup = resolver.lookup_ip(name.clone()).await?;
-                // TODO: how to choose from multiple addresses
-                if let Some(ip) = lookup.iter().next() {
-                    Ok(SocketAddr::new(ip, port))
-                } else {
-                    Err(ResolveErrorKind::NoRecordsFound {
-                        query: lookup.query().clone(),
+                let addrs = resolver
+                    .lookup_addrs(name.clone(), AddressFamily::Any)
+                    .await?;
+                if addrs.is_empty() {
+                    return Err(ResolveErrorKind::NoRecordsFound {
+                        query: Query::query(name.clone(), rr::RecordType::A),
                         soa: None,
                         negative_ttl: None,
                         response_code: ResponseCode::NXDomain,
                         trusted: false,
                     }
-                    .into())
+                    .into());
                 }
+                let ip = match select {
+                    AddrSelect::First => addrs[0],
+                    AddrSelect::Random => *addrs.choose(&mut rand::thread_rng()).unwrap(),
+                    AddrSelect::Index(i) => *addrs.get(i).ok_or_else(|| {
+                        ResolveError::from(format!(
+                            "{} has only {} addresses, cannot select index {}",
+                            name,
+                            addrs.len(),
+                            i
+                        ))
+                    })?,
+                };
+                Ok(SocketAddr::new(ip, port))
             }
             SocketName::IpAddr(addr) => Ok(SocketAddr::new(*addr, default_port)),
             SocketName::SocketAddr(addr) => Ok(*addr),
@@ -57,6 +517,20 @@ impl SocketName {
     }
 }
 
+impl SocketName {
+    /// Returns the socket name, with a host name made fully qualified.
+    ///
+    /// See `make_fqdn` for the meaning of `strict`.
+    pub fn to_fqdn(&self, strict: bool) -> Result<SocketName, NotFullyQualified> {
+        match self {
+            SocketName::HostName(name, port) => {
+                Ok(SocketName::HostName(make_fqdn(name, strict)?, *port))
+            }
+            other => Ok(other.clone()),
+        }
+    }
+}
+
 impl FromStr for SocketName {
     type Err = ParseSocketNameError;
 
@@ -81,6 +555,17 @@ impl FromStr for SocketName {
     }
 }
 
+impl fmt::Display for SocketName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketName::HostName(name, None) => write!(f, "{}", name),
+            SocketName::HostName(name, Some(port)) => write!(f, "{}:{}", name, port),
+            SocketName::IpAddr(addr) => write!(f, "{}", addr),
+            SocketName::SocketAddr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
 impl fmt::Display for ParseSocketNameError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use ParseSocketNameError::*;
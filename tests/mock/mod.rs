@@ -1,8 +1,11 @@
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
+    future::Future,
     net::SocketAddr,
+    pin::Pin,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::anyhow;
@@ -12,7 +15,7 @@ use trust_dns_client::{
     op::update_message::UpdateMessage,
     proto::{
         error::ProtoError,
-        op::{Message, OpCode, Query},
+        op::{Message, OpCode, Query, ResponseCode},
         rr,
         xfer::{DnsRequest, DnsResponse},
         DnsHandle,
@@ -25,32 +28,121 @@ use trust_dns_resolver::{
     lookup_ip,
 };
 
-use tdns_cli::{Backend, Resolver, Runtime};
+use tdns_cli::{backend::RuntimeHandle, Backend, Resolver};
 
 pub type Handle<T> = Arc<Mutex<T>>;
-pub type FutureResult<T, E> = future::Ready<Result<T, E>>;
 
 #[derive(Debug, Clone)]
 pub struct Zone(Vec<rr::Record>);
 
 impl Zone {
+    /// Returns the records matching `query`.
+    pub fn lookup(&self, query: &Query) -> Vec<rr::Record> {
+        self.matches(query).collect()
+    }
     fn matches(&self, query: &Query) -> impl Iterator<Item = rr::Record> + '_ {
         let query = query.clone();
         self.0
             .iter()
-            .filter(move |r| r.name() == query.name())
+            .filter(move |r| {
+                r.name() == query.name()
+                    && (query.query_type() == rr::RecordType::ANY
+                        || r.record_type() == query.query_type())
+            })
             .cloned()
     }
+    /// Returns the `NS` records of `name`, or of its closest ancestor that has
+    /// any.
+    fn closest_ns(&self, name: &rr::Name) -> Vec<rr::Record> {
+        let mut name = name.clone();
+        loop {
+            let ns = Query::query(name.clone(), rr::RecordType::NS);
+            let delegation: Vec<_> = self.matches(&ns).collect();
+            if !delegation.is_empty() || name.is_root() {
+                return delegation;
+            }
+            name = name.base_name();
+        }
+    }
+    /// Returns the address records of the nameservers named by `ns_records`.
+    fn glue<'a>(&'a self, ns_records: &'a [rr::Record]) -> impl Iterator<Item = rr::Record> + 'a {
+        ns_records
+            .iter()
+            .filter_map(|record| record.rdata().as_ns())
+            .flat_map(move |ns| {
+                self.matches(&Query::query(ns.clone(), rr::RecordType::A))
+                    .chain(self.matches(&Query::query(ns.clone(), rr::RecordType::AAAA)))
+                    .collect::<Vec<_>>()
+            })
+    }
+    /// Checks value-dependent "RRset exists" and "RRset does not exist"
+    /// prerequisites, returning the response code of a failed check; other
+    /// kinds of prerequisites are ignored.
+    fn satisfies(&self, prerequisites: &[rr::Record]) -> Result<(), ResponseCode> {
+        let absent = prerequisites.iter().filter(|r| {
+            r.dns_class() == rr::DNSClass::NONE && r.record_type() != rr::RecordType::ANY
+        });
+        for req in absent {
+            let query = Query::query(req.name().clone(), req.record_type());
+            if self.matches(&query).next().is_some() {
+                return Err(ResponseCode::YXRRSet);
+            }
+        }
+        let required: Vec<_> = prerequisites
+            .iter()
+            .filter(|r| r.dns_class() != rr::DNSClass::NONE && r.dns_class() != rr::DNSClass::ANY)
+            .collect();
+        let exists = required.iter().all(|req| {
+            let query = Query::query(req.name().clone(), req.record_type());
+            let current: Vec<_> = self.matches(&query).collect();
+            current.len()
+                == required
+                    .iter()
+                    .filter(|r| r.name() == req.name() && r.record_type() == req.record_type())
+                    .count()
+                && current.iter().any(|r| r.rdata() == req.rdata())
+        });
+        if exists {
+            Ok(())
+        } else {
+            Err(ResponseCode::NXRRSet)
+        }
+    }
+    /// Applies an update record, following RFC 2136, section 3.4.2.
     pub fn update(&mut self, update: &rr::Record) {
-        if update.dns_class() == rr::DNSClass::NONE {
-            self.0
-                .retain(|r| r.record_type() != update.record_type() && r.name() != update.name());
-        } else if let Some(record) = self
-            .0
-            .iter_mut()
-            .find(|r| r.record_type() == update.record_type() && r.name() == update.name())
-        {
-            record.set_rdata(update.rdata().clone());
+        let same_rrset =
+            |r: &rr::Record| r.name() == update.name() && r.record_type() == update.record_type();
+        match update.dns_class() {
+            // Delete all RRsets of a name, except for the SOA and NS
+            // records at the apex of the zone, i.e., at the name of the SOA
+            // record
+            rr::DNSClass::ANY if update.record_type() == rr::RecordType::ANY => {
+                let apex = self
+                    .0
+                    .iter()
+                    .any(|r| r.name() == update.name() && r.record_type() == rr::RecordType::SOA);
+                self.0.retain(|r| {
+                    r.name() != update.name()
+                        || (apex
+                            && matches!(r.record_type(), rr::RecordType::SOA | rr::RecordType::NS))
+                })
+            }
+            // Delete an RRset
+            rr::DNSClass::ANY => self.0.retain(|r| !same_rrset(r)),
+            // Delete a record from an RRset
+            rr::DNSClass::NONE => self
+                .0
+                .retain(|r| !(same_rrset(r) && r.rdata() == update.rdata())),
+            // Add a record to an RRset, unless it is already present
+            _ => {
+                if !self
+                    .0
+                    .iter()
+                    .any(|r| same_rrset(r) && r.rdata() == update.rdata())
+                {
+                    self.0.push(update.clone());
+                }
+            }
         }
     }
 }
@@ -61,6 +153,8 @@ pub fn parse_rdata(rtype: &str, rdata: &str) -> anyhow::Result<rr::RData> {
         "A" => Ok(RData::A(rdata.parse()?)),
         "AAAA" => Ok(RData::AAAA(rdata.parse()?)),
         "NS" => Ok(RData::NS(rdata.parse()?)),
+        "ANAME" => Ok(RData::ANAME(rdata.parse()?)),
+        "TXT" => Ok(RData::TXT(rr::rdata::TXT::new(vec![rdata.to_owned()]))),
         "SOA" => {
             let parts: Vec<_> = rdata.split(' ').collect();
             // This quite ugly -- is there a better way?
@@ -103,6 +197,7 @@ impl<'a> TryFrom<ZoneEntries<'a>> for Zone {
 pub struct MockBackend {
     resolv_conf: Option<SocketAddr>,
     servers: HashMap<SocketAddr, Handle<Server>>,
+    in_flight: Handle<InFlight>,
 }
 
 impl MockBackend {
@@ -110,20 +205,36 @@ impl MockBackend {
     where
         T: TryInto<Zone>,
     {
-        let server = Arc::new(Mutex::new(Server {
-            zone: Arc::new(Mutex::new(zone.try_into()?)),
-            query_log: Default::default(),
-        }));
+        let server = self.new_server(Arc::new(Mutex::new(zone.try_into()?)));
         self.servers.insert(addr, server.clone());
         Ok(server)
     }
 
     pub fn add_shared(&mut self, addr: SocketAddr, zone: Handle<Zone>) {
-        let server = Arc::new(Mutex::new(Server {
+        let server = self.new_server(zone);
+        self.servers.insert(addr, server);
+    }
+
+    /// The highest number of queries that were in flight at the same time,
+    /// across all servers; only delayed queries are counted.
+    pub fn max_in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().max
+    }
+
+    fn new_server(&self, zone: Handle<Zone>) -> Handle<Server> {
+        Arc::new(Mutex::new(Server {
             zone,
             query_log: Default::default(),
-        }));
-        self.servers.insert(addr, server);
+            ignore_updates: false,
+            update_response_code: None,
+            concurrent_change: None,
+            connections: 0,
+            refused_connections: 0,
+            failing_queries: 0,
+            query_delay: None,
+            referrals: false,
+            in_flight: Arc::clone(&self.in_flight),
+        }))
     }
 
     fn open_client(&self, addr: SocketAddr) -> Client {
@@ -141,10 +252,18 @@ impl Backend for MockBackend {
     type Resolver = Client;
     async fn open(
         &mut self,
-        _runtime: &Runtime,
+        _runtime: &RuntimeHandle,
         addr: SocketAddr,
     ) -> Result<Self::Client, ProtoError> {
-        Ok(self.open_client(addr))
+        let client = self.open_client(addr);
+        let mut server = client.0.lock().unwrap();
+        if server.refused_connections > 0 {
+            server.refused_connections -= 1;
+            return Err(ProtoError::from("connection refused"));
+        }
+        server.connections += 1;
+        drop(server);
+        Ok(client)
     }
     fn open_resolver(&mut self, addr: SocketAddr) -> Result<Self::Resolver, ResolveError> {
         Ok(self.open_client(addr))
@@ -170,23 +289,95 @@ impl Client {
     }
     fn lookup_base(&self, name: rr::Name, rtype: rr::RecordType) -> Result<Lookup, ResolveError> {
         let query = Query::query(name, rtype);
-        self.query(query.clone())
-            .map(|response| {
-                Lookup::new_with_max_ttl(query, response.answers().iter().cloned().collect())
-            })
-            .map_err(Into::into)
+        let response = self.query(query.clone())?;
+        if response.answers().is_empty() {
+            // Mimic the behavior of the real resolver, which treats an empty
+            // answer as an error.
+            return Err(ResolveErrorKind::NoRecordsFound {
+                query: query.clone(),
+                soa: None,
+                negative_ttl: None,
+                response_code: ResponseCode::NoError,
+                trusted: true,
+            }
+            .into());
+        }
+        Ok(Lookup::new_with_max_ttl(
+            query,
+            response.answers().iter().cloned().collect(),
+        ))
     }
 }
 
 pub struct Server {
     zone: Handle<Zone>,
     query_log: Vec<DnsRequest>,
+    ignore_updates: bool,
+    update_response_code: Option<ResponseCode>,
+    concurrent_change: Option<rr::Record>,
+    connections: usize,
+    refused_connections: usize,
+    failing_queries: usize,
+    query_delay: Option<Duration>,
+    referrals: bool,
+    in_flight: Handle<InFlight>,
+}
+
+/// Counts the queries currently in flight, and the highest number reached.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    current: usize,
+    max: usize,
 }
 
 impl Server {
     pub fn zone(&self) -> Handle<Zone> {
         Arc::clone(&self.zone)
     }
+    pub fn query_log(&self) -> &[DnsRequest] {
+        &self.query_log
+    }
+    /// The number of clients opened via `Backend::open`, corresponding to
+    /// connections for TCP.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+    /// Makes the next `n` attempts to open a client fail, as if the server
+    /// was unreachable.
+    pub fn refuse_connections(&mut self, n: usize) {
+        self.refused_connections = n;
+    }
+    /// Makes the next `n` queries sent by clients fail, as if the connection
+    /// was closed.
+    pub fn fail_queries(&mut self, n: usize) {
+        self.failing_queries = n;
+    }
+    /// Makes clients receive the answers to their queries only after
+    /// `delay`, so that queries to different servers overlap.
+    pub fn set_query_delay(&mut self, delay: Duration) {
+        self.query_delay = Some(delay);
+    }
+    /// Makes the server answer updates successfully, without applying them.
+    /// Makes the server act as the nameserver of a parent zone, which answers
+    /// queries at or below a delegated name with a referral: the `NS` records
+    /// in the authority section, and their addresses as glue in the
+    /// additional section.
+    pub fn set_referrals(&mut self, referrals: bool) {
+        self.referrals = referrals;
+    }
+
+    pub fn set_ignore_updates(&mut self, ignore: bool) {
+        self.ignore_updates = ignore;
+    }
+    /// Makes the server reject updates with the given response code.
+    pub fn set_update_response_code(&mut self, code: Option<ResponseCode>) {
+        self.update_response_code = code;
+    }
+    /// Adds `record` to the zone right before processing the next update,
+    /// as if it was added concurrently by another client.
+    pub fn add_before_update(&mut self, record: rr::Record) {
+        self.concurrent_change = Some(record);
+    }
     fn request(&mut self, request: DnsRequest) -> Result<DnsResponse, ProtoError> {
         self.query_log.push(request.clone());
         match request.op_code() {
@@ -194,16 +385,56 @@ impl Server {
                 let mut message = Message::new();
                 let zone = self.zone.lock().unwrap();
                 for query in request.queries() {
-                    for record in zone.matches(query) {
-                        message.add_answer(record);
+                    let delegation = zone.closest_ns(query.name());
+                    if self.referrals && !delegation.is_empty() {
+                        let glue: Vec<_> = zone.glue(&delegation).collect();
+                        message.add_name_servers(delegation);
+                        for record in glue {
+                            message.add_additional(record);
+                        }
+                        continue;
+                    }
+                    let answers: Vec<_> = zone.matches(query).collect();
+                    if answers.is_empty() {
+                        // Refer to the closest delegation, as the nameserver
+                        // of a parent zone would.
+                        let glue: Vec<_> = zone.glue(&delegation).collect();
+                        message.add_name_servers(delegation);
+                        for record in glue {
+                            message.add_additional(record);
+                        }
+                    } else {
+                        // Additional section processing, adding the addresses
+                        // of the nameservers named in the answer, as done by
+                        // authoritative servers for their own `NS` records.
+                        let additional: Vec<_> = zone.glue(&answers).collect();
+                        message.add_answers(answers);
+                        for record in additional {
+                            message.add_additional(record);
+                        }
                     }
                 }
                 Ok(message.into())
             }
             OpCode::Update => {
+                if let Some(code) = self.update_response_code {
+                    let mut response = Message::new();
+                    response.set_response_code(code);
+                    return Ok(response.into());
+                }
                 let mut zone = self.zone.lock().unwrap();
-                for update in request.updates() {
-                    zone.update(update);
+                if let Some(record) = self.concurrent_change.take() {
+                    zone.update(&record);
+                }
+                if let Err(code) = zone.satisfies(request.prerequisites()) {
+                    let mut response = Message::new();
+                    response.set_response_code(code);
+                    return Ok(response.into());
+                }
+                if !self.ignore_updates {
+                    for update in request.updates() {
+                        zone.update(update);
+                    }
                 }
                 Ok(Message::new().into())
             }
@@ -214,11 +445,31 @@ impl Server {
 
 impl DnsHandle for Client {
     type Error = ProtoError;
-    type Response = FutureResult<DnsResponse, ProtoError>;
+    type Response = Pin<Box<dyn Future<Output = Result<DnsResponse, ProtoError>> + Send>>;
 
     fn send<R: Into<DnsRequest>>(&mut self, request: R) -> Self::Response {
         let mut server = self.0.lock().unwrap();
-        future::ready(server.request(request.into()))
+        let request = request.into();
+        if request.op_code() == OpCode::Query && server.failing_queries > 0 {
+            server.failing_queries -= 1;
+            return Box::pin(future::ready(Err(ProtoError::from("connection closed"))));
+        }
+        let response = server.request(request);
+        let delay = match server.query_delay {
+            Some(delay) => delay,
+            None => return Box::pin(future::ready(response)),
+        };
+        let in_flight = Arc::clone(&server.in_flight);
+        {
+            let mut in_flight = in_flight.lock().unwrap();
+            in_flight.current += 1;
+            in_flight.max = in_flight.max.max(in_flight.current);
+        }
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            in_flight.lock().unwrap().current -= 1;
+            response
+        })
     }
 }
 
@@ -232,8 +483,16 @@ impl Resolver for Client {
         self.lookup_base(name, rtype)
     }
     async fn lookup_ip(&self, host: rr::Name) -> Result<lookup_ip::LookupIp, ResolveError> {
-        // TODO: IPv6
-        self.lookup_base(host, rr::RecordType::A).map(Into::into)
+        let v4 = self.lookup_base(host.clone(), rr::RecordType::A);
+        let v6 = self.lookup_base(host, rr::RecordType::AAAA);
+        match (v4, v6) {
+            (Ok(v4), Ok(v6)) => {
+                let records: Vec<_> = v4.record_iter().chain(v6.record_iter()).cloned().collect();
+                Ok(Lookup::new_with_max_ttl(v4.query().clone(), records.into()).into())
+            }
+            (Ok(lookup), Err(_)) | (Err(_), Ok(lookup)) => Ok(lookup.into()),
+            (Err(e), Err(_)) => Err(e),
+        }
     }
     async fn lookup_soa(&self, name: rr::Name) -> Result<lookup::SoaLookup, ResolveError> {
         self.lookup_base(name, rr::RecordType::SOA).map(Into::into)
@@ -242,3 +501,79 @@ impl Resolver for Client {
         self.lookup_base(name, rr::RecordType::NS).map(Into::into)
     }
 }
+
+/// A resolver that never answers queries for one record type, passing all
+/// others on to a `Client`.
+#[derive(Clone)]
+pub struct StallingResolver {
+    pub client: Client,
+    pub stalled: rr::RecordType,
+}
+
+#[async_trait]
+impl Resolver for StallingResolver {
+    async fn lookup(
+        &self,
+        name: rr::Name,
+        rtype: rr::RecordType,
+    ) -> Result<lookup::Lookup, ResolveError> {
+        if rtype == self.stalled {
+            future::pending().await
+        } else {
+            self.client.lookup(name, rtype).await
+        }
+    }
+    async fn lookup_ip(&self, host: rr::Name) -> Result<lookup_ip::LookupIp, ResolveError> {
+        self.client.lookup_ip(host).await
+    }
+    async fn lookup_soa(&self, name: rr::Name) -> Result<lookup::SoaLookup, ResolveError> {
+        self.client.lookup_soa(name).await
+    }
+    async fn lookup_ns(&self, name: rr::Name) -> Result<lookup::NsLookup, ResolveError> {
+        self.client.lookup_ns(name).await
+    }
+}
+
+/// A resolver that fails the first `servfails` lookups with `SERVFAIL`,
+/// passing the others on to a `Client`, and counts the lookups.
+#[derive(Clone)]
+pub struct ServfailResolver {
+    pub client: Client,
+    pub servfails: Handle<u32>,
+    pub lookups: Handle<u32>,
+}
+
+#[async_trait]
+impl Resolver for ServfailResolver {
+    async fn lookup(
+        &self,
+        name: rr::Name,
+        rtype: rr::RecordType,
+    ) -> Result<lookup::Lookup, ResolveError> {
+        *self.lookups.lock().unwrap() += 1;
+        {
+            let mut servfails = self.servfails.lock().unwrap();
+            if *servfails > 0 {
+                *servfails -= 1;
+                return Err(ResolveErrorKind::NoRecordsFound {
+                    query: Query::query(name, rtype),
+                    soa: None,
+                    negative_ttl: None,
+                    response_code: ResponseCode::ServFail,
+                    trusted: false,
+                }
+                .into());
+            }
+        }
+        self.client.lookup(name, rtype).await
+    }
+    async fn lookup_ip(&self, host: rr::Name) -> Result<lookup_ip::LookupIp, ResolveError> {
+        self.client.lookup_ip(host).await
+    }
+    async fn lookup_soa(&self, name: rr::Name) -> Result<lookup::SoaLookup, ResolveError> {
+        self.client.lookup_soa(name).await
+    }
+    async fn lookup_ns(&self, name: rr::Name) -> Result<lookup::NsLookup, ResolveError> {
+        self.client.lookup_ns(name).await
+    }
+}
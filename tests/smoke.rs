@@ -1,16 +1,37 @@
-use std::{pin::Pin, time::Duration};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use futures::{prelude::*, stream::FuturesUnordered};
 use tdns_cli::{
-    record::RecordSet,
-    update::{monitor_update, perform_update, Expectation, Monitor, Operation, Update},
-    Backend,
+    backend, batch,
+    config::{Config, Transport},
+    query::{self, perform_query, perform_raw_query, DisplayFormat, Query, QueryFailures},
+    record::{RecordSet, RsData},
+    serial::{poll_serials, serial_lag, SerialWatch},
+    update::{
+        authoritative_servers, find_zone, monitor_update, monitor_update_with_progress,
+        perform_update, resolve_master, ApexNsDeletion, Changes, ConcurrentChange,
+        CurrentValueMismatch, Expectation, Monitor, MonitorEvent, MonitorMetrics, MonitorTimeout,
+        NameserverWarning, Operation, PollEvent, TriesExhausted, Update, UpdateNotApplied,
+        UpdateOutcome, UpdateRejected,
+    },
+    util, AddressFamily, Backend, ConnectorBackend, FixedIdBackend, Resolver, ResolverSettings,
+    ServfailRetry, SplitBackend, UdpBackend,
 };
+#[cfg(feature = "tsig")]
+use tdns_cli::{tsig, update_message};
 use tokio::{runtime::Runtime, time::sleep};
-use trust_dns_client::rr;
+use trust_dns_client::{op, rr};
+use trust_dns_resolver::error::ResolveErrorKind;
 
 mod mock;
-use mock::{parse_rdata, MockBackend, ZoneEntries};
+use mock::{parse_rdata, MockBackend, ServfailResolver, StallingResolver, ZoneEntries};
 
 const TIMEOUT: Duration = Duration::from_millis(10);
 
@@ -28,9 +49,27 @@ fn monitor_settings(expected: &str) -> Monitor {
             Expectation::Is(rset)
         },
         exclude: Default::default(),
+        dns_class: rr::DNSClass::IN,
         interval: TIMEOUT / 100,
+        interval_max: None,
+        backoff_factor: 2.0,
         timeout: TIMEOUT,
+        tries: None,
         verbose: true,
+        ns_refresh: None,
+        poll_concurrency: None,
+        lookup_retry: Default::default(),
+        source: None,
+    }
+}
+
+/// Adapts a callback for poll events to a monitoring progress callback,
+/// ignoring warnings.
+fn poll_events(f: impl Fn(&PollEvent)) -> impl Fn(&MonitorEvent) {
+    move |event| {
+        if let MonitorEvent::Poll(event) = event {
+            f(event)
+        }
     }
 }
 
@@ -39,8 +78,18 @@ fn update_settings(operation: Operation) -> Update {
         zone: "example.org".parse().unwrap(),
         server: None,
         operation,
+        #[cfg(feature = "tsig")]
         tsig_key: None,
         ttl: 300,
+        dns_class: rr::DNSClass::IN,
+        match_ttl: false,
+        skip_if_present: false,
+        lookup_retry: Default::default(),
+        master_addr_select: Default::default(),
+        require_current: None,
+        verify: false,
+        force: false,
+        message_id: None,
     }
 }
 
@@ -103,97 +152,3574 @@ fn test_monitor_match() {
     let runtime = Runtime::new().unwrap();
     let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
     let resolver = runtime
-        .block_on(dns.open(&runtime, "127.0.0.1:53".parse().unwrap()))
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
         .expect("failed to open resolver");
     let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
     runtime.block_on(monitor).unwrap();
 }
 
 #[test]
-fn test_monitor_mismatch() {
+fn test_monitor_match_with_ttl() {
     let runtime = Runtime::new().unwrap();
-    let mut dns = mock_dns_fixed(
-        &[("foo.example.org", "A", "192.168.1.1")],
-        &[("foo.example.org", "A", "192.168.1.1")],
-        &[("foo.example.org", "A", "192.168.1.2")],
-    );
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
     let resolver = runtime
-        .block_on(dns.open(&runtime, "127.0.0.1:53".parse().unwrap()))
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
         .expect("failed to open resolver");
-    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
-    let result = runtime.block_on(monitor);
-    assert!(result.is_err()); // TODO: check for timeout error, specifically
+    // The TTL of the desired records does not take part in matching.
+    let rset = RecordSet::new(
+        "foo.example.org".parse().unwrap(),
+        "A:192.168.1.1".parse().unwrap(),
+    )
+    .with_ttl(60);
+    let settings = Monitor {
+        expectation: Expectation::Is(rset),
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let monitor = monitor_update(&runtime, dns, resolver, settings);
+    runtime.block_on(monitor).unwrap();
 }
 
 #[test]
-fn test_create_immediate() {
+fn test_monitor_unresolvable_nameserver() {
     let runtime = Runtime::new().unwrap();
     let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "a.iana-servers.net."),
+        ("example.org", "NS", "b.iana-servers.net."),
+        ("example.org", "NS", "c.iana-servers.net."),
+        ("a.iana-servers.net", "A", "199.43.135.53"),
+        ("b.iana-servers.net", "A", "199.43.133.53"),
+    ];
+    let rec_addr = "127.0.0.2:53".parse().unwrap();
+    dns.add_server(rec_addr, rec_data).unwrap();
     let resolver = runtime
-        .block_on(dns.open(&runtime, "127.0.0.1:53".parse().unwrap()))
+        .block_on(dns.open(runtime.handle(), rec_addr))
         .expect("failed to open resolver");
-    let update = perform_update(
+    let warnings = RefCell::new(Vec::new());
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        monitor_settings("A:192.168.1.1"),
+        |event| {
+            if let MonitorEvent::Warning(warning) = event {
+                warnings.borrow_mut().push(warning.clone());
+            }
+        },
+    );
+    let convergence = runtime.block_on(monitor).unwrap();
+    assert_eq!(convergence.len(), 2);
+    let warnings = warnings.into_inner();
+    assert_eq!(warnings.len(), 1);
+    match &warnings[0] {
+        NameserverWarning::Unresolvable { server_name, .. } => {
+            assert_eq!(server_name.to_string(), "c.iana-servers.net.")
+        }
+        warning => panic!("unexpected warning: {}", warning),
+    }
+}
+
+#[test]
+fn test_monitor_poll_concurrency() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        poll_concurrency: Some(1),
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let monitor = monitor_update(&runtime, dns, resolver, settings);
+    runtime.block_on(monitor).unwrap();
+}
+
+#[test]
+fn test_monitor_poll_concurrency_lagging() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    // The first nameserver lags behind and never provides the record.
+    let auth1 = dns
+        .add_server("199.43.135.53:53".parse().unwrap(), &[][..])
+        .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    for server in &[auth1, auth2] {
+        server.lock().unwrap().set_query_delay(TIMEOUT / 10);
+    }
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        poll_concurrency: Some(1),
+        timeout: TIMEOUT * 5,
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let events = RefCell::new(Vec::new());
+    let monitor = monitor_update_with_progress(
         &runtime,
         dns.clone(),
-        resolver.clone(),
-        update_settings(Operation::Create(RecordSet::new(
-            "foo.example.org".parse().unwrap(),
-            "A:192.168.1.2".parse().unwrap(),
-        ))),
+        resolver,
+        settings,
+        poll_events(|event| events.borrow_mut().push(event.clone())),
     );
-    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.2"));
-    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+    assert!(runtime.block_on(monitor).is_err());
+    // The up-to-date nameserver is polled while the lagging one waits for its
+    // next poll.
+    let events = events.into_inner();
+    assert!(events
+        .iter()
+        .any(|event| event.matched && event.server_name.to_string() == "b.iana-servers.net."));
+    assert!(events.iter().filter(|event| !event.matched).count() > 1);
+    assert_eq!(dns.max_in_flight(), 1);
 }
 
 #[test]
-fn test_create_delayed() {
+fn test_monitor_progress() {
     let runtime = Runtime::new().unwrap();
-    let (mut dns, zone) = mock_dns_independent(&[("foo.example.org", "A", "192.168.1.1")]);
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
     let resolver = runtime
-        .block_on(dns.open(&runtime, "127.0.0.1:53".parse().unwrap()))
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
         .expect("failed to open resolver");
-    let update = perform_update(
+    let events = RefCell::new(Vec::new());
+    let monitor = monitor_update_with_progress(
         &runtime,
         dns,
         resolver,
-        update_settings(Operation::create(
-            "foo.example.org".parse().unwrap(),
-            "A:192.168.1.2".parse().unwrap(),
-        )),
+        monitor_settings("A:192.168.1.1"),
+        poll_events(|event| events.borrow_mut().push(event.clone())),
     );
-    async fn update_auth(zone: mock::Handle<mock::Zone>) -> anyhow::Result<()> {
-        sleep(TIMEOUT / 2).await;
-        let updated = rr::Record::from_rdata(
-            "foo.example.org".parse().unwrap(),
-            0,
-            parse_rdata("A", "192.168.1.2").unwrap(),
-        );
-        let mut zone = zone.lock().unwrap();
-        zone.update(&updated);
-        Ok(())
-    }
-    let parallel = FuturesUnordered::new();
-    parallel.push(Box::pin(update) as Pin<Box<dyn Future<Output = anyhow::Result<()>>>>);
-    parallel.push(Box::pin(update_auth(zone)));
-    runtime.block_on(parallel.try_collect::<Vec<_>>()).unwrap();
+    runtime.block_on(monitor).unwrap();
+    let events = events.into_inner();
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|event| event.matched));
 }
 
 #[test]
-fn test_delete() {
+fn test_monitor_metrics() {
     let runtime = Runtime::new().unwrap();
     let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
     let resolver = runtime
-        .block_on(dns.open(&runtime, "127.0.0.1:53".parse().unwrap()))
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = monitor_settings("A:192.168.1.1");
+    let (zone, entry) = (settings.zone.clone(), settings.entry.clone());
+    let metrics = RefCell::new(MonitorMetrics::default());
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| metrics.borrow_mut().record(event)),
+    );
+    runtime.block_on(monitor).unwrap();
+    let mut output = Vec::new();
+    metrics
+        .borrow()
+        .write_prometheus(&mut output, &zone, &entry)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output
+        .contains("tdns_monitor_polls_total{zone=\"example.org\",entry=\"foo.example.org\"} 2\n"));
+    assert!(output.contains(
+        "tdns_monitor_server_converged{zone=\"example.org\",entry=\"foo.example.org\",\
+         server=\"a.iana-servers.net.\",address=\"199.43.135.53\"} 1\n"
+    ));
+}
+
+#[test]
+fn test_monitor_metrics_unreachable() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    dns.add_server(
+        "199.43.135.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    auth2.lock().unwrap().fail_queries(usize::MAX);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = monitor_settings("A:192.168.1.1");
+    let (zone, entry) = (settings.zone.clone(), settings.entry.clone());
+    let metrics = RefCell::new(MonitorMetrics::default());
+    let seeded = RefCell::new(false);
+    let monitor =
+        monitor_update_with_progress(&runtime, dns, resolver, settings, |event| match event {
+            MonitorEvent::Nameservers(servers) if !*seeded.borrow() => {
+                let mut metrics = metrics.borrow_mut();
+                metrics.seed(servers);
+                let json = metrics.to_json(&zone, &entry);
+                assert_eq!(json["servers"].as_array().unwrap().len(), 2);
+                for server in json["servers"].as_array().unwrap() {
+                    assert_eq!(server["polls"], 0);
+                    assert_eq!(server["converged"], false);
+                }
+                *seeded.borrow_mut() = true;
+            }
+            MonitorEvent::Poll(event) => {
+                assert!(*seeded.borrow(), "polled before reporting the nameservers");
+                metrics.borrow_mut().record(event);
+            }
+            _ => {}
+        });
+    runtime.block_on(monitor).unwrap_err();
+    let mut output = Vec::new();
+    metrics
+        .borrow()
+        .write_prometheus(&mut output, &zone, &entry)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let labels = "zone=\"example.org\",entry=\"foo.example.org\",\
+                  server=\"b.iana-servers.net.\",address=\"199.43.133.53\"";
+    assert!(output.contains(&format!("tdns_monitor_server_converged{{{}}} 0\n", labels)));
+    // The failed polls of the unreachable nameserver are counted.
+    let json = metrics.borrow().to_json(&zone, &entry);
+    assert!(json["servers"][1]["polls"].as_u64().unwrap() > 0);
+    assert_eq!(json["servers"][1]["converged"], false);
+}
+
+#[test]
+fn test_monitor_convergence() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    dns.add_server(
+        "199.43.135.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.2")][..],
+        )
+        .unwrap();
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        timeout: TIMEOUT * 100,
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let (zone, entry) = (settings.zone.clone(), settings.entry.clone());
+    let metrics = RefCell::new(MonitorMetrics::default());
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| {
+            metrics.borrow_mut().record(event);
+            // The second server catches up after its third poll.
+            if event.poll == 3 && !event.matched {
+                let zone = auth2.lock().unwrap().zone();
+                let mut zone = zone.lock().unwrap();
+                for (class, rdata) in &[
+                    (rr::DNSClass::ANY, "192.168.1.2"),
+                    (rr::DNSClass::IN, "192.168.1.1"),
+                ] {
+                    let mut record = rr::Record::from_rdata(
+                        "foo.example.org".parse().unwrap(),
+                        0,
+                        parse_rdata("A", rdata).unwrap(),
+                    );
+                    record.set_dns_class(*class);
+                    zone.update(&record);
+                }
+            }
+        }),
+    );
+    let convergence = runtime.block_on(monitor).unwrap();
+    let polls: Vec<_> = convergence
+        .iter()
+        .map(|c| (c.server_name.to_string(), c.polls))
+        .collect();
+    assert_eq!(
+        polls,
+        vec![
+            ("a.iana-servers.net.".to_string(), 1),
+            ("b.iana-servers.net.".to_string(), 4)
+        ]
+    );
+    let json = metrics.borrow().to_json(&zone, &entry);
+    assert_eq!(json["polls_total"], 5);
+    assert_eq!(json["servers"][1]["server"], "b.iana-servers.net.");
+    assert_eq!(json["servers"][1]["polls"], 4);
+    assert_eq!(json["servers"][1]["converged"], true);
+}
+
+fn assert_send<T: Send>(_: &T) {}
+
+#[test]
+fn test_futures_send() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
         .expect("failed to open resolver");
     let update = perform_update(
         &runtime,
         dns.clone(),
         resolver.clone(),
-        update_settings(Operation::delete(
+        update_settings(Operation::create(
             "foo.example.org".parse().unwrap(),
-            "A:192.168.1.1".parse().unwrap(),
+            "A:192.168.1.2".parse().unwrap(),
         )),
     );
-    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A"));
-    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+    assert_send(&update);
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    assert_send(&monitor);
+}
+
+#[test]
+fn test_monitor_mismatch() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = mock_dns_fixed(
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.2")],
+    );
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    let result = runtime.block_on(monitor);
+    let e = result.unwrap_err();
+    assert!(e.downcast_ref::<MonitorTimeout>().is_some(), "{}", e);
+}
+
+#[test]
+fn test_monitor_timeout_stops_polling() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    let auths: Vec<_> = ["199.43.135.53:53", "199.43.133.53:53"]
+        .iter()
+        .map(|addr| {
+            dns.add_server(
+                addr.parse().unwrap(),
+                &[("foo.example.org", "A", "192.168.1.2")][..],
+            )
+            .unwrap()
+        })
+        .collect();
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    let e = runtime.block_on(monitor).unwrap_err();
+    assert!(e.downcast_ref::<MonitorTimeout>().is_some(), "{}", e);
+    // The tasks polling the nameservers are aborted on timeout.
+    let n_queries = || -> Vec<_> {
+        auths
+            .iter()
+            .map(|auth| auth.lock().unwrap().query_log().len())
+            .collect()
+    };
+    let before = n_queries();
+    assert!(before.iter().all(|&n| n > 1));
+    runtime.block_on(async { sleep(TIMEOUT).await });
+    assert_eq!(n_queries(), before);
+}
+
+#[test]
+fn test_monitor_tries() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = mock_dns_fixed(
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.2")],
+    );
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        timeout: TIMEOUT * 100,
+        tries: Some(3),
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let polls = RefCell::new(0);
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| {
+            if !event.matched {
+                *polls.borrow_mut() += 1;
+            }
+        }),
+    );
+    let e = runtime.block_on(monitor).unwrap_err();
+    let exhausted = e
+        .downcast_ref::<TriesExhausted>()
+        .expect("unexpected error");
+    assert_eq!(
+        exhausted.servers,
+        vec!["b.iana-servers.net.".parse::<rr::Name>().unwrap()]
+    );
+    assert_eq!(polls.into_inner(), 3);
+    assert_eq!(
+        e.to_string(),
+        "update not seen after 3 tries on b.iana-servers.net."
+    );
+}
+
+#[test]
+fn test_monitor_reconnect() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    dns.add_server(
+        "199.43.135.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    // The first poll fails, as do the next two attempts to reconnect.
+    auth2.lock().unwrap().fail_queries(1);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        timeout: TIMEOUT * 100,
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let errors = RefCell::new(Vec::new());
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| {
+            if let Some(error) = &event.error {
+                if errors.borrow().is_empty() {
+                    auth2.lock().unwrap().refuse_connections(2);
+                }
+                errors.borrow_mut().push(error.clone());
+            }
+        }),
+    );
+    let convergence = runtime.block_on(monitor).unwrap();
+    assert_eq!(convergence[1].polls, 4);
+    let errors = errors.into_inner();
+    assert_eq!(errors.len(), 3);
+    assert!(errors[1..]
+        .iter()
+        .all(|error| error.starts_with("could not connect")));
+    assert_eq!(auth2.lock().unwrap().connections(), 2);
+}
+
+#[test]
+fn test_monitor_tries_unreachable() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    dns.add_server(
+        "199.43.135.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    auth2.lock().unwrap().fail_queries(usize::MAX);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        timeout: TIMEOUT * 100,
+        tries: Some(3),
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let failures = RefCell::new(Vec::new());
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| {
+            if event.error.is_some() {
+                assert!(!event.matched);
+                failures.borrow_mut().push(event.poll);
+            }
+        }),
+    );
+    let e = runtime.block_on(monitor).unwrap_err();
+    let exhausted = e
+        .downcast_ref::<TriesExhausted>()
+        .expect("unexpected error");
+    assert_eq!(
+        exhausted.servers,
+        vec!["b.iana-servers.net.".parse::<rr::Name>().unwrap()]
+    );
+    assert_eq!(failures.into_inner(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_poll_serials() {
+    let runtime = Runtime::new().unwrap();
+    let soa = |serial| {
+        format!(
+            "sns.dns.icann.org. noc.dns.icann.org. {} 7200 3600 1209600 3600",
+            serial
+        )
+    };
+    let (current, stale) = (soa(2019090512), soa(2019090510));
+    let mut dns = mock_dns_fixed(
+        &[("example.org", "SOA", &current)],
+        &[("example.org", "SOA", &current)],
+        &[("example.org", "SOA", &stale)],
+    );
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let options = SerialWatch {
+        zone: "example.org.".parse().unwrap(),
+        primary: None,
+        exclude: vec![],
+        lookup_retry: Default::default(),
+    };
+    let serials = runtime
+        .block_on(poll_serials(&runtime, dns, resolver, &options))
+        .unwrap();
+    let primary = serials.primary.as_ref().unwrap();
+    assert_eq!(primary.addr, "192.0.32.162:53".parse().unwrap());
+    assert_eq!(primary.serial.as_ref().ok(), Some(&2019090512));
+    assert_eq!(serials.reference(), Some(2019090512));
+    let lags: Vec<_> = serials
+        .nameservers
+        .iter()
+        .map(|ns| (ns.server.to_string(), ns.lag(2019090512)))
+        .collect();
+    assert_eq!(
+        lags,
+        vec![
+            ("a.iana-servers.net.".to_owned(), Some(0)),
+            ("b.iana-servers.net.".to_owned(), Some(2)),
+        ]
+    );
+}
+
+#[test]
+fn test_serial_lag() {
+    assert_eq!(serial_lag(10, 12), Some(2));
+    assert_eq!(serial_lag(12, 10), None);
+    assert_eq!(serial_lag(u32::MAX, 1), Some(2));
+    assert_eq!(serial_lag(1, u32::MAX), None);
+}
+
+#[test]
+fn test_monitor_backoff() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = mock_dns_fixed(
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.1")],
+        &[("foo.example.org", "A", "192.168.1.2")],
+    );
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Monitor {
+        interval: TIMEOUT / 10,
+        interval_max: Some(TIMEOUT * 100),
+        backoff_factor: 1000.0,
+        ..monitor_settings("A:192.168.1.1")
+    };
+    let polls = RefCell::new(0);
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        settings,
+        poll_events(|event| {
+            if !event.matched {
+                *polls.borrow_mut() += 1;
+            }
+        }),
+    );
+    assert!(runtime.block_on(monitor).is_err());
+    // The second poll is followed by a delay exceeding the timeout.
+    assert!(polls.into_inner() <= 2);
+}
+
+#[test]
+fn test_monitor_reuses_connection() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    let auth1 = dns
+        .add_server(
+            "199.43.135.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    let auth2 = dns
+        .add_server(
+            "199.43.133.53:53".parse().unwrap(),
+            &[("foo.example.org", "A", "192.168.1.2")][..],
+        )
+        .unwrap();
+    // The first query fails, as if the connection was closed.
+    auth1.lock().unwrap().fail_queries(1);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let polls = RefCell::new(0);
+    let monitor = monitor_update_with_progress(
+        &runtime,
+        dns,
+        resolver,
+        monitor_settings("A:192.168.1.1"),
+        poll_events(|event| {
+            if !event.matched {
+                *polls.borrow_mut() += 1;
+            }
+        }),
+    );
+    assert!(runtime.block_on(monitor).is_err());
+    // The connection to the server which never matches is reused for all
+    // polls, while the failed one is re-established once.
+    assert!(polls.into_inner() > 1);
+    assert_eq!(auth2.lock().unwrap().connections(), 1);
+    assert_eq!(auth1.lock().unwrap().connections(), 2);
+}
+
+#[test]
+fn test_monitor_in_zone_ns() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "ns1.example.org."),
+        ("org", "NS", "a0.org-servers.net."),
+        ("a0.org-servers.net", "A", "199.19.56.1"),
+    ];
+    let parent_data: &[_] = &[
+        ("example.org", "NS", "ns1.example.org."),
+        ("ns1.example.org", "A", "192.0.2.53"),
+    ];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let parent = dns
+        .add_server("199.19.56.1:53".parse().unwrap(), parent_data)
+        .unwrap();
+    parent.lock().unwrap().set_referrals(true);
+    dns.add_server(
+        "192.0.2.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    runtime.block_on(monitor).unwrap();
+}
+
+#[test]
+fn test_monitor_in_zone_ns_parent_failure() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "ns1.example.org."),
+        ("org", "NS", "a0.org-servers.net."),
+        ("org", "NS", "a1.org-servers.net."),
+        ("org", "NS", "a2.org-servers.net."),
+        ("a0.org-servers.net", "A", "199.19.56.1"),
+        ("a1.org-servers.net", "A", "199.19.57.1"),
+        ("a2.org-servers.net", "A", "199.249.112.1"),
+    ];
+    let parent_data: &[_] = &[
+        ("example.org", "NS", "ns1.example.org."),
+        ("ns1.example.org", "A", "192.0.2.53"),
+    ];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    // The first parent nameserver is unreachable, the second one fails to
+    // answer, so the glue has to be obtained from the third one.
+    let unreachable = dns
+        .add_server("199.19.56.1:53".parse().unwrap(), parent_data)
+        .unwrap();
+    unreachable.lock().unwrap().refuse_connections(usize::MAX);
+    let failing = dns
+        .add_server("199.19.57.1:53".parse().unwrap(), parent_data)
+        .unwrap();
+    failing.lock().unwrap().fail_queries(usize::MAX);
+    let working = dns
+        .add_server("199.249.112.1:53".parse().unwrap(), parent_data)
+        .unwrap();
+    working.lock().unwrap().set_referrals(true);
+    dns.add_server(
+        "192.0.2.53:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    runtime.block_on(monitor).unwrap();
+    assert_eq!(failing.lock().unwrap().connections(), 1);
+    assert_eq!(working.lock().unwrap().connections(), 1);
+}
+
+#[test]
+fn test_monitor_ipv6_ns() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "ns.example.net."),
+        ("ns.example.net", "AAAA", "2001:db8::53"),
+    ];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    dns.add_server(
+        "[2001:db8::53]:53".parse().unwrap(),
+        &[("foo.example.org", "AAAA", "2001:db8::1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let monitor = monitor_update(
+        &runtime,
+        dns,
+        resolver,
+        monitor_settings("AAAA:2001:db8::1"),
+    );
+    runtime.block_on(monitor).unwrap();
+}
+
+#[test]
+fn test_monitor_source() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "ns4.example.net."),
+        ("example.org", "NS", "ns6.example.net."),
+        ("ns4.example.net", "A", "192.0.2.53"),
+        ("ns6.example.net", "AAAA", "2001:db8::53"),
+    ];
+    let data: &[_] = &[("foo.example.org", "A", "192.0.2.1")];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let ns4 = dns
+        .add_server("192.0.2.53:53".parse().unwrap(), data)
+        .unwrap();
+    let ns6 = dns
+        .add_server("[2001:db8::53]:53".parse().unwrap(), data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let mut settings = monitor_settings("A:192.0.2.1");
+    settings.source = Some("2001:db8::1".parse().unwrap());
+    runtime
+        .block_on(monitor_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            settings,
+        ))
+        .unwrap();
+    assert!(ns4.lock().unwrap().query_log().is_empty());
+    assert!(!ns6.lock().unwrap().query_log().is_empty());
+
+    let rec_data: &[_] = &[
+        ("example.org", "NS", "ns4.example.net."),
+        ("ns4.example.net", "A", "192.0.2.53"),
+    ];
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let mut settings = monitor_settings("A:192.0.2.1");
+    settings.source = Some("2001:db8::1".parse().unwrap());
+    let e = runtime
+        .block_on(monitor_update(&runtime, dns, resolver, settings))
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "no nameserver of example.org can be reached from source address 2001:db8::1"
+    );
+}
+
+#[test]
+fn test_monitor_split_backend() {
+    let runtime = Runtime::new().unwrap();
+    let (resolver_dns, _) = mock_dns(&[]);
+    let mut auth_dns = MockBackend::default();
+    for addr in &["199.43.135.53:53", "199.43.133.53:53"] {
+        auth_dns
+            .add_server(
+                addr.parse().unwrap(),
+                &[("foo.example.org", "A", "192.168.1.1")][..],
+            )
+            .unwrap();
+    }
+    let mut dns = SplitBackend {
+        resolver: resolver_dns,
+        client: auth_dns,
+    };
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.1"));
+    runtime.block_on(monitor).unwrap();
+}
+
+#[test]
+fn test_monitor_no_nameservers() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    dns.add_server(
+        "127.0.0.1:53".parse().unwrap(),
+        &[("foo.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let monitor = monitor_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        monitor_settings("A:192.168.1.1"),
+    );
+    let e = runtime.block_on(monitor).unwrap_err();
+    assert!(
+        e.to_string()
+            .starts_with("could not determine nameservers of example.org"),
+        "{}",
+        e
+    );
+
+    let dns = mock_dns_fixed(&[], &[], &[]);
+    let resolver = dns
+        .clone()
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let mut settings = monitor_settings("A:192.168.1.1");
+    settings.exclude = vec![
+        "199.43.135.53".parse().unwrap(),
+        "199.43.133.53".parse().unwrap(),
+    ];
+    let e = runtime
+        .block_on(monitor_update(&runtime, dns, resolver, settings))
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "all nameservers of example.org are excluded from monitoring"
+    );
+}
+
+#[test]
+fn test_create_immediate() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("bar.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let update = perform_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        update_settings(Operation::Create(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.2".parse().unwrap(),
+        ))),
+    );
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.2"));
+    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+}
+
+#[test]
+fn test_update_rejected() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[("bar.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    for code in [op::ResponseCode::Refused, op::ResponseCode::NotAuth] {
+        master.lock().unwrap().set_update_response_code(Some(code));
+        let update = perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            update_settings(Operation::Create(RecordSet::new(
+                "foo.example.org".parse().unwrap(),
+                "A:192.168.1.2".parse().unwrap(),
+            ))),
+        );
+        let e = runtime.block_on(update).unwrap_err();
+        let e = e.downcast_ref::<UpdateRejected>().unwrap();
+        assert_eq!(e.response_code, code);
+        assert_eq!(e.server, "192.0.32.162:53".parse().unwrap());
+    }
+}
+
+#[test]
+fn test_update_match_ttl() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[]);
+    let existing = rr::Record::from_rdata(
+        "foo.example.org.".parse().unwrap(),
+        3600,
+        parse_rdata("A", "192.168.1.1").unwrap(),
+    );
+    let zone = master.lock().unwrap().zone();
+    zone.lock().unwrap().update(&existing);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = Update {
+        match_ttl: true,
+        ..update_settings(Operation::Append(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.2".parse().unwrap(),
+        )))
+    };
+    runtime
+        .block_on(perform_update(&runtime, dns, resolver, settings))
+        .unwrap();
+    // The added record gets the TTL of the existing one, not `Update::ttl`.
+    let master = master.lock().unwrap();
+    let message = master.query_log().last().unwrap();
+    assert_eq!(message.op_code(), op::OpCode::Update);
+    let added: Vec<_> = message
+        .name_servers()
+        .iter()
+        .map(|record| (record.rdata().to_string(), record.ttl()))
+        .collect();
+    assert_eq!(added, vec![("192.168.1.2".to_owned(), 3600)]);
+}
+
+#[test]
+fn test_update_class() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let rset = RecordSet::new(
+        "foo.example.org".parse().unwrap(),
+        "TXT:hello".parse().unwrap(),
+    )
+    .with_class(rr::DNSClass::CH);
+    // The class of the updated records, for each operation.
+    let operations = [
+        (Operation::Create(rset.clone()), rr::DNSClass::CH),
+        (Operation::Append(rset.clone()), rr::DNSClass::CH),
+        (Operation::Delete(rset), rr::DNSClass::NONE),
+        (
+            Operation::DeleteAll("foo.example.org".parse().unwrap()),
+            rr::DNSClass::ANY,
+        ),
+    ];
+    for (operation, update_class) in operations {
+        let settings = Update {
+            dns_class: rr::DNSClass::CH,
+            ..update_settings(operation.clone())
+        };
+        runtime
+            .block_on(perform_update(
+                &runtime,
+                dns.clone(),
+                resolver.clone(),
+                settings,
+            ))
+            .unwrap();
+        let master = master.lock().unwrap();
+        let message = master.query_log().last().unwrap();
+        assert_eq!(
+            message.queries()[0].query_class(),
+            rr::DNSClass::CH,
+            "zone class of {:?}",
+            operation
+        );
+        assert!(
+            message
+                .name_servers()
+                .iter()
+                .all(|record| record.dns_class() == update_class),
+            "update class of {:?}",
+            operation
+        );
+    }
+}
+
+#[test]
+fn test_update_hidden_primary() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        (
+            "example.org",
+            "SOA",
+            "hidden.example.org. noc.dns.icann.org. 2019090512 7200 3600 1209600 3600",
+        ),
+        ("example.org", "NS", "a.iana-servers.net."),
+        ("a.iana-servers.net", "A", "199.43.135.53"),
+    ];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let auth = dns
+        .add_server(
+            "199.43.135.53:53".parse().unwrap(),
+            &[("bar.example.org", "A", "192.168.1.1")][..],
+        )
+        .unwrap();
+    auth.lock()
+        .unwrap()
+        .set_update_response_code(Some(op::ResponseCode::Refused));
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let settings = update_settings(Operation::Create(RecordSet::new(
+        "foo.example.org".parse().unwrap(),
+        "A:192.168.1.2".parse().unwrap(),
+    )));
+    let master = runtime
+        .block_on(resolve_master(resolver.clone(), &settings))
+        .unwrap();
+    assert_eq!(master.addr, "199.43.135.53:53".parse().unwrap());
+    let fallback = master.fallback.expect("no fallback reported");
+    assert_eq!(fallback.mname, "hidden.example.org.".parse().unwrap());
+    assert_eq!(fallback.nameserver, "a.iana-servers.net.".parse().unwrap());
+    // The fallback server refusing the update must not go unnoticed.
+    let e = runtime
+        .block_on(perform_update(&runtime, dns, resolver, settings))
+        .unwrap_err();
+    let e = e.downcast_ref::<UpdateRejected>().unwrap();
+    assert_eq!(e.response_code, op::ResponseCode::Refused);
+    assert_eq!(e.server, master.addr);
+}
+
+#[test]
+fn test_create_openpgpkey() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("bar.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let data = "OPENPGPKEY:mQINBF1e";
+    let update = perform_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        update_settings(Operation::Create(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            data.parse().unwrap(),
+        ))),
+    );
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings(data));
+    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+    let e = "OPENPGPKEY:not base64".parse::<RsData>().unwrap_err();
+    assert!(e.to_string().starts_with("invalid base64 data"));
+}
+
+#[test]
+fn test_mx_srv_order() {
+    let data: RsData = "MX:20 backup.example.org,10 mail.example.org,10 alt.example.org"
+        .parse()
+        .unwrap();
+    let rset = RecordSet::new("example.org.".parse().unwrap(), data);
+    let order: Vec<_> = rset
+        .iter_data()
+        .map(|rdata| {
+            let mx = rdata.as_mx().unwrap();
+            (mx.preference(), mx.exchange().to_string())
+        })
+        .collect();
+    assert_eq!(
+        order,
+        vec![
+            (10, "alt.example.org.".to_owned()),
+            (10, "mail.example.org.".to_owned()),
+            (20, "backup.example.org.".to_owned()),
+        ]
+    );
+    let records: Vec<_> = rset
+        .to_rrset(300)
+        .records_without_rrsigs()
+        .cloned()
+        .collect();
+    assert_eq!(RecordSet::try_from(records.as_slice()).unwrap(), rset);
+
+    // The same exchange with a different preference is a distinct record.
+    let data: RsData = "MX:10 mail.example.org,20 mail.example.org"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        data.to_string(),
+        "MX:10 mail.example.org.,20 mail.example.org."
+    );
+
+    let data: RsData =
+        "SRV:20 0 5060 b.example.org,10 60 5060 a.example.org,10 20 5060 c.example.org"
+            .parse()
+            .unwrap();
+    let rset = RecordSet::new("_sip._udp.example.org.".parse().unwrap(), data);
+    let order: Vec<_> = rset
+        .iter_data()
+        .map(|rdata| {
+            let srv = rdata.as_srv().unwrap();
+            (srv.priority(), srv.weight())
+        })
+        .collect();
+    assert_eq!(order, vec![(10, 20), (10, 60), (20, 0)]);
+    assert!("MX:mail.example.org".parse::<RsData>().is_err());
+    assert!("SRV:10 0 70000 a.example.org".parse::<RsData>().is_err());
+}
+
+#[test]
+fn test_record_set_ops() {
+    let name: rr::Name = "example.org.".parse().unwrap();
+    let rset = |data: &str| RecordSet::new(name.clone(), data.parse().unwrap());
+    let desired = rset("A:192.0.2.1,192.0.2.2");
+    let actual = rset("A:192.0.2.2,192.0.2.3");
+    assert_eq!(
+        desired.union(&actual).unwrap(),
+        rset("A:192.0.2.1,192.0.2.2,192.0.2.3")
+    );
+    assert_eq!(desired.intersection(&actual).unwrap(), rset("A:192.0.2.2"));
+    assert_eq!(desired.difference(&actual).unwrap(), rset("A:192.0.2.1"));
+    assert_eq!(actual.difference(&desired).unwrap(), rset("A:192.0.2.3"));
+    assert!(desired
+        .intersection(&rset("A:192.0.2.9"))
+        .unwrap()
+        .is_empty());
+
+    let err = desired.union(&rset("AAAA:2001:db8::1")).unwrap_err();
+    assert_eq!(err.right.record_type(), rr::RecordType::AAAA);
+    let other_name = RecordSet::new(
+        "example.net.".parse().unwrap(),
+        "A:192.0.2.1".parse().unwrap(),
+    );
+    assert!(desired.difference(&other_name).is_err());
+    let other_class = rset("A:192.0.2.1").with_class(rr::DNSClass::CH);
+    assert!(desired.intersection(&other_class).is_err());
+}
+
+#[test]
+fn test_txt_quoting() {
+    let spf = "v=spf1 include:_spf.example.com, -all";
+    let data: RsData = r#"TXT:"v=spf1 include:_spf.example.com, -all",plain"#
+        .parse()
+        .unwrap();
+    let expected = RsData::TXT(vec![spf.into(), "plain".into()].into_iter().collect());
+    assert_eq!(data, expected);
+    assert_eq!(data.to_string().parse::<RsData>().unwrap(), data);
+    let data: RsData = r#"TXT:"say \"hi\", \\o/""#.parse().unwrap();
+    assert_eq!(
+        data,
+        RsData::TXT(vec![r#"say "hi", \o/"#.into()].into_iter().collect())
+    );
+    assert_eq!(data.to_string().parse::<RsData>().unwrap(), data);
+    // Unquoted items are taken verbatim.
+    let data: RsData = r"TXT:a\b".parse().unwrap();
+    assert_eq!(data, RsData::TXT(vec![r"a\b".into()].into_iter().collect()));
+    assert!(r#"TXT:"unterminated"#.parse::<RsData>().is_err());
+    assert!(r#"TXT:"a"b"#.parse::<RsData>().is_err());
+    // Quoted NAPTR fields may contain commas, too.
+    let naptr = r#"100 10 "U" "E2U+sip" "!^(a,b)$!sip:info@example.org!" ."#;
+    let data: RsData = format!("NAPTR:{},{}", naptr, naptr.replace("100", "200"))
+        .parse()
+        .unwrap();
+    match data {
+        RsData::NAPTR(naptrs) => assert_eq!(naptrs.len(), 2),
+        _ => panic!("unexpected data: {:?}", data),
+    }
+}
+
+#[test]
+fn test_naptr_data() {
+    let item = r#"100 10 "U" "E2U+sip" "!^.*$!sip:info@example.org!" ."#;
+    let data = RsData::from_parts("NAPTR", vec![item]).unwrap();
+    assert_eq!(data.to_string(), format!("NAPTR:{}", item));
+    let rset = RecordSet::new("4.3.2.1.e164.arpa.".parse().unwrap(), data);
+    let records: Vec<_> = rset
+        .to_rrset(300)
+        .records_without_rrsigs()
+        .cloned()
+        .collect();
+    assert_eq!(RecordSet::try_from(records.as_slice()).unwrap(), rset);
+    assert!(rset.contains(records[0].rdata()));
+    assert!(RsData::from_parts("NAPTR", vec![r#"100 10 "U" "E2U+sip"#]).is_err());
+    assert!(RsData::from_parts("NAPTR", vec![r#"100 10 "U+" "" "" ."#]).is_err());
+}
+
+#[test]
+fn test_dnssec_data() {
+    use trust_dns_client::serialize::binary::{BinDecodable, BinEncodable};
+
+    let digest = "2bb183af5f22588179a53b0a98631fad1a292118";
+    let data: RsData = format!("DS:60485 RSASHA1 1 {}", digest.to_uppercase())
+        .parse()
+        .unwrap();
+    assert_eq!(data.to_string(), format!("DS:60485 RSASHA1 1 {}", digest));
+    let key = "AQPSKmynfzW4kyBv015MUG2DeIQ3 Cbl+BBZH4b/0PY1kxkmvHjcZc8no";
+    let data = RsData::from_parts(
+        "DNSKEY",
+        vec![format!("257 3 8 {}", key).as_str(), "256 3 253 AQID"],
+    )
+    .unwrap();
+    assert_eq!(
+        data.to_string(),
+        format!(
+            "DNSKEY:256 3 253 AQID,257 3 RSASHA256 {}",
+            key.replace(' ', "")
+        )
+    );
+    let rset = RecordSet::new("example.org.".parse().unwrap(), data);
+    let records: Vec<_> = rset
+        .to_rrset(300)
+        .records_without_rrsigs()
+        .map(|record| rr::Record::from_bytes(&record.to_bytes().unwrap()).unwrap())
+        .collect();
+    assert_eq!(RecordSet::try_from(records.as_slice()).unwrap(), rset);
+    assert!(records.iter().all(|record| rset.contains(record.rdata())));
+    assert!(rset.cleared().is_subset(&rset));
+    assert!(!rset.is_subset(&rset.cleared()));
+
+    let e = format!("DS:60485 5 2 {}", digest)
+        .parse::<RsData>()
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "invalid DS data: expected a digest of 32 octets for digest type 2, found 20"
+    );
+    assert!(format!("DS:60485 5 3 {}", digest)
+        .parse::<RsData>()
+        .is_err());
+    assert!("DS:60485 5 1 xyz".parse::<RsData>().is_err());
+    assert!("DNSKEY:257 2 8 AQID".parse::<RsData>().is_err());
+    assert!("DNSKEY:2 3 8 AQID".parse::<RsData>().is_err());
+}
+
+#[test]
+fn test_append_skip_if_present() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[
+        ("foo.example.org", "A", "192.168.1.1"),
+        ("foo.example.org", "A", "192.168.1.2"),
+    ]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let append = |data: &str| {
+        let mut settings = update_settings(Operation::Append(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            data.parse().unwrap(),
+        )));
+        settings.skip_if_present = true;
+        runtime.block_on(perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            settings,
+        ))
+    };
+    let n_updates = || {
+        master
+            .lock()
+            .unwrap()
+            .query_log()
+            .iter()
+            .filter(|request| request.op_code() == op::OpCode::Update)
+            .count()
+    };
+    assert_eq!(
+        append("A:192.168.1.2").unwrap(),
+        UpdateOutcome::AlreadyPresent
+    );
+    assert_eq!(n_updates(), 0);
+    assert_eq!(
+        append("A:192.168.1.2,192.168.1.3").unwrap(),
+        UpdateOutcome::Sent
+    );
+    assert_eq!(n_updates(), 1);
+}
+
+#[test]
+fn test_ensure() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[
+        ("foo.example.org", "A", "192.168.1.1"),
+        ("foo.example.org", "A", "192.168.1.2"),
+    ]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let rset =
+        |data: &str| RecordSet::new("foo.example.org".parse().unwrap(), data.parse().unwrap());
+    let ensure = |data: &str| {
+        let mut settings = update_settings(Operation::Ensure(rset(data)));
+        settings.verify = true;
+        runtime.block_on(perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            settings,
+        ))
+    };
+    let n_updates = || {
+        master
+            .lock()
+            .unwrap()
+            .query_log()
+            .iter()
+            .filter(|request| request.op_code() == op::OpCode::Update)
+            .count()
+    };
+    let changes = Changes {
+        added: rset("A:192.168.1.3"),
+        removed: rset("A:192.168.1.1"),
+    };
+    assert_eq!(
+        ensure("A:192.168.1.2,192.168.1.3").unwrap(),
+        UpdateOutcome::Ensured(Box::new(changes))
+    );
+    assert_eq!(n_updates(), 1);
+    match ensure("A:192.168.1.2,192.168.1.3").unwrap() {
+        UpdateOutcome::Ensured(changes) => assert!(changes.is_empty()),
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    }
+    assert_eq!(n_updates(), 1);
+}
+
+#[test]
+fn test_ensure_concurrent_change() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[("foo.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let ensure = |name: &str, data: &str| {
+        let rset = RecordSet::new(name.parse().unwrap(), data.parse().unwrap());
+        runtime.block_on(perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            update_settings(Operation::Ensure(rset)),
+        ))
+    };
+    let add_before_update = |name: &str| {
+        let record = rr::Record::from_rdata(
+            name.parse().unwrap(),
+            3600,
+            parse_rdata("A", "192.168.1.9").unwrap(),
+        );
+        master.lock().unwrap().add_before_update(record);
+    };
+    let lookup = |name: &str| {
+        let query = op::Query::query(name.parse().unwrap(), rr::RecordType::A);
+        let zone = master.lock().unwrap().zone();
+        let zone = zone.lock().unwrap();
+        zone.lookup(&query)
+    };
+
+    // The RRset read is required to be unchanged.
+    add_before_update("foo.example.org");
+    let e = ensure("foo.example.org", "A:192.168.1.2").unwrap_err();
+    let e = e.downcast::<ConcurrentChange>().unwrap();
+    assert_eq!(e.0.data(), &"A:192.168.1.1".parse().unwrap());
+    assert_eq!(lookup("foo.example.org").len(), 2);
+
+    // An RRset found missing is required to not exist.
+    add_before_update("bar.example.org");
+    let e = ensure("bar.example.org", "A:192.168.1.2").unwrap_err();
+    let e = e.downcast::<ConcurrentChange>().unwrap();
+    assert!(e.0.is_empty());
+    assert_eq!(
+        e.to_string(),
+        "bar.example.org A was created concurrently; update not applied"
+    );
+    assert_eq!(lookup("bar.example.org").len(), 1);
+
+    ensure("baz.example.org", "A:192.168.1.2").unwrap();
+    assert_eq!(lookup("baz.example.org").len(), 1);
+}
+
+#[test]
+fn test_create_verify() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[("bar.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let create = |data: &str| {
+        let mut update = update_settings(Operation::Create(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            data.parse().unwrap(),
+        )));
+        update.verify = true;
+        perform_update(&runtime, dns.clone(), resolver.clone(), update)
+    };
+    master.lock().unwrap().set_ignore_updates(true);
+    let e = runtime.block_on(create("A:192.168.1.2")).unwrap_err();
+    let e = e.downcast::<UpdateNotApplied>().unwrap();
+    assert!(e.records.is_empty());
+    master.lock().unwrap().set_ignore_updates(false);
+    runtime.block_on(create("A:192.168.1.2")).unwrap();
+}
+
+#[test]
+fn test_update_apex_ns_guard() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[
+        (
+            "example.org",
+            "SOA",
+            "sns.dns.icann.org. noc.dns.icann.org. 2019090512 7200 3600 1209600 3600",
+        ),
+        ("example.org", "NS", "ns1.example.org."),
+        ("example.org", "NS", "ns2.example.org."),
+        ("example.org", "TXT", "hello"),
+        ("sub.example.org", "NS", "ns1.example.org."),
+    ]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let update = |operation: Operation, force: bool| {
+        let settings = Update {
+            force,
+            ..update_settings(operation)
+        };
+        runtime.block_on(perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            settings,
+        ))
+    };
+    let delete =
+        |name: &str, data: &str| Operation::delete(name.parse().unwrap(), data.parse().unwrap());
+    let updates_sent = || {
+        master
+            .lock()
+            .unwrap()
+            .query_log()
+            .iter()
+            .filter(|message| message.op_code() == op::OpCode::Update)
+            .count()
+    };
+    // Deleting all apex NS records, directly or as part of a batch, or by
+    // ensuring an empty RRset, is refused without sending anything.
+    let refused = [
+        delete("example.org", "NS"),
+        delete("example.org", "NS:ns1.example.org,ns2.example.org"),
+        Operation::Batch(vec![
+            delete("example.org", "NS:ns1.example.org"),
+            delete("example.org", "NS:ns2.example.org"),
+        ]),
+        Operation::Ensure(RecordSet::new(
+            "example.org".parse().unwrap(),
+            "NS".parse().unwrap(),
+        )),
+    ];
+    for operation in refused {
+        let e = update(operation.clone(), false).unwrap_err();
+        assert!(e.downcast_ref::<ApexNsDeletion>().is_some(), "{}", e);
+    }
+    assert_eq!(updates_sent(), 0);
+    // Deletions of other records, of NS records below the apex, and of all
+    // records at the apex, which leaves its NS records alone, are accepted.
+    update(delete("example.org", "TXT"), false).unwrap();
+    update(delete("sub.example.org", "NS"), false).unwrap();
+    update(Operation::DeleteAll("example.org".parse().unwrap()), false).unwrap();
+    let zone = master.lock().unwrap().zone();
+    let ns_query = op::Query::query("example.org.".parse().unwrap(), rr::RecordType::NS);
+    assert_eq!(zone.lock().unwrap().lookup(&ns_query).len(), 2);
+    // Replacing a nameserver is fine, as one is left in between.
+    update(
+        Operation::Batch(vec![
+            Operation::Append(RecordSet::new(
+                "example.org".parse().unwrap(),
+                "NS:ns3.example.org".parse().unwrap(),
+            )),
+            delete("example.org", "NS:ns1.example.org"),
+        ]),
+        false,
+    )
+    .unwrap();
+    assert_eq!(updates_sent(), 4);
+    // Deleting the remaining ones is refused, unless forced.
+    let last = delete("example.org", "NS:ns2.example.org,ns3.example.org");
+    let e = update(last.clone(), false).unwrap_err();
+    assert!(e.downcast_ref::<ApexNsDeletion>().is_some(), "{}", e);
+    assert_eq!(updates_sent(), 4);
+    update(last, true).unwrap();
+    assert_eq!(updates_sent(), 5);
+}
+
+#[test]
+fn test_delete_require_current() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.0.2.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let delete = |current: &str| {
+        let mut settings = update_settings(Operation::delete(
+            "foo.example.org".parse().unwrap(),
+            "A".parse().unwrap(),
+        ));
+        settings.require_current = Some(RecordSet::new(
+            "foo.example.org".parse().unwrap(),
+            current.parse().unwrap(),
+        ));
+        perform_update(&runtime, dns.clone(), resolver.clone(), settings)
+    };
+    let e = runtime.block_on(delete("A:192.0.2.2")).unwrap_err();
+    assert!(e.downcast_ref::<CurrentValueMismatch>().is_some(), "{}", e);
+    let monitor = monitor_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        monitor_settings("A:192.0.2.1"),
+    );
+    runtime.block_on(monitor).unwrap();
+    runtime.block_on(delete("A:192.0.2.1")).unwrap();
+}
+
+#[test]
+fn test_create_hidden_primary() {
+    let runtime = Runtime::new().unwrap();
+    let rec_data: &[_] = &[
+        (
+            "example.org",
+            "SOA",
+            "hidden.example.org. noc.example.org. 2019090512 7200 3600 1209600 3600",
+        ),
+        ("example.org", "NS", "a.iana-servers.net."),
+        ("a.iana-servers.net", "A", "199.43.135.53"),
+    ];
+    let mut dns = MockBackend::default();
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    dns.add_server(
+        "199.43.135.53:53".parse().unwrap(),
+        &[("bar.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let update = perform_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        update_settings(Operation::create(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.2".parse().unwrap(),
+        )),
+    );
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A:192.168.1.2"));
+    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+}
+
+#[test]
+fn test_create_delayed() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, zone) = mock_dns_independent(&[("bar.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let update = perform_update(
+        &runtime,
+        dns,
+        resolver,
+        update_settings(Operation::create(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.2".parse().unwrap(),
+        )),
+    );
+    async fn update_auth(zone: mock::Handle<mock::Zone>) -> anyhow::Result<()> {
+        sleep(TIMEOUT / 2).await;
+        let updated = rr::Record::from_rdata(
+            "foo.example.org".parse().unwrap(),
+            0,
+            parse_rdata("A", "192.168.1.2").unwrap(),
+        );
+        let mut zone = zone.lock().unwrap();
+        zone.update(&updated);
+        Ok(())
+    }
+    let parallel = FuturesUnordered::new();
+    parallel
+        .push(Box::pin(update.map_ok(|_| ())) as Pin<Box<dyn Future<Output = anyhow::Result<()>>>>);
+    parallel.push(Box::pin(update_auth(zone)));
+    runtime.block_on(parallel.try_collect::<Vec<_>>()).unwrap();
+}
+
+#[test]
+fn test_delete() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[("foo.example.org", "A", "192.168.1.1")]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let update = perform_update(
+        &runtime,
+        dns.clone(),
+        resolver.clone(),
+        update_settings(Operation::delete(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.1".parse().unwrap(),
+        )),
+    );
+    let monitor = monitor_update(&runtime, dns, resolver, monitor_settings("A"));
+    runtime.block_on(update.and_then(|_| monitor)).unwrap();
+}
+
+#[test]
+fn test_delete_partial() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns_shared(&[
+        ("foo.example.org", "A", "192.168.1.1"),
+        ("foo.example.org", "A", "192.168.1.2"),
+        ("foo.example.org", "TXT", "hello"),
+        ("bar.example.org", "A", "192.168.1.1"),
+    ]);
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let delete = |operation| {
+        perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            update_settings(operation),
+        )
+    };
+    let monitor = |expected| {
+        monitor_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            monitor_settings(expected),
+        )
+    };
+    runtime
+        .block_on(delete(Operation::delete(
+            "foo.example.org".parse().unwrap(),
+            "A:192.168.1.1".parse().unwrap(),
+        )))
+        .unwrap();
+    runtime.block_on(monitor("A:192.168.1.2")).unwrap();
+    runtime
+        .block_on(delete(Operation::delete(
+            "foo.example.org".parse().unwrap(),
+            "A".parse().unwrap(),
+        )))
+        .unwrap();
+    runtime.block_on(monitor("A")).unwrap();
+    runtime.block_on(monitor("TXT:hello")).unwrap();
+    runtime
+        .block_on(delete(Operation::DeleteAll(
+            "foo.example.org".parse().unwrap(),
+        )))
+        .unwrap();
+    runtime.block_on(monitor("TXT")).unwrap();
+    let mut monitor = monitor_settings("A:192.168.1.1");
+    monitor.entry = "bar.example.org".parse().unwrap();
+    monitor.expectation = Expectation::Is(RecordSet::new(
+        monitor.entry.clone(),
+        "A:192.168.1.1".parse().unwrap(),
+    ));
+    runtime
+        .block_on(monitor_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            monitor,
+        ))
+        .unwrap();
+}
+
+#[test]
+fn test_query_names_ndots() {
+    let query = |entry: &str, ndots| Query {
+        entry: entry.parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec!["example.org.".parse().unwrap()],
+        ndots,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let names = |entry, ndots| -> Vec<String> {
+        query(entry, ndots)
+            .names()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    };
+    // Below the threshold, the search list comes first.
+    assert_eq!(names("www", 1), vec!["www.example.org.", "www."]);
+    assert_eq!(
+        names("www.sub", 1),
+        vec!["www.sub.", "www.sub.example.org."]
+    );
+    assert_eq!(
+        names("www.sub", 2),
+        vec!["www.sub.example.org.", "www.sub."]
+    );
+    // At or above the threshold, the name is tried verbatim first.
+    assert_eq!(names("a.b.c", 2), vec!["a.b.c.", "a.b.c.example.org."]);
+    assert_eq!(names("www", 0), vec!["www.", "www.example.org."]);
+    // Fully qualified names are never subject to the search list.
+    assert_eq!(names("www.", 5), vec!["www."]);
+}
+
+#[test]
+fn test_query_search() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[]);
+    dns.add_server(
+        "127.0.0.2:53".parse().unwrap(),
+        &[("www.example.org", "A", "192.168.1.1")][..],
+    )
+    .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.2:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let query = Query {
+        entry: "www".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![
+            "example.net.".parse().unwrap(),
+            "example.org.".parse().unwrap(),
+        ],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let results: Vec<_> = runtime
+        .block_on(perform_query(resolver, query).try_collect())
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].record_type, rr::RecordType::A);
+    assert_eq!(results[0].server, None);
+    assert_eq!(results[0].records.len(), 1);
+    assert_eq!(
+        results[0].records[0].name(),
+        &"www.example.org.".parse::<rr::Name>().unwrap()
+    );
+    assert_eq!(
+        results[0].name,
+        "www.example.org.".parse::<rr::Name>().unwrap()
+    );
+    assert_eq!(results[0].response_code, op::ResponseCode::NoError);
+    assert!(results[0].negative.is_none());
+}
+
+#[test]
+fn test_query_via_reports_server() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[]);
+    let first = "127.0.0.2:53".parse().unwrap();
+    let second = "127.0.0.3:53".parse().unwrap();
+    dns.add_server(first, &[("www.example.org", "A", "192.168.1.1")][..])
+        .unwrap();
+    dns.add_server(second, &[("www.example.org", "AAAA", "2001:db8::1")][..])
+        .unwrap();
+    let resolvers = vec![
+        (first, dns.open_resolver(first).unwrap()),
+        (second, dns.open_resolver(second).unwrap()),
+    ];
+    let query = Query {
+        entry: "www.example.org.".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let results: Vec<_> = runtime
+        .block_on(query::perform_query_via(resolvers.clone(), query.clone()).try_collect())
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].server, Some(first));
+    assert_eq!(results[0].record_type, rr::RecordType::A);
+    assert_eq!(results[0].records.len(), 1);
+    // A negative answer is final, and does not lead to the next server being
+    // asked.
+    let query = Query {
+        record_types: vec![rr::RecordType::AAAA],
+        ..query
+    };
+    let results: Vec<_> = runtime
+        .block_on(query::perform_query_via(resolvers, query).try_collect())
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].server, Some(first));
+    assert!(results[0].records.is_empty());
+    assert_eq!(results[0].response_code, op::ResponseCode::NoError);
+    assert!(results[0].name_exists());
+    let negative = results[0].negative.as_ref().unwrap();
+    assert_eq!(negative.query.query_type(), rr::RecordType::AAAA);
+}
+
+#[test]
+fn test_trace() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let rec_data: &[_] = &[
+        (".", "NS", "a.root-servers.net."),
+        ("a.root-servers.net", "A", "198.41.0.4"),
+        ("ns.example.net", "A", "192.0.2.53"),
+    ];
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let root_data: &[_] = &[
+        ("org.", "NS", "a0.org.afilias-nst.info."),
+        ("a0.org.afilias-nst.info", "A", "199.19.56.1"),
+    ];
+    dns.add_server("198.41.0.4:53".parse().unwrap(), root_data)
+        .unwrap();
+    // The nameserver of `example.org` is referred to without glue.
+    let org_data: &[_] = &[("example.org.", "NS", "ns.example.net.")];
+    dns.add_server("199.19.56.1:53".parse().unwrap(), org_data)
+        .unwrap();
+    let example_data: &[_] = &[("www.example.org", "A", "192.0.2.80")];
+    let example = dns
+        .add_server("192.0.2.53:53".parse().unwrap(), example_data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let mut steps = Vec::new();
+    let response = runtime
+        .block_on(query::trace(
+            &runtime,
+            dns,
+            resolver,
+            "www.example.org.".parse().unwrap(),
+            rr::RecordType::A,
+            |step| steps.push(step.clone()),
+        ))
+        .unwrap();
+    assert_eq!(
+        response.answers()[0].rdata(),
+        &parse_rdata("A", "192.0.2.80").unwrap()
+    );
+    let summary: Vec<_> = steps
+        .iter()
+        .map(|step| {
+            (
+                step.zone.to_string(),
+                step.server_name.to_string(),
+                step.server.to_string(),
+                step.glueless,
+            )
+        })
+        .collect();
+    let expected = [
+        (".", "a.root-servers.net.", "198.41.0.4:53", false),
+        ("org.", "a0.org.afilias-nst.info.", "199.19.56.1:53", false),
+        ("example.org.", "ns.example.net.", "192.0.2.53:53", true),
+    ];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|&(zone, name, addr, glueless)| (zone.into(), name.into(), addr.into(), glueless))
+        .collect();
+    assert_eq!(summary, expected);
+    assert!(!example.lock().unwrap().query_log()[0].recursion_desired());
+
+    let mut output = Vec::new();
+    query::write_trace_step(&mut output, &steps[2]).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        ";; example.org. from ns.example.net. (192.0.2.53:53), looked up without glue\n"
+    );
+}
+
+#[test]
+fn test_query_with_timeout() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[]);
+    let addr = "127.0.0.2:53".parse().unwrap();
+    dns.add_server(addr, &[("www.example.org", "A", "192.168.1.1")][..])
+        .unwrap();
+    let resolver = StallingResolver {
+        client: dns.open_resolver(addr).unwrap(),
+        stalled: rr::RecordType::AAAA,
+    };
+    let query = Query {
+        entry: "www.example.org.".parse().unwrap(),
+        record_types: vec![rr::RecordType::A, rr::RecordType::AAAA],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let results: Vec<_> = runtime
+        .block_on(query::perform_query_with_timeout(resolver, query, TIMEOUT).collect::<Vec<_>>());
+    assert_eq!(results.len(), 2);
+    let answered: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+    assert_eq!(answered.len(), 1);
+    assert_eq!(answered[0].record_type, rr::RecordType::A);
+    let e = results.into_iter().find_map(Result::err).unwrap();
+    assert!(matches!(e.kind(), ResolveErrorKind::Timeout));
+}
+
+#[test]
+fn test_retry_servfail() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[]);
+    let addr = "127.0.0.2:53".parse().unwrap();
+    dns.add_server(addr, &[("www.example.org", "A", "192.168.1.1")][..])
+        .unwrap();
+    let resolver = |servfails, retries| ServfailRetry {
+        resolver: ServfailResolver {
+            client: dns.clone().open_resolver(addr).unwrap(),
+            servfails: Arc::new(Mutex::new(servfails)),
+            lookups: Default::default(),
+        },
+        retries,
+    };
+    let name: rr::Name = "www.example.org.".parse().unwrap();
+    let lookups =
+        |resolver: &ServfailRetry<ServfailResolver>| *resolver.resolver.lookups.lock().unwrap();
+
+    let retrying = resolver(2, 2);
+    let lookup = runtime.block_on(Resolver::lookup(&retrying, name.clone(), rr::RecordType::A));
+    assert_eq!(lookup.unwrap().record_iter().count(), 1);
+    assert_eq!(lookups(&retrying), 3);
+
+    let exhausted = resolver(3, 1);
+    let e = runtime
+        .block_on(Resolver::lookup(
+            &exhausted,
+            name.clone(),
+            rr::RecordType::A,
+        ))
+        .unwrap_err();
+    assert!(backend::is_servfail(&e));
+    assert_eq!(lookups(&exhausted), 2);
+
+    // Negative answers are not retried.
+    let negative = resolver(0, 3);
+    let query = Query {
+        entry: name,
+        record_types: vec![rr::RecordType::AAAA],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let results: Vec<_> =
+        runtime.block_on(perform_query(negative.clone(), query).collect::<Vec<_>>());
+    assert!(results[0].as_ref().unwrap().negative.is_some());
+    assert_eq!(lookups(&negative), 1);
+}
+
+#[test]
+fn test_parse_ttl() {
+    assert_eq!(util::parse_ttl("300").unwrap(), 300);
+    assert_eq!(util::parse_ttl("30m").unwrap(), 1800);
+    assert_eq!(util::parse_ttl("1h30m").unwrap(), 5400);
+    assert_eq!(util::parse_ttl("1D").unwrap(), 86400);
+    assert_eq!(util::parse_ttl("2w1s").unwrap(), 1_209_601);
+    assert_eq!(util::parse_ttl("4294967295").unwrap(), u32::MAX);
+    for invalid in &["", "h", "1x", "1h30", "-5", "4294967296", "99999999w"] {
+        assert!(util::parse_ttl(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_human_duration() {
+    let human = |millis| util::HumanDuration(Duration::from_millis(millis)).to_string();
+    assert_eq!(human(3_600_000), "1h");
+    assert_eq!(human(330_000), "5m30s");
+    assert_eq!(human(90_061_000), "1d1h1m1s");
+    assert_eq!(human(1500), "1.5s");
+    assert_eq!(human(61_025), "1m1.025s");
+    assert_eq!(human(250), "250ms");
+    assert_eq!(human(0), "0ms");
+    assert_eq!(
+        MonitorTimeout(Duration::from_secs(120)).to_string(),
+        "timeout; update not complete within 2m"
+    );
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::parse(
+        r#"
+resolver = ["192.0.2.53", "192.0.2.54:5353"]
+transport = "tcp"
+key-file = "/etc/tdns/keys.conf"
+timeout = 120
+"#,
+    )
+    .unwrap();
+    let resolvers: Vec<_> = config.resolver.iter().map(ToString::to_string).collect();
+    assert_eq!(resolvers, vec!["192.0.2.53", "192.0.2.54:5353"]);
+    assert_eq!(config.transport, Some(Transport::Tcp));
+    assert_eq!(
+        config.key_file.unwrap().to_str(),
+        Some("/etc/tdns/keys.conf")
+    );
+    assert_eq!(config.timeout, Some(120));
+    assert_eq!(config.interval, None);
+    assert_eq!(config.common_types, None);
+    assert!(config.fallback_resolver.is_none());
+    let config = Config::parse(r#"resolver = "192.0.2.53""#).unwrap();
+    assert_eq!(config.resolver.len(), 1);
+    let config = Config::parse(r#"fallback-resolver = "192.0.2.53:5353""#).unwrap();
+    assert_eq!(
+        config.fallback_resolver.map(|name| name.to_string()),
+        Some("192.0.2.53:5353".to_owned())
+    );
+    assert!(Config::parse(r#"fallback-resolver = "bad name!""#).is_err());
+    let config = Config::parse(r#"common-types = ["a", "AAAA", "HTTPS"]"#).unwrap();
+    assert_eq!(
+        config.common_types,
+        Some(vec![
+            rr::RecordType::A,
+            rr::RecordType::AAAA,
+            rr::RecordType::HTTPS
+        ])
+    );
+    assert!(Config::parse("common-types = []").is_err());
+    assert!(Config::parse(r#"common-types = ["BOGUS"]"#).is_err());
+    assert!(Config::parse("resolvers = []").is_err());
+    assert!(Config::parse(r#"transport = "quic""#).is_err());
+    assert!(Config::parse(r#"resolver = "192.0.2.53:dns""#).is_err());
+}
+
+#[test]
+fn test_read_resolv_conf() {
+    let dir = std::env::temp_dir().join(format!("tdns-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("resolv.conf");
+    assert!(matches!(
+        util::read_resolv_conf(&path),
+        Err(util::SystemResolverError::Missing(_))
+    ));
+    std::fs::write(&path, "").unwrap();
+    assert!(matches!(
+        util::read_resolv_conf(&path),
+        Err(util::SystemResolverError::NoNameservers(_))
+    ));
+    std::fs::write(&path, "search example.org\nnameserver 192.0.2.53 # local\n").unwrap();
+    assert_eq!(
+        util::read_resolv_conf(&path).unwrap(),
+        vec!["192.0.2.53:53".parse().unwrap()]
+    );
+    std::fs::write(&path, "nameserver\n").unwrap();
+    assert!(matches!(
+        util::read_resolv_conf(&path),
+        Err(util::SystemResolverError::Parse { line: 1, .. })
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_find_zone() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, _) = mock_dns(&[]);
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let zone = runtime
+        .block_on(find_zone(
+            &resolver,
+            &"a.b.foo.example.org.".parse().unwrap(),
+        ))
+        .unwrap();
+    assert_eq!(zone, "example.org.".parse::<rr::Name>().unwrap());
+    assert!(runtime
+        .block_on(find_zone(&resolver, &"example.net.".parse().unwrap()))
+        .is_err());
+}
+
+#[test]
+fn test_make_fqdn() {
+    let relative: rr::Name = "foo.example.org".parse().unwrap();
+    let absolute: rr::Name = "foo.example.org.".parse().unwrap();
+    assert!(util::make_fqdn(&relative, false).unwrap().is_fqdn());
+    assert!(util::make_fqdn(&relative, true).is_err());
+    assert_eq!(util::make_fqdn(&absolute, true).unwrap(), absolute);
+}
+
+#[test]
+fn test_update_outside_zone() {
+    let update = update_settings(Operation::create(
+        "foo.other.net".parse().unwrap(),
+        "A:192.0.2.1".parse().unwrap(),
+    ));
+    let e = update.get_update().unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "entry foo.other.net is not within zone example.org"
+    );
+}
+
+#[cfg(feature = "tsig")]
+#[test]
+fn test_build_signed_update() {
+    let zone: rr::Name = "example.org.".parse().unwrap();
+    let operation = Operation::create(
+        "foo.example.org".parse().unwrap(),
+        "A:192.0.2.1".parse().unwrap(),
+    );
+    let key = tsig::Key::new(
+        "key.example.org.".parse().unwrap(),
+        tsig::Algorithm::HmacSha256,
+        b"secret".to_vec(),
+    );
+    let message =
+        update_message::build(&operation, &zone, rr::DNSClass::IN, 300, Some(&key)).unwrap();
+    assert_eq!(message.name_servers().len(), 1);
+    assert_eq!(message.name_servers()[0].ttl(), 300);
+    let signature = message.additionals().last().unwrap();
+    assert_eq!(signature.name(), key.name());
+    assert_eq!(signature.dns_class(), rr::DNSClass::ANY);
+
+    let unsigned = update_message::build(&operation, &zone, rr::DNSClass::IN, 300, None).unwrap();
+    assert!(unsigned.additionals().is_empty());
+
+    let e = update_message::build_unsigned(&Operation::Batch(vec![]), &zone, rr::DNSClass::IN, 300)
+        .unwrap_err();
+    assert!(matches!(e, update_message::BuildError::EmptyBatch));
+
+    let mut empty = update_message::empty(zone.clone(), rr::DNSClass::IN);
+    assert_eq!(empty.op_code(), op::OpCode::Update);
+    assert_eq!(empty.queries()[0].name(), &zone);
+    assert!(empty.answers().is_empty() && empty.name_servers().is_empty());
+    assert!(!tsig::is_signed(&empty));
+    tsig::add_signature(&mut empty, &key).unwrap();
+    let empty = op::Message::from_vec(&empty.to_vec().unwrap()).unwrap();
+    assert!(tsig::is_signed(&empty));
+}
+
+#[cfg(feature = "tsig")]
+#[test]
+fn test_tsig_server_error() {
+    fn tsig_response(error: u16, other_data: &[u8]) -> op::Message {
+        let mut rdata = Vec::new();
+        rdata.extend_from_slice(b"\x0bhmac-sha256\x00");
+        rdata.extend_from_slice(&[0, 0, 0x5f, 0x5e, 0x10, 0x00]); // time signed
+        rdata.extend_from_slice(&300u16.to_be_bytes()); // fudge
+        rdata.extend_from_slice(&0u16.to_be_bytes()); // MAC size
+        rdata.extend_from_slice(&4711u16.to_be_bytes()); // original ID
+        rdata.extend_from_slice(&error.to_be_bytes());
+        rdata.extend_from_slice(&(other_data.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(other_data);
+        let rdata = rr::RData::Unknown {
+            code: 250,
+            rdata: rr::rdata::NULL::with(rdata),
+        };
+        let mut record = rr::Record::from_rdata("key.example.org.".parse().unwrap(), 0, rdata);
+        record.set_dns_class(rr::DNSClass::ANY);
+        let mut message = op::Message::new();
+        message
+            .set_response_code(op::ResponseCode::NotAuth)
+            .add_additional(record);
+        // Round-trip through the wire format, as a received response would.
+        op::Message::from_vec(&message.to_vec().unwrap()).unwrap()
+    }
+    let e = tsig::ServerError::from_response(&tsig_response(17, &[])).unwrap();
+    assert_eq!(e.code, tsig::ServerError::BADKEY);
+    assert_eq!(e.key_name, "key.example.org.".parse::<rr::Name>().unwrap());
+    assert!(e.to_string().contains("check the key name"));
+    let e = tsig::ServerError::from_response(&tsig_response(16, &[])).unwrap();
+    assert!(e.to_string().contains("check the key secret"));
+    let e = tsig::ServerError::from_response(&tsig_response(18, &[0, 0, 0, 0, 0, 42])).unwrap();
+    assert_eq!(e.server_time, Some(42));
+    assert!(e.to_string().contains("NTP"));
+    assert!(tsig::ServerError::from_response(&tsig_response(0, &[])).is_none());
+    assert!(tsig::ServerError::from_response(&op::Message::new()).is_none());
+}
+
+#[cfg(feature = "tsig")]
+#[test]
+fn test_tsig_key_spec() {
+    let key_name: rr::Name = "key.example.org.".parse().unwrap();
+    match "key.example.org.".parse().unwrap() {
+        tsig::KeySpec::Name(name) => assert_eq!(name, key_name),
+        spec => panic!("unexpected key spec {:?}", spec),
+    }
+    match "key.example.org.:hmac-sha512:c2VjcmV0".parse().unwrap() {
+        tsig::KeySpec::Key(key) => {
+            assert_eq!(key.name(), &key_name);
+            assert_eq!(key.algorithm(), tsig::Algorithm::HmacSha512);
+        }
+        spec => panic!("unexpected key spec {:?}", spec),
+    }
+    let key = tsig::Key::from_spec(" key.example.org. : hmac-sha256 : c2VjcmV0 ").unwrap();
+    assert_eq!(key.algorithm(), tsig::Algorithm::HmacSha256);
+    for (spec, error) in [
+        ("key.example.org.", "expected NAME:ALGORITHM:KEY"),
+        ("key:hmac-sha256", "expected NAME:ALGORITHM:KEY"),
+        ("key::c2VjcmV0", "expected NAME:ALGORITHM:KEY"),
+        ("key:hmac-md4:c2VjcmV0", "invalid algorithm hmac-md4"),
+        ("key:hmac-sha256:c2VjcmV0!", "invalid key data"),
+    ] {
+        let e = tsig::Key::from_spec(spec).unwrap_err();
+        assert!(e.to_string().starts_with(error), "{}: {}", spec, e);
+    }
+    assert!("key:hmac-sha256".parse::<tsig::KeySpec>().is_err());
+
+    let file = "# Keys for example.org\n\nkey.example.org.:hmac-sha256:c2VjcmV0 # primary\n";
+    let keys = tsig::read_keys(file.as_bytes()).unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].name(), &key_name);
+    let e = tsig::read_keys("\nkey.example.org.\n".as_bytes()).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "2: expected NAME:ALGORITHM:KEY, found key.example.org."
+    );
+}
+
+#[cfg(feature = "tsig")]
+#[test]
+fn test_tsig_key_for_zone() {
+    let zone: rr::Name = "example.org.".parse().unwrap();
+    let file = "\
+other.example.:hmac-md4:c2VjcmV0
+example.net.:hmac-sha256:c2VjcmV0
+example.org.:hmac-sha512:c2VjcmV0
+broken
+";
+    let file = tsig::KeyFile::read(file.as_bytes()).unwrap();
+    let key = tsig::get_key(None, Some(&file), Some(&zone))
+        .unwrap()
+        .unwrap();
+    assert_eq!(key.name(), &zone);
+    assert_eq!(key.algorithm(), tsig::Algorithm::HmacSha512);
+    // The invalid entries are only skipped.
+    let lines: Vec<_> = file.invalid_entries().map(|(line_no, _)| line_no).collect();
+    assert_eq!(lines, [1, 4]);
+
+    let other: rr::Name = "example.com.".parse().unwrap();
+    let e = tsig::get_key(None, Some(&file), Some(&other)).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "no key for zone example.com. found; available keys: example.net., example.org."
+    );
+
+    let spec = "example.org.".parse().unwrap();
+    let e = tsig::get_key(Some(spec), Some(&file), Some(&zone)).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--key-for-zone cannot be combined with --key"
+    );
+    assert!(matches!(
+        tsig::get_key(None, None, Some(&zone)),
+        Err(tsig::GetKeyError::ZoneWithoutKeyFile)
+    ));
+
+    // Selecting an invalid entry fails, by name and as the first key.
+    let spec = "other.example.".parse().unwrap();
+    let e = tsig::get_key(Some(spec), Some(&file), None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "1: invalid algorithm hmac-md4: unknown algorithm"
+    );
+    assert!(tsig::get_key(None, Some(&file), None).is_err());
+    let spec = "example.net.".parse().unwrap();
+    let key = tsig::get_key(Some(spec), Some(&file), None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(key.algorithm(), tsig::Algorithm::HmacSha256);
+}
+
+#[test]
+fn test_update_json() {
+    let zone: rr::Name = "example.org.".parse().unwrap();
+    let json = r#"[
+        {"op": "create", "name": "foo.example.org", "type": "A", "ttl": 60, "data": "192.0.2.1"},
+        {"op": "append", "name": "bar.example.org", "type": "TXT", "data": ["a,b", "c"]},
+        {"op": "delete", "name": "baz.example.org"}
+    ]"#;
+    let operation = batch::read_json(json.as_bytes(), &zone, rr::DNSClass::IN).unwrap();
+    let mut update = update_settings(operation);
+    update.zone = zone.clone();
+    let message = update.get_update().unwrap();
+    assert_eq!(message.answers().len(), 1); // the prerequisite of the creation
+    let updates: Vec<_> = message
+        .name_servers()
+        .iter()
+        .map(|r| (r.name().to_string(), r.ttl()))
+        .collect();
+    assert_eq!(
+        updates,
+        vec![
+            ("foo.example.org.".to_owned(), 60),
+            ("bar.example.org.".to_owned(), 300),
+            ("bar.example.org.".to_owned(), 300),
+            ("baz.example.org.".to_owned(), 0),
+        ]
+    );
+
+    let json = r#"[{"op": "delete", "name": "foo.other.net"}]"#;
+    let e = batch::read_json(json.as_bytes(), &zone, rr::DNSClass::IN).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "invalid entry #1: entry foo.other.net. is not within zone example.org."
+    );
+}
+
+#[test]
+fn test_update_zonefile() {
+    let zone: rr::Name = "example.org.".parse().unwrap();
+    let text = "foo 60 IN A 192.0.2.1
+foo A 192.0.2.2
+$TTL 120
+bar TXT \"hello, world\"
+$ORIGIN sub.example.org.
+@ AAAA 2001:db8::1
+";
+    let operation =
+        batch::read_zonefile(text, &zone, rr::DNSClass::IN, 300, Operation::Append).unwrap();
+    let mut update = update_settings(operation);
+    update.zone = zone.clone();
+    let message = update.get_update().unwrap();
+    let mut updates: Vec<_> = message
+        .name_servers()
+        .iter()
+        .map(|r| (r.name().to_string(), r.record_type(), r.ttl()))
+        .collect();
+    updates.sort();
+    assert_eq!(
+        updates,
+        vec![
+            ("bar.example.org.".to_owned(), rr::RecordType::TXT, 120),
+            ("foo.example.org.".to_owned(), rr::RecordType::A, 60),
+            ("foo.example.org.".to_owned(), rr::RecordType::A, 60),
+            ("sub.example.org.".to_owned(), rr::RecordType::AAAA, 120),
+        ]
+    );
+
+    let e = batch::read_zonefile(
+        "foo.other.net. A 192.0.2.1\n",
+        &zone,
+        rr::DNSClass::IN,
+        300,
+        Operation::Create,
+    )
+    .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "entry foo.other.net. is not within zone example.org."
+    );
+}
+
+#[test]
+fn test_record_set_ttl() {
+    let data: RsData = "A:192.0.2.1".parse().unwrap();
+    let rset = RecordSet::new("foo.example.org.".parse().unwrap(), data);
+    assert_eq!(rset.ttl(), None);
+    assert_eq!(rset.to_rrset(300).ttl(), 300);
+    let rset = rset.with_ttl(60);
+    assert_eq!(rset.ttl(), Some(60));
+    assert_eq!(rset.to_rrset(300).ttl(), 60);
+
+    let zone: rr::Name = "example.org.".parse().unwrap();
+    let text = "bar A 192.0.2.3\nfoo 120 A 192.0.2.1\nfoo 60 A 192.0.2.2\n";
+    let operation =
+        batch::read_zonefile(text, &zone, rr::DNSClass::IN, 300, Operation::Create).unwrap();
+    let mut ttls: Vec<_> = match operation {
+        Operation::Batch(entries) => entries
+            .into_iter()
+            .map(|entry| match entry {
+                Operation::Create(rset) => (rset.name().to_string(), rset.ttl()),
+                operation => panic!("unexpected operation {:?}", operation),
+            })
+            .collect(),
+        operation => panic!("unexpected operation {:?}", operation),
+    };
+    ttls.sort();
+    assert_eq!(
+        ttls,
+        vec![
+            ("bar.example.org.".to_owned(), Some(300)),
+            ("foo.example.org.".to_owned(), Some(60)),
+        ]
+    );
+}
+
+#[test]
+fn test_lookup_addrs() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let rec_data: &[_] = &[
+        ("ns.example.org", "A", "192.0.2.53"),
+        ("ns.example.org", "AAAA", "2001:db8::53"),
+    ];
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let name: rr::Name = "ns.example.org".parse().unwrap();
+    let v4: IpAddr = "192.0.2.53".parse().unwrap();
+    let v6: IpAddr = "2001:db8::53".parse().unwrap();
+    let addrs = runtime
+        .block_on(resolver.lookup_addrs(name.clone(), AddressFamily::PreferIpv6))
+        .unwrap();
+    assert_eq!(addrs, vec![v6, v4]);
+    let addrs = runtime
+        .block_on(resolver.lookup_addrs(name, AddressFamily::PreferIpv4))
+        .unwrap();
+    assert_eq!(addrs, vec![v4, v6]);
+}
+
+#[test]
+fn test_resolve_addr_select() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let rec_data: &[_] = &[
+        ("ns.example.org", "A", "192.0.2.53"),
+        ("ns.example.org", "A", "192.0.2.54"),
+    ];
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let name: util::SocketName = "ns.example.org".parse().unwrap();
+    let resolve = |select| runtime.block_on(name.resolve_with(resolver.clone(), 53, select));
+    assert_eq!(
+        resolve(util::AddrSelect::Index(1)).unwrap(),
+        "192.0.2.54:53".parse().unwrap()
+    );
+    assert!(resolve(util::AddrSelect::Index(2)).is_err());
+    assert_eq!(
+        "random".parse::<util::AddrSelect>().unwrap(),
+        util::AddrSelect::Random
+    );
+}
+
+#[test]
+fn test_flatten_aname() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let rec_data: &[_] = &[
+        ("example.org", "ANAME", "a.example.net."),
+        ("a.example.net", "A", "192.0.2.1"),
+        ("a.example.net", "ANAME", "b.example.net."),
+        ("b.example.net", "AAAA", "2001:db8::1"),
+        ("b.example.net", "ANAME", "a.example.net."),
+    ];
+    dns.add_server("127.0.0.1:53".parse().unwrap(), rec_data)
+        .unwrap();
+    let resolver = dns
+        .open_resolver("127.0.0.1:53".parse().unwrap())
+        .expect("failed to open resolver");
+    let target = "a.example.net.".parse().unwrap();
+    let records = runtime
+        .block_on(query::flatten_aname(resolver, &target))
+        .unwrap();
+    let rdata: Vec<_> = records.iter().map(|r| r.rdata().clone()).collect();
+    assert_eq!(
+        rdata,
+        vec![
+            parse_rdata("A", "192.0.2.1").unwrap(),
+            parse_rdata("AAAA", "2001:db8::1").unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_raw_query() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let data: &[_] = &[
+        ("www.example.org", "A", "192.0.2.80"),
+        ("www.example.org", "AAAA", "2001:db8::80"),
+    ];
+    let addr = "192.0.2.53:53".parse().unwrap();
+    let server = dns.add_server(addr, data).unwrap();
+    let query = Query {
+        entry: "www.example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::A, rr::RecordType::AAAA],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(responses.len(), 2);
+    assert!(responses
+        .iter()
+        .all(|response| response.answers().len() == 1));
+    // Both record types are queried over a single connection.
+    let server = server.lock().unwrap();
+    assert_eq!(server.connections(), 1);
+    assert_eq!(server.query_log().len(), 2);
+}
+
+#[test]
+fn test_send_message() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let data: &[_] = &[("www.example.org.", "A", "192.0.2.80")];
+    let addr = "192.0.2.53:53".parse().unwrap();
+    let server = dns.add_server(addr, data).unwrap();
+    let mut message = op::Message::new();
+    message.add_query(op::Query::query(
+        "www.example.org.".parse().unwrap(),
+        rr::RecordType::A,
+    ));
+    let wire = message.to_vec().unwrap();
+    let response = runtime
+        .block_on(query::send_message(&runtime, dns.clone(), addr, &wire))
+        .unwrap();
+    assert_eq!(response.response_code(), op::ResponseCode::NoError);
+    let mut output = Vec::new();
+    query::write_response(&mut output, &response).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        ";; response code: No Error\nwww.example.org. 0 IN A 192.0.2.80\n"
+    );
+    let server = server.lock().unwrap();
+    assert_eq!(server.query_log()[0].queries(), message.queries());
+    drop(server);
+
+    let e = runtime
+        .block_on(query::send_message(&runtime, dns, addr, &wire[..5]))
+        .unwrap_err();
+    assert!(matches!(e, query::SendError::Parse(_)), "{}", e);
+}
+
+#[test]
+fn test_raw_exchange_hexdump() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let data: &[_] = &[("www.example.org", "A", "192.0.2.80")];
+    let addr = "192.0.2.53:53".parse().unwrap();
+    dns.add_server(addr, data).unwrap();
+    let query = Query {
+        entry: "www.example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: Some(0x1234),
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let exchanges: Vec<_> = runtime
+        .block_on(query::perform_raw_exchange(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(exchanges.len(), 1);
+    let exchange = &exchanges[0];
+    assert_eq!(exchange.name, "www.example.org.".parse().unwrap());
+    assert_eq!(exchange.record_type, rr::RecordType::A);
+    assert_eq!(exchange.response.answers().len(), 1);
+    let wire = exchange.query.to_vec().unwrap();
+    let mut dump = Vec::new();
+    query::write_hexdump(&mut dump, "query", &wire).unwrap();
+    assert_eq!(
+        String::from_utf8(dump).unwrap(),
+        "# query\n\
+         000000 12 34 01 00 00 01 00 00 00 00 00 00 03 77 77 77\n\
+         000010 07 65 78 61 6d 70 6c 65 03 6f 72 67 00 00 01 00\n\
+         000020 01\n"
+    );
+}
+
+#[test]
+fn test_raw_query_chaos() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    // The mock server ignores the class, so it stands in for a server
+    // answering in the CH class.
+    let data: &[_] = &[("version.bind.", "TXT", "9.18.24")];
+    let addr = "192.0.2.53:53".parse().unwrap();
+    let server = dns.add_server(addr, data).unwrap();
+    let query = Query {
+        entry: query::CHAOS_NAMES[0].parse().unwrap(),
+        record_types: vec![rr::RecordType::TXT],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::CH,
+        pad_block: None,
+    };
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(responses[0].answers().len(), 1);
+    let server = server.lock().unwrap();
+    let request = &server.query_log()[0];
+    assert_eq!(request.queries()[0].query_class(), rr::DNSClass::CH);
+}
+
+#[test]
+fn test_query_direct() {
+    let runtime = Runtime::new().unwrap();
+    let current: &[_] = &[("foo.example.org", "A", "192.168.1.1")];
+    let stale: &[_] = &[("foo.example.org", "A", "192.168.1.2")];
+    let (mut dns, _) = mock_dns(current);
+    let auth1 = dns
+        .add_server("199.43.135.53:53".parse().unwrap(), current)
+        .unwrap();
+    dns.add_server("199.43.133.53:53".parse().unwrap(), stale)
+        .unwrap();
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let zone = "example.org.".parse().unwrap();
+    let servers: Vec<_> = runtime
+        .block_on(authoritative_servers(
+            &runtime,
+            dns.clone(),
+            resolver,
+            &zone,
+            &["199.43.133.53".parse().unwrap()],
+        ))
+        .unwrap()
+        .servers;
+    assert_eq!(
+        servers,
+        vec![(
+            "a.iana-servers.net.".parse().unwrap(),
+            "199.43.135.53:53".parse().unwrap()
+        )]
+    );
+    let query = Query {
+        entry: "foo.example.org.".parse().unwrap(),
+        record_types: vec![rr::RecordType::A, rr::RecordType::AAAA],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let addrs = vec![
+        "199.43.135.53:53".parse().unwrap(),
+        "199.43.133.53:53".parse().unwrap(),
+    ];
+    let mut results: Vec<_> = runtime
+        .block_on(query::perform_query_direct(&runtime, dns, addrs, query).try_collect::<Vec<_>>())
+        .unwrap()
+        .into_iter()
+        .map(|result| {
+            let data: Vec<_> = result
+                .records
+                .iter()
+                .map(|record| record.rdata().to_string())
+                .collect();
+            (result.server.unwrap().to_string(), result.record_type, data)
+        })
+        .collect();
+    results.sort();
+    assert_eq!(
+        results,
+        vec![
+            (
+                "199.43.133.53:53".to_owned(),
+                rr::RecordType::A,
+                vec!["192.168.1.2".to_owned()]
+            ),
+            ("199.43.133.53:53".to_owned(), rr::RecordType::AAAA, vec![]),
+            (
+                "199.43.135.53:53".to_owned(),
+                rr::RecordType::A,
+                vec!["192.168.1.1".to_owned()]
+            ),
+            ("199.43.135.53:53".to_owned(), rr::RecordType::AAAA, vec![]),
+        ]
+    );
+    let auth1 = auth1.lock().unwrap();
+    assert!(auth1
+        .query_log()
+        .iter()
+        .all(|request| !request.recursion_desired()));
+}
+
+#[test]
+fn test_resolver_settings() {
+    let runtime = Runtime::new().unwrap();
+    // A server that never answers, so each attempt runs into the timeout.
+    let socket = runtime
+        .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut dns = UdpBackend {
+        resolver: ResolverSettings {
+            timeout: Some(TIMEOUT * 10),
+            attempts: Some(1),
+            no_cache: true,
+        },
+    };
+    let resolver = dns.open_resolver(addr).unwrap();
+    let started = std::time::Instant::now();
+    let e = runtime
+        .block_on(Resolver::lookup(
+            &resolver,
+            "foo.example.org.".parse().unwrap(),
+            rr::RecordType::A,
+        ))
+        .unwrap_err();
+    // The default timeout is several seconds.
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        started.elapsed()
+    );
+    assert!(matches!(e.kind(), ResolveErrorKind::Timeout), "{}", e);
+    drop(socket);
+}
+
+#[test]
+fn test_raw_query_fixed_id() {
+    let runtime = Runtime::new().unwrap();
+    let socket = runtime
+        .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = socket.local_addr().unwrap();
+    // Answers a single query with an empty response, echoing its ID.
+    let server = runtime.spawn(async move {
+        let mut buf = vec![0; 512];
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+        let request = op::Message::from_vec(&buf[..len]).unwrap();
+        let mut response = op::Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(op::MessageType::Response)
+            .add_queries(request.queries().to_vec());
+        socket
+            .send_to(&response.to_vec().unwrap(), peer)
+            .await
+            .unwrap();
+        request.id()
+    });
+    let query = Query {
+        entry: "www.example.org.".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: Some(4711),
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let dns = FixedIdBackend {
+        inner: MockBackend::default(),
+        tcp: false,
+    };
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].id(), 4711);
+    assert_eq!(runtime.block_on(server).unwrap(), 4711);
+
+    let mut update = update_settings(Operation::create(
+        "foo.example.org".parse().unwrap(),
+        "A:192.0.2.1".parse().unwrap(),
+    ));
+    update.message_id = Some(4711);
+    assert_eq!(update.get_update().unwrap().id(), 4711);
+}
+
+#[test]
+fn test_connector_backend() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let runtime = Runtime::new().unwrap();
+    let connected = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = std::sync::Arc::clone(&connected);
+    // Each connection is a pipe, whose other end answers a single query.
+    let dns = ConnectorBackend::new(MockBackend::default(), move |addr| {
+        log.lock().unwrap().push(addr);
+        async move {
+            let (client, mut server) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut len = [0; 2];
+                server.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0; usize::from(u16::from_be_bytes(len))];
+                server.read_exact(&mut buf).await.unwrap();
+                let request = op::Message::from_vec(&buf).unwrap();
+                let mut response = op::Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(op::MessageType::Response)
+                    .add_queries(request.queries().to_vec())
+                    .add_answer(rr::Record::from_rdata(
+                        request.queries()[0].name().clone(),
+                        300,
+                        parse_rdata("A", "192.0.2.1").unwrap(),
+                    ));
+                let data = response.to_vec().unwrap();
+                let len = u16::try_from(data.len()).unwrap();
+                server.write_all(&len.to_be_bytes()).await.unwrap();
+                server.write_all(&data).await.unwrap();
+            });
+            Ok(client)
+        }
+    });
+    let query = Query {
+        entry: "www.example.org.".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let addr = "192.0.2.53:53".parse().unwrap();
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(
+        responses[0].answers()[0].rdata(),
+        &parse_rdata("A", "192.0.2.1").unwrap()
+    );
+    assert_eq!(*connected.lock().unwrap(), vec![addr]);
+}
+
+#[test]
+fn test_write_record_ttl() {
+    let mut query = Query {
+        entry: "www.example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: true,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let record = rr::Record::from_rdata(
+        "www.example.org.".parse().unwrap(),
+        300,
+        parse_rdata("A", "192.0.2.1").unwrap(),
+    );
+    let mut output = Vec::new();
+    query.write_record(&mut output, &record).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "300 192.0.2.1");
+    query.display_format = DisplayFormat::Zone;
+    let mut output = Vec::new();
+    query.write_record(&mut output, &record).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "www.example.org. 300 IN A 192.0.2.1"
+    );
+    query.reset_ttl = Some(3600);
+    let mut output = Vec::new();
+    query.write_record(&mut output, &record).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "www.example.org. 3600 IN A 192.0.2.1"
+    );
+    assert_eq!(record.ttl(), 300);
+}
+
+#[test]
+fn test_write_record_text_types() {
+    let zone_line = |rdata: rr::RData| {
+        let record = rr::Record::from_rdata("www.example.org.".parse().unwrap(), 300, rdata);
+        let mut output = Vec::new();
+        query::write_record(&mut output, &record, DisplayFormat::Zone, None).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let unknown = |code, data: &[u8]| rr::RData::Unknown {
+        code,
+        rdata: rr::rdata::NULL::with(data.to_vec()),
+    };
+    assert_eq!(
+        zone_line(unknown(258, b"\x07app=foo\x03v=1")),
+        "www.example.org. 300 IN AVC \"app=foo\" \"v=1\""
+    );
+    assert_eq!(
+        zone_line(unknown(99, b"\x0bv=spf1 -all")),
+        "www.example.org. 300 IN SPF \"v=spf1 -all\""
+    );
+    // Malformed data of a text type is shown in the generic notation.
+    assert_eq!(
+        zone_line(unknown(258, b"\x07app")),
+        "www.example.org. 300 IN AVC \\# 4 07617070"
+    );
+    assert_eq!(
+        zone_line(unknown(4711, &[0xca, 0xfe])),
+        "www.example.org. 300 IN TYPE4711 \\# 2 CAFE"
+    );
+    assert_eq!(
+        zone_line(rr::RData::NULL(rr::rdata::NULL::new())),
+        "www.example.org. 300 IN NULL \\# 0"
+    );
+    assert_eq!(
+        zone_line(rr::RData::HINFO(rr::rdata::HINFO::new(
+            "x86_64".into(),
+            "Linux 6".into()
+        ))),
+        "www.example.org. 300 IN HINFO \"x86_64\" \"Linux 6\""
+    );
+
+    assert_eq!(
+        query::parse_record_type("avc").unwrap(),
+        rr::RecordType::Unknown(258)
+    );
+    assert_eq!(
+        query::parse_record_type("TYPE4711").unwrap(),
+        rr::RecordType::Unknown(4711)
+    );
+    assert_eq!(
+        query::parse_record_type("TYPE1").unwrap(),
+        rr::RecordType::A
+    );
+    assert!(query::parse_record_type("TYPEX").is_err());
+}
+
+#[test]
+fn test_skipped_record_types() {
+    let parse = |s: &str| {
+        query::parse_record_type(s).map_err(|error| query::InvalidRecordType {
+            name: s.into(),
+            error,
+        })
+    };
+    let types: Vec<_> = ["A", "FOO", "AAAA", "BAR"]
+        .iter()
+        .map(|s| parse(s))
+        .collect();
+    let skipped = query::skipped_record_types(&types, false).unwrap();
+    let names: Vec<_> = skipped.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, ["FOO", "BAR"]);
+    // Two of four queries count as failed, which is a partial failure.
+    let failures = QueryFailures::check(0, 2, skipped.len()).unwrap_err();
+    assert_eq!(
+        failures,
+        QueryFailures {
+            n_failed: 2,
+            total: 4
+        }
+    );
+    assert_eq!(failures.exit_code(), 2);
+    assert_eq!(
+        QueryFailures::check(2, 2, skipped.len())
+            .unwrap_err()
+            .exit_code(),
+        3
+    );
+
+    let error = query::skipped_record_types(&types, true).unwrap_err();
+    assert_eq!(error.name, "FOO");
+
+    // Without any valid type, there is nothing to query.
+    let error = query::skipped_record_types(&types[1..2], false).unwrap_err();
+    assert_eq!(error.name, "FOO");
+
+    let valid = [parse("A"), parse("MX")];
+    for &strict in &[false, true] {
+        assert!(query::skipped_record_types(&valid, strict)
+            .unwrap()
+            .is_empty());
+    }
+    assert_eq!(QueryFailures::check(0, 2, 0), Ok(()));
+}
+
+#[test]
+fn test_write_sections_skips_opt() {
+    let name: rr::Name = "www.example.org.".parse().unwrap();
+    let mut message = op::Message::new();
+    message
+        .add_answer(rr::Record::from_rdata(
+            name.clone(),
+            300,
+            parse_rdata("A", "192.0.2.1").unwrap(),
+        ))
+        .add_additional(rr::Record::from_rdata(
+            rr::Name::root(),
+            0,
+            rr::RData::OPT(rr::rdata::OPT::new(Default::default())),
+        ));
+    let mut output = Vec::new();
+    query::write_sections(&mut output, &message, DisplayFormat::Zone, None).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        ";; ANSWER SECTION:\nwww.example.org. 300 IN A 192.0.2.1\n;; ADDITIONAL SECTION:\n"
+    );
+}
+
+#[cfg(not(feature = "dnssec"))]
+#[test]
+fn test_write_dnssec_generic() {
+    use trust_dns_client::rr::{
+        dnssec::Algorithm,
+        rdata::{DNSSECRData, DNSKEY},
+    };
+
+    let dnskey = DNSKEY::new(true, true, false, Algorithm::ED25519, vec![0xca, 0xfe]);
+    let record = rr::Record::from_rdata(
+        "example.org.".parse().unwrap(),
+        3600,
+        rr::RData::DNSSEC(DNSSECRData::DNSKEY(dnskey)),
+    );
+    let mut output = Vec::new();
+    query::write_record(&mut output, &record, DisplayFormat::Zone, None).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "example.org. 3600 IN DNSKEY \\# 6 0101030FCAFE"
+    );
+}
+
+#[test]
+fn test_avc_record_set() {
+    let long = "x".repeat(300);
+    let data: RsData = format!("AVC:app=foo,\"a,b\",{}", long).parse().unwrap();
+    assert_eq!(data.record_type(), rr::RecordType::Unknown(258));
+    let rset = RecordSet::new("www.example.org.".parse().unwrap(), data);
+    let records: Vec<_> = rset
+        .iter_data()
+        .map(|rdata| rr::Record::from_rdata(rset.name().clone(), 300, rdata))
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|record| rset.contains(record.rdata())));
+    assert_eq!(RecordSet::try_from(records.as_slice()).unwrap(), rset);
+    assert_eq!(
+        rset.data().to_string(),
+        format!("AVC:\"a,b\",app=foo,{}", long)
+    );
+}
+
+#[test]
+fn test_txt_record_set() {
+    let name: rr::Name = "example.org.".parse().unwrap();
+    let txt = |strings: &[&str]| {
+        let strings = strings.iter().map(|s| s.as_bytes()).collect();
+        rr::Record::from_rdata(
+            name.clone(),
+            300,
+            rr::RData::TXT(rr::rdata::TXT::from_bytes(strings)),
+        )
+    };
+    // An SPF record split into multiple character-strings, alongside a
+    // verification token.
+    let records = vec![
+        txt(&["v=spf1 ", "include:_spf.example.com -all"]),
+        txt(&["token=abc"]),
+    ];
+    let rset = RecordSet::try_from(records.as_slice()).unwrap();
+    assert_eq!(
+        rset.data(),
+        &RsData::TXT(
+            vec![
+                "v=spf1 include:_spf.example.com -all".into(),
+                "token=abc".into()
+            ]
+            .into_iter()
+            .collect()
+        )
+    );
+    let expect = |expectation: fn(RecordSet) -> Expectation, data: &str| {
+        expectation(RecordSet::new(name.clone(), data.parse().unwrap())).satisfied_by(&records)
+    };
+    assert!(expect(Expectation::Contains, "TXT:token=abc"));
+    assert!(expect(
+        Expectation::Is,
+        "TXT:token=abc,v=spf1 include:_spf.example.com -all"
+    ));
+    assert!(!expect(Expectation::Is, "TXT:token=abc"));
+    assert!(!expect(Expectation::Contains, "TXT:token=abc,token=def"));
+    assert!(expect(Expectation::NotAny, "TXT:token=def"));
+    assert!(!expect(Expectation::NotAny, "TXT:token=def,token=abc"));
+    // Long text is split into character-strings of at most 255 bytes.
+    let long = "x".repeat(300);
+    let rset = RecordSet::new(name.clone(), format!("TXT:{}", long).parse().unwrap());
+    let rdata: Vec<_> = rset.iter_data().collect();
+    let lengths: Vec<_> = rdata[0]
+        .as_txt()
+        .unwrap()
+        .iter()
+        .map(|string| string.len())
+        .collect();
+    assert_eq!(lengths, vec![255, 45]);
+    assert!(rset.contains(&rdata[0]));
+}
+
+#[test]
+fn test_ensure_txt_split() {
+    let runtime = Runtime::new().unwrap();
+    let (mut dns, master) = mock_dns(&[("bar.example.org", "A", "192.168.1.1")]);
+    let zone = master.lock().unwrap().zone();
+    // An SPF record split into character-strings at a non-255 boundary.
+    zone.lock().unwrap().update(&rr::Record::from_rdata(
+        "foo.example.org".parse().unwrap(),
+        300,
+        rr::RData::TXT(rr::rdata::TXT::new(vec![
+            "v=spf1 ".to_owned(),
+            "-all".to_owned(),
+        ])),
+    ));
+    let resolver = runtime
+        .block_on(dns.open(runtime.handle(), "127.0.0.1:53".parse().unwrap()))
+        .expect("failed to open resolver");
+    let ensure = |data: &str| {
+        let rset = RecordSet::new("foo.example.org".parse().unwrap(), data.parse().unwrap());
+        runtime.block_on(perform_update(
+            &runtime,
+            dns.clone(),
+            resolver.clone(),
+            update_settings(Operation::Ensure(rset)),
+        ))
+    };
+    let txts = || {
+        let query = op::Query::query("foo.example.org".parse().unwrap(), rr::RecordType::TXT);
+        let records = zone.lock().unwrap().lookup(&query);
+        records
+            .iter()
+            .map(|record| record.rdata().as_txt().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // The text matches regardless of the split.
+    match ensure("TXT:v=spf1 -all").unwrap() {
+        UpdateOutcome::Ensured(changes) => assert!(changes.is_empty()),
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    }
+    // Removing the record deletes it with its original character-strings.
+    ensure("TXT:v=spf1 mx -all,token=abc").unwrap();
+    let mut found = txts();
+    found.sort();
+    assert_eq!(found, vec!["token=abc", "v=spf1 mx -all"]);
+}
+
+#[test]
+fn test_type_formats() {
+    let formats: query::DisplayFormats = "zone,a=short,AAAA=hosts".parse().unwrap();
+    assert_eq!(formats.default, Some(DisplayFormat::Zone));
+    assert_eq!(formats.by_type.len(), 2);
+    let formats: query::DisplayFormats = "SOA=zone".parse().unwrap();
+    assert_eq!(formats.default, None);
+    assert!("short,zone".parse::<query::DisplayFormats>().is_err());
+    assert!("BOGUS=zone".parse::<query::DisplayFormats>().is_err());
+    assert!("A=tabular".parse::<query::DisplayFormats>().is_err());
+    for format in ["short", "zone", "hosts", "json-rfc8427"] {
+        assert_eq!(format.parse::<DisplayFormat>().unwrap().to_string(), format);
+    }
+
+    let query = Query {
+        entry: "example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::A, rr::RecordType::SOA],
+        display_format: DisplayFormat::Short,
+        type_formats: formats.by_type,
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    assert_eq!(query.format_for(rr::RecordType::A), DisplayFormat::Short);
+    assert_eq!(query.format_for(rr::RecordType::SOA), DisplayFormat::Zone);
+    assert!(query.uses_format(DisplayFormat::Zone));
+    assert!(!query.uses_format(DisplayFormat::Hosts));
+    let record = rr::Record::from_rdata(
+        "example.org.".parse().unwrap(),
+        300,
+        parse_rdata("A", "192.0.2.1").unwrap(),
+    );
+    let mut output = Vec::new();
+    query.write_record(&mut output, &record).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "192.0.2.1");
+}
+
+#[test]
+fn test_pad_message() {
+    for name in [
+        "a.",
+        "www.example.org.",
+        "a-rather-long-label.in.some.deeply.nested.zone.example.",
+    ] {
+        for &block_size in &[1, 128, 468] {
+            let mut message = op::Message::new();
+            message.add_query(op::Query::query(name.parse().unwrap(), rr::RecordType::A));
+            assert_eq!(query::padding_len(&message), None);
+            query::pad_message(&mut message, block_size).unwrap();
+            let len = message.to_vec().unwrap().len();
+            assert_eq!(len % usize::from(block_size), 0, "{} {}", name, block_size);
+            assert!(query::padding_len(&message).unwrap() < usize::from(block_size));
+        }
+    }
+    let mut message = op::Message::new();
+    message.add_query(op::Query::query(
+        "www.example.org.".parse().unwrap(),
+        rr::RecordType::A,
+    ));
+    query::pad_message(&mut message, 128).unwrap();
+    let mut output = Vec::new();
+    query::write_padding(&mut output, &message).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        ";; PADDING: 80 bytes, size 128\n"
+    );
+}
+
+#[test]
+fn test_message_json() {
+    let mut message = op::Message::new();
+    message
+        .set_id(4711)
+        .set_message_type(op::MessageType::Response)
+        .set_recursion_desired(true)
+        .set_response_code(op::ResponseCode::NXDomain)
+        .add_query(op::Query::query(
+            "www.example.org.".parse().unwrap(),
+            rr::RecordType::A,
+        ))
+        .add_answer(rr::Record::from_rdata(
+            "www.example.org.".parse().unwrap(),
+            300,
+            parse_rdata("A", "192.0.2.1").unwrap(),
+        ))
+        .set_edns(op::Edns::new());
+    let json = query::message_json(&message).unwrap();
+    assert_eq!(json["ID"], 4711);
+    assert_eq!(json["QR"], true);
+    assert_eq!(json["RD"], true);
+    assert_eq!(json["AA"], false);
+    assert_eq!(json["RCODE"], 3);
+    assert_eq!(json["QNAME"], "www.example.org.");
+    assert_eq!(json["QTYPE"], 1);
+    assert_eq!(json["QTYPEname"], "A");
+    assert_eq!(json["ANCOUNT"], 1);
+    assert_eq!(json["ARCOUNT"], 1);
+    let answer = &json["answerRRs"][0];
+    assert_eq!(answer["TYPE"], 1);
+    assert_eq!(answer["CLASSname"], "IN");
+    assert_eq!(answer["TTL"], 300);
+    assert_eq!(answer["RDLENGTH"], 4);
+    assert_eq!(answer["RDATAHEX"], "C0000201");
+    assert_eq!(answer["rdataA"], "192.0.2.1");
+    let opt = &json["additionalRRs"][0];
+    assert_eq!(opt["TYPEname"], "OPT");
+    assert!(opt.get("rdataOPT").is_none());
+    assert!(json["authorityRRs"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn test_write_sections_hosts() {
+    let mut message = op::Message::new();
+    for (rtype, rdata) in &[
+        ("NS", "ns1.example.org."),
+        ("A", "192.0.2.1"),
+        ("A", "192.0.2.2"),
+        ("AAAA", "2001:db8::1"),
+    ] {
+        message.add_answer(rr::Record::from_rdata(
+            "web.example.org.".parse().unwrap(),
+            300,
+            parse_rdata(rtype, rdata).unwrap(),
+        ));
+    }
+    let mut output = Vec::new();
+    query::write_sections(&mut output, &message, DisplayFormat::Hosts, None).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        ";; ANSWER SECTION:\n\
+         192.0.2.1\tweb.example.org\n\
+         192.0.2.2\tweb.example.org\n\
+         2001:db8::1\tweb.example.org\n"
+    );
+    assert_eq!(
+        "hosts".parse::<DisplayFormat>().ok(),
+        Some(DisplayFormat::Hosts)
+    );
+}
+
+#[test]
+fn test_svcb_hints() {
+    use rr::rdata::svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue, SVCB};
+    let svcb = SVCB::new(
+        1,
+        rr::Name::root(),
+        vec![
+            (
+                SvcParamKey::Alpn,
+                SvcParamValue::Alpn(Alpn(vec!["h2".into(), "h3".into()])),
+            ),
+            (
+                SvcParamKey::Ipv4Hint,
+                SvcParamValue::Ipv4Hint(IpHint(vec!["192.0.2.1".parse().unwrap()])),
+            ),
+            (
+                SvcParamKey::Ipv6Hint,
+                SvcParamValue::Ipv6Hint(IpHint(vec!["2001:db8::1".parse().unwrap()])),
+            ),
+        ],
+    );
+    let record =
+        rr::Record::from_rdata("example.org.".parse().unwrap(), 300, rr::RData::HTTPS(svcb));
+    let mut output = Vec::new();
+    query::write_record(&mut output, &record, DisplayFormat::Short, None).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "1 . alpn=h2,h3 ipv4hint=192.0.2.1 ipv6hint=2001:db8::1"
+    );
+    let hints = query::svcb_hints(&record);
+    assert_eq!(hints.len(), 2);
+    assert!(hints.iter().all(|hint| hint.name() == record.name()));
+    assert_eq!(hints[0].record_type(), rr::RecordType::A);
+    assert_eq!(hints[1].record_type(), rr::RecordType::AAAA);
+}
+
+#[test]
+fn test_write_sections() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let data: &[_] = &[
+        ("example.org", "NS", "ns1.example.org."),
+        ("ns1.example.org", "A", "192.0.2.53"),
+    ];
+    let addr = "192.0.2.53:53".parse().unwrap();
+    dns.add_server(addr, data).unwrap();
+    let query = Query {
+        entry: "example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::NS],
+        display_format: DisplayFormat::Zone,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: false,
+        dnssec_ok: false,
+        checking_disabled: false,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    let mut output = Vec::new();
+    query::write_sections(&mut output, &responses[0], DisplayFormat::Zone, None).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let headers: Vec<_> = output.lines().filter(|l| l.starts_with(";;")).collect();
+    assert_eq!(
+        headers,
+        vec![";; ANSWER SECTION:", ";; ADDITIONAL SECTION:"]
+    );
+    assert!(
+        output.contains("ns1.example.org 0 IN A 192.0.2.53\n"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn test_raw_query_dnssec_flags() {
+    let runtime = Runtime::new().unwrap();
+    let mut dns = MockBackend::default();
+    let addr = "192.0.2.53:53".parse().unwrap();
+    let server = dns
+        .add_server(addr, &[("www.example.org", "A", "192.0.2.80")][..])
+        .unwrap();
+    let query = Query {
+        entry: "www.example.org".parse().unwrap(),
+        record_types: vec![rr::RecordType::A],
+        display_format: DisplayFormat::Short,
+        type_formats: Default::default(),
+        search: vec![],
+        ndots: 1,
+        origin: None,
+        recursion_desired: true,
+        dnssec_ok: true,
+        checking_disabled: true,
+        show_ttl: false,
+        reset_ttl: None,
+        message_id: None,
+        dns_class: rr::DNSClass::IN,
+        pad_block: None,
+    };
+    let responses: Vec<_> = runtime
+        .block_on(perform_raw_query(&runtime, dns, addr, query).try_collect())
+        .unwrap();
+    assert_eq!(responses.len(), 1);
+    let server = server.lock().unwrap();
+    let request = &server.query_log()[0];
+    assert!(request.checking_disabled());
+    assert!(request.edns().unwrap().dnssec_ok());
+}
+
+#[test]
+fn test_translate_dig_options() {
+    let translate = |args: &[&str]| {
+        let args = args.iter().map(Into::into).collect();
+        util::translate_dig_options(args).map(|args| {
+            args.into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(
+        translate(&[
+            "tdns",
+            "query",
+            "+tcp",
+            "+dnssec",
+            "+cd",
+            "+noshort",
+            "example.org"
+        ])
+        .unwrap(),
+        ["tdns", "query", "--tcp", "--dnssec", "--cd", "example.org"]
+    );
+    assert_eq!(
+        translate(&["tdns", "query", "+notcp", "--", "+short"]).unwrap(),
+        ["tdns", "query", "--udp", "--", "+short"]
+    );
+    // Only the arguments of the query subcommand are translated.
+    for args in &[&["tdns", "update", "+tcp"][..], &["tdns"]] {
+        assert_eq!(&translate(args).unwrap(), args);
+    }
+    let e = translate(&["tdns", "query", "+trace", "example.org"]).unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("unsupported dig-style option '+trace'; supported are: +tcp, "));
+}
+
+#[test]
+fn test_negative_answer() {
+    let mut message = trust_dns_client::op::Message::new();
+    message.add_query(trust_dns_client::op::Query::query(
+        "foo.example.org".parse().unwrap(),
+        rr::RecordType::A,
+    ));
+    message.set_response_code(trust_dns_client::op::ResponseCode::NXDomain);
+    let soa = parse_rdata(
+        "SOA",
+        "sns.dns.icann.org. noc.dns.icann.org. 2019090512 7200 3600 1209600 300",
+    )
+    .unwrap();
+    message.add_name_server(rr::Record::from_rdata(
+        "example.org".parse().unwrap(),
+        600,
+        soa,
+    ));
+    let negative = query::NegativeAnswer::from_response(&message).unwrap();
+    assert_eq!(negative.negative_ttl, Some(300));
+    assert!(negative.soa.is_some());
 }